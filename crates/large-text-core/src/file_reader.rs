@@ -1,57 +1,243 @@
+use crate::line_indexer::count_lines;
 use anyhow::Result;
-use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
-use memmap2::Mmap;
+use encoding_rs::{
+    Encoding, BIG5, EUC_KR, GBK, SHIFT_JIS, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252,
+};
+use flate2::read::MultiGzDecoder;
+use memmap2::{Mmap, MmapOptions};
+use std::borrow::Cow;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Largest decompressed size a gzip file may have; its content is held in memory.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Largest file or window mapped at once. A 32-bit address space has little
+/// more than this free, so bigger files are opened a window at a time there
+/// (`new_windowed`, then `adjacent_window` to move on); 64-bit targets have no
+/// limit. The paging is up to the caller: a reader never remaps by itself, so
+/// offsets, search and the line index only ever cover the current window.
+#[cfg(target_pointer_width = "64")]
+pub const MAX_MAPPED_LEN: usize = usize::MAX;
+#[cfg(not(target_pointer_width = "64"))]
+pub const MAX_MAPPED_LEN: usize = 1024 * 1024 * 1024;
 
 pub struct FileReader {
-    mmap: Mmap,
+    data: Backing,
     path: PathBuf,
     encoding: &'static Encoding,
+    // Byte range of the original file when opened windowed
+    window: Option<(usize, usize)>,
+    compressed: bool,
+    has_file: bool,
+}
+
+enum Backing {
+    Mapped(Mmap),
+    // Decompressed or piped-in content, or nothing for an empty file (which can't
+    // be memory-mapped)
+    Owned(Vec<u8>),
 }
 
 // 用来访问 MMAP 的底层API
 impl FileReader {
+    /// Gzip files (recognized by their magic bytes) are decompressed into memory, so
+    /// every offset, search and replacement refers to the decompressed text.
+    /// Fails for files over `MAX_MAPPED_LEN`, which need `new_windowed`.
     pub fn new(path: PathBuf, encoding: &'static Encoding) -> Result<Self> {
+        Self::new_with_map_limit(path, encoding, MAX_MAPPED_LEN)
+    }
+
+    fn new_with_map_limit(
+        path: PathBuf,
+        encoding: &'static Encoding,
+        max_mapped: usize,
+    ) -> Result<Self> {
         let file = File::open(&path)?;
         let metadata = file.metadata()?;
-        if metadata.len() == 0 {
-            anyhow::bail!("Cannot memory-map an empty file: {:?}", path);
+        let mut compressed = false;
+        let data = if metadata.len() == 0 {
+            Backing::Owned(Vec::new())
+        } else {
+            check_mappable(&path, metadata.len(), max_mapped)?;
+            let mmap = unsafe { Mmap::map(&file)? };
+            if is_gzip(&mmap) {
+                compressed = true;
+                let mut content = Vec::new();
+                read_limited(MultiGzDecoder::new(&mmap[..]), &mut content, &path)?;
+                Backing::Owned(content)
+            } else {
+                Backing::Mapped(mmap)
+            }
+        };
+
+        Ok(Self {
+            data,
+            path,
+            encoding,
+            window: None,
+            compressed,
+            has_file: true,
+        })
+    }
+
+    /// Wraps content that doesn't come from a file, such as piped-in stdin. `path()`
+    /// is `-`, as on the command line.
+    pub fn from_bytes(data: Vec<u8>, encoding: &'static Encoding) -> Self {
+        Self {
+            data: Backing::Owned(data),
+            path: PathBuf::from("-"),
+            encoding,
+            window: None,
+            compressed: false,
+            has_file: false,
+        }
+    }
+
+    /// Appends to content wrapped by [`from_bytes`](Self::from_bytes), such as
+    /// stdin that is still being read. A reader of a file can't grow this way.
+    pub fn extend_bytes(&mut self, more: &[u8]) -> Result<()> {
+        match &mut self.data {
+            Backing::Owned(bytes) if !self.has_file => {
+                bytes.extend_from_slice(more);
+                Ok(())
+            }
+            _ => anyhow::bail!("Only content from memory can be extended"),
+        }
+    }
+
+    /// Maps only the byte range `[start, end)` of the file and presents it as a standalone
+    /// document: all offsets taken by this reader are relative to `start`. `end` is clamped
+    /// to the file length. memmap2 takes care of aligning the mapping to a page boundary.
+    pub fn new_windowed(
+        path: PathBuf,
+        encoding: &'static Encoding,
+        start: usize,
+        end: usize,
+    ) -> Result<Self> {
+        let file = File::open(&path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let end = end.min(file_len);
+        if start >= end {
+            anyhow::bail!(
+                "Empty byte range {}..{} for {:?} ({} bytes)",
+                start,
+                end,
+                path,
+                file_len
+            );
+        }
+        check_mappable(&path, (end - start) as u64, MAX_MAPPED_LEN)?;
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(start as u64)
+                .len(end - start)
+                .map(&file)?
+        };
+        if start == 0 && is_gzip(&mmap) {
+            anyhow::bail!("Byte ranges of gzip-compressed files can't be opened");
         }
-        let mmap = unsafe { Mmap::map(&file)? };
 
         Ok(Self {
-            mmap,
+            data: Backing::Mapped(mmap),
             path,
             encoding,
+            window: Some((start, end)),
+            compressed: false,
+            has_file: true,
         })
     }
 
+    /// Decodes `[start, end)`. A byte order mark at the start of the content is
+    /// never part of the text, so a range overlapping it starts after it.
     pub fn get_chunk(&self, start: usize, end: usize) -> String {
-        let end = end.min(self.mmap.len());
+        self.get_chunk_str(start, end).into_owned()
+    }
+
+    /// `get_chunk` without the copy where it can be avoided: text that is
+    /// already valid in place (UTF-8, or ASCII in an ASCII-compatible
+    /// encoding) is borrowed straight from the file; anything else is decoded
+    /// into a new string.
+    pub fn get_chunk_str(&self, start: usize, end: usize) -> Cow<'_, str> {
+        let start = start.max(self.bom_len());
+        let end = end.min(self.len());
         if start >= end {
-            return String::new();
+            return "".into();
         }
+        // The BOM is skipped above, so a U+FEFF anywhere else is kept as text
+        self.encoding
+            .decode_without_bom_handling(&self.all_data()[start..end])
+            .0
+    }
 
-        let bytes = &self.mmap[start..end];
-        let (cow, _encoding, _had_errors) = self.encoding.decode(bytes);
-        cow.into_owned()
+    /// Length in bytes of `get_chunk(start, end)`, for turning file offsets
+    /// into positions within decoded text.
+    pub fn decoded_len(&self, start: usize, end: usize) -> usize {
+        self.get_chunk_str(start, end).len()
+    }
+
+    /// Length of the byte order mark the content starts with, or 0 when there
+    /// is none or it belongs to another encoding than the reader's. Readers
+    /// windowed past offset 0 never have one.
+    pub fn bom_len(&self) -> usize {
+        if self.window.is_some_and(|(start, _)| start > 0) {
+            return 0;
+        }
+        match Encoding::for_bom(self.all_data()) {
+            Some((encoding, len)) if encoding == self.encoding => len,
+            _ => 0,
+        }
     }
 
     pub fn get_bytes(&self, start: usize, end: usize) -> &[u8] {
-        let end = end.min(self.mmap.len());
+        let end = end.min(self.len());
         if start >= end {
             return &[];
         }
-        &self.mmap[start..end]
+        &self.all_data()[start..end]
     }
 
+    /// Size of the content in bytes: the decompressed size for gzip files, and
+    /// the window's size for [`new_windowed`](Self::new_windowed) readers.
     pub fn len(&self) -> usize {
-        self.mmap.len()
+        self.all_data().len()
+    }
+
+    /// Exact number of lines, counted like [`LineIndexer::total_lines`]. Scans
+    /// all the content, so a `LineIndexer` is cheaper when one is built anyway.
+    ///
+    /// [`LineIndexer::total_lines`]: crate::line_indexer::LineIndexer::total_lines
+    pub fn line_count(&self) -> usize {
+        let data = self.all_data();
+        let newlines = data.iter().filter(|&&b| b == b'\n').count();
+        count_lines(newlines, data)
+    }
+
+    /// Newlines in `[start, end)` as the text decodes. In UTF-16 only a whole
+    /// `\n` code unit counts, not a `0x0A` byte that is half of another character.
+    pub fn newlines_between(&self, start: usize, end: usize) -> usize {
+        let data = self.get_bytes(start, end);
+        let newline: &[u8] = if self.encoding == UTF_16LE {
+            b"\n\0"
+        } else if self.encoding == UTF_16BE {
+            b"\0\n"
+        } else {
+            return data.iter().filter(|&&b| b == b'\n').count();
+        };
+        // Code units are counted from an even offset
+        let data = data.get(start % 2..).unwrap_or_default();
+        data.chunks_exact(2).filter(|&unit| unit == newline).count()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.mmap.is_empty()
+        self.all_data().is_empty()
     }
 
     pub fn path(&self) -> &PathBuf {
@@ -62,32 +248,410 @@ impl FileReader {
         self.encoding
     }
 
+    /// The `[start, end)` range of the original file when opened with [`new_windowed`](Self::new_windowed).
+    pub fn window(&self) -> Option<(usize, usize)> {
+        self.window
+    }
+
+    /// For paging through a big file window by window: the range of the same
+    /// size half a window further on (or back), so a line cut off at one
+    /// window's edge is whole in the next. The last one ends at the end of the
+    /// file. `None` past either end, or when this reader isn't windowed.
+    pub fn adjacent_window(&self, forward: bool) -> Option<(usize, usize)> {
+        let (start, end) = self.window?;
+        let file_len = std::fs::metadata(&self.path).ok()?.len() as usize;
+        let size = end - start;
+        // Even, so a UTF-16 file's windows all start on a code unit
+        let step = ((size / 2) & !1).max(1);
+        let start = if forward {
+            if end >= file_len {
+                return None;
+            }
+            start + step
+        } else {
+            if start == 0 {
+                return None;
+            }
+            start.saturating_sub(step)
+        };
+        Some((start, (start + size).min(file_len)))
+    }
+
+    /// Maps an offset in this reader back to an offset in the original file.
+    pub fn to_file_offset(&self, offset: usize) -> usize {
+        self.window.map_or(0, |(start, _)| start) + offset
+    }
+
+    /// False for readers made with [`from_bytes`](Self::from_bytes), whose `path()` names no file.
+    pub fn has_file(&self) -> bool {
+        self.has_file
+    }
+
+    /// Whether the file is gzip-compressed and this reader holds its decompressed content.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
     pub fn all_data(&self) -> &[u8] {
-        &self.mmap[..]
+        match &self.data {
+            Backing::Mapped(mmap) => mmap,
+            Backing::Owned(bytes) => bytes,
+        }
+    }
+
+    /// Iterates over `(offset, bytes)` for every line, scanning for newlines lazily.
+    /// The yielded bytes include the trailing `\n` when present.
+    pub fn lines(&self) -> Lines<'_> {
+        self.lines_from(0)
+    }
+
+    /// Same as [`lines`](Self::lines) but starts at `offset`, which is assumed to be a line start.
+    pub fn lines_from(&self, offset: usize) -> Lines<'_> {
+        Lines {
+            data: self.all_data(),
+            pos: offset.min(self.len()),
+        }
+    }
+
+    /// Iterates over `(offset, text)` for every line starting at `offset`, decoded with
+    /// the reader's encoding and with the line terminator (`\n` or `\r\n`) removed.
+    pub fn decoded_lines_from(&self, offset: usize) -> impl Iterator<Item = (usize, String)> + '_ {
+        let bom_len = self.bom_len();
+        self.lines_from(offset).map(move |(start, bytes)| {
+            let bytes = &bytes[bom_len.saturating_sub(start).min(bytes.len())..];
+            let (cow, _had_errors) = self
+                .encoding
+                .decode_without_bom_handling(trim_line_ending(bytes));
+            (start, cow.into_owned())
+        })
+    }
+}
+
+pub struct Lines<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.data.len() {
+            return None;
+        }
+
+        let start = self.pos;
+        let rest = &self.data[start..];
+        let len = match rest.iter().position(|&b| b == b'\n') {
+            Some(pos) => pos + 1, // Include newline
+            None => rest.len(),
+        };
+        self.pos += len;
+
+        Some((start, &rest[..len]))
     }
 }
 
-pub fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+fn check_mappable(path: &Path, len: u64, max_mapped: usize) -> Result<()> {
+    if len > max_mapped as u64 {
+        anyhow::bail!(
+            "{:?} is too large to map at once here ({} MB at most); open it a byte range at a time",
+            path,
+            max_mapped / (1024 * 1024)
+        );
+    }
+    Ok(())
+}
+
+pub fn is_gzip(bytes: &[u8]) -> bool {
+    bytes.starts_with(&GZIP_MAGIC)
+}
+
+/// Opens `path` for streaming its content, decompressing gzip files on the fly.
+/// Also returns the content length, which for gzip is read from the trailer and
+/// is only exact below 4 GiB.
+pub fn open_content(path: &Path) -> Result<(Box<dyn Read + Send>, usize)> {
+    let (content, len, _) = open_counted_content(path)?;
+    Ok((content, len))
+}
+
+/// Like `open_content`, also counting the bytes read from the file on disk so
+/// far. For gzip that's the compressed stream, so unlike the content length it
+/// ends at exactly the file size, whatever the content decompresses to.
+pub fn open_counted_content(
+    path: &Path,
+) -> Result<(Box<dyn Read + Send>, usize, Arc<AtomicUsize>)> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut magic = [0u8; 2];
+    let is_compressed = file.read_exact(&mut magic).is_ok() && is_gzip(&magic);
+    file.seek(SeekFrom::Start(0))?;
+    let read = Arc::new(AtomicUsize::new(0));
+    if !is_compressed {
+        let file = CountingReader {
+            inner: file,
+            read: read.clone(),
+        };
+        return Ok((Box::new(file), file_len as usize, read));
+    }
+
+    // ISIZE: the uncompressed length modulo 2^32, stored in the last four bytes
+    let mut isize_bytes = [0u8; 4];
+    file.seek(SeekFrom::End(-4))?;
+    file.read_exact(&mut isize_bytes)?;
+    file.seek(SeekFrom::Start(0))?;
+    let content_len = u32::from_le_bytes(isize_bytes) as usize;
+    let file = CountingReader {
+        inner: file,
+        read: read.clone(),
+    };
+    Ok((Box::new(MultiGzDecoder::new(file)), content_len, read))
+}
+
+struct CountingReader<R> {
+    inner: R,
+    read: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Reads all of `reader` into `buf`, refusing content beyond MAX_DECOMPRESSED_SIZE
+fn read_limited(reader: impl Read, buf: &mut Vec<u8>, path: &Path) -> Result<()> {
+    reader.take(MAX_DECOMPRESSED_SIZE + 1).read_to_end(buf)?;
+    if buf.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        anyhow::bail!(
+            "{:?} decompresses to more than {} MB",
+            path,
+            MAX_DECOMPRESSED_SIZE / (1024 * 1024)
+        );
+    }
+    Ok(())
+}
+
+fn trim_line_ending(bytes: &[u8]) -> &[u8] {
+    let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+    bytes.strip_suffix(b"\r").unwrap_or(bytes)
+}
+
+/// Why `detect_encoding` settled on an encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectionReason {
+    Bom,
+    Utf8Valid,
+    HeuristicGuess,
+    Statistical,
+    Fallback,
+}
+
+impl DetectionReason {
+    pub fn description(&self) -> &'static str {
+        match self {
+            DetectionReason::Bom => "byte order mark",
+            DetectionReason::Utf8Valid => "valid UTF-8",
+            DetectionReason::HeuristicGuess => "guessed from NUL byte pattern, no BOM",
+            DetectionReason::Statistical => "guessed from character statistics, no BOM",
+            DetectionReason::Fallback => "fallback — no BOM, invalid UTF-8",
+        }
+    }
+}
+
+// Below this confidence the UI should warn that the guess may be wrong
+const UNCERTAIN_BELOW: f32 = 0.25;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DetectedEncoding {
+    pub encoding: &'static Encoding,
+    pub reason: DetectionReason,
+    /// From 0.0 to 1.0. Only guesses sit below 1.0, and the fallback is 0.0.
+    pub confidence: f32,
+}
+
+impl DetectedEncoding {
+    pub fn is_uncertain(&self) -> bool {
+        self.confidence < UNCERTAIN_BELOW
+    }
+}
+
+pub fn detect_encoding(bytes: &[u8]) -> DetectedEncoding {
+    let detected = |encoding, reason, confidence| DetectedEncoding {
+        encoding,
+        reason,
+        confidence,
+    };
+
     // Check for BOM
     if bytes.len() >= 3 && bytes[0..3] == [0xEF, 0xBB, 0xBF] {
-        return UTF_8;
+        return detected(UTF_8, DetectionReason::Bom, 1.0);
     }
     if bytes.len() >= 2 {
         if bytes[0..2] == [0xFF, 0xFE] {
-            return UTF_16LE;
+            return detected(UTF_16LE, DetectionReason::Bom, 1.0);
         }
         if bytes[0..2] == [0xFE, 0xFF] {
-            return UTF_16BE;
+            return detected(UTF_16BE, DetectionReason::Bom, 1.0);
         }
     }
 
-    // Try UTF-8 validation
-    if std::str::from_utf8(bytes).is_ok() {
-        return UTF_8;
+    // BOM-less UTF-16 text is mostly ASCII with a NUL in every other byte,
+    // which would otherwise pass as valid UTF-8
+    if let Some(encoding) = guess_utf16(bytes) {
+        return detected(encoding, DetectionReason::HeuristicGuess, 0.9);
+    }
+
+    // Try UTF-8 validation. A sample can end partway through a character,
+    // which doesn't count against text that already has multibyte ones.
+    match std::str::from_utf8(bytes) {
+        Ok(_) => return detected(UTF_8, DetectionReason::Utf8Valid, 1.0),
+        Err(e) if e.error_len().is_none() && !bytes[..e.valid_up_to()].is_ascii() => {
+            return detected(UTF_8, DetectionReason::Utf8Valid, 1.0);
+        }
+        Err(_) => {}
+    }
+
+    if let Some((encoding, confidence)) = guess_legacy(bytes) {
+        return detected(encoding, DetectionReason::Statistical, confidence);
     }
 
     // Default to WINDOWS_1252 (similar to ISO-8859-1)
-    WINDOWS_1252
+    detected(WINDOWS_1252, DetectionReason::Fallback, 0.0)
+}
+
+/// Whether `prefix`, the start of a file, looks like binary data rather than
+/// text: more than 1% of it NUL bytes. UTF-16 text, where every ASCII
+/// character comes with a NUL, doesn't count.
+pub fn is_probably_binary(prefix: &[u8]) -> bool {
+    if prefix.starts_with(&[0xFF, 0xFE]) || prefix.starts_with(&[0xFE, 0xFF]) {
+        return false;
+    }
+    if guess_utf16(prefix).is_some() {
+        return false;
+    }
+    let nuls = prefix.iter().filter(|&&b| b == 0).count();
+    nuls * 100 > prefix.len()
+}
+
+// Legacy encodings the statistical guess chooses between
+const LEGACY_CANDIDATES: [&Encoding; 5] = [SHIFT_JIS, EUC_KR, GBK, BIG5, WINDOWS_1252];
+// Fewer non-ASCII characters than this is too little to go on
+const MIN_EVIDENCE: usize = 4;
+// Best scores under this mean no candidate looks like real text
+const MIN_SCORE: f32 = 0.3;
+
+// Decodes the sample with every candidate and keeps the one whose text looks
+// most like its language. Confidence is the lead over the runner-up, so two
+// encodings that both read well (GBK and Big5 often do) give a low one.
+fn guess_legacy(bytes: &[u8]) -> Option<(&'static Encoding, f32)> {
+    let mut scores: Vec<(&'static Encoding, f32)> = LEGACY_CANDIDATES
+        .iter()
+        .filter_map(|&encoding| plausibility(encoding, bytes).map(|score| (encoding, score)))
+        .collect();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    let &(best, best_score) = scores.first()?;
+    if best_score < MIN_SCORE {
+        return None;
+    }
+    let runner_up = scores.get(1).map_or(0.0, |&(_, score)| score);
+    Some((best, (best_score - runner_up).clamp(0.0, 1.0)))
+}
+
+// How much `bytes` decoded as `encoding` looks like text in that encoding's
+// script, from 0.0 to 1.0. Decoding errors weigh heavily against it.
+fn plausibility(encoding: &'static Encoding, bytes: &[u8]) -> Option<f32> {
+    let (text, _) = encoding.decode_without_bom_handling(bytes);
+    // The sample may end partway through a character
+    let text = text.strip_suffix('\u{FFFD}').unwrap_or(&text);
+    let chars: Vec<char> = text.chars().collect();
+    let common = common_chars(encoding);
+
+    let (mut seen, mut errors, mut fits, mut frequent) = (0usize, 0usize, 0usize, 0usize);
+    for (i, &c) in chars.iter().enumerate() {
+        if c.is_ascii() {
+            continue;
+        }
+        if c == '\u{FFFD}' {
+            errors += 1;
+            continue;
+        }
+        seen += 1;
+        let prev = i.checked_sub(1).map(|p| chars[p]);
+        let next = chars.get(i + 1).copied();
+        if fits_script(encoding, c, prev, next) {
+            fits += 1;
+        }
+        if common.contains(c) {
+            frequent += 1;
+        }
+    }
+    if seen < MIN_EVIDENCE {
+        return None;
+    }
+
+    let fit = fits as f32 / seen as f32;
+    // A fair share of any real text is its most common characters
+    let frequency = (frequent as f32 * 3.0 / seen as f32).min(1.0);
+    let error_rate = errors as f32 / (seen + errors) as f32;
+    Some((0.6 * fit + 0.4 * frequency) * (1.0 - 5.0 * error_rate).max(0.0))
+}
+
+fn fits_script(
+    encoding: &'static Encoding,
+    c: char,
+    prev: Option<char>,
+    next: Option<char>,
+) -> bool {
+    let cjk_punctuation = matches!(c, '\u{3000}'..='\u{303F}' | '\u{FF00}'..='\u{FFEF}');
+    if encoding == SHIFT_JIS {
+        cjk_punctuation || matches!(c, '\u{3040}'..='\u{30FF}' | '\u{4E00}'..='\u{9FFF}')
+    } else if encoding == EUC_KR {
+        cjk_punctuation || matches!(c, '\u{AC00}'..='\u{D7A3}')
+    } else if encoding == GBK || encoding == BIG5 {
+        cjk_punctuation || matches!(c, '\u{4E00}'..='\u{9FFF}')
+    } else {
+        // Accented letters sit inside words, while CJK bytes read as
+        // Windows-1252 come out as long runs of them
+        let in_word = |n: Option<char>| n.is_some_and(|n| n.is_ascii_alphabetic());
+        (c.is_alphabetic() && (in_word(prev) || in_word(next)))
+            || matches!(
+                c,
+                '\u{2013}' | '\u{2014}' | '\u{2018}'..='\u{201D}' | '\u{2026}' | '\u{00A0}'
+            )
+    }
+}
+
+fn common_chars(encoding: &'static Encoding) -> &'static str {
+    if encoding == SHIFT_JIS {
+        "のにはをたがでてとしれさいうかるなっすまもこ。、ん"
+    } else if encoding == EUC_KR {
+        "이다의는에을를가한하고서지기로도사인있니습"
+    } else if encoding == GBK {
+        "的一是不了在人有我他这个们中来上大为和国地到以说时要就出会可也你对生能而子那得于着下"
+    } else if encoding == BIG5 {
+        "的一是不了在人有我他這個們中來上大為和國地到以說時要就出會可也你對生能而子那得於著下"
+    } else {
+        "éèàçüöäñáíóúêâôß"
+    }
+}
+
+fn guess_utf16(bytes: &[u8]) -> Option<&'static Encoding> {
+    let pairs = bytes.len() / 2;
+    if pairs < 2 {
+        return None;
+    }
+    let even_nuls = bytes.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_nuls = bytes.iter().skip(1).step_by(2).filter(|&&b| b == 0).count();
+    if even_nuls == 0 && odd_nuls * 2 >= pairs {
+        Some(UTF_16LE)
+    } else if odd_nuls == 0 && even_nuls * 2 >= pairs {
+        Some(UTF_16BE)
+    } else {
+        None
+    }
 }
 
 pub fn available_encodings() -> Vec<(&'static str, &'static Encoding)> {
@@ -95,25 +659,109 @@ pub fn available_encodings() -> Vec<(&'static str, &'static Encoding)> {
         ("UTF-8", UTF_8),
         ("UTF-16 LE", UTF_16LE),
         ("UTF-16 BE", UTF_16BE),
-        ("Windows-1252", WINDOWS_1252),
-        ("ISO-8859-1", encoding_rs::WINDOWS_1252), // Similar enough
+        // encoding_rs follows the WHATWG spec, where ISO-8859-1 is a label for
+        // Windows-1252 (they only differ in the C1 control range)
+        ("Windows-1252 / ISO-8859-1", WINDOWS_1252),
+        ("GBK", GBK),
+        ("Big5", BIG5),
+        ("Shift_JIS", SHIFT_JIS),
+        ("EUC-KR", EUC_KR),
     ]
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::line_indexer::LineIndexer;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
     #[test]
     fn test_detect_encoding() {
-        assert_eq!(detect_encoding(b"\xEF\xBB\xBFhello"), UTF_8);
-        assert_eq!(detect_encoding(b"\xFF\xFEhello"), UTF_16LE);
-        assert_eq!(detect_encoding(b"\xFE\xFFhello"), UTF_16BE);
-        assert_eq!(detect_encoding(b"hello world"), UTF_8);
+        assert_eq!(detect_encoding(b"\xEF\xBB\xBFhello").encoding, UTF_8);
+        assert_eq!(detect_encoding(b"\xFF\xFEhello").encoding, UTF_16LE);
+        assert_eq!(detect_encoding(b"\xFE\xFFhello").encoding, UTF_16BE);
+        assert_eq!(detect_encoding(b"hello world").encoding, UTF_8);
         // Invalid UTF-8 sequence
-        assert_eq!(detect_encoding(b"\xFF\xFF\xFF"), WINDOWS_1252);
+        assert_eq!(detect_encoding(b"\xFF\xFF\xFF").encoding, WINDOWS_1252);
+    }
+
+    #[test]
+    fn test_detect_encoding_reason() {
+        let reason = |bytes: &[u8]| detect_encoding(bytes).reason;
+        assert_eq!(reason(b"\xFF\xFEh\0i\0"), DetectionReason::Bom);
+        assert_eq!(reason("héllo".as_bytes()), DetectionReason::Utf8Valid);
+        assert_eq!(reason(b"caf\xE9"), DetectionReason::Fallback);
+
+        let guessed = detect_encoding(b"h\0e\0l\0l\0o\0");
+        assert_eq!(guessed.encoding, UTF_16LE);
+        assert_eq!(guessed.reason, DetectionReason::HeuristicGuess);
+        assert_eq!(detect_encoding(b"\0h\0i").encoding, UTF_16BE);
+    }
+
+    #[test]
+    fn test_is_probably_binary() {
+        assert!(!is_probably_binary(b""));
+        assert!(!is_probably_binary(b"plain text\nwith lines\n"));
+        assert!(is_probably_binary(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0"));
+        // One NUL in a long text file is tolerated
+        let mut text = "x".repeat(200).into_bytes();
+        text[100] = 0;
+        assert!(!is_probably_binary(&text));
+        // UTF-16 is full of NULs but still text
+        assert!(!is_probably_binary(b"\xFF\xFEh\0i\0"));
+        assert!(!is_probably_binary(b"h\0e\0l\0l\0o\0"));
+    }
+
+    #[test]
+    fn test_detect_legacy_encodings() {
+        let samples = [
+            (
+                SHIFT_JIS,
+                "これは日本語のテキストです。ファイルを開いて、文字コードを確かめます。",
+            ),
+            (
+                EUC_KR,
+                "이것은 한국어 텍스트 파일입니다. 인코딩 감지를 시험하고 있습니다.",
+            ),
+            (
+                GBK,
+                "这是一个简体中文的测试文件，我们在这里看看检测是不是正确的。",
+            ),
+            (
+                BIG5,
+                "這是一個繁體中文的測試檔案，我們在這裡看看偵測是不是正確的。",
+            ),
+            (
+                WINDOWS_1252,
+                "Café crème, naïve façade — déjà vu à la française.",
+            ),
+        ];
+        for (encoding, text) in samples {
+            let (bytes, _, _) = encoding.encode(text);
+            let detected = detect_encoding(&bytes);
+            assert_eq!(detected.encoding, encoding, "{}", text);
+            assert_eq!(detected.reason, DetectionReason::Statistical);
+            assert!(!detected.is_uncertain(), "{} {}", text, detected.confidence);
+        }
+
+        // Cut partway through a character, the sample still reads the same
+        let (bytes, _, _) = SHIFT_JIS.encode("日本語のテキストです");
+        assert_eq!(
+            detect_encoding(&bytes[..bytes.len() - 1]).encoding,
+            SHIFT_JIS
+        );
+        let utf8 = "日本語のテキストです".as_bytes();
+        assert_eq!(
+            detect_encoding(&utf8[..utf8.len() - 1]).reason,
+            DetectionReason::Utf8Valid
+        );
+
+        // Too little to go on, or noise, stays on the fallback
+        let fallback = detect_encoding(b"caf\xE9");
+        assert_eq!(fallback.reason, DetectionReason::Fallback);
+        assert!(fallback.is_uncertain());
+        assert!(!detect_encoding(b"hello").is_uncertain());
     }
 
     #[test]
@@ -131,12 +779,268 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_get_chunk_str_borrows_valid_text() {
+        let reader = FileReader::from_bytes("héllo\nwörld\n".as_bytes().to_vec(), UTF_8);
+        assert!(matches!(reader.get_chunk_str(0, 6), Cow::Borrowed("héllo")));
+
+        // Invalid UTF-8 is decoded lossily into a new string
+        let reader = FileReader::from_bytes(b"ab\xffcd".to_vec(), UTF_8);
+        let text = reader.get_chunk_str(0, 5);
+        assert!(matches!(text, Cow::Owned(_)));
+        assert_eq!(text, "ab\u{fffd}cd");
+
+        // Legacy encodings borrow ASCII and decode the rest
+        let reader = FileReader::from_bytes(b"caf\xe9".to_vec(), WINDOWS_1252);
+        assert!(matches!(reader.get_chunk_str(0, 3), Cow::Borrowed("caf")));
+        assert_eq!(reader.get_chunk_str(0, 4), "café");
+    }
+
+    #[test]
+    fn test_bom_is_not_text() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "\u{feff}first\nsecond \u{feff}\n")?;
+        let reader = FileReader::new(file.path().to_path_buf(), UTF_8)?;
+        assert_eq!(reader.bom_len(), 3);
+
+        let mut indexer = LineIndexer::new();
+        indexer.index_file(&reader);
+        let (start, end) = indexer.get_line_range(0).unwrap();
+        assert_eq!(start, 3);
+        assert_eq!(reader.get_chunk(start, end), "first\n");
+        // Ranges overlapping the BOM start after it, and offsets stay aligned
+        assert_eq!(reader.get_chunk(0, 8), "first");
+        assert_eq!(reader.decoded_len(0, 8), 5);
+        assert_eq!(reader.decoded_lines_from(0).next().unwrap().1, "first");
+        // U+FEFF past the start is text like any other
+        assert_eq!(reader.get_chunk(9, 19), "second \u{feff}");
+
+        // A BOM for another encoding than the reader's is left alone
+        let other = FileReader::new(file.path().to_path_buf(), WINDOWS_1252)?;
+        assert_eq!(other.bom_len(), 0);
+        assert!(other.get_chunk(0, 8).starts_with("ï»¿"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lines() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "one\r\ntwo\n\nthree")?;
+        let path = file.path().to_path_buf();
+
+        let reader = FileReader::new(path, UTF_8)?;
+        let lines: Vec<_> = reader.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                (0, &b"one\r\n"[..]),
+                (5, &b"two\n"[..]),
+                (9, &b"\n"[..]),
+                (10, &b"three"[..]),
+            ]
+        );
+
+        let decoded: Vec<_> = reader.decoded_lines_from(5).collect();
+        assert_eq!(
+            decoded,
+            vec![
+                (5, "two".to_string()),
+                (9, String::new()),
+                (10, "three".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_decoded_lines_honor_encoding() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"caf\xE9\nna\xEFve")?;
+        let path = file.path().to_path_buf();
+
+        let reader = FileReader::new(path, WINDOWS_1252)?;
+        let decoded: Vec<_> = reader.decoded_lines_from(0).map(|(_, l)| l).collect();
+        assert_eq!(decoded, vec!["café", "naïve"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_available_encodings_decode_their_text() {
+        let encodings = available_encodings();
+        for (i, &(name, encoding)) in encodings.iter().enumerate() {
+            assert!(
+                encodings[..i].iter().all(|&(_, e)| e != encoding),
+                "{} listed twice",
+                name
+            );
+        }
+
+        // Each legacy CJK encoding round-trips its own script
+        for (encoding, line) in [
+            (GBK, "中文日志"),
+            (BIG5, "中文日誌"),
+            (SHIFT_JIS, "日本語のログ"),
+            (EUC_KR, "한국어 로그"),
+        ] {
+            let reader = FileReader::from_bytes(encoding.encode(line).0.into_owned(), encoding);
+            assert_eq!(
+                reader.get_chunk(0, reader.len()),
+                line,
+                "{}",
+                encoding.name()
+            );
+        }
+    }
+
+    #[test]
+    fn test_windowed_reader() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "{}", "0123456789".repeat(1000))?;
+        let path = file.path().to_path_buf();
+
+        // Unaligned start, end past EOF gets clamped
+        let reader = FileReader::new_windowed(path.clone(), UTF_8, 4097, 20_000)?;
+        assert_eq!(reader.len(), 10_000 - 4097);
+        assert_eq!(reader.get_chunk(0, 3), "789");
+        assert_eq!(reader.window(), Some((4097, 10_000)));
+        assert_eq!(reader.to_file_offset(3), 4100);
+
+        assert!(FileReader::new_windowed(path, UTF_8, 10_000, 20_000).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjacent_windows() -> Result<()> {
+        let text: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&text)?;
+        let path = file.path().to_path_buf();
+
+        // A file over the limit can only be opened windowed
+        let limited = FileReader::new_with_map_limit(path.clone(), UTF_8, 4000);
+        assert!(limited.is_err_and(|e| e.to_string().contains("too large")));
+        assert!(FileReader::new(path.clone(), UTF_8)?
+            .adjacent_window(true)
+            .is_none());
+
+        let mut windows = vec![(0, 4000)];
+        let mut reader = FileReader::new_windowed(path.clone(), UTF_8, 0, 4000)?;
+        while let Some((start, end)) = reader.adjacent_window(true) {
+            windows.push((start, end));
+            reader = FileReader::new_windowed(path.clone(), UTF_8, start, end)?;
+            // Every window sees the file's bytes, up to its edges
+            assert_eq!(reader.get_bytes(0, usize::MAX), &text[start..end]);
+            assert_eq!(reader.to_file_offset(reader.len() - 1), end - 1);
+        }
+        assert_eq!(
+            windows,
+            vec![(0, 4000), (2000, 6000), (4000, 8000), (6000, 10_000)]
+        );
+        assert_eq!(reader.adjacent_window(false), Some((4000, 8000)));
+
+        let first = FileReader::new_windowed(path.clone(), UTF_8, 0, 4000)?;
+        assert_eq!(first.adjacent_window(false), None);
+
+        // Half of an odd-sized half window is rounded down to stay even
+        let odd = FileReader::new_windowed(path, UTF_8, 0, 4002)?;
+        assert_eq!(odd.adjacent_window(true), Some((2000, 6002)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gzip_file() -> Result<()> {
+        let text = "first line\nsecond line\n".repeat(100);
+        let mut file = NamedTempFile::new()?;
+        let mut encoder = flate2::write::GzEncoder::new(&mut file, flate2::Compression::default());
+        encoder.write_all(text.as_bytes())?;
+        encoder.finish()?;
+        let path = file.path().to_path_buf();
+
+        let reader = FileReader::new(path.clone(), UTF_8)?;
+        assert!(reader.is_compressed());
+        assert_eq!(reader.len(), text.len());
+        assert_eq!(reader.get_chunk(11, 17), "second");
+        assert_eq!(reader.lines().count(), 200);
+
+        let (mut content, len) = open_content(&path)?;
+        let mut streamed = String::new();
+        content.read_to_string(&mut streamed)?;
+        assert_eq!(streamed, text);
+        assert_eq!(len, text.len());
+
+        assert!(FileReader::new_windowed(path, UTF_8, 0, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_count() {
+        for (text, lines) in [
+            ("", 0),
+            ("one", 1),
+            ("one\n", 1),
+            ("one\ntwo", 2),
+            ("\n\n", 2),
+        ] {
+            let reader = FileReader::from_bytes(text.as_bytes().to_vec(), UTF_8);
+            assert_eq!(reader.line_count(), lines, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_newlines_between() {
+        let reader = FileReader::from_bytes(b"a\nb\n".to_vec(), UTF_8);
+        assert_eq!(reader.newlines_between(0, 4), 2);
+        assert_eq!(reader.newlines_between(2, 3), 0);
+
+        // "\u{10A}\n" in UTF-16 LE: the first character's low byte is 0x0A
+        let reader = FileReader::from_bytes(b"\x0A\x01\x0A\x00".to_vec(), UTF_16LE);
+        assert_eq!(reader.newlines_between(0, 4), 1);
+        assert_eq!(reader.newlines_between(1, 4), 1);
+        let reader = FileReader::from_bytes(b"\x0A\x01\x00\x0A".to_vec(), UTF_16BE);
+        assert_eq!(reader.newlines_between(0, 4), 1);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let reader = FileReader::from_bytes(b"caf\xE9\nline 2".to_vec(), WINDOWS_1252);
+        assert!(!reader.has_file());
+        assert_eq!(reader.len(), 11);
+        assert_eq!(reader.get_chunk(0, 4), "café");
+        assert_eq!(reader.get_bytes(5, 11), b"line 2");
+        assert_eq!(reader.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_extend_bytes() -> Result<()> {
+        let mut reader = FileReader::from_bytes(Vec::new(), UTF_8);
+        reader.extend_bytes(b"first\nsec")?;
+        reader.extend_bytes(b"ond\n")?;
+        assert_eq!(reader.get_chunk(0, reader.len()), "first\nsecond\n");
+
+        let mut file = NamedTempFile::new()?;
+        write!(file, "on disk")?;
+        let mut reader = FileReader::new(file.path().to_path_buf(), UTF_8)?;
+        assert!(reader.extend_bytes(b"more").is_err());
+        assert_eq!(reader.len(), 7);
+        Ok(())
+    }
+
     #[test]
     fn test_empty_file() -> Result<()> {
         let file = NamedTempFile::new()?;
         let path = file.path().to_path_buf();
-        let result = FileReader::new(path, UTF_8);
-        assert!(result.is_err());
+        let reader = FileReader::new(path, UTF_8)?;
+        assert_eq!(reader.len(), 0);
+        assert!(reader.is_empty());
+        assert!(reader.all_data().is_empty());
+        assert_eq!(reader.get_bytes(0, 10), b"");
+        assert_eq!(reader.get_chunk(0, 10), "");
+        assert_eq!(reader.lines().count(), 0);
+
+        let mut indexer = crate::line_indexer::LineIndexer::new();
+        indexer.index_file(&reader);
+        assert_eq!(indexer.total_lines(), 0);
+        assert_eq!(indexer.get_line_range(0), None);
         Ok(())
     }
 }
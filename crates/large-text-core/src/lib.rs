@@ -1,5 +1,16 @@
+//! Backend of the Large Text Viewer: memory-mapped reading, line indexing,
+//! search and replace for files too big to load whole. None of it depends on
+//! the GUI; the main types are re-exported here.
+
 pub mod file_reader;
 pub mod line_indexer;
+pub mod multi_file_search;
 pub mod replacer;
 pub mod search_engine;
-// mod 声明导入的模块
\ No newline at end of file
+// mod 声明导入的模块
+
+pub use file_reader::{detect_encoding, FileReader};
+pub use line_indexer::LineIndexer;
+pub use multi_file_search::MultiFileSearch;
+pub use replacer::Replacer;
+pub use search_engine::{RegexFlags, SearchEngine, SearchResult};
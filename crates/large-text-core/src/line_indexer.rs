@@ -1,5 +1,8 @@
 use crate::file_reader::FileReader;
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+#[derive(Clone)]
 pub struct LineIndexer {
     line_offsets: Vec<usize>,
     total_lines: usize,
@@ -8,6 +11,11 @@ pub struct LineIndexer {
     sample_interval: usize,
     file_size: usize,
     avg_line_length: f64,
+    // With a memory budget only every `line_stride`-th line start is stored
+    memory_budget: Option<usize>,
+    // Stride asked for directly, taking precedence over the budget
+    fixed_stride: Option<usize>,
+    line_stride: usize,
 }
 
 impl Default for LineIndexer {
@@ -25,52 +33,214 @@ impl LineIndexer {
             sample_interval: 0,
             file_size: 0,
             avg_line_length: 80.0,
+            memory_budget: None,
+            fixed_stride: None,
+            line_stride: 1,
         }
     }
 
+    /// Caps the offset table at roughly `budget` bytes. Files are then indexed
+    /// exactly, but only every Nth line start is kept, with N chosen from the
+    /// estimated line count. `None` restores the default full/sparse behavior.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+
+    /// Keeps only every `stride`-th line start, whatever the file size, so the
+    /// offset table holds about `lines / stride` entries. Lines in between are
+    /// found by scanning forward from the nearest stored one, which needs
+    /// [`get_line_with_reader`](Self::get_line_with_reader). Takes precedence
+    /// over a memory budget; `None` goes back to it.
+    pub fn set_line_stride(&mut self, stride: Option<usize>) {
+        self.fixed_stride = stride.map(|stride| stride.max(1));
+    }
+
     pub fn index_file(&mut self, reader: &FileReader) {
+        // Can only fail when cancelled, which never happens here
+        let _ =
+            self.index_file_with_progress(reader, &AtomicUsize::new(0), &AtomicBool::new(false));
+    }
+
+    /// Same as [`index_file`](Self::index_file), but stores the number of bytes scanned so far
+    /// in `progress` and stops when `cancel` is set. A cancelled run returns an error and
+    /// leaves the indexer empty rather than half-built.
+    pub fn index_file_with_progress(
+        &mut self,
+        reader: &FileReader,
+        progress: &AtomicUsize,
+        cancel: &AtomicBool,
+    ) -> Result<()> {
+        // The first line starts after any byte order mark
         self.line_offsets.clear();
-        self.line_offsets.push(0);
+        self.line_offsets.push(reader.bom_len());
         self.file_size = reader.len();
+        self.indexed = false;
+        self.line_stride = 1;
+        progress.store(0, Ordering::Relaxed);
 
         // For small files (< 10MB), do full indexing
         // For large files, use sparse sampling only
         const FULL_INDEX_THRESHOLD: usize = 10_000_000; // 10 MB
 
-        if self.file_size <= FULL_INDEX_THRESHOLD {
+        let stride = self.fixed_stride.or_else(|| {
+            self.memory_budget
+                .map(|budget| stride_for_budget(reader.all_data(), budget))
+        });
+        let completed = if let Some(stride) = stride {
+            self.sample_interval = 0;
+            self.line_stride = stride;
+            self.strided_index(reader.all_data(), progress, cancel)
+        } else if self.file_size <= FULL_INDEX_THRESHOLD {
             // Full indexing for smaller files
             let data = reader.all_data();
-            self.full_index(data);
             self.sample_interval = 0;
+            self.full_index(data, progress, cancel)
         } else {
             // Sparse sampling for large files - only sample at intervals
-            self.sparse_sample_index(reader);
+            self.sparse_sample_index(reader, progress, cancel)
+        };
+
+        if !completed {
+            *self = Self::new();
+            anyhow::bail!("Indexing cancelled");
         }
 
         self.total_lines = if self.sample_interval > 0 {
             // Estimate total lines based on sampling
             self.estimate_total_lines()
+        } else if self.line_stride > 1 {
+            self.total_lines
         } else {
-            self.line_offsets.len()
+            count_lines(self.line_offsets.len() - 1, reader.all_data())
         };
 
         self.indexed = true;
+        Ok(())
     }
 
-    fn full_index(&mut self, data: &[u8]) {
-        for (i, &byte) in data.iter().enumerate() {
+    /// Extends the index after the file grew, scanning only the bytes from
+    /// `from_offset` on. `from_offset` must be the length indexed last time
+    /// (the old file size); anything else falls back to a full re-index.
+    /// A strided index keeps its stride, so a fresh `index_file` re-applies
+    /// the memory budget to the new size.
+    pub fn append_index(&mut self, reader: &FileReader, from_offset: usize) {
+        if !self.indexed || from_offset != self.file_size || from_offset > reader.len() {
+            self.index_file(reader);
+            return;
+        }
+
+        let new_bytes = reader.get_bytes(from_offset, reader.len());
+        self.file_size = reader.len();
+
+        if self.sample_interval > 0 {
+            // Sparse index: nothing to extend, just re-estimate
+            self.total_lines = self.estimate_total_lines();
+            return;
+        }
+
+        // Newlines seen so far: every line but an unterminated last one
+        let old_tail = reader.get_bytes(from_offset.saturating_sub(1), from_offset);
+        let mut line = self.total_lines - count_lines(0, old_tail);
+        for (i, &byte) in new_bytes.iter().enumerate() {
+            if byte == b'\n' {
+                line += 1;
+                if line.is_multiple_of(self.line_stride) {
+                    self.line_offsets.push(from_offset + i + 1);
+                }
+            }
+        }
+        self.total_lines = count_lines(line, reader.all_data());
+    }
+
+    /// Indexes only the lines that end within the first `max_bytes`, so the top of
+    /// a big file can be shown while [`index_file_with_progress`](Self::index_file_with_progress)
+    /// works through the rest elsewhere. The index is exact but partial: a line cut off
+    /// at `max_bytes` isn't counted, and [`append_index`](Self::append_index) does a
+    /// full index until a complete one replaces it.
+    pub fn index_head(&mut self, reader: &FileReader, max_bytes: usize) {
+        let data = reader.all_data();
+        let head = &data[..max_bytes.min(data.len())];
+        self.line_offsets.clear();
+        self.line_offsets.push(reader.bom_len());
+        self.sample_interval = 0;
+        self.line_stride = 1;
+        for (i, &byte) in head.iter().enumerate() {
             if byte == b'\n' {
                 self.line_offsets.push(i + 1);
             }
         }
+        let newlines = self.line_offsets.len() - 1;
+        self.file_size = head.len();
+        self.indexed = head.len() == data.len();
+        self.total_lines = if self.indexed {
+            count_lines(newlines, data)
+        } else {
+            newlines
+        };
+    }
+
+    // Returns false if cancelled
+    fn full_index(&mut self, data: &[u8], progress: &AtomicUsize, cancel: &AtomicBool) -> bool {
+        const PROGRESS_BLOCK: usize = 1024 * 1024; // 1 MB
+
+        for (block_idx, block) in data.chunks(PROGRESS_BLOCK).enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let block_start = block_idx * PROGRESS_BLOCK;
+            for (i, &byte) in block.iter().enumerate() {
+                if byte == b'\n' {
+                    self.line_offsets.push(block_start + i + 1);
+                }
+            }
+            progress.store(block_start + block.len(), Ordering::Relaxed);
+        }
+        true
+    }
+
+    // Like full_index, but keeps only every `line_stride`-th line start.
+    // Returns false if cancelled
+    fn strided_index(&mut self, data: &[u8], progress: &AtomicUsize, cancel: &AtomicBool) -> bool {
+        if self.line_stride == 1 {
+            return self.full_index(data, progress, cancel);
+        }
+
+        const PROGRESS_BLOCK: usize = 1024 * 1024; // 1 MB
+        let mut line: usize = 0;
+
+        for (block_idx, block) in data.chunks(PROGRESS_BLOCK).enumerate() {
+            if cancel.load(Ordering::Relaxed) {
+                return false;
+            }
+
+            let block_start = block_idx * PROGRESS_BLOCK;
+            for (i, &byte) in block.iter().enumerate() {
+                if byte == b'\n' {
+                    line += 1;
+                    if line.is_multiple_of(self.line_stride) {
+                        self.line_offsets.push(block_start + i + 1);
+                    }
+                }
+            }
+            progress.store(block_start + block.len(), Ordering::Relaxed);
+        }
+        self.total_lines = count_lines(line, data);
+        true
     }
 
-    fn sparse_sample_index(&mut self, reader: &FileReader) {
+    // Returns false if cancelled
+    fn sparse_sample_index(
+        &mut self,
+        reader: &FileReader,
+        progress: &AtomicUsize,
+        cancel: &AtomicBool,
+    ) -> bool {
         // Only sample every 10MB for large files - creates sparse checkpoint index
         const SPARSE_SAMPLE_SIZE: usize = 10_000_000; // 10MB
         self.sample_interval = SPARSE_SAMPLE_SIZE;
 
-        let mut pos = 0;
+        let mut pos = self.line_offsets[0];
         //TODO change this for bigger
         let sample_count_limit = 100; // Limit to 100 samples max
         let mut sample_count = 0;
@@ -80,6 +250,10 @@ impl LineIndexer {
 
         // Sample a few chunks to estimate average line length
         while pos < self.file_size && sample_count < sample_count_limit {
+            if cancel.load(Ordering::Relaxed) {
+                return false;
+            }
+
             let chunk_end = (pos + SPARSE_SAMPLE_SIZE).min(self.file_size);
             let chunk = reader.get_bytes(pos, chunk_end);
 
@@ -96,11 +270,15 @@ impl LineIndexer {
 
             pos = chunk_end;
             sample_count += 1;
+            progress.store(pos, Ordering::Relaxed);
         }
 
         if total_newlines_found > 0 {
             self.avg_line_length = total_bytes_sampled as f64 / total_newlines_found as f64;
         }
+        // Checkpoints stop after the sample limit; the remainder is estimated
+        progress.store(self.file_size, Ordering::Relaxed);
+        true
     }
 
     fn estimate_total_lines(&self) -> usize {
@@ -111,10 +289,28 @@ impl LineIndexer {
         }
     }
 
+    /// The `[start, end)` byte range of `line_num`, from the index alone. Only
+    /// exact with a full index: under a line stride it is `None` between
+    /// checkpoints and `(start, usize::MAX)` on them, and a sparse index gives
+    /// an estimated start. [`get_line_with_reader`](Self::get_line_with_reader)
+    /// is exact in every mode.
     pub fn get_line_range(&self, line_num: usize) -> Option<(usize, usize)> {
+        if self.line_stride > 1 {
+            // Lines between checkpoints need a reader, see get_line_with_reader
+            if !line_num.is_multiple_of(self.line_stride) {
+                return None;
+            }
+            if line_num >= self.total_lines {
+                return None;
+            }
+            let checkpoint = line_num / self.line_stride;
+            let start = *self.line_offsets.get(checkpoint)?;
+            return Some((start, usize::MAX));
+        }
+
         if self.sample_interval == 0 {
             // Full index available
-            if line_num >= self.line_offsets.len() {
+            if line_num >= self.total_lines {
                 return None;
             }
 
@@ -137,6 +333,23 @@ impl LineIndexer {
     // 估计位置->该行range
     // Helper method to get actual line content by scanning from estimated position
     pub fn get_line_with_reader(&self, line_num: usize, reader: &FileReader, ) -> Option<(usize, usize)> {
+        if self.line_stride > 1 {
+            // Walk forward from the nearest checkpoint
+            if line_num >= self.total_lines {
+                return None;
+            }
+            let data = reader.all_data();
+            let mut start = *self.line_offsets.get(line_num / self.line_stride)?;
+            for _ in 0..line_num % self.line_stride {
+                start += data[start..].iter().position(|&b| b == b'\n')? + 1;
+            }
+            let end = data[start..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(usize::MAX, |pos| start + pos + 1);
+            return Some((start, end));
+        }
+
         if self.sample_interval == 0 {
             // Use full index
             return self.get_line_range(line_num);
@@ -197,6 +410,20 @@ impl LineIndexer {
 
     // 也是根据offset 估计 line啊
     pub fn find_line_at_offset(&self, offset: usize) -> usize {
+        if self.line_stride > 1 {
+            // Without a reader, interpolate within the checkpoint's span
+            let checkpoint = self.checkpoint_before(offset);
+            let span_start = self.line_offsets[checkpoint];
+            let span_end = self
+                .line_offsets
+                .get(checkpoint + 1)
+                .copied()
+                .unwrap_or(self.file_size);
+            let span_len = span_end.saturating_sub(span_start).max(1);
+            let within = offset.saturating_sub(span_start) * self.line_stride / span_len;
+            return checkpoint * self.line_stride + within.min(self.line_stride - 1);
+        }
+
         if self.sample_interval == 0 {
             // Full index
             match self.line_offsets.binary_search(&offset) {
@@ -213,9 +440,64 @@ impl LineIndexer {
         }
     }
 
+    /// Exact version of [`find_line_at_offset`](Self::find_line_at_offset) for
+    /// strided indexes, counting newlines from the nearest checkpoint.
+    pub fn find_line_at_offset_with_reader(&self, offset: usize, reader: &FileReader) -> usize {
+        if self.line_stride == 1 {
+            return self.find_line_at_offset(offset);
+        }
+        let checkpoint = self.checkpoint_before(offset);
+        let skipped = reader.get_bytes(self.line_offsets[checkpoint], offset);
+        checkpoint * self.line_stride + skipped.iter().filter(|&&b| b == b'\n').count()
+    }
+
+    fn checkpoint_before(&self, offset: usize) -> usize {
+        match self.line_offsets.binary_search(&offset) {
+            Ok(idx) => idx,
+            Err(idx) => idx.saturating_sub(1),
+        }
+    }
+
+    /// Lines as a user sees them: every `\n` ends a line, and text after the last
+    /// `\n` forms one more line. An empty file has no lines, and a final newline
+    /// doesn't start an extra empty one. Estimated for sparse indexes.
     pub fn total_lines(&self) -> usize {
         self.total_lines
     }
+
+    /// Whether `total_lines` is estimated from samples rather than counted.
+    pub fn is_estimated(&self) -> bool {
+        self.sample_interval > 0
+    }
+
+    /// Number of lines between stored offsets (1 means every line is indexed).
+    pub fn line_stride(&self) -> usize {
+        self.line_stride
+    }
+
+    /// Memory used by the offset table, in bytes.
+    pub fn index_size_bytes(&self) -> usize {
+        self.line_offsets.len() * std::mem::size_of::<usize>()
+    }
+}
+
+// `newlines` plus one for the text after the last newline, if any
+pub(crate) fn count_lines(newlines: usize, data: &[u8]) -> usize {
+    newlines + usize::from(data.last().is_some_and(|&b| b != b'\n'))
+}
+
+// stride = max(1, estimated_line_count * 8 / budget), estimating the line count
+// from the first 10 MB
+fn stride_for_budget(data: &[u8], budget: usize) -> usize {
+    const ESTIMATE_SAMPLE: usize = 10_000_000;
+    let sample = &data[..data.len().min(ESTIMATE_SAMPLE)];
+    if sample.is_empty() {
+        return 1;
+    }
+    let newlines = sample.iter().filter(|&&b| b == b'\n').count().max(1);
+    let estimated_lines =
+        data.len() / sample.len() * newlines + data.len() % sample.len() * newlines / sample.len();
+    (estimated_lines * std::mem::size_of::<usize>() / budget.max(1)).max(1)
 }
 
 #[cfg(test)]
@@ -231,7 +513,7 @@ mod tests {
         write!(file, "Line 1\nLine 2\nLine 3")?;
         let path = file.path().to_path_buf();
 
-        let reader = FileReader::new(path, detect_encoding(b""))?;
+        let reader = FileReader::new(path, detect_encoding(b"").encoding)?;
         let mut indexer = LineIndexer::new();
         indexer.index_file(&reader);
 
@@ -240,17 +522,218 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_index_with_progress() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        let line = "x".repeat(99) + "\n";
+        write!(file, "{}", line.repeat(50_000))?; // ~5 MB, fully indexed
+        let path = file.path().to_path_buf();
+
+        let reader = FileReader::new(path, detect_encoding(b"").encoding)?;
+        let progress = AtomicUsize::new(0);
+        let cancel = AtomicBool::new(false);
+
+        let mut indexer = LineIndexer::new();
+        indexer.index_file_with_progress(&reader, &progress, &cancel)?;
+        assert_eq!(progress.load(Ordering::Relaxed), reader.len());
+        assert_eq!(indexer.get_line_range(49_999), Some((4_999_900, 5_000_000)));
+
+        cancel.store(true, Ordering::Relaxed);
+        let mut cancelled = LineIndexer::new();
+        assert!(cancelled
+            .index_file_with_progress(&reader, &progress, &cancel)
+            .is_err());
+        assert_eq!(cancelled.total_lines(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_head() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "a\nbb\nccc\ndddd")?;
+        let reader = FileReader::new(file.path().to_path_buf(), detect_encoding(b"").encoding)?;
+
+        // "ccc" is cut off at byte 7, so only two lines are known
+        let mut indexer = LineIndexer::new();
+        indexer.index_head(&reader, 7);
+        assert_eq!(indexer.total_lines(), 2);
+        assert_eq!(indexer.get_line_range(1), Some((2, 5)));
+        assert_eq!(indexer.get_line_range(2), None);
+
+        // A head covering the whole file is the full index
+        indexer.index_head(&reader, 100);
+        let mut full = LineIndexer::new();
+        full.index_file(&reader);
+        assert_eq!(indexer.total_lines(), 4);
+        assert_eq!(indexer.line_offsets, full.line_offsets);
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_budget_stride() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        for i in 0..1000 {
+            writeln!(file, "line {}", i)?;
+        }
+        let reader = FileReader::new(file.path().to_path_buf(), detect_encoding(b"").encoding)?;
+
+        // 1000 lines * 8 bytes in a 1000-byte budget -> every 8th line
+        let mut indexer = LineIndexer::new();
+        indexer.set_memory_budget(Some(1000));
+        indexer.index_file(&reader);
+        assert_eq!(indexer.line_stride(), 8);
+        assert_eq!(indexer.total_lines(), 1000);
+        assert_eq!(indexer.index_size_bytes(), 126 * 8);
+
+        let mut full = LineIndexer::new();
+        full.index_file(&reader);
+        for line in [0, 1, 7, 8, 9, 500, 999] {
+            let range = full.get_line_range(line).unwrap();
+            assert_eq!(indexer.get_line_with_reader(line, &reader), Some(range));
+            assert_eq!(
+                indexer.find_line_at_offset_with_reader(range.0, &reader),
+                line
+            );
+        }
+        assert_eq!(indexer.get_line_with_reader(1000, &reader), None);
+        assert_eq!(indexer.get_line_range(1000), None);
+
+        // A roomy budget keeps every line
+        indexer.set_memory_budget(Some(1 << 20));
+        indexer.index_file(&reader);
+        assert_eq!(indexer.line_stride(), 1);
+        assert_eq!(indexer.line_offsets, full.line_offsets);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_stride_bounds_memory() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        for i in 0..100_000 {
+            writeln!(file, "{}", i)?;
+        }
+        let reader = FileReader::new(file.path().to_path_buf(), detect_encoding(b"").encoding)?;
+        let mut full = LineIndexer::new();
+        full.index_file(&reader);
+
+        let mut indexer = LineIndexer::new();
+        indexer.set_line_stride(Some(64));
+        // The explicit stride wins over a budget that would keep every line
+        indexer.set_memory_budget(Some(1 << 30));
+        indexer.index_file(&reader);
+        assert_eq!(indexer.line_stride(), 64);
+        assert_eq!(indexer.total_lines(), 100_000);
+        assert!(indexer.index_size_bytes() <= (100_000 / 64 + 2) * 8);
+        assert!(indexer.index_size_bytes() * 60 < full.index_size_bytes());
+
+        for line in [0, 1, 63, 64, 65, 12_345, 99_999] {
+            let (start, end) = indexer.get_line_with_reader(line, &reader).unwrap();
+            assert_eq!(Some((start, end)), full.get_line_range(line));
+            assert_eq!(reader.get_chunk(start, end), format!("{}\n", line));
+        }
+        assert_eq!(indexer.get_line_with_reader(100_000, &reader), None);
+
+        indexer.set_line_stride(None);
+        indexer.index_file(&reader);
+        assert_eq!(indexer.line_stride(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_append_index() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "one\ntwo\nthr")?;
+        file.flush()?;
+        let path = file.path().to_path_buf();
+
+        for budget in [None, Some(8)] {
+            let reader = FileReader::new(path.clone(), detect_encoding(b"").encoding)?;
+            let mut indexer = LineIndexer::new();
+            indexer.set_memory_budget(budget);
+            indexer.index_file(&reader);
+            let old_len = reader.len();
+
+            // Grow the file, then compare against indexing it from scratch
+            let mut grown = std::fs::OpenOptions::new().append(true).open(&path)?;
+            write!(grown, "ee\nfour\n\nfive")?;
+            let reader = FileReader::new(path.clone(), detect_encoding(b"").encoding)?;
+            indexer.append_index(&reader, old_len);
+
+            // The stride chosen for the old size is kept while appending
+            let mut full = LineIndexer::new();
+            full.index_file(&reader);
+            assert_eq!(indexer.total_lines(), 6);
+            for line in 0..6 {
+                assert_eq!(
+                    indexer.get_line_with_reader(line, &reader),
+                    full.get_line_range(line)
+                );
+            }
+
+            // Reset for the next round
+            std::fs::write(&path, "one\ntwo\nthr")?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_total_lines_final_newline() -> anyhow::Result<()> {
+        // (text, lines, last line)
+        let cases = [
+            ("", 0, ""),
+            ("a", 1, "a"),
+            ("a\n", 1, "a\n"),
+            ("a\nb", 2, "b"),
+            ("a\nb\n", 2, "b\n"),
+        ];
+        for (text, lines, last_line) in cases {
+            let mut file = NamedTempFile::new()?;
+            write!(file, "{}", text)?;
+            let reader = FileReader::new(file.path().to_path_buf(), detect_encoding(b"").encoding)?;
+
+            for budget in [None, Some(1)] {
+                let mut indexer = LineIndexer::new();
+                indexer.set_memory_budget(budget);
+                indexer.index_file(&reader);
+                assert_eq!(
+                    indexer.total_lines(),
+                    lines,
+                    "{:?} budget {:?}",
+                    text,
+                    budget
+                );
+                assert_eq!(indexer.get_line_with_reader(lines, &reader), None);
+                if let Some(last) = lines.checked_sub(1) {
+                    let (start, end) = indexer.get_line_with_reader(last, &reader).unwrap();
+                    assert_eq!(reader.get_chunk(start, end), last_line);
+                }
+            }
+
+            // Appending to it counts the same as indexing the result
+            let mut indexer = LineIndexer::new();
+            indexer.index_file(&reader);
+            let mut grown = std::fs::OpenOptions::new().append(true).open(file.path())?;
+            writeln!(grown, "c")?;
+            let reader = FileReader::new(file.path().to_path_buf(), detect_encoding(b"").encoding)?;
+            indexer.append_index(&reader, text.len());
+            let mut full = LineIndexer::new();
+            full.index_file(&reader);
+            assert_eq!(indexer.total_lines(), full.total_lines(), "{:?} + c", text);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_line_indexer_empty_lines() -> anyhow::Result<()> {
         let mut file = NamedTempFile::new()?;
         write!(file, "\n\n\n")?;
         let path = file.path().to_path_buf();
 
-        let reader = FileReader::new(path, detect_encoding(b""))?;
+        let reader = FileReader::new(path, detect_encoding(b"").encoding)?;
         let mut indexer = LineIndexer::new();
         indexer.index_file(&reader);
 
-        assert_eq!(indexer.total_lines, 4);
+        assert_eq!(indexer.total_lines, 3);
         assert_eq!(indexer.line_offsets, vec![0, 1, 2, 3]);
         Ok(())
     }
@@ -0,0 +1,347 @@
+use crate::file_reader::{detect_encoding, is_probably_binary, FileReader};
+use crate::search_engine::{SearchEngine, SearchMessage, SearchResult};
+use anyhow::Result;
+use encoding_rs::UTF_8;
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::{self, Sender},
+    Arc,
+};
+use std::thread;
+
+/// Longest line text sent with a match, in bytes; the rest is cut off.
+pub const MAX_PREVIEW_BYTES: usize = 500;
+
+/// A match in one of the files, with the line it's on for showing it.
+#[derive(Clone, Debug)]
+pub struct FileMatch {
+    pub result: SearchResult,
+    /// 1-based
+    pub line: usize,
+    /// The line without its ending, cut to `MAX_PREVIEW_BYTES` on either
+    /// side of the match
+    pub text: String,
+}
+
+pub enum MultiFileMessage {
+    Match(PathBuf, FileMatch),
+    // Files searched so far, and how many there are
+    Progress(usize, usize),
+    // A file that couldn't be read; the others are still searched
+    Error(PathBuf, String),
+    Done,
+}
+
+/// Searches every file under a folder whose name matches a glob, one
+/// `FileReader` and search per file, several files at a time.
+#[derive(Clone)]
+pub struct MultiFileSearch {
+    root: PathBuf,
+    pattern: String,
+    max_per_file: usize,
+}
+
+impl MultiFileSearch {
+    /// `pattern` is matched against file names, see `glob_match`; an empty one
+    /// takes every file.
+    pub fn new(root: PathBuf, pattern: &str) -> Self {
+        Self {
+            root,
+            pattern: pattern.trim().to_string(),
+            max_per_file: 1000,
+        }
+    }
+
+    /// Matches reported from each file at most, 1000 by default.
+    pub fn set_max_per_file(&mut self, max_per_file: usize) {
+        self.max_per_file = max_per_file;
+    }
+
+    /// The files that would be searched, in path order, and the folders (or
+    /// entries) that couldn't be read, which the walk goes on past. Subfolders
+    /// are included, except hidden ones (`.git`), and symlinks aren't followed.
+    pub fn files(&self) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    errors.push((dir, e.to_string()));
+                    continue;
+                }
+            };
+            for entry in entries {
+                let (entry, file_type) = match entry.and_then(|e| Ok((e.file_type()?, e))) {
+                    Ok((file_type, entry)) => (entry, file_type),
+                    Err(e) => {
+                        errors.push((dir.clone(), e.to_string()));
+                        continue;
+                    }
+                };
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if file_type.is_dir() && !name.starts_with('.') {
+                    dirs.push(entry.path());
+                } else if file_type.is_file()
+                    && (self.pattern.is_empty() || glob_match(&self.pattern, &name))
+                {
+                    files.push(entry.path());
+                }
+            }
+        }
+        files.sort();
+        (files, errors)
+    }
+
+    /// Searches the files on a background thread with `engine`'s query and
+    /// options, sending each match as it's found. Files that look binary are
+    /// skipped; folders and files that can't be read are reported with an
+    /// `Error` each. Matches of one file arrive in order, but files are
+    /// interleaved. Setting `cancel_token` stops the whole batch, without a `Done`.
+    pub fn search(
+        &self,
+        engine: &SearchEngine,
+        tx: Sender<MultiFileMessage>,
+        cancel_token: Arc<AtomicBool>,
+    ) {
+        let walk = self.clone();
+        let engine = engine.clone();
+        let max_per_file = self.max_per_file;
+
+        thread::spawn(move || {
+            let (files, errors) = walk.files();
+            for (path, e) in errors {
+                let _ = tx.send(MultiFileMessage::Error(path, e));
+            }
+            let done = AtomicUsize::new(0);
+            files.par_iter().for_each_with(tx.clone(), |tx, path| {
+                if cancel_token.load(Ordering::Relaxed) {
+                    return;
+                }
+                if let Err(e) = search_file(&engine, path, max_per_file, tx, &cancel_token) {
+                    let _ = tx.send(MultiFileMessage::Error(path.clone(), e.to_string()));
+                }
+                let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                let _ = tx.send(MultiFileMessage::Progress(done, files.len()));
+            });
+            if !cancel_token.load(Ordering::Relaxed) {
+                let _ = tx.send(MultiFileMessage::Done);
+            }
+        });
+    }
+}
+
+fn search_file(
+    engine: &SearchEngine,
+    path: &Path,
+    max_results: usize,
+    tx: &Sender<MultiFileMessage>,
+    cancel_token: &Arc<AtomicBool>,
+) -> Result<()> {
+    let mut reader = FileReader::new(path.to_path_buf(), UTF_8)?;
+    let prefix = reader.get_bytes(0, reader.len().min(4096));
+    if is_probably_binary(prefix) {
+        return Ok(());
+    }
+    let encoding = detect_encoding(prefix).encoding;
+    if encoding != UTF_8 {
+        reader = FileReader::new(path.to_path_buf(), encoding)?;
+    }
+    let reader = Arc::new(reader);
+
+    let (fetch_tx, fetch_rx) = mpsc::sync_channel(4);
+    engine.fetch_matches(
+        reader.clone(),
+        fetch_tx,
+        0,
+        max_results,
+        None,
+        cancel_token.clone(),
+    );
+    // Lines are counted on from the previous match, as they come in order
+    let (mut counted_to, mut line) = (0, 1);
+    for message in fetch_rx {
+        let matches = match message {
+            SearchMessage::ChunkResult(chunk) => chunk.matches,
+            SearchMessage::Error(e) => anyhow::bail!(e),
+            _ => break,
+        };
+        for result in matches {
+            let offset = result.byte_offset;
+            line += reader.newlines_between(counted_to, offset);
+            counted_to = offset;
+            let (text, _) =
+                SearchEngine::context_snippet(&reader, offset, result.match_len, MAX_PREVIEW_BYTES);
+            let found = FileMatch { result, line, text };
+            if tx
+                .send(MultiFileMessage::Match(path.to_path_buf(), found))
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters and `?` for one. Several patterns can be given separated by
+/// `;` or spaces (`*.log *.txt`).
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    pattern
+        .split([';', ' '])
+        .filter(|p| !p.is_empty())
+        .any(|p| wildcard_match(&p.chars().collect::<Vec<_>>(), &name))
+}
+
+// Backtracks to the last `*` only, which is enough for these two wildcards
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.log", "app.log"));
+        assert!(glob_match("*.log", ".log"));
+        assert!(!glob_match("*.log", "app.log.gz"));
+        assert!(glob_match("app-??.txt", "app-01.txt"));
+        assert!(!glob_match("app-??.txt", "app-1.txt"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*a*b", "xxaxxbxxc"));
+        assert!(glob_match("*.log *.txt", "notes.txt"));
+        assert!(glob_match("*.csv;*.tsv", "data.tsv"));
+        assert!(glob_match("読み*", "読み込み.log"));
+    }
+
+    #[test]
+    fn test_multi_file_search() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::write(dir.path().join("a.log"), "one\nerror here\nthree error\n")?;
+        std::fs::write(dir.path().join("b.txt"), "error not searched\n")?;
+        std::fs::create_dir(dir.path().join("sub"))?;
+        std::fs::write(dir.path().join("sub/c.log"), "fine\n\n\nerror\n")?;
+        std::fs::create_dir(dir.path().join(".hidden"))?;
+        std::fs::write(dir.path().join(".hidden/d.log"), "error\n")?;
+        std::fs::write(dir.path().join("e.log"), [0u8; 100])?;
+
+        let search = MultiFileSearch::new(dir.path().to_path_buf(), "*.log");
+        let (files, errors) = search.files();
+        assert_eq!(
+            files,
+            vec![
+                dir.path().join("a.log"),
+                dir.path().join("e.log"),
+                dir.path().join("sub/c.log"),
+            ]
+        );
+        assert!(errors.is_empty());
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("error".to_string(), false, false, false);
+        let (tx, rx) = mpsc::channel();
+        search.search(&engine, tx, Arc::new(AtomicBool::new(false)));
+        let mut found = Vec::new();
+        let mut done = false;
+        for message in rx {
+            match message {
+                MultiFileMessage::Match(path, m) => {
+                    found.push((path, m.result.byte_offset, m.line, m.text))
+                }
+                MultiFileMessage::Error(path, e) => panic!("{:?}: {}", path, e),
+                MultiFileMessage::Progress(_, total) => assert_eq!(total, 3),
+                MultiFileMessage::Done => done = true,
+            }
+        }
+        found.sort();
+        assert!(done);
+        assert_eq!(
+            found,
+            vec![
+                (dir.path().join("a.log"), 4, 2, "error here".to_string()),
+                (dir.path().join("a.log"), 21, 3, "three error".to_string()),
+                (dir.path().join("sub/c.log"), 7, 4, "error".to_string()),
+            ]
+        );
+
+        // Cancelled before it starts, nothing is searched
+        let (tx, rx) = mpsc::channel();
+        search.search(&engine, tx, Arc::new(AtomicBool::new(true)));
+        assert!(rx.iter().next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unreadable_folder_is_reported() {
+        let root = PathBuf::from("/nonexistent/large-text-viewer");
+        let search = MultiFileSearch::new(root.clone(), "*");
+        let (files, errors) = search.files();
+        assert!(files.is_empty());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, root);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("x".to_string(), false, false, false);
+        let (tx, rx) = mpsc::channel();
+        search.search(&engine, tx, Arc::new(AtomicBool::new(false)));
+        let messages: Vec<MultiFileMessage> = rx.iter().collect();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(&messages[0], MultiFileMessage::Error(path, _) if *path == root));
+        assert!(matches!(messages[1], MultiFileMessage::Done));
+    }
+
+    #[test]
+    fn test_long_line_preview_is_around_the_match() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let line = format!("{}needle{}\n", "a".repeat(2000), "b".repeat(2000));
+        std::fs::write(dir.path().join("long.log"), line)?;
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("needle".to_string(), false, true, false);
+        let (tx, rx) = mpsc::channel();
+        let search = MultiFileSearch::new(dir.path().to_path_buf(), "");
+        search.search(&engine, tx, Arc::new(AtomicBool::new(false)));
+        let found: Vec<FileMatch> = rx
+            .iter()
+            .filter_map(|message| match message {
+                MultiFileMessage::Match(_, found) => Some(found),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(found.len(), 1);
+        let text = &found[0].text;
+        assert_eq!(text.len(), 2 * MAX_PREVIEW_BYTES + "needle".len());
+        assert_eq!(&text[MAX_PREVIEW_BYTES..MAX_PREVIEW_BYTES + 6], "needle");
+        Ok(())
+    }
+}
@@ -1,8 +1,9 @@
+use crate::file_reader::{is_gzip, open_content, open_counted_content};
 use anyhow::Result;
 use regex::bytes::Regex;
 use std::fs::{File, OpenOptions};
 use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     mpsc::Sender,
@@ -11,10 +12,190 @@ use std::sync::{
 
 pub enum ReplaceMessage {
     Progress(usize, usize), // bytes_processed, total_bytes
-    Done,
+    Done(usize),            // number of replacements made
     Error(String),
 }
 
+/// ANSI escape sequences: CSI (including SGR colors like `ESC[1;31m`), OSC
+/// (terminated by BEL or `ESC \`), and other two-byte escapes.
+pub const ANSI_ESCAPE_PATTERN: &str =
+    r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)|\x1b[@-_]";
+
+// Input is streamed through a 1 MB buffer; its last OVERLAP_SIZE bytes, enough
+// to cover the longest match expected, are carried over into the next fill
+const BUFFER_SIZE: usize = 1024 * 1024;
+const OVERLAP_SIZE: usize = 4096;
+
+/// Gives `replacement` the casing of `matched` when that is all lowercase, all
+/// uppercase or Capitalized (only the first letter uppercase): with "hi",
+/// "hello" gives "hi", "HELLO" gives "HI" and "Hello" gives "Hi". Other mixes,
+/// and text without cased letters, leave `replacement` as it is.
+pub fn match_case(matched: &str, replacement: &str) -> String {
+    let mut letters = matched
+        .chars()
+        .filter(|c| c.is_uppercase() || c.is_lowercase());
+    let Some(first) = letters.next() else {
+        return replacement.to_string();
+    };
+    let rest: Vec<char> = letters.collect();
+    if rest.iter().all(|c| c.is_lowercase()) {
+        if first.is_lowercase() {
+            return replacement.to_lowercase();
+        }
+        let mut chars = replacement.chars();
+        return match chars.next() {
+            Some(c) => c.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        };
+    }
+    if first.is_uppercase() && rest.iter().all(|c| c.is_uppercase()) {
+        return replacement.to_uppercase();
+    }
+    replacement.to_string()
+}
+
+/// Checks the `$N` / `${name}` references in a regex replacement template
+/// against the groups `pattern` defines, since an unknown group silently
+/// expands to nothing. Returns a warning for the first bad reference, or
+/// `None` when the template is fine or `pattern` doesn't compile.
+pub fn check_replacement_template(pattern: &str, replacement: &str) -> Option<String> {
+    let regex = Regex::new(pattern).ok()?;
+    let groups = regex.captures_len() - 1;
+
+    // Same parsing as the regex crate's expansion: `$$` is a literal dollar,
+    // `${name}` is braced, otherwise the name is the longest run of [_0-9A-Za-z]
+    let mut rest = replacement;
+    while let Some(pos) = rest.find('$') {
+        rest = &rest[pos + 1..];
+        let (name, reference) = if let Some(stripped) = rest.strip_prefix('$') {
+            rest = stripped;
+            continue;
+        } else if let Some(braced) = rest.strip_prefix('{') {
+            match braced.find('}') {
+                Some(end) => {
+                    rest = &braced[end + 1..];
+                    (&braced[..end], format!("${{{}}}", &braced[..end]))
+                }
+                None => break,
+            }
+        } else {
+            let end = rest
+                .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            rest = &rest[end..];
+            (name, format!("${}", name))
+        };
+
+        if name.is_empty() {
+            continue;
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            if index > groups {
+                let plural = if groups == 1 { "group" } else { "groups" };
+                return Some(format!(
+                    "{} referenced but pattern has {} {}",
+                    reference, groups, plural
+                ));
+            }
+        } else if !regex.capture_names().flatten().any(|n| n == name) {
+            let hint = match name.find(|c: char| !c.is_ascii_digit()) {
+                // "$1a" reads as the group named "1a"
+                Some(digits) if digits > 0 => format!(
+                    " (use ${{{}}} for group {})",
+                    &name[..digits],
+                    &name[..digits]
+                ),
+                _ => String::new(),
+            };
+            return Some(format!(
+                "{} referenced but pattern has no group named '{}'{}",
+                reference, name, hint
+            ));
+        }
+    }
+    None
+}
+
+/// Whether `a` and `b` are the same file, also when reached through different
+/// spellings of the path or through links. False if either can't be read, so
+/// a file that doesn't exist yet is never the same as anything.
+pub fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (file_id(a), file_id(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+// Without inode numbers, resolving symlinks and `..` is the next best thing
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<PathBuf> {
+    std::fs::canonicalize(path).ok()
+}
+
+/// Creates or replaces `path` by letting `write` fill a temp file beside it,
+/// then renaming that into place. The temp file is in the same directory, so
+/// the rename stays on one filesystem and is atomic: readers see the old file
+/// or the new one, and a failed `write` leaves `path` untouched. A file being
+/// replaced keeps its permissions (Unix mode bits); the modification time is
+/// the new content's.
+pub fn write_atomically<T>(path: &Path, write: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let temp_path = temp_path_for(path);
+    let result = write(&temp_path).and_then(|value| {
+        copy_permissions(path, &temp_path)?;
+        persist(&temp_path, path)?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// Gives `to` the permissions of `from`, if that exists
+fn copy_permissions(from: &Path, to: &Path) -> Result<()> {
+    match std::fs::metadata(from) {
+        Ok(metadata) => Ok(std::fs::set_permissions(to, metadata.permissions())?),
+        Err(_) => Ok(()),
+    }
+}
+
+// Hidden sibling of `path`; never equal to it, whatever its extension
+fn temp_path_for(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map_or_else(|| "output".into(), |name| name.to_string_lossy());
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+fn persist(temp_path: &Path, path: &Path) -> Result<()> {
+    // On Windows, rename might fail if target exists.
+    if std::fs::rename(temp_path, path).is_err() {
+        // Try to remove target and rename again.
+        if std::fs::remove_file(path).is_ok() {
+            std::fs::rename(temp_path, path)?;
+        } else {
+            return Err(anyhow::anyhow!(
+                "Failed to replace file. It might be open by another process."
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// What [`Replacer::preview`] found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplacePreview {
+    pub total: usize,
+    pub samples: Vec<(usize, String, String)>,
+}
+
 pub struct Replacer;
 
 impl Replacer {
@@ -26,6 +207,11 @@ impl Replacer {
     ) -> Result<()> {
         let new_bytes = new_text.as_bytes();
 
+        let mut magic = [0u8; 2];
+        if File::open(file_path)?.read_exact(&mut magic).is_ok() && is_gzip(&magic) {
+            anyhow::bail!("Can't edit a gzip-compressed file in place");
+        }
+
         if new_bytes.len() == old_len {
             // In-place optimization
             let mut file = OpenOptions::new().write(true).open(file_path)?;
@@ -35,10 +221,9 @@ impl Replacer {
         }
 
         // Different length: rewrite file
-        let temp_path = file_path.with_extension("tmp");
-        {
+        write_atomically(file_path, |temp_path| {
             let mut input_file = File::open(file_path)?;
-            let mut output_file = BufWriter::new(File::create(&temp_path)?);
+            let mut output_file = BufWriter::new(File::create(temp_path)?);
 
             // Copy before match
             let mut buffer = vec![0u8; 8192];
@@ -62,30 +247,79 @@ impl Replacer {
 
             // Copy rest
             std::io::copy(&mut input_file, &mut output_file)?;
-        }
+            output_file.flush()?;
+            Ok(())
+        })
+    }
 
-        // Replace original file
-        // On Windows, rename might fail if target exists.
-        if std::fs::rename(&temp_path, file_path).is_err() {
-            // Try to remove target and rename again.
-            if std::fs::remove_file(file_path).is_ok() {
-                std::fs::rename(&temp_path, file_path)?;
-            } else {
-                return Err(anyhow::anyhow!(
-                    "Failed to replace file. It might be open by another process."
-                ));
+    /// Writes `source` to `output_path` with every `(offset, old_len, new_text)`
+    /// edit applied, in a single pass. Edits must be in offset order and must
+    /// not overlap. The output is written atomically, so `source` may read from
+    /// `output_path` itself as long as nothing else keeps that file mapped.
+    pub fn write_with_edits<'a>(
+        source: impl Read,
+        output_path: &Path,
+        edits: impl IntoIterator<Item = (usize, usize, &'a str)>,
+    ) -> Result<()> {
+        write_atomically(output_path, |temp_path| {
+            let mut source = source;
+            let mut output_file = BufWriter::new(File::create(temp_path)?);
+            let mut pos = 0;
+            for (offset, old_len, new_text) in edits {
+                if offset < pos {
+                    anyhow::bail!("Edits overlap or are out of order at offset {}", offset);
+                }
+                let copied = std::io::copy(
+                    &mut source.by_ref().take((offset - pos) as u64),
+                    &mut output_file,
+                )?;
+                let skipped = std::io::copy(
+                    &mut source.by_ref().take(old_len as u64),
+                    &mut std::io::sink(),
+                )?;
+                if copied + skipped < (offset - pos + old_len) as u64 {
+                    anyhow::bail!("Edit at offset {} is past the end of the input", offset);
+                }
+                output_file.write_all(new_text.as_bytes())?;
+                pos = offset + old_len;
             }
-        }
+            std::io::copy(&mut source, &mut output_file)?;
+            output_file.flush()?;
+            Ok(())
+        })
+    }
 
-        Ok(())
+    /// Replaces exactly the given `(offset, old_len, new_text)` spans of
+    /// `input_path`, decompressed first if it's gzip, writing `output_path`
+    /// (which may be the input itself). Nothing is searched, so the replaced set
+    /// is whatever produced the offsets, such as a `SearchEngine`'s results.
+    /// Returns the number of replacements.
+    pub fn replace_at_offsets(
+        input_path: &Path,
+        output_path: &Path,
+        mut edits: Vec<(usize, usize, String)>,
+    ) -> Result<usize> {
+        edits.sort_by_key(|&(offset, _, _)| offset);
+        let (content, _) = open_content(input_path)?;
+        let spans = edits
+            .iter()
+            .map(|(offset, old_len, new_text)| (*offset, *old_len, new_text.as_str()));
+        Self::write_with_edits(content, output_path, spans)?;
+        Ok(edits.len())
     }
 
+    /// Replaces every match of `query` in `input_path`, writing `output_path`.
+    /// With `preserve_case` each replacement follows its match's casing, see
+    /// [`match_case`].
+    #[allow(clippy::too_many_arguments)]
     pub fn replace_all(
         input_path: &Path,
         output_path: &Path,
         query: &str,
         replace_with: &str,
         use_regex: bool,
+        case_sensitive: bool,
+        preserve_case: bool,
         tx: Sender<ReplaceMessage>,
         cancel_token: Arc<AtomicBool>,
     ) {
@@ -95,11 +329,79 @@ impl Replacer {
             query,
             replace_with,
             use_regex,
+            case_sensitive,
+            preserve_case,
             &tx,
             cancel_token,
         ) {
-            Ok(_) => {
-                let _ = tx.send(ReplaceMessage::Done);
+            Ok(count) => {
+                let _ = tx.send(ReplaceMessage::Done(count));
+            }
+            Err(e) => {
+                let _ = tx.send(ReplaceMessage::Error(e.to_string()));
+            }
+        }
+    }
+
+    /// Dry run of [`replace_all`](Self::replace_all): streams the file the same way
+    /// but writes nothing. Returns the number of replacements it would make and up
+    /// to `sample_limit` `(byte_offset, old, new)` examples, in file order.
+    pub fn preview(
+        input_path: &Path,
+        query: &str,
+        replace_with: &str,
+        use_regex: bool,
+        case_sensitive: bool,
+        preserve_case: bool,
+        sample_limit: usize,
+    ) -> Result<ReplacePreview> {
+        let regex = build_regex(query, use_regex, case_sensitive)?;
+        let mut samples = Vec::new();
+        let total = Self::stream_matches(
+            input_path,
+            &regex,
+            replace_with.as_bytes(),
+            preserve_case,
+            &mut std::io::sink(),
+            |offset, old, new| {
+                if samples.len() < sample_limit {
+                    samples.push((
+                        offset,
+                        String::from_utf8_lossy(old).into_owned(),
+                        String::from_utf8_lossy(new).into_owned(),
+                    ));
+                }
+            },
+            |_, _| {},
+            &AtomicBool::new(false),
+        )?;
+        Ok(ReplacePreview { total, samples })
+    }
+
+    /// Streams `input_path` to `output_path` with all ANSI escape sequences removed.
+    /// `ReplaceMessage::Done` carries the number of sequences stripped.
+    pub fn strip_ansi(
+        input_path: &Path,
+        output_path: &Path,
+        tx: Sender<ReplaceMessage>,
+        cancel_token: Arc<AtomicBool>,
+    ) {
+        let result = Regex::new(ANSI_ESCAPE_PATTERN)
+            .map_err(anyhow::Error::from)
+            .and_then(|regex| {
+                Self::stream_replace(
+                    input_path,
+                    output_path,
+                    &regex,
+                    b"",
+                    false,
+                    &tx,
+                    cancel_token,
+                )
+            });
+        match result {
+            Ok(count) => {
+                let _ = tx.send(ReplaceMessage::Done(count));
             }
             Err(e) => {
                 let _ = tx.send(ReplaceMessage::Error(e.to_string()));
@@ -107,57 +409,113 @@ impl Replacer {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn replace_all_inner(
         input_path: &Path,
         output_path: &Path,
         query: &str,
         replace_with: &str,
         use_regex: bool,
+        case_sensitive: bool,
+        preserve_case: bool,
         tx: &Sender<ReplaceMessage>,
         cancel_token: Arc<AtomicBool>,
-    ) -> Result<()> {
-        let mut input_file = File::open(input_path)?;
-        let file_len = input_file.metadata()?.len() as usize;
-        let mut output_file = BufWriter::new(File::create(output_path)?);
+    ) -> Result<usize> {
+        let regex = build_regex(query, use_regex, case_sensitive)?;
 
-        let regex = if use_regex {
-            Regex::new(query)?
-        } else {
-            let pattern = format!("(?i){}", regex::escape(query));
-            Regex::new(&pattern)?
-        };
+        Self::stream_replace(
+            input_path,
+            output_path,
+            &regex,
+            replace_with.as_bytes(),
+            preserve_case,
+            tx,
+            cancel_token,
+        )
+    }
 
-        let replace_with_bytes = replace_with.as_bytes();
+    // Returns the number of replacements made. The output only appears once
+    // the whole input went through, so it may also be the input file itself.
+    fn stream_replace(
+        input_path: &Path,
+        output_path: &Path,
+        regex: &Regex,
+        replace_with_bytes: &[u8],
+        preserve_case: bool,
+        tx: &Sender<ReplaceMessage>,
+        cancel_token: Arc<AtomicBool>,
+    ) -> Result<usize> {
+        write_atomically(output_path, |temp_path| {
+            let mut output_file = BufWriter::new(File::create(temp_path)?);
+            // A new output file starts with the input's permissions
+            copy_permissions(input_path, temp_path)?;
+            let replacements = Self::stream_matches(
+                input_path,
+                regex,
+                replace_with_bytes,
+                preserve_case,
+                &mut output_file,
+                |_, _, _| {},
+                |processed, total| {
+                    let _ = tx.send(ReplaceMessage::Progress(processed, total));
+                },
+                &cancel_token,
+            )?;
+            if cancel_token.load(Ordering::Relaxed) {
+                anyhow::bail!("Cancelled; {} was not written", output_path.display());
+            }
+            output_file.flush()?;
+            Ok(replacements)
+        })
+    }
 
-        // Buffer size: 1MB
-        const BUFFER_SIZE: usize = 1024 * 1024;
-        // Overlap: enough to cover max match length.
-        const OVERLAP_SIZE: usize = 4096;
+    // Streams `input_path` through `regex`, writing the replaced text to
+    // `output_file`. `on_match` sees the offset, old and new bytes of every
+    // replacement. Returns the number of replacements made
+    #[allow(clippy::too_many_arguments)]
+    fn stream_matches(
+        input_path: &Path,
+        regex: &Regex,
+        replace_with_bytes: &[u8],
+        preserve_case: bool,
+        output_file: &mut impl Write,
+        mut on_match: impl FnMut(usize, &[u8], &[u8]),
+        mut on_progress: impl FnMut(usize, usize),
+        cancel_token: &AtomicBool,
+    ) -> Result<usize> {
+        // Gzip input is replaced in its decompressed form. Progress goes by the
+        // bytes read off disk, which end at the file size even when the
+        // replaced text is shorter or longer, or the gzip length is unknown.
+        let (mut input_file, _, read_so_far) = open_counted_content(input_path)?;
+        let file_len = std::fs::metadata(input_path)?.len() as usize;
 
         let mut buffer = vec![0u8; BUFFER_SIZE + OVERLAP_SIZE];
         let mut eof = false;
 
         // Initial fill
-        let n = input_file.read(&mut buffer[0..BUFFER_SIZE])?;
+        let n = read_full(&mut input_file, &mut buffer[0..BUFFER_SIZE])?;
         let mut buffer_len = n;
         if n < BUFFER_SIZE {
             eof = true;
         }
 
         let mut processed_offset = 0;
+        let mut replacements = 0;
 
         while buffer_len > 0 {
             if cancel_token.load(Ordering::Relaxed) {
-                return Ok(());
+                return Ok(replacements);
             }
 
             // Ensure we end at a char boundary to avoid splitting UTF-8 chars
             // even though we use bytes regex, we want to respect text boundaries if possible.
-            let mut valid_len = buffer_len;
-            while valid_len > 0 && !is_utf8_char_boundary(buffer[valid_len]) {
-                valid_len -= 1;
-            }
-            if valid_len == 0 && buffer_len > 0 {
+            // A character cut off by the fill waits for the rest of it.
+            let mut valid_len = if eof {
+                buffer_len
+            } else {
+                utf8_prefix_len(&buffer[..buffer_len])
+            };
+            if valid_len == 0 {
                 valid_len = buffer_len;
             }
 
@@ -183,7 +541,18 @@ impl Replacer {
                 // Expand replacement
                 let mut dst = Vec::new();
                 cap.expand(replace_with_bytes, &mut dst);
+                if preserve_case {
+                    // Casing only applies to text; other bytes go through as they are
+                    if let (Ok(old), Ok(new)) = (
+                        std::str::from_utf8(mat.as_bytes()),
+                        std::str::from_utf8(&dst),
+                    ) {
+                        dst = match_case(old, new).into_bytes();
+                    }
+                }
                 output_file.write_all(&dst)?;
+                on_match(processed_offset + mat.start(), mat.as_bytes(), &dst);
+                replacements += 1;
 
                 last_match_end = mat.end();
             }
@@ -210,8 +579,10 @@ impl Replacer {
             // Fill the rest of the buffer
             if !eof {
                 let bytes_to_read = BUFFER_SIZE - remaining_len;
-                let n =
-                    input_file.read(&mut buffer[remaining_len..remaining_len + bytes_to_read])?;
+                let n = read_full(
+                    &mut input_file,
+                    &mut buffer[remaining_len..remaining_len + bytes_to_read],
+                )?;
                 buffer_len = remaining_len + n;
                 if n == 0 {
                     eof = true;
@@ -224,11 +595,71 @@ impl Replacer {
             }
 
             processed_offset += shift_start;
-            let _ = tx.send(ReplaceMessage::Progress(processed_offset, file_len));
+            // Capped in case the file grew since it was opened; the last
+            // report is always the whole file
+            let read = match buffer_len {
+                0 => file_len,
+                _ => read_so_far.load(Ordering::Relaxed).min(file_len),
+            };
+            on_progress(read, file_len);
         }
 
-        output_file.flush()?;
-        Ok(())
+        Ok(replacements)
+    }
+}
+
+// Like `read`, but only returns less than `buf.len()` at end of input. Decoders
+// such as gzip hand out data in smaller pieces than requested.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// The pattern `replace_all` searches with. Case-insensitive queries get `(?i)`,
+// regexes included, as in `SearchEngine`.
+fn build_regex(query: &str, use_regex: bool, case_sensitive: bool) -> Result<Regex> {
+    let pattern = if use_regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+    let pattern = if case_sensitive {
+        pattern
+    } else {
+        format!("(?i){}", pattern)
+    };
+    Ok(Regex::new(&pattern)?)
+}
+
+// Length of `bytes` without a UTF-8 character cut off at its end, if any. Bytes
+// that aren't UTF-8 are left alone.
+fn utf8_prefix_len(bytes: &[u8]) -> usize {
+    let len = bytes.len();
+    // A character is at most 4 bytes, so a cut-off one starts in the last 3
+    let Some(lead) = (len.saturating_sub(3)..len)
+        .rev()
+        .find(|&i| is_utf8_char_boundary(bytes[i]))
+    else {
+        return len;
+    };
+    let width = match bytes[lead] {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    };
+    if lead + width > len {
+        lead
+    } else {
+        len
     }
 }
 
@@ -242,6 +673,9 @@ fn is_utf8_char_boundary(b: u8) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::file_reader::FileReader;
+    use crate::line_indexer::LineIndexer;
+    use crate::search_engine::SearchEngine;
     use std::io::Write;
     use std::sync::mpsc;
     use tempfile::NamedTempFile;
@@ -264,6 +698,8 @@ mod tests {
             "Hello",
             "Hi",
             false,
+            false,
+            false,
             tx,
             cancel_token,
         );
@@ -271,7 +707,7 @@ mod tests {
         // Wait for done
         loop {
             match rx.recv() {
-                Ok(ReplaceMessage::Done) => break,
+                Ok(ReplaceMessage::Done(_)) => break,
                 Ok(ReplaceMessage::Error(e)) => panic!("Error: {}", e),
                 Ok(ReplaceMessage::Progress(_, _)) => continue,
                 Err(_) => break,
@@ -283,6 +719,519 @@ mod tests {
         Ok(())
     }
 
+    fn replace_literal(
+        text: &str,
+        query: &str,
+        replace_with: &str,
+        case_sensitive: bool,
+    ) -> Result<String> {
+        let mut input = NamedTempFile::new()?;
+        write!(input, "{}", text)?;
+        let output = NamedTempFile::new()?;
+
+        let (tx, rx) = mpsc::channel();
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        Replacer::replace_all(
+            input.path(),
+            output.path(),
+            query,
+            replace_with,
+            false,
+            case_sensitive,
+            false,
+            tx,
+            cancel_token,
+        );
+        for msg in rx {
+            if let ReplaceMessage::Error(e) = msg {
+                panic!("Error: {}", e);
+            }
+        }
+
+        Ok(std::fs::read_to_string(output.path())?)
+    }
+
+    #[test]
+    fn test_replace_all_gzip_input() -> Result<()> {
+        // Larger than one buffer, so the decoder is read in several pieces
+        let text = "key=value\n".repeat(300_000);
+        let mut input = NamedTempFile::new()?;
+        let mut encoder = flate2::write::GzEncoder::new(&mut input, flate2::Compression::fast());
+        encoder.write_all(text.as_bytes())?;
+        encoder.finish()?;
+        let output = NamedTempFile::new()?;
+
+        let (tx, rx) = mpsc::channel();
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        Replacer::replace_all(
+            input.path(),
+            output.path(),
+            "value",
+            "v",
+            false,
+            true,
+            false,
+            tx,
+            cancel_token,
+        );
+        let mut count = 0;
+        for msg in rx {
+            match msg {
+                ReplaceMessage::Done(n) => count = n,
+                ReplaceMessage::Error(e) => panic!("Error: {}", e),
+                ReplaceMessage::Progress(done, total) => assert!(done <= total),
+            }
+        }
+
+        // The output is the decompressed text with the replacements applied
+        assert_eq!(count, 300_000);
+        assert_eq!(
+            std::fs::read_to_string(output.path())?,
+            "key=v\n".repeat(300_000)
+        );
+        assert!(Replacer::replace_single(input.path(), 4, 5, "other").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_all_progress() -> Result<()> {
+        // Replacements much shorter and much longer than the text they replace,
+        // over several buffers, plain and gzipped
+        let text = "abcdefgh\n".repeat(3 * BUFFER_SIZE / 9);
+        let plain = NamedTempFile::new()?;
+        std::fs::write(plain.path(), &text)?;
+        let mut gzipped = NamedTempFile::new()?;
+        let mut encoder = flate2::write::GzEncoder::new(&mut gzipped, flate2::Compression::fast());
+        encoder.write_all(text.as_bytes())?;
+        encoder.finish()?;
+
+        for input in [&plain, &gzipped] {
+            let file_len = std::fs::metadata(input.path())?.len() as usize;
+            for (query, replace_with) in [("abcdefgh", "x"), ("a", "0123456789")] {
+                let output = NamedTempFile::new()?;
+                let (tx, rx) = mpsc::channel();
+                let cancel_token = Arc::new(AtomicBool::new(false));
+                Replacer::replace_all(
+                    input.path(),
+                    output.path(),
+                    query,
+                    replace_with,
+                    false,
+                    true,
+                    false,
+                    tx,
+                    cancel_token,
+                );
+                let mut reports = Vec::new();
+                for msg in rx {
+                    match msg {
+                        ReplaceMessage::Progress(done, total) => reports.push((done, total)),
+                        ReplaceMessage::Done(_) => {}
+                        ReplaceMessage::Error(e) => panic!("Error: {}", e),
+                    }
+                }
+                assert!(reports.len() > 1);
+                assert!(reports.iter().all(|&(_, total)| total == file_len));
+                assert!(reports.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+                assert_eq!(reports.last(), Some(&(file_len, file_len)));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_single_persists_match() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "id=42; id=7; id=100")?;
+        let path = file.path().to_path_buf();
+
+        // Literal, same length (in place) and longer (rewritten)
+        Replacer::replace_single(&path, 0, 5, "id=43")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "id=43; id=7; id=100");
+        Replacer::replace_single(&path, 7, 4, "id=seven")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "id=43; id=seven; id=100");
+
+        // Regex: the pending text is the match's own expansion, old_len its length
+        let matched = "id=100";
+        let mut engine = SearchEngine::new();
+        engine.set_query(r"id=(\d+)".to_string(), true, true, false);
+        let new_text = engine.expand_replacement("#$1", matched);
+        assert_eq!(new_text, "#100");
+        Replacer::replace_single(&path, 17, matched.len(), &new_text)?;
+        assert_eq!(std::fs::read_to_string(&path)?, "id=43; id=seven; #100");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_with_edits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("in.txt");
+        std::fs::write(&path, "id=42; id=7; id=100")?;
+
+        let copy = dir.path().join("copy.txt");
+        let edits = [(0, 5, "id=43"), (7, 4, "id=seven"), (13, 6, "")];
+        Replacer::write_with_edits(File::open(&path)?, &copy, edits)?;
+        assert_eq!(std::fs::read_to_string(&copy)?, "id=43; id=seven; ");
+        assert_eq!(std::fs::read_to_string(&path)?, "id=42; id=7; id=100");
+
+        // Into the file being read, from in-memory content, and with no edits
+        Replacer::write_with_edits(File::open(&path)?, &path, [(3, 2, "1")])?;
+        assert_eq!(std::fs::read_to_string(&path)?, "id=1; id=7; id=100");
+        Replacer::write_with_edits(&b"abc"[..], &copy, [(3, 0, "d")])?;
+        assert_eq!(std::fs::read_to_string(&copy)?, "abcd");
+        Replacer::write_with_edits(&b"abc"[..], &copy, [])?;
+        assert_eq!(std::fs::read_to_string(&copy)?, "abc");
+
+        // Bad edits fail without touching the output
+        assert!(
+            Replacer::write_with_edits(&b"abc"[..], &copy, [(2, 1, "x"), (1, 1, "y")]).is_err()
+        );
+        assert!(Replacer::write_with_edits(&b"abc"[..], &copy, [(2, 5, "x")]).is_err());
+        assert_eq!(std::fs::read_to_string(&copy)?, "abc");
+        Ok(())
+    }
+
+    #[test]
+    fn test_edits_change_line_count() -> Result<()> {
+        let mut input = NamedTempFile::new()?;
+        write!(input, "one\ntwo\nthree\n")?;
+        let lines_of = |path: &Path| -> Result<(usize, String)> {
+            let reader = FileReader::new(path.to_path_buf(), encoding_rs::UTF_8)?;
+            let mut indexer = LineIndexer::new();
+            indexer.index_file(&reader);
+            Ok((indexer.total_lines(), reader.get_chunk(0, reader.len())))
+        };
+
+        // Removing a newline joins two lines
+        let joined = NamedTempFile::new()?;
+        Replacer::replace_at_offsets(input.path(), joined.path(), vec![(3, 1, " ".into())])?;
+        assert_eq!(
+            lines_of(joined.path())?,
+            (2, "one two\nthree\n".to_string())
+        );
+
+        // Adding newlines splits lines
+        let split = NamedTempFile::new()?;
+        let edits = vec![(10, 0, "\n".into()), (0, 3, "o\nne".into())];
+        Replacer::replace_at_offsets(input.path(), split.path(), edits)?;
+        assert_eq!(
+            lines_of(split.path())?,
+            (5, "o\nne\ntwo\nth\nree\n".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_at_offsets() -> Result<()> {
+        let mut input = NamedTempFile::new()?;
+        write!(input, "id=42; id=7; id=100")?;
+        let output = NamedTempFile::new()?;
+
+        // Only the listed spans change, in any order, other matches stay
+        let edits = vec![(13, 6, "#100".to_string()), (0, 5, "#42".to_string())];
+        let count = Replacer::replace_at_offsets(input.path(), output.path(), edits)?;
+        assert_eq!(count, 2);
+        assert_eq!(std::fs::read_to_string(output.path())?, "#42; id=7; #100");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomically() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("out.tmp");
+        std::fs::write(&path, "original")?;
+
+        // A failed write leaves the file and no temp file behind
+        let failed: Result<()> = write_atomically(&path, |temp_path| {
+            std::fs::write(temp_path, "partial")?;
+            anyhow::bail!("disk full")
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path)?, "original");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        write_atomically(&path, |temp_path| Ok(std::fs::write(temp_path, "new")?))?;
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 1);
+
+        // Replacing into the input itself reads it whole before it's swapped
+        let (tx, rx) = mpsc::channel();
+        Replacer::replace_all(
+            &path,
+            &path,
+            "new",
+            "newer",
+            false,
+            true,
+            false,
+            tx,
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(rx.iter().any(|msg| matches!(msg, ReplaceMessage::Done(1))));
+        assert_eq!(std::fs::read_to_string(&path)?, "newer");
+
+        // A cancelled replace doesn't touch the output
+        let output = dir.path().join("cancelled.txt");
+        let (tx, rx) = mpsc::channel();
+        Replacer::replace_all(
+            &path,
+            &output,
+            "new",
+            "old",
+            false,
+            true,
+            false,
+            tx,
+            Arc::new(AtomicBool::new(true)),
+        );
+        assert!(rx.iter().any(|msg| matches!(msg, ReplaceMessage::Error(_))));
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_all_keeps_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = |path: &Path| -> Result<u32> {
+            Ok(std::fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("input.txt");
+        let existing = dir.path().join("existing.txt");
+        let new = dir.path().join("new.txt");
+        std::fs::write(&input, "secret token\n")?;
+        std::fs::set_permissions(&input, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::write(&existing, "")?;
+        std::fs::set_permissions(&existing, std::fs::Permissions::from_mode(0o755))?;
+
+        for output in [&input, &existing, &new] {
+            let (tx, rx) = mpsc::channel();
+            Replacer::replace_all(
+                &input,
+                output,
+                "token",
+                "value",
+                false,
+                true,
+                false,
+                tx,
+                Arc::new(AtomicBool::new(false)),
+            );
+            for msg in rx {
+                if let ReplaceMessage::Error(e) = msg {
+                    panic!("Error: {}", e);
+                }
+            }
+        }
+
+        // Replaced in place or over another file, each keeps its own mode;
+        // a new file gets the input's
+        assert_eq!(std::fs::read_to_string(&input)?, "secret value\n");
+        assert_eq!(mode(&input)?, 0o600);
+        assert_eq!(mode(&existing)?, 0o755);
+        assert_eq!(mode(&new)?, 0o600);
+
+        write_atomically(&existing, |temp_path| {
+            Ok(std::fs::write(temp_path, "edited")?)
+        })?;
+        assert_eq!(mode(&existing)?, 0o755);
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_same_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        std::fs::write(&a, "same")?;
+        std::fs::write(&b, "same")?;
+
+        assert!(is_same_file(&a, &a));
+        assert!(is_same_file(&a, &dir.path().join(".").join("a.txt")));
+        assert!(!is_same_file(&a, &b));
+        assert!(!is_same_file(&a, &dir.path().join("missing.txt")));
+        #[cfg(unix)]
+        {
+            let link = dir.path().join("link.txt");
+            std::os::unix::fs::symlink(&a, &link)?;
+            assert!(is_same_file(&a, &link));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview() -> Result<()> {
+        let text = "id=1 id=22\nid=333\n";
+        let mut input = NamedTempFile::new()?;
+        write!(input, "{}", text)?;
+
+        let preview = Replacer::preview(input.path(), r"id=(\d+)", "#$1", true, true, false, 2)?;
+        assert_eq!(preview.total, 3);
+        assert_eq!(
+            preview.samples,
+            vec![
+                (0, "id=1".to_string(), "#1".to_string()),
+                (5, "id=22".to_string(), "#22".to_string()),
+            ]
+        );
+        // Nothing is written
+        assert_eq!(std::fs::read_to_string(input.path())?, text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_all_case_sensitivity() -> Result<()> {
+        assert_eq!(
+            replace_literal("Foo foo FOO", "Foo", "Bar", true)?,
+            "Bar foo FOO"
+        );
+        assert_eq!(
+            replace_literal("Foo foo FOO", "Foo", "Bar", false)?,
+            "Bar Bar Bar"
+        );
+
+        // Regex queries follow the setting too
+        let mut input = NamedTempFile::new()?;
+        write!(input, "id=1 ID=2 Id=3")?;
+        let preview = Replacer::preview(input.path(), r"id=(\d)", "#$1", true, false, false, 3)?;
+        assert_eq!(preview.total, 3);
+        assert_eq!(preview.samples[1], (5, "ID=2".to_string(), "#2".to_string()));
+        let preview = Replacer::preview(input.path(), r"id=(\d)", "#$1", true, true, false, 3)?;
+        assert_eq!(preview.total, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_case() {
+        assert_eq!(match_case("hello", "Hi There"), "hi there");
+        assert_eq!(match_case("HELLO", "hi"), "HI");
+        assert_eq!(match_case("Hello", "hi"), "Hi");
+        assert_eq!(match_case("Hello", "hiThere"), "HiThere");
+        // Mixed casing and text without letters keep the replacement as is
+        assert_eq!(match_case("hELLo", "hi"), "hi");
+        assert_eq!(match_case("123", "hI"), "hI");
+    }
+
+    #[test]
+    fn test_replace_all_preserve_case() -> Result<()> {
+        let mut input = NamedTempFile::new()?;
+        write!(input, "hello Hello HELLO")?;
+        let output = NamedTempFile::new()?;
+
+        let (tx, rx) = mpsc::channel();
+        Replacer::replace_all(
+            input.path(),
+            output.path(),
+            "hello",
+            "hi",
+            false,
+            false,
+            true,
+            tx,
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(rx.iter().any(|msg| matches!(msg, ReplaceMessage::Done(3))));
+        assert_eq!(std::fs::read_to_string(output.path())?, "hi Hi HI");
+
+        let preview = Replacer::preview(input.path(), "hello", "hi", false, false, true, 3)?;
+        let new_texts: Vec<&str> = preview
+            .samples
+            .iter()
+            .map(|(_, _, new)| new.as_str())
+            .collect();
+        assert_eq!(new_texts, ["hi", "Hi", "HI"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_utf8_prefix_len() {
+        assert_eq!(utf8_prefix_len(b""), 0);
+        assert_eq!(utf8_prefix_len(b"abc"), 3);
+        assert_eq!(utf8_prefix_len("aé".as_bytes()), 3);
+        assert_eq!(utf8_prefix_len(&"aé".as_bytes()[..2]), 1);
+        assert_eq!(utf8_prefix_len(&"a😀".as_bytes()[..4]), 1);
+        assert_eq!(utf8_prefix_len("a😀".as_bytes()), 5);
+        // Stray continuation bytes aren't a character to wait for
+        assert_eq!(utf8_prefix_len(b"a\x80\x80\x80\x80"), 5);
+    }
+
+    #[test]
+    fn test_replace_all_multibyte_at_buffer_boundaries() -> Result<()> {
+        // Characters of every width, back to back; shifting them by each pad
+        // cuts the one on the first buffer boundary after each of its bytes
+        let unit = "aé€😀\n";
+        let units = (BUFFER_SIZE + OVERLAP_SIZE) / unit.len() + 1;
+        for pad in 0..unit.len() {
+            let text = "a".repeat(pad) + &unit.repeat(units);
+            let mut input = NamedTempFile::new()?;
+            input.write_all(text.as_bytes())?;
+            let output = NamedTempFile::new()?;
+            let (tx, rx) = mpsc::channel();
+            Replacer::replace_all(
+                input.path(),
+                output.path(),
+                "[é€😀]",
+                "<>",
+                true,
+                true,
+                false,
+                tx,
+                Arc::new(AtomicBool::new(false)),
+            );
+            let done = rx.iter().find_map(|msg| match msg {
+                ReplaceMessage::Done(count) => Some(count),
+                ReplaceMessage::Error(e) => panic!("Error: {}", e),
+                _ => None,
+            });
+            assert_eq!(done, Some(3 * units), "pad {}", pad);
+            let expected = text.replace(['é', '€', '😀'], "<>");
+            assert!(
+                std::fs::read(output.path())? == expected.as_bytes(),
+                "pad {}",
+                pad
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_all_preserves_line_endings() -> Result<()> {
+        // Replacement streams raw bytes, so CRLF and a missing final newline survive
+        assert_eq!(
+            replace_literal("one foo\r\ntwo\r\nfoo three\r\n", "foo", "bar", true)?,
+            "one bar\r\ntwo\r\nbar three\r\n"
+        );
+        assert_eq!(
+            replace_literal("foo\nlast foo", "foo", "bar", true)?,
+            "bar\nlast bar"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_replacement_template() {
+        assert_eq!(check_replacement_template(r"Item (\d)", "Object $1"), None);
+        assert_eq!(
+            check_replacement_template(r"Item (\d)", "$2-$1"),
+            Some("$2 referenced but pattern has 1 group".to_string())
+        );
+        assert_eq!(
+            check_replacement_template(r"(?P<id>\d+)", "${id} $name"),
+            Some("$name referenced but pattern has no group named 'name'".to_string())
+        );
+        assert_eq!(
+            check_replacement_template(r"(\d)", "$1a"),
+            Some(
+                "$1a referenced but pattern has no group named '1a' (use ${1} for group 1)"
+                    .to_string()
+            )
+        );
+        // Escaped dollars, whole-match references and a bad pattern are fine
+        assert_eq!(check_replacement_template(r"\d", "$$5 $0 ${0}"), None);
+        assert_eq!(check_replacement_template(r"(", "$9"), None);
+    }
+
     #[test]
     fn test_replace_all_regex() -> Result<()> {
         let mut input = NamedTempFile::new()?;
@@ -301,13 +1250,15 @@ mod tests {
             r"Item (\d)",
             "Object $1",
             true,
+            false,
+            false,
             tx,
             cancel_token,
         );
 
         loop {
             match rx.recv() {
-                Ok(ReplaceMessage::Done) => break,
+                Ok(ReplaceMessage::Done(_)) => break,
                 Ok(ReplaceMessage::Error(e)) => panic!("Error: {}", e),
                 Ok(ReplaceMessage::Progress(_, _)) => continue,
                 Err(_) => break,
@@ -318,4 +1269,38 @@ mod tests {
         assert_eq!(content, "Object 1, Object 2, Object 3");
         Ok(())
     }
+
+    #[test]
+    fn test_strip_ansi() -> Result<()> {
+        let mut input = NamedTempFile::new()?;
+        input
+            .write_all(b"\x1b[1;31mERROR\x1b[0m disk full\n\x1b]0;title\x07plain \x1b[2Kline\n")?;
+        let input_path = input.path().to_path_buf();
+
+        let output = NamedTempFile::new()?;
+        let output_path = output.path().to_path_buf();
+
+        let (tx, rx) = mpsc::channel();
+        let cancel_token = Arc::new(AtomicBool::new(false));
+
+        Replacer::strip_ansi(&input_path, &output_path, tx, cancel_token);
+
+        let mut stripped = 0;
+        loop {
+            match rx.recv() {
+                Ok(ReplaceMessage::Done(count)) => {
+                    stripped = count;
+                    break;
+                }
+                Ok(ReplaceMessage::Error(e)) => panic!("Error: {}", e),
+                Ok(ReplaceMessage::Progress(_, _)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        let content = std::fs::read_to_string(&output_path)?;
+        assert_eq!(content, "ERROR disk full\nplain line\n");
+        assert_eq!(stripped, 4);
+        Ok(())
+    }
 }
@@ -1,26 +1,169 @@
 use crate::file_reader::FileReader;
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8};
 use rayon::prelude::*;
-use regex::Regex;
+use regex::{Regex, RegexSet};
+use std::borrow::Cow;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicUsize, Ordering},
     mpsc::SyncSender,
     Arc,
 };
 use std::thread;
 
+#[derive(Clone)]
 pub struct SearchEngine {
     query: String,
+    // Alternatives searched together, set by `set_patterns`; empty for a single query
+    patterns: Vec<String>,
     use_regex: bool,
     case_sensitive: bool,
-    regex: Option<Regex>,
+    whole_line: bool,
+    invert: bool,
+    regex_flags: RegexFlags,
+    // Longest match expected, in file bytes, as set by the caller
+    max_match_len: Option<usize>,
+    // Matches counted at most
+    max_total: Option<usize>,
+    // Longest match the compiled pattern allows, in UTF-8 bytes; `None` when unbounded
+    pattern_max_len: Option<usize>,
+    density_buckets: usize,
+    matcher: Option<Matcher>,
     results: Vec<SearchResult>,
     total_results: usize,
 }
 
+// A single query is one `regex`. Several patterns share a `RegexSet`, which
+// first tells which of them match a chunk at all, so only those are run over it.
+#[derive(Clone)]
+enum Matcher {
+    Regex(Regex),
+    Set(RegexSet, Vec<Regex>),
+}
+
+impl Matcher {
+    // Yields `(start, end, pattern)`, `pattern` indexing the alternatives
+    fn find_iter<'m, 't>(&'m self, text: &'t str) -> MatchIter<'m, 't> {
+        match self {
+            Matcher::Regex(re) => MatchIter::Regex(re.find_iter(text)),
+            Matcher::Set(set, regexes) => MatchIter::Set(set_matches(set, regexes, text).into_iter()),
+        }
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        match self {
+            Matcher::Regex(re) => re.is_match(text),
+            Matcher::Set(set, _) => set.is_match(text),
+        }
+    }
+}
+
+// The matches of the patterns in `set`, as one alternation of them would find
+// them: from where the last match ended, the leftmost match of any pattern,
+// the lowest pattern index winning a tie, and no empty match right at the end
+// of the previous one. Only the patterns the set finds in `text` are run, and
+// each one's next match is kept until the scan passes its start.
+fn set_matches(set: &RegexSet, regexes: &[Regex], text: &str) -> Vec<(usize, usize, usize)> {
+    let patterns: Vec<usize> = set.matches(text).into_iter().collect();
+    // Per pattern: not searched yet, its next match, or `Some(None)` once it has no more
+    let mut next: Vec<Option<Option<(usize, usize)>>> = vec![None; patterns.len()];
+    let mut found = Vec::new();
+    let mut pos = 0;
+    let mut last_end = None;
+    while pos <= text.len() {
+        let mut best: Option<(usize, usize, usize)> = None;
+        for (slot, &pattern) in next.iter_mut().zip(&patterns) {
+            if slot.is_none_or(|m| m.is_some_and(|(start, _)| start < pos)) {
+                *slot = Some(
+                    regexes[pattern]
+                        .find_at(text, pos)
+                        .map(|m| (m.start(), m.end())),
+                );
+            }
+            if let Some(Some((start, end))) = *slot {
+                if best.is_none_or(|(best_start, _, _)| start < best_start) {
+                    best = Some((start, end, pattern));
+                }
+            }
+        }
+        let Some((start, end, pattern)) = best else {
+            break;
+        };
+        if start == end && last_end == Some(start) {
+            // Step over one char and search again, as `find_iter` does
+            match text[start..].chars().next() {
+                Some(c) => pos = start + c.len_utf8(),
+                None => break,
+            }
+            continue;
+        }
+        found.push((start, end, pattern));
+        last_end = Some(end);
+        pos = end;
+    }
+    found
+}
+
+enum MatchIter<'m, 't> {
+    Regex(regex::Matches<'m, 't>),
+    Set(std::vec::IntoIter<(usize, usize, usize)>),
+}
+
+impl Iterator for MatchIter<'_, '_> {
+    type Item = (usize, usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            MatchIter::Regex(it) => it.next().map(|m| (m.start(), m.end(), 0)),
+            MatchIter::Set(it) => it.next(),
+        }
+    }
+}
+
+/// Inline flags for regex queries, put in front of the pattern as `(?msx)`.
+/// Literal queries ignore them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegexFlags {
+    /// `(?m)`: `^` and `$` also match at line starts and ends
+    pub multiline: bool,
+    /// `(?s)`: `.` also matches `\n`
+    pub dot_matches_newline: bool,
+    /// `(?x)`: whitespace in the pattern is ignored and `#` starts a comment
+    pub ignore_whitespace: bool,
+}
+
+impl RegexFlags {
+    /// `pattern` with these flags turned on, or unchanged when none are set.
+    pub fn apply(&self, pattern: &str) -> String {
+        let flags: String = [
+            (self.multiline, 'm'),
+            (self.dot_matches_newline, 's'),
+            (self.ignore_whitespace, 'x'),
+        ]
+        .iter()
+        .filter(|(on, _)| *on)
+        .map(|(_, flag)| flag)
+        .collect();
+        if flags.is_empty() {
+            pattern.to_string()
+        } else {
+            format!("(?{}){}", flags, pattern)
+        }
+    }
+
+    // Whether a match can run on across lines, far past what the pattern's
+    // length suggests
+    fn spans_lines(&self) -> bool {
+        self.multiline || self.dot_matches_newline
+    }
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct SearchResult {
     pub byte_offset: usize,
     pub match_len: usize,
+    // Which of the `set_patterns` alternatives matched; 0 for a single query
+    pub pattern: usize,
 }
 
 //用于在多线程中传输部分结果
@@ -37,7 +180,15 @@ pub enum SearchType {
 // 线程间通信消息
 pub enum SearchMessage {
     ChunkResult(ChunkSearchResult),
-    CountResult(usize),
+    // Matches, and the distinct lines they start on
+    CountResult(usize, usize),
+    // Bytes counted so far (across all threads) and the file length
+    CountProgress(usize, usize),
+    // Matches per bucket of the file, see `set_density_buckets`
+    CountDensity(Vec<usize>),
+    // The count reached the `set_max_total` limit and stopped there; sent
+    // just before a `CountResult` of exactly that limit
+    CountTruncated,
     Done(SearchType),
     Error(String),
 }
@@ -52,158 +203,325 @@ impl SearchEngine {
     pub fn new() -> Self {
         Self {
             query: String::new(),
+            patterns: Vec::new(),
             use_regex: false,
             case_sensitive: false,
-            regex: None,
+            whole_line: false,
+            invert: false,
+            regex_flags: RegexFlags::default(),
+            max_match_len: None,
+            max_total: None,
+            pattern_max_len: None,
+            density_buckets: 0,
+            matcher: None,
             results: Vec::new(), //返回结果？
             total_results: 0,
         }
     }
 
     //核心还是用正则Regex去匹配啊
-    pub fn set_query(&mut self, query: String, use_regex: bool, case_sensitive: bool) {
+    pub fn set_query(
+        &mut self,
+        query: String,
+        use_regex: bool,
+        case_sensitive: bool,
+        whole_line: bool,
+    ) {
         self.query = query;
+        self.patterns.clear();
         self.use_regex = use_regex;
         self.case_sensitive = case_sensitive; //啥用？
+        self.whole_line = whole_line;
+        self.results.clear();
+        self.build_matcher();
+    }
 
-        let pattern = if use_regex {
-            if !case_sensitive {
-                format!("(?i){}", self.query)
-            } else {
-                self.query.clone()
-            }
-        } else if !case_sensitive {
-            format!("(?i){}", regex::escape(&self.query))
+    /// Searches for any of `patterns` at once, e.g. a list of error codes,
+    /// with the same options for all. Each result's `pattern` is the index of
+    /// the one that matched. The matches are those of a single `a|b|c`
+    /// alternation, so where several match at the same offset the earliest in
+    /// the list wins; but a chunk of the file is only scanned for the patterns
+    /// that occur in it. Replaced by the next `set_query`.
+    pub fn set_patterns(
+        &mut self,
+        patterns: Vec<String>,
+        use_regex: bool,
+        case_sensitive: bool,
+        whole_line: bool,
+    ) {
+        // Only checked for emptiness, and shown as what is searched for
+        self.query = patterns.join("|");
+        self.patterns = patterns;
+        self.use_regex = use_regex;
+        self.case_sensitive = case_sensitive;
+        self.whole_line = whole_line;
+        self.results.clear();
+        self.build_matcher();
+    }
+
+    /// Sets the inline flags regex queries are compiled with, recompiling the
+    /// current query. Segments are read with a much wider overlap while
+    /// multiline or dot-matches-newline is on, as such matches often run over
+    /// many lines; a match longer than the overlap that crosses a segment
+    /// boundary is still missed.
+    pub fn set_regex_flags(&mut self, flags: RegexFlags) {
+        self.regex_flags = flags;
+        self.build_matcher();
+    }
+
+    /// Tells the search how long a match can get, in bytes of the file. Each
+    /// segment is read with at least this much overlap, so a match crossing a
+    /// segment boundary is found as long as it is no longer. Worth setting for
+    /// unbounded regexes such as `.*error` or `(?s)BEGIN.*?END`, whose matches
+    /// can be far longer than the pattern; bounded patterns size the overlap
+    /// themselves. `None`, the default, leaves it to the pattern.
+    pub fn set_max_match_len(&mut self, max_match_len: Option<usize>) {
+        self.max_match_len = max_match_len;
+    }
+
+    /// Whether the query can match text of any length (it has `*`, `+` or an
+    /// open-ended `{n,}`), so matches longer than the overlap may be missed
+    /// where they cross a segment boundary unless `set_max_match_len` covers
+    /// them.
+    pub fn is_unbounded(&self) -> bool {
+        self.matcher.is_some() && self.pattern_max_len.is_none()
+    }
+
+    fn build_matcher(&mut self) {
+        if !self.patterns.is_empty() {
+            return self.build_set_matcher();
+        }
+        let (use_regex, case_sensitive, whole_line) =
+            (self.use_regex, self.case_sensitive, self.whole_line);
+        let query = if use_regex {
+            self.regex_flags.apply(&self.query)
         } else {
-            regex::escape(&self.query)
+            self.query.clone()
         };
+        let pattern = regex_pattern(&query, use_regex, case_sensitive, whole_line);
+        self.matcher = Regex::new(&pattern).ok().map(Matcher::Regex);
+        self.pattern_max_len = regex_syntax::parse(&pattern)
+            .ok()
+            .and_then(|hir| hir.properties().maximum_len());
+    }
 
-        self.regex = Regex::new(&pattern).ok();
+    // `build_matcher` for `set_patterns`; the overlap has to fit the longest
+    fn build_set_matcher(&mut self) {
+        let (use_regex, case_sensitive, whole_line) =
+            (self.use_regex, self.case_sensitive, self.whole_line);
+        let patterns: Vec<String> = self
+            .patterns
+            .iter()
+            .map(|query| {
+                let query = if use_regex {
+                    self.regex_flags.apply(query)
+                } else {
+                    query.clone()
+                };
+                regex_pattern(&query, use_regex, case_sensitive, whole_line)
+            })
+            .collect();
+        let regexes: Result<Vec<Regex>, _> = patterns.iter().map(|p| Regex::new(p)).collect();
+        self.matcher = match (RegexSet::new(&patterns), regexes) {
+            (Ok(set), Ok(regexes)) => Some(Matcher::Set(set, regexes)),
+            _ => None,
+        };
+        self.pattern_max_len = patterns
+            .iter()
+            .map(|pattern| {
+                regex_syntax::parse(pattern)
+                    .ok()
+                    .and_then(|hir| hir.properties().maximum_len())
+            })
+            .try_fold(0, |longest, len| Some(longest.max(len?)));
+    }
 
-        self.results.clear();
+    /// Invert the match (like `grep -v`): count and fetch then report one
+    /// result per line that does *not* contain the query, spanning the line's
+    /// text without its terminator.
+    pub fn set_invert(&mut self, invert: bool) {
+        self.invert = invert;
+    }
+
+    pub fn is_inverted(&self) -> bool {
+        self.invert
+    }
+
+    /// Caps `count_matches`: once it has found `max_total` matches it stops
+    /// scanning and sends `SearchMessage::CountTruncated` and that total, so a
+    /// pattern matching nearly everywhere (`x*`, `.`) can't keep every core
+    /// busy counting billions. The line count is then unknown and given as 0.
+    /// `None`, the default, counts everything.
+    pub fn set_max_total(&mut self, max_total: Option<usize>) {
+        self.max_total = max_total;
+    }
+
+    /// Makes `count_matches` also tally where the matches are: the whole file
+    /// is split into `buckets` equal byte ranges and the number of matches
+    /// starting in each is sent as `SearchMessage::CountDensity`, just before
+    /// the total. 0, the default, turns it off.
+    pub fn set_density_buckets(&mut self, buckets: usize) {
+        self.density_buckets = buckets;
+    }
+
+    /// The text to put in place of `matched`, one match of the query. For regex
+    /// queries `$N` / `${name}` in `replace_with` expand from the match's
+    /// captures; otherwise, or when the regex doesn't match `matched` on its
+    /// own, `replace_with` is used as is.
+    pub fn expand_replacement(&self, replace_with: &str, matched: &str) -> String {
+        match &self.matcher {
+            Some(Matcher::Set(_, regexes)) if self.use_regex => {
+                match regexes.iter().find_map(|re| re.captures(matched)) {
+                    Some(cap) => {
+                        let mut dst = String::new();
+                        cap.expand(replace_with, &mut dst);
+                        dst
+                    }
+                    None => replace_with.to_string(),
+                }
+            }
+            Some(Matcher::Regex(re)) if self.use_regex => match re.captures(matched) {
+                Some(cap) => {
+                    let mut dst = String::new();
+                    cap.expand(replace_with, &mut dst);
+                    dst
+                }
+                None => replace_with.to_string(),
+            },
+            _ => replace_with.to_string(),
+        }
     }
 
     pub fn find_in_text(&self, text: &str) -> Vec<(usize, usize)> {
+        self.find_patterns_in_text(text)
+            .into_iter()
+            .map(|(start, end, _)| (start, end))
+            .collect()
+    }
+
+    /// [`find_in_text`](Self::find_in_text) with the index of the pattern each
+    /// match is of, as in `SearchResult::pattern`.
+    pub fn find_patterns_in_text(&self, text: &str) -> Vec<(usize, usize, usize)> {
         let mut matches = Vec::new();
         if self.query.is_empty() {
             return matches;
         }
 
-        if let Some(re) = &self.regex {
-            for m in re.find_iter(text) {
-                matches.push((m.start(), m.end()));
-            }
+        if let Some(matcher) = &self.matcher {
+            matches.extend(matcher.find_iter(text));
         }
         matches
     }
 
-    pub fn count_matches(&self, reader: Arc<FileReader>, tx: SyncSender<SearchMessage>, cancel_token: Arc<AtomicBool>,
+    /// Counts the matches in the whole file, or only those inside `range` (see
+    /// [`fetch_matches`](Self::fetch_matches) for which matches that is).
+    pub fn count_matches(
+        &self,
+        reader: Arc<FileReader>,
+        tx: SyncSender<SearchMessage>,
+        range: Option<(usize, usize)>,
+        cancel_token: Arc<AtomicBool>,
     ) {
         let file_len = reader.len();
         if file_len == 0 || self.query.is_empty() {
-            let _ = tx.send(SearchMessage::CountResult(0));
+            let _ = tx.send(SearchMessage::CountResult(0, 0));
             let _ = tx.send(SearchMessage::Done(SearchType::Count));
             return;
         }
 
-        // 获取可用cpu数量
-        let num_threads = rayon::current_num_threads();
-
-        let chunk_size = file_len.div_ceil(num_threads);
-        let query_len = self.query.len();
-        // 设置重叠区域（overlap）避免跨边界匹配丢失
-        let overlap = query_len.saturating_sub(1).max(1000);
-
-        let regex = self.regex.clone();
+        let Some(matcher) = self.matcher.clone() else {
+            let _ = tx.send(SearchMessage::Error("Invalid regex".to_string()));
+            return;
+        };
+        let segments = Segments::new(&reader, self, range);
+        let bytes_total = segments.range.1 - segments.range.0;
+        let bytes_done = AtomicUsize::new(0);
+        let buckets = self.density_buckets;
+        let bucket_bytes = file_len.div_ceil(buckets.max(1));
+        let max_total = self.max_total.unwrap_or(usize::MAX);
+        // Matches counted by finished segments, and whether that reached max_total
+        let counted = AtomicUsize::new(0);
+        let truncated = AtomicBool::new(false);
 
         //使用 Rayon 并行处理不同分区
         thread::spawn(move || {
-            // 使用 Rayon 的并行迭代器处理所有线程块
-            let chunk_results: Vec<Result<usize, String>> = (0..num_threads)
+            // 使用 Rayon 的并行迭代器处理所有分段
+            let (count, density, lines) = segments
+                .indices()
                 .into_par_iter()
-                .map(|i| {
-                    let thread_start = i * chunk_size;
-                    if thread_start >= file_len {
-                        return Ok(0);
+                .map(|index| {
+                    let mut count = 0;
+                    let mut density = vec![0; buckets];
+                    let mut lines = LineTally::default();
+                    if truncated.load(Ordering::Relaxed) {
+                        return (0, density, lines);
                     }
-                    let thread_end = (thread_start + chunk_size).min(file_len);
-
-                    if let Some(ref regex) = regex {
-                        let mut pos = thread_start;
-                        // Process in smaller batches to avoid high memory usage
-                        const BATCH_SIZE: usize = 4 * 1024 * 1024; // 4MB
-                        let mut local_count = 0;
-
-                        while pos < thread_end {
-                            if cancel_token.load(Ordering::Relaxed) {
-                                return Ok(local_count);
-                            }
-
-                            let batch_end = (pos + BATCH_SIZE).min(thread_end);
-                            // Add overlap to catch matches crossing batch boundaries
-                            let read_end = (batch_end + overlap).min(file_len);
-
-                            let chunk_bytes = reader.get_bytes(pos, read_end);
-                            let chunk_text = match std::str::from_utf8(chunk_bytes) {
-                                Ok(t) => t.to_string(),
-                                Err(_) => {
-                                    let (cow, _, _) = reader.encoding().decode(chunk_bytes);
-                                    cow.into_owned()
-                                }
-                            };
-
-                            for mat in regex.find_iter(&chunk_text) {
-                                if cancel_token.load(Ordering::Relaxed) {
-                                    return Ok(local_count);
-                                }
-                                let match_start = mat.start();
-                                let absolute_start = pos + match_start;
-
-                                // Only accept matches starting in [pos, batch_end)
-                                if absolute_start >= batch_end {
-                                    continue;
-                                }
-
-                                local_count += 1;
+                    let visited =
+                        segments.visit_matches(&matcher, index, &cancel_token, |offset, _, _| {
+                            count += 1;
+                            if buckets > 0 {
+                                density[offset / bucket_bytes] += 1;
                             }
-
-                            pos = batch_end;
-                        }
-                        Ok(local_count)
-                    } else {
-                        Err("Invalid regex".to_string())
+                            lines.add(&segments.reader, offset);
+                            count < max_total
+                        });
+                    if visited.is_none() {
+                        return (0, density, LineTally::default());
                     }
+                    if counted.fetch_add(count, Ordering::Relaxed) + count >= max_total {
+                        truncated.store(true, Ordering::Relaxed);
+                    }
+
+                    // Progress is only informative, so never block on a full channel
+                    let scanned = segments.bytes_in_range(index);
+                    let done = bytes_done.fetch_add(scanned, Ordering::Relaxed) + scanned;
+                    let _ = tx.try_send(SearchMessage::CountProgress(done, bytes_total));
+                    (count, density, lines)
                 })
-                .collect();
+                // Reduces neighbouring segments, in order, so a line split
+                // between two is only counted once
+                .reduce(
+                    || (0, vec![0; buckets], LineTally::default()),
+                    |(count, mut density, lines), (more, more_density, more_lines)| {
+                        density
+                            .iter_mut()
+                            .zip(more_density)
+                            .for_each(|(tally, more)| *tally += more);
+                        let lines = lines.merge(more_lines, &segments.reader);
+                        (count + more, density, lines)
+                    },
+                );
 
             // 处理结果
             if cancel_token.load(Ordering::Relaxed) {
                 return;
             }
-
-            for result in chunk_results {
-                match result {
-                    Ok(count) => {
-                        if tx.send(SearchMessage::CountResult(count)).is_err() {
-                            return;
-                        }
-                    }
-                    Err(e) => {
-                        let _ = tx.send(SearchMessage::Error(e));
-                        return;
-                    }
-                }
+            if buckets > 0 && tx.send(SearchMessage::CountDensity(density)).is_err() {
+                return;
             }
-
-            if !cancel_token.load(Ordering::Relaxed) {
+            let (count, lines) = if count >= max_total {
+                if tx.send(SearchMessage::CountTruncated).is_err() {
+                    return;
+                }
+                (max_total, 0)
+            } else {
+                (count, lines.lines)
+            };
+            if tx.send(SearchMessage::CountResult(count, lines)).is_ok() {
                 let _ = tx.send(SearchMessage::Done(SearchType::Count));
             }
         });
     }
 
     //获取实际匹配位置
+    /// Fetches up to `max_results` matches starting at or after `start_offset`.
+    /// With a `range` of `[start, end)` byte bounds only matches lying entirely
+    /// inside it count: one that begins before `start` or ends after `end` is
+    /// left out, even if the rest of it is inside.
+    #[allow(clippy::too_many_arguments)]
     pub fn fetch_matches(&self, reader: Arc<FileReader>, tx: SyncSender<SearchMessage>,
-                         start_offset: usize, max_results: usize, cancel_token: Arc<AtomicBool>,
+                         start_offset: usize, max_results: usize, range: Option<(usize, usize)>,
+                         cancel_token: Arc<AtomicBool>,
     ) {
         let file_len = reader.len();
         if file_len == 0 || self.query.is_empty() {
@@ -211,102 +529,646 @@ impl SearchEngine {
             return;
         }
 
-        let regex = self.regex.clone();
-        let query_len = self.query.len();
-        let overlap = query_len.saturating_sub(1).max(1000);
+        let Some(matcher) = self.matcher.clone() else {
+            let _ = tx.send(SearchMessage::Error("Invalid regex".to_string()));
+            return;
+        };
+        let segments = Segments::new(&reader, self, range);
+        let start_offset = start_offset.max(segments.range.0);
 
         thread::spawn(move || {
-            if let Some(regex) = regex {
-                const CHUNK_SIZE: usize = 10 * 1024 * 1024; // 10 MB chunks
-                let mut chunk_start = start_offset;
-                let mut results_found = 0;
-
-                while chunk_start < file_len && results_found < max_results {
-                    if cancel_token.load(Ordering::Relaxed) {
-                        return;
-                    }
+            let mut results_found = 0;
 
-                    let chunk_end = (chunk_start + CHUNK_SIZE).min(file_len);
-                    let chunk_bytes = reader.get_bytes(chunk_start, chunk_end);
-
-                    let chunk_text = match std::str::from_utf8(chunk_bytes) {
-                        Ok(t) => t.to_string(),
-                        Err(_) => {
-                            let (cow, _, _) = reader.encoding().decode(chunk_bytes);
-                            cow.into_owned()
-                        }
-                    };
-
-                    let mut local_matches = Vec::new();
-
-                    // Define the valid range for starting positions in this chunk
-                    // We want to process matches that start in [chunk_start, chunk_end - overlap)
-                    // Unless we are at the end of the file, then [chunk_start, chunk_end)
-                    // 重叠处理
-                    let valid_end = if chunk_end >= file_len {
-                        file_len
-                    } else {
-                        chunk_end - overlap
-                    };
-
-                    for mat in regex.find_iter(&chunk_text) {
-                        if cancel_token.load(Ordering::Relaxed) {
-                            return;
-                        }
-                        if results_found >= max_results {
-                            break;
-                        }
-
-                        let match_start = mat.start();
-                        let absolute_start = chunk_start + match_start;
-
-                        // Skip matches that start beyond our valid range for this chunk
-                        // They will be picked up by the next chunk which starts at `valid_end`
-                        if absolute_start >= valid_end {
-                            continue;
-                        }
-
-                        local_matches.push(SearchResult {
-                            byte_offset: absolute_start,
-                            match_len: mat.end() - mat.start(),
-                        });
-                        results_found += 1;
-                    }
+            for index in segments.index_of(start_offset)..segments.indices().end {
+                if results_found >= max_results {
+                    break;
+                }
+                let Some(matches) = segments.matches(&matcher, index, &cancel_token) else {
+                    return;
+                };
 
-                    if !local_matches.is_empty()
-                        && tx
-                            .send(SearchMessage::ChunkResult(ChunkSearchResult {
-                                matches: local_matches,
-                            }))
-                            .is_err()
-                    {
-                        return;
-                    }
+                // The first segment may begin before the requested offset
+                let local_matches: Vec<SearchResult> = matches
+                    .into_iter()
+                    .filter(|m| m.byte_offset >= start_offset)
+                    .take(max_results - results_found)
+                    .collect();
+                results_found += local_matches.len();
 
-                    // Move to next chunk with overlap
-                    if chunk_end >= file_len {
-                        break;
-                    }
+                if !local_matches.is_empty()
+                    && tx
+                        .send(SearchMessage::ChunkResult(ChunkSearchResult {
+                            matches: local_matches,
+                        }))
+                        .is_err()
+                {
+                    return;
+                }
+            }
+
+            if !cancel_token.load(Ordering::Relaxed) {
+                let _ = tx.send(SearchMessage::Done(SearchType::Fetch));
+            }
+        });
+    }
+
+    /// Fetches the last `max_results` matches that end at or before `end_offset`,
+    /// scanning backward from it. They are sent in ascending order, in a single
+    /// `ChunkResult`, followed by `Done(SearchType::Fetch)`.
+    pub fn fetch_matches_before(
+        &self,
+        reader: Arc<FileReader>,
+        tx: SyncSender<SearchMessage>,
+        end_offset: usize,
+        max_results: usize,
+        cancel_token: Arc<AtomicBool>,
+    ) {
+        let file_len = reader.len();
+        let end_offset = end_offset.min(file_len);
+        if end_offset == 0 || max_results == 0 || self.query.is_empty() {
+            let _ = tx.send(SearchMessage::Done(SearchType::Fetch));
+            return;
+        }
+
+        let Some(matcher) = self.matcher.clone() else {
+            let _ = tx.send(SearchMessage::Error("Invalid regex".to_string()));
+            return;
+        };
+        let segments = Segments::new(&reader, self, None);
+
+        thread::spawn(move || {
+            // Segments are visited last to first, each holding matches in ascending order
+            let mut chunks: Vec<Vec<SearchResult>> = Vec::new();
+            let mut results_found = 0;
 
-                    chunk_start = chunk_end - overlap;
+            for index in (0..=segments.index_of(end_offset - 1)).rev() {
+                if results_found >= max_results {
+                    break;
                 }
-                if !cancel_token.load(Ordering::Relaxed) {
-                    let _ = tx.send(SearchMessage::Done(SearchType::Fetch));
+                let Some(mut matches) = segments.matches(&matcher, index, &cancel_token) else {
+                    return;
+                };
+
+                matches.retain(|m| m.byte_offset + m.match_len <= end_offset);
+                let wanted = max_results - results_found;
+                if matches.len() > wanted {
+                    matches.drain(..matches.len() - wanted);
                 }
-            } else {
-                let _ = tx.send(SearchMessage::Error("Invalid regex".to_string()));
+                results_found += matches.len();
+                chunks.push(matches);
+            }
+
+            let matches: Vec<SearchResult> = chunks.into_iter().rev().flatten().collect();
+            if !matches.is_empty()
+                && tx
+                    .send(SearchMessage::ChunkResult(ChunkSearchResult { matches }))
+                    .is_err()
+            {
+                return;
+            }
+            if !cancel_token.load(Ordering::Relaxed) {
+                let _ = tx.send(SearchMessage::Done(SearchType::Fetch));
             }
         });
     }
 
+    /// Every match in file order, found on the calling thread as the iterator
+    /// is advanced: no channels or threads, and memory bounded by one segment
+    /// of the file. Yields nothing for an empty query or an invalid regex.
+    pub fn iter_matches(&self, reader: &Arc<FileReader>) -> impl Iterator<Item = SearchResult> {
+        let matcher = self.matcher.clone().filter(|_| !self.query.is_empty());
+        let segments = Segments::new(reader, self, None);
+        let indices = match matcher {
+            Some(_) => segments.indices(),
+            None => 0..0,
+        };
+        let never_cancelled = AtomicBool::new(false);
+        indices.flat_map(move |index| {
+            matcher
+                .as_ref()
+                .and_then(|matcher| segments.matches(matcher, index, &never_cancelled))
+                .unwrap_or_default()
+        })
+    }
+
+    /// The text around the match at `byte_offset`: up to `radius` bytes on
+    /// either side, cut short at line ends, and the match's range within it.
+    /// Only that much is read and decoded, however long the line, so listing
+    /// results stays cheap on files with enormous lines.
+    pub fn context_snippet(
+        reader: &FileReader,
+        byte_offset: usize,
+        match_len: usize,
+        radius: usize,
+    ) -> (String, std::ops::Range<usize>) {
+        let match_end = (byte_offset + match_len).min(reader.len());
+        let byte_offset = byte_offset.min(match_end);
+        let mut start = byte_offset.saturating_sub(radius);
+        let mut end = (match_end + radius).min(reader.len());
+
+        // Start and end on whole characters
+        let encoding = reader.encoding();
+        if is_utf16(encoding) {
+            start += (byte_offset - start) % 2;
+            end -= (end - match_end) % 2;
+        } else if encoding == UTF_8 {
+            let is_continuation = |offset: usize| {
+                (reader.get_bytes(offset, offset + 1).first()).is_some_and(|&b| b & 0xC0 == 0x80)
+            };
+            while start < byte_offset && is_continuation(start) {
+                start += 1;
+            }
+            while end > match_end && is_continuation(end) {
+                end -= 1;
+            }
+        }
+
+        let text = reader.get_chunk(start, end);
+        let match_start = reader.decoded_len(start, byte_offset);
+        let match_stop = reader.decoded_len(start, match_end);
+        let line_start = text[..match_start].rfind('\n').map_or(0, |pos| pos + 1);
+        let line_end = text[match_stop..]
+            .find('\n')
+            .map_or(text.len(), |pos| match_stop + pos);
+        let line_end = line_end.max(line_start);
+        let line = text[line_start..line_end].trim_end_matches('\r');
+        let range = match_start - line_start..(match_stop - line_start).min(line.len());
+        (line.to_string(), range)
+    }
+
     pub fn clear(&mut self) {
         self.query.clear();
+        self.patterns.clear();
         self.results.clear();
-        self.regex = None;
+        self.matcher = None;
+        self.whole_line = false;
+        self.invert = false;
+        self.regex_flags = RegexFlags::default();
+        self.max_match_len = None;
+        self.max_total = None;
+        self.pattern_max_len = None;
+        self.density_buckets = 0;
         self.total_results = 0;
     }
 }
 
+/// Why `query` doesn't compile as a regex with these options, or `None` when
+/// it does; `set_query` leaves such a query without a matcher, so searching it
+/// only reports "Invalid regex". Checks the pattern `set_query` would build.
+pub fn pattern_error(
+    query: &str,
+    case_sensitive: bool,
+    whole_line: bool,
+    flags: RegexFlags,
+) -> Option<String> {
+    Regex::new(&regex_pattern(
+        &flags.apply(query),
+        true,
+        case_sensitive,
+        whole_line,
+    ))
+    .err()
+    .map(|e| e.to_string())
+}
+
+fn regex_pattern(query: &str, use_regex: bool, case_sensitive: bool, whole_line: bool) -> String {
+    let mut pattern = if use_regex {
+        query.to_string()
+    } else {
+        regex::escape(query)
+    };
+
+    // Whole-line mode (like `grep -x`): anchor to line boundaries. CRLF mode
+    // lets `$` match before "\r\n" so Windows line endings don't break it.
+    if whole_line {
+        pattern = format!("(?mR)^(?:{})$", pattern);
+    }
+
+    if !case_sensitive {
+        pattern = format!("(?i){}", pattern);
+    }
+    pattern
+}
+
+// Distinct lines holding a match, counted over a run of the file from matches
+// met in order. The first and last match are kept so runs can be joined.
+#[derive(Default)]
+struct LineTally {
+    lines: usize,
+    // Offsets of the first and last match
+    ends: Option<(usize, usize)>,
+}
+
+impl LineTally {
+    fn add(&mut self, reader: &FileReader, offset: usize) {
+        match &mut self.ends {
+            Some((_, last)) => {
+                if has_newline(reader, *last, offset) {
+                    self.lines += 1;
+                }
+                *last = offset;
+            }
+            None => {
+                self.lines = 1;
+                self.ends = Some((offset, offset));
+            }
+        }
+    }
+
+    // `self` followed by `next`
+    fn merge(self, next: Self, reader: &FileReader) -> Self {
+        match (self.ends, next.ends) {
+            (Some((first, last)), Some((next_first, next_last))) => Self {
+                lines: self.lines + next.lines
+                    - usize::from(!has_newline(reader, last, next_first)),
+                ends: Some((first, next_last)),
+            },
+            (Some(_), None) => self,
+            (None, _) => next,
+        }
+    }
+}
+
+// Whether a line ends between two offsets
+fn has_newline(reader: &FileReader, start: usize, end: usize) -> bool {
+    let newline = newline_bytes(reader.encoding());
+    let bytes = reader.get_bytes(start, end);
+    match newline {
+        [b] => bytes.contains(b),
+        // Both offsets start UTF-16 code units
+        _ => bytes.chunks_exact(2).any(|unit| unit == newline),
+    }
+}
+
+// Every scan splits the file into the same fixed segments, whatever the thread
+// count, and a match belongs to exactly the segment whose `[start, end)` holds
+// its first byte. Each segment is read with `overlap` extra bytes so a match
+// crossing its end is seen whole. Count, fetch and fetch-before thus agree on
+// the match set, however many threads or pages they use.
+struct Segments {
+    reader: Arc<FileReader>,
+    whole_line: bool,
+    invert: bool,
+    // Bytes of byte order mark at offset 0, which are never searched
+    bom_len: usize,
+    overlap: usize,
+    // Only matches inside these byte bounds are taken
+    range: (usize, usize),
+}
+
+impl Segments {
+    // Even, so every segment starts on a UTF-16 code unit (which only decodes
+    // correctly from an even offset)
+    const SIZE: usize = 4 * 1024 * 1024;
+    // Overlap for regex flags that let matches run over several lines
+    const SPANNING_OVERLAP: usize = 64 * 1024;
+
+    fn new(reader: &Arc<FileReader>, engine: &SearchEngine, range: Option<(usize, usize)>) -> Self {
+        let len = reader.len();
+        let (start, end) = range.map_or((0, len), |(start, end)| (start.min(len), end.min(len)));
+        Self {
+            reader: reader.clone(),
+            whole_line: engine.whole_line,
+            invert: engine.invert,
+            bom_len: reader.bom_len(),
+            overlap: Self::overlap(engine, reader.encoding()),
+            range: (start, end.max(start)),
+        }
+    }
+
+    // Enough to see whole any match starting before a segment end: the longest
+    // the caller or the pattern says a match can be, and never less than a
+    // floor for unbounded patterns. Each byte of decoded UTF-8 comes from at
+    // most two bytes of a UTF-16 file, and at most one in other encodings.
+    fn overlap(engine: &SearchEngine, encoding: &'static Encoding) -> usize {
+        let floor = if engine.use_regex && engine.regex_flags.spans_lines() {
+            Self::SPANNING_OVERLAP
+        } else {
+            1000
+        };
+        let units = if is_utf16(encoding) { 2 } else { 1 };
+        let pattern_len = engine.pattern_max_len.unwrap_or(0).saturating_mul(units);
+        pattern_len
+            .max(engine.max_match_len.unwrap_or(0))
+            .saturating_sub(1)
+            .max(floor)
+            .next_multiple_of(2)
+    }
+
+    // The segments overlapping the range
+    fn indices(&self) -> std::ops::Range<usize> {
+        let (start, end) = self.range;
+        if start == end {
+            return 0..0;
+        }
+        self.index_of(start)..self.index_of(end - 1) + 1
+    }
+
+    fn bytes_in_range(&self, index: usize) -> usize {
+        let (start, end) = self.bounds(index);
+        end.min(self.range.1)
+            .saturating_sub(start.max(self.range.0))
+    }
+
+    fn index_of(&self, offset: usize) -> usize {
+        offset / Self::SIZE
+    }
+
+    fn bounds(&self, index: usize) -> (usize, usize) {
+        let start = index * Self::SIZE;
+        let end = (start + Self::SIZE).min(self.reader.len());
+        (start.max(self.bom_len).min(end), end)
+    }
+
+    // The matches starting in segment `index`, in order; `None` once cancelled
+    fn matches(
+        &self,
+        matcher: &Matcher,
+        index: usize,
+        cancel_token: &AtomicBool,
+    ) -> Option<Vec<SearchResult>> {
+        let mut matches = Vec::new();
+        self.visit_matches(matcher, index, cancel_token, |byte_offset, match_len, pattern| {
+            matches.push(SearchResult {
+                byte_offset,
+                match_len,
+                pattern,
+            });
+            true
+        })?;
+        Some(matches)
+    }
+
+    // Calls `found(offset, len, pattern)` for each match `matches` would return, without
+    // collecting them, so counting costs no allocation per match. `found`
+    // returns false to stop there.
+    fn visit_matches(
+        &self,
+        matcher: &Matcher,
+        index: usize,
+        cancel_token: &AtomicBool,
+        mut found: impl FnMut(usize, usize, usize) -> bool,
+    ) -> Option<()> {
+        if self.invert {
+            return self.non_matching_lines(matcher, index, cancel_token, found);
+        }
+        let (start, end) = self.bounds(index);
+        let read_end = end.saturating_add(self.overlap).min(self.reader.len());
+        if cancel_token.load(Ordering::Relaxed) {
+            return None;
+        }
+        let bytes = self.reader.get_bytes(start, read_end);
+        let (text, mut offsets) = decode_chunk(bytes, self.reader.encoding());
+
+        for (match_start, match_end, pattern) in matcher.find_iter(&text) {
+            if cancel_token.load(Ordering::Relaxed) {
+                return None;
+            }
+            let absolute_start = start + offsets.source_offset(&text, match_start);
+            // Starts in the overlap belong to the next segment
+            if absolute_start >= end || absolute_start >= self.range.1 {
+                break;
+            }
+            if absolute_start < self.range.0 {
+                continue;
+            }
+            if self.whole_line && !starts_line(&self.reader, absolute_start) {
+                continue;
+            }
+            let absolute_end = start + offsets.source_offset(&text, match_end);
+            if absolute_end > self.range.1 {
+                continue;
+            }
+            if !found(absolute_start, absolute_end - absolute_start, pattern) {
+                break;
+            }
+        }
+        if cancel_token.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(())
+    }
+
+    // The lines starting in segment `index` that contain no match. Each line is
+    // read whole, however far past the segment end it runs.
+    fn non_matching_lines(
+        &self,
+        matcher: &Matcher,
+        index: usize,
+        cancel_token: &AtomicBool,
+        mut found: impl FnMut(usize, usize, usize) -> bool,
+    ) -> Option<()> {
+        let (start, end) = self.bounds(index);
+        let (start, end) = (start.max(self.range.0), end.min(self.range.1));
+        let encoding = self.reader.encoding();
+        let newline = newline_bytes(encoding);
+        let carriage_return: &[u8] = match encoding {
+            enc if enc == UTF_16LE => b"\r\0",
+            enc if enc == UTF_16BE => b"\0\r",
+            _ => b"\r",
+        };
+
+        // UTF-16 only decodes from an even offset
+        let mut line_start = start.next_multiple_of(newline.len());
+        if line_start < end && !starts_line(&self.reader, line_start) {
+            line_start = next_line(&self.reader, line_start, newline).1;
+        }
+
+        while line_start < end {
+            if cancel_token.load(Ordering::Relaxed) {
+                return None;
+            }
+            let (mut text_end, next_start) = next_line(&self.reader, line_start, newline);
+            if text_end > self.range.1 {
+                break;
+            }
+            if next_start > text_end
+                && self
+                    .reader
+                    .get_bytes(line_start, text_end)
+                    .ends_with(carriage_return)
+            {
+                text_end -= carriage_return.len();
+            }
+            if !self.line_has_match(matcher, line_start, text_end, cancel_token)?
+                && !found(line_start, text_end - line_start, 0)
+            {
+                break;
+            }
+            line_start = next_start;
+        }
+        Some(())
+    }
+
+    // Whether the line text `[start, end)` holds a match; `None` once cancelled.
+    // A long line is searched a segment at a time, each read with the usual
+    // overlap, so a cancel never waits on decoding all of it. Whole-line
+    // patterns have to see the line in one piece.
+    fn line_has_match(
+        &self,
+        matcher: &Matcher,
+        start: usize,
+        end: usize,
+        cancel_token: &AtomicBool,
+    ) -> Option<bool> {
+        let piece = if self.whole_line {
+            usize::MAX
+        } else {
+            Self::SIZE
+        };
+        let mut piece_start = start;
+        loop {
+            if cancel_token.load(Ordering::Relaxed) {
+                return None;
+            }
+            let piece_end = piece_start
+                .saturating_add(piece)
+                .saturating_add(self.overlap)
+                .min(end);
+            let bytes = self.reader.get_bytes(piece_start, piece_end);
+            let (text, _) = decode_chunk(bytes, self.reader.encoding());
+            if matcher.is_match(&text) {
+                return Some(true);
+            }
+            if piece_end >= end {
+                return Some(false);
+            }
+            piece_start += piece;
+        }
+    }
+}
+
+// End of the line's text and start of the next line, for the line running
+// through `offset`
+fn next_line(reader: &FileReader, offset: usize, newline: &[u8]) -> (usize, usize) {
+    let rest = reader.get_bytes(offset, reader.len());
+    match rest
+        .chunks_exact(newline.len())
+        .position(|unit| unit == newline)
+    {
+        Some(pos) => {
+            let text_end = offset + pos * newline.len();
+            (text_end, text_end + newline.len())
+        }
+        None => (reader.len(), reader.len()),
+    }
+}
+
+fn newline_bytes(encoding: &'static Encoding) -> &'static [u8] {
+    match encoding {
+        enc if enc == UTF_16LE => b"\n\0",
+        enc if enc == UTF_16BE => b"\0\n",
+        _ => b"\n",
+    }
+}
+
+// Chunks may begin mid-line, where `(?m)^` would still match at the chunk start.
+// Check the newline before the match in the file itself.
+fn starts_line(reader: &FileReader, offset: usize) -> bool {
+    let newline = newline_bytes(reader.encoding());
+    offset == 0
+        || offset == reader.bom_len()
+        || (offset >= newline.len() && reader.get_bytes(offset - newline.len(), offset) == newline)
+}
+
+fn is_utf16(encoding: &'static Encoding) -> bool {
+    encoding == UTF_16LE || encoding == UTF_16BE
+}
+
+// Decodes a chunk for matching. Byte offsets into the returned text only equal
+// offsets into `bytes` when no decoding was needed, so the returned map
+// translates them back.
+fn decode_chunk<'a>(bytes: &'a [u8], encoding: &'static Encoding) -> (Cow<'a, str>, OffsetMap<'a>) {
+    let utf8 = if encoding == UTF_8 {
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Some(text),
+            // Only a sequence cut off by the chunk end: the overlap covers it
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()
+            }
+            Err(_) => None,
+        }
+    } else if encoding.is_ascii_compatible() && bytes.is_ascii() {
+        std::str::from_utf8(bytes).ok()
+    } else {
+        None
+    };
+
+    if let Some(text) = utf8 {
+        return (
+            Cow::Borrowed(text),
+            OffsetMap::new(bytes, OffsetKind::Identity),
+        );
+    }
+
+    let kind = if encoding.is_single_byte() {
+        OffsetKind::SingleByte
+    } else if is_utf16(encoding) {
+        OffsetKind::Utf16
+    } else {
+        OffsetKind::Stepping(encoding.new_decoder_without_bom_handling())
+    };
+    // Without BOM handling a BOM decodes to U+FEFF, so it keeps its bytes in the map
+    let text = encoding.decode_without_bom_handling(bytes).0;
+    (text, OffsetMap::new(bytes, kind))
+}
+
+enum OffsetKind {
+    Identity,
+    // Every byte decodes to exactly one char
+    SingleByte,
+    // Every UTF-16 code unit is two bytes
+    Utf16,
+    // Variable-width encodings: replay the decoder one source byte at a time
+    Stepping(Decoder),
+}
+
+// Maps byte offsets in decoded text back to offsets in the source bytes.
+// Lookups must be made in non-decreasing order, matching how matches are found.
+struct OffsetMap<'a> {
+    source: &'a [u8],
+    kind: OffsetKind,
+    text_pos: usize,
+    source_pos: usize,
+}
+
+impl<'a> OffsetMap<'a> {
+    fn new(source: &'a [u8], kind: OffsetKind) -> Self {
+        Self {
+            source,
+            kind,
+            text_pos: 0,
+            source_pos: 0,
+        }
+    }
+
+    fn source_offset(&mut self, text: &str, text_offset: usize) -> usize {
+        debug_assert!(text_offset >= self.text_pos);
+        let skipped = &text[self.text_pos..text_offset];
+        match &mut self.kind {
+            OffsetKind::Identity => return text_offset,
+            OffsetKind::SingleByte => self.source_pos += skipped.chars().count(),
+            OffsetKind::Utf16 => {
+                self.source_pos += skipped.chars().map(|c| c.len_utf16() * 2).sum::<usize>()
+            }
+            OffsetKind::Stepping(decoder) => {
+                let mut buf = [0u8; 16];
+                let mut text_pos = self.text_pos;
+                while text_pos < text_offset && self.source_pos < self.source.len() {
+                    let byte = &self.source[self.source_pos..self.source_pos + 1];
+                    let (_, read, written, _) = decoder.decode_to_utf8(byte, &mut buf, false);
+                    self.source_pos += read;
+                    text_pos += written;
+                }
+            }
+        }
+        self.text_pos = text_offset;
+        // Replacement characters for a truncated tail can run past the input
+        self.source_pos = self.source_pos.min(self.source.len());
+        self.source_pos
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -318,7 +1180,7 @@ mod tests {
     #[test]
     fn test_find_in_text() {
         let mut engine = SearchEngine::new();
-        engine.set_query("test".to_string(), false, false);
+        engine.set_query("test".to_string(), false, false, false);
 
         let text = "This is a test string. Another test.";
         let matches = engine.find_in_text(text);
@@ -330,7 +1192,7 @@ mod tests {
     #[test]
     fn test_find_in_text_regex() {
         let mut engine = SearchEngine::new();
-        engine.set_query(r"\d+".to_string(), true, false);
+        engine.set_query(r"\d+".to_string(), true, false, false);
 
         let text = "There are 123 apples and 456 oranges.";
         let matches = engine.find_in_text(text);
@@ -340,24 +1202,94 @@ mod tests {
     }
 
     #[test]
-    fn test_count_matches() -> anyhow::Result<()> {
-        let mut file = NamedTempFile::new()?;
+    fn test_find_in_text_literal_case() {
+        let mut engine = SearchEngine::new();
+        engine.set_query("Error".to_string(), false, true, false);
+        assert_eq!(engine.find_in_text("error Error ERROR"), vec![(6, 11)]);
+
+        engine.set_query("Error".to_string(), false, false, false);
+        assert_eq!(
+            engine.find_in_text("error Error ERROR"),
+            vec![(0, 5), (6, 11), (12, 17)]
+        );
+
+        // Case-insensitive literals still get Unicode case folding
+        engine.set_query("ÄPFEL".to_string(), false, false, false);
+        assert_eq!(engine.find_in_text("äpfel"), vec![(0, 6)]);
+    }
+
+    #[test]
+    fn test_literal_metacharacters_only_match_themselves() {
+        // Only `use_regex` decides; a query that happens to compile stays literal
+        let mut engine = SearchEngine::new();
+        for case_sensitive in [true, false] {
+            engine.set_query("a.b".to_string(), false, case_sensitive, false);
+            assert_eq!(engine.find_in_text("axb a.b"), vec![(4, 7)]);
+        }
+        engine.set_query("a.b".to_string(), true, true, false);
+        assert_eq!(engine.find_in_text("axb a.b"), vec![(0, 3), (4, 7)]);
+    }
+
+    #[test]
+    fn test_find_in_text_whole_line() {
+        let mut engine = SearchEngine::new();
+        engine.set_query("error".to_string(), false, false, true);
+
+        assert_eq!(engine.find_in_text("ERROR"), vec![(0, 5)]);
+        assert!(engine.find_in_text("error: disk full").is_empty());
+
+        engine.set_query("error".to_string(), false, true, true);
+        assert!(engine.find_in_text("ERROR").is_empty());
+    }
+
+    #[test]
+    fn test_count_matches_whole_line() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "a.b\r\nxa.b\na.b\na.bc\na.b")?;
+        let path = file.path().to_path_buf();
+
+        let reader = Arc::new(FileReader::new(path, detect_encoding(b"").encoding)?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("a.b".to_string(), false, true, true);
+
+        let (tx, rx) = mpsc::sync_channel(10);
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        engine.count_matches(reader, tx, None, cancel_token);
+
+        let mut count = 0;
+        loop {
+            match rx.recv() {
+                Ok(SearchMessage::CountResult(c, _)) => count += c,
+                Ok(SearchMessage::Done(SearchType::Count)) => break,
+                Ok(SearchMessage::Error(e)) => panic!("Error: {}", e),
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+
+        assert_eq!(count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_matches() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
         write!(file, "test\ntest\ntest")?;
         let path = file.path().to_path_buf();
 
-        let reader = Arc::new(FileReader::new(path, detect_encoding(b""))?);
+        let reader = Arc::new(FileReader::new(path, detect_encoding(b"").encoding)?);
         let mut engine = SearchEngine::new();
-        engine.set_query("test".to_string(), false, false);
+        engine.set_query("test".to_string(), false, false, false);
 
         let (tx, rx) = mpsc::sync_channel(10);
         let cancel_token = Arc::new(AtomicBool::new(false));
 
-        engine.count_matches(reader, tx, cancel_token);
+        engine.count_matches(reader, tx, None, cancel_token);
 
         let mut count = 0;
         loop {
             match rx.recv() {
-                Ok(SearchMessage::CountResult(c)) => count += c,
+                Ok(SearchMessage::CountResult(c, _)) => count += c,
                 Ok(SearchMessage::Done(SearchType::Count)) => break,
                 Ok(SearchMessage::Error(e)) => panic!("Error: {}", e),
                 Ok(_) => continue,
@@ -368,6 +1300,988 @@ mod tests {
         assert_eq!(count, 3);
         Ok(())
     }
+
+    #[test]
+    fn test_count_density() -> anyhow::Result<()> {
+        // 40 bytes in four buckets of 10: two matches in the first, one in the last
+        let mut file = NamedTempFile::new()?;
+        write!(file, "ab ab.....{}ab", ".".repeat(28))?;
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_8)?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("ab".to_string(), false, true, false);
+        engine.set_density_buckets(4);
+
+        let (tx, rx) = mpsc::sync_channel(10);
+        engine.count_matches(reader, tx, None, Arc::new(AtomicBool::new(false)));
+        let mut density = None;
+        let mut count = 0;
+        for message in rx {
+            match message {
+                SearchMessage::CountDensity(tallies) => density = Some(tallies),
+                SearchMessage::CountResult(c, _) => count += c,
+                _ => {}
+            }
+        }
+        assert_eq!(density, Some(vec![2, 0, 0, 1]));
+        assert_eq!(count, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_matching_lines() -> anyhow::Result<()> {
+        let count_with_lines = |engine: &SearchEngine, reader: Arc<FileReader>| {
+            let (tx, rx) = mpsc::sync_channel(10);
+            engine.count_matches(reader, tx, None, Arc::new(AtomicBool::new(false)));
+            rx.iter().find_map(|message| match message {
+                SearchMessage::CountResult(count, lines) => Some((count, lines)),
+                _ => None,
+            })
+        };
+        let mut engine = SearchEngine::new();
+        engine.set_query("ab".to_string(), false, true, false);
+
+        let reader = Arc::new(FileReader::from_bytes(
+            b"ab ab ab\nnone\nab\n\nxab abab".to_vec(),
+            UTF_8,
+        ));
+        assert_eq!(count_with_lines(&engine, reader.clone()), Some((7, 3)));
+
+        // A line running across a segment boundary, with matches on both sides
+        let mut data = vec![b'.'; 2 * Segments::SIZE];
+        data[10..12].copy_from_slice(b"ab");
+        data[Segments::SIZE - 100] = b'\n';
+        data[Segments::SIZE - 10..Segments::SIZE - 8].copy_from_slice(b"ab");
+        data[Segments::SIZE + 10..Segments::SIZE + 12].copy_from_slice(b"ab");
+        let big = Arc::new(FileReader::from_bytes(data, UTF_8));
+        assert_eq!(count_with_lines(&engine, big), Some((3, 2)));
+
+        let utf16: Vec<u8> = "ab ab\nab\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        let utf16 = Arc::new(FileReader::from_bytes(utf16, UTF_16LE));
+        assert_eq!(count_with_lines(&engine, utf16), Some((3, 2)));
+
+        // Inverted results are lines themselves
+        engine.set_invert(true);
+        assert_eq!(count_with_lines(&engine, reader), Some((2, 2)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_stops_at_max_total() -> anyhow::Result<()> {
+        let count = |engine: &SearchEngine, reader: Arc<FileReader>| {
+            let (tx, rx) = mpsc::sync_channel(10);
+            engine.count_matches(reader, tx, None, Arc::new(AtomicBool::new(false)));
+            let mut truncated = false;
+            for message in rx {
+                match message {
+                    SearchMessage::CountTruncated => truncated = true,
+                    SearchMessage::CountResult(count, lines) => return (count, lines, truncated),
+                    _ => {}
+                }
+            }
+            panic!("no count result");
+        };
+        // Matches in every segment, far more than the limit
+        let reader = Arc::new(FileReader::from_bytes(
+            b"a\n".repeat(3 * Segments::SIZE / 2),
+            UTF_8,
+        ));
+        let mut engine = SearchEngine::new();
+        engine.set_query("a".to_string(), false, false, false);
+        assert_eq!(
+            count(&engine, reader.clone()),
+            (3 * Segments::SIZE / 2, 3 * Segments::SIZE / 2, false)
+        );
+
+        engine.set_max_total(Some(1000));
+        assert_eq!(count(&engine, reader.clone()), (1000, 0, true));
+
+        // Under the limit nothing changes
+        engine.set_max_total(Some(usize::MAX - 1));
+        assert!(!count(&engine, reader.clone()).2);
+        engine.set_query("none".to_string(), false, false, false);
+        engine.set_max_total(Some(1));
+        assert_eq!(count(&engine, reader), (0, 0, false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_progress() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        let line = "x".repeat(99) + "\n";
+        write!(file, "{}", line.repeat(100_000))?; // 10 MB, several batches
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("\n".to_string(), false, true, false);
+
+        let (tx, rx) = mpsc::sync_channel(1000);
+        engine.count_matches(reader.clone(), tx, None, Arc::new(AtomicBool::new(false)));
+        let mut count = 0;
+        let mut progress = Vec::new();
+        for msg in rx {
+            match msg {
+                SearchMessage::CountResult(c, _) => count += c,
+                SearchMessage::CountProgress(done, total) => {
+                    assert_eq!(total, reader.len());
+                    progress.push(done);
+                }
+                SearchMessage::Done(SearchType::Count) => break,
+                SearchMessage::Error(e) => panic!("Error: {}", e),
+                _ => {}
+            }
+        }
+        assert_eq!(count, 100_000);
+        assert!(progress.len() >= 3);
+        assert_eq!(progress.iter().max(), Some(&reader.len()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_matches_match_len() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "id=7 id=4242\nid=31")?;
+        let path = file.path().to_path_buf();
+
+        let reader = Arc::new(FileReader::new(path, detect_encoding(b"").encoding)?);
+        let mut engine = SearchEngine::new();
+        engine.set_query(r"id=\d+".to_string(), true, false, false);
+
+        let results = fetch_all(&engine, reader, 0, 100);
+        let spans: Vec<_> = results
+            .iter()
+            .map(|r| (r.byte_offset, r.match_len))
+            .collect();
+        assert_eq!(spans, vec![(0, 4), (5, 7), (13, 5)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_every_occurrence_on_a_line() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "apple apple apple\npear")?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+
+        for use_regex in [false, true] {
+            let mut engine = SearchEngine::new();
+            engine.set_query("apple".to_string(), use_regex, true, false);
+            let spans: Vec<_> = fetch_all(&engine, reader.clone(), 0, 100)
+                .iter()
+                .map(|r| (r.byte_offset, r.byte_offset + r.match_len))
+                .collect();
+            assert_eq!(spans, vec![(0, 5), (6, 11), (12, 17)]);
+            assert_eq!(count_all(&engine, reader.clone()), 3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_and_fetch_agree_at_boundaries() -> anyhow::Result<()> {
+        // Runs of "a" across every segment boundary, where scanning "aa" from a
+        // different starting point would pair the letters up differently
+        let mut data = vec![b'.'; 3 * Segments::SIZE + 100];
+        for boundary in (1..=3).map(|k| k * Segments::SIZE) {
+            for run_start in [boundary - 3, boundary - 1000] {
+                data[run_start..run_start + 7].fill(b'a');
+            }
+        }
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+
+        for (query, use_regex) in [("aa", false), ("a+", true), ("a.", true)] {
+            let mut engine = SearchEngine::new();
+            engine.set_query(query.to_string(), use_regex, true, false);
+            let fetched = fetch_all(&engine, reader.clone(), 0, usize::MAX);
+            assert_eq!(
+                count_all(&engine, reader.clone()),
+                fetched.len(),
+                "{}",
+                query
+            );
+
+            // Paging through from recorded offsets reaches the same matches
+            let mut paged = Vec::new();
+            let mut offset = 0;
+            loop {
+                let page = fetch_all(&engine, reader.clone(), offset, 4);
+                paged.extend(page.iter().map(|r| r.byte_offset));
+                match page.last() {
+                    Some(last) if page.len() == 4 => offset = last.byte_offset + 1,
+                    _ => break,
+                }
+            }
+            let all: Vec<_> = fetched.iter().map(|r| r.byte_offset).collect();
+            assert_eq!(paged, all, "{}", query);
+
+            let before = fetch_before(&engine, reader.clone(), data.len(), usize::MAX);
+            assert_eq!(before.len(), fetched.len(), "{}", query);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_owned_by_segment_holding_its_start() -> anyhow::Result<()> {
+        // Matches ending exactly at, starting exactly at, and straddling each
+        // boundary, in files of 2, 3 and 4 segments
+        for segment_count in 2..=4 {
+            for layout in [&[-2isize, 0][..], &[-1]] {
+                let mut data = vec![b'.'; segment_count * Segments::SIZE];
+                let mut expected = Vec::new();
+                for boundary in (1..segment_count).map(|k| k * Segments::SIZE) {
+                    for &delta in layout {
+                        let start = boundary.checked_add_signed(delta).unwrap();
+                        data[start..start + 2].copy_from_slice(b"ab");
+                        expected.push(start);
+                    }
+                }
+                let mut file = NamedTempFile::new()?;
+                file.write_all(&data)?;
+                let reader = Arc::new(FileReader::new(
+                    file.path().to_path_buf(),
+                    detect_encoding(b"").encoding,
+                )?);
+
+                for use_regex in [false, true] {
+                    let mut engine = SearchEngine::new();
+                    engine.set_query("ab".to_string(), use_regex, true, false);
+                    let fetched: Vec<_> = fetch_all(&engine, reader.clone(), 0, usize::MAX)
+                        .iter()
+                        .map(|r| r.byte_offset)
+                        .collect();
+                    assert_eq!(
+                        fetched, expected,
+                        "{} segments, {:?}",
+                        segment_count, layout
+                    );
+                    assert_eq!(count_all(&engine, reader.clone()), expected.len());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_matches() -> anyhow::Result<()> {
+        let mut data = vec![b'.'; 2 * Segments::SIZE + 10];
+        for start in [
+            0,
+            100,
+            Segments::SIZE - 1,
+            Segments::SIZE + 5,
+            2 * Segments::SIZE + 7,
+        ] {
+            data[start..start + 3].copy_from_slice(b"foo");
+        }
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("foo".to_string(), false, true, false);
+        let iterated: Vec<_> = engine
+            .iter_matches(&reader)
+            .map(|r| r.byte_offset)
+            .collect();
+        let fetched: Vec<_> = fetch_all(&engine, reader.clone(), 0, usize::MAX)
+            .iter()
+            .map(|r| r.byte_offset)
+            .collect();
+        assert_eq!(iterated, fetched);
+        assert_eq!(iterated.len(), 5);
+
+        // Lazy: taking the first match doesn't need the rest of the file
+        assert_eq!(
+            engine.iter_matches(&reader).next().map(|r| r.byte_offset),
+            Some(0)
+        );
+
+        engine.set_query("(".to_string(), true, true, false);
+        assert_eq!(engine.iter_matches(&reader).count(), 0);
+        engine.set_query(String::new(), false, true, false);
+        assert_eq!(engine.iter_matches(&reader).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_search_in_range() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"foo.foo.foo.foo")?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("foo".to_string(), false, true, false);
+
+        // Matches cut by either edge are left out
+        for (range, expected) in [
+            ((2, 11), vec![4, 8]),
+            ((2, 10), vec![4]),
+            ((0, 15), vec![0, 4, 8, 12]),
+            ((5, 5), vec![]),
+            ((12, 100), vec![12]),
+        ] {
+            let fetched = fetch_range(&engine, reader.clone(), 0, usize::MAX, Some(range));
+            let offsets: Vec<_> = fetched.iter().map(|r| r.byte_offset).collect();
+            assert_eq!(offsets, expected, "{:?}", range);
+            assert_eq!(
+                count_range(&engine, reader.clone(), Some(range)),
+                expected.len()
+            );
+        }
+        // Paging inside the range starts from the later of the two offsets
+        let fetched = fetch_range(&engine, reader.clone(), 5, usize::MAX, Some((2, 15)));
+        assert_eq!(
+            fetched.iter().map(|r| r.byte_offset).collect::<Vec<_>>(),
+            vec![8, 12]
+        );
+
+        // A range spanning a segment boundary
+        let mut data = vec![b'.'; Segments::SIZE + 100];
+        data[Segments::SIZE - 1..Segments::SIZE + 2].copy_from_slice(b"foo");
+        data[Segments::SIZE + 50..Segments::SIZE + 53].copy_from_slice(b"foo");
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let range = Some((Segments::SIZE - 10, Segments::SIZE + 10));
+        let fetched = fetch_range(&engine, reader.clone(), 0, usize::MAX, range);
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].byte_offset, Segments::SIZE - 1);
+        assert_eq!(count_range(&engine, reader, range), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_inverted_results_complement_matches() -> anyhow::Result<()> {
+        // Enough lines to span several segments, some crossing their boundaries
+        let mut data = String::new();
+        for i in 0..700_000 {
+            if i % 3 == 0 {
+                data.push_str(&format!("line {} has foo\r\n", i));
+            } else if i % 7 == 0 {
+                data.push('\n');
+            } else {
+                data.push_str(&format!("line {}\n", i));
+            }
+        }
+        data.push_str("last foo");
+        let mut file = NamedTempFile::new()?;
+        file.write_all(data.as_bytes())?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("foo".to_string(), false, true, false);
+        let matching: Vec<_> = fetch_all(&engine, reader.clone(), 0, usize::MAX)
+            .iter()
+            .map(|r| data[..r.byte_offset].rfind('\n').map_or(0, |pos| pos + 1))
+            .collect();
+
+        engine.set_invert(true);
+        let inverted = fetch_all(&engine, reader.clone(), 0, usize::MAX);
+        assert_eq!(count_all(&engine, reader.clone()), inverted.len());
+
+        let mut line_starts = vec![0];
+        line_starts.extend(data.match_indices('\n').map(|(pos, _)| pos + 1));
+        let expected: Vec<_> = line_starts
+            .into_iter()
+            .filter(|start| matching.binary_search(start).is_err())
+            .collect();
+        assert_eq!(
+            inverted.iter().map(|r| r.byte_offset).collect::<Vec<_>>(),
+            expected
+        );
+        for result in &inverted {
+            let text = &data[result.byte_offset..result.byte_offset + result.match_len];
+            assert!(!text.contains('\n') && !text.contains("foo"), "{:?}", text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_final_page_from_recorded_offset() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        for i in 0..25 {
+            writeln!(file, "match {}", i)?;
+        }
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("match".to_string(), false, false, false);
+        let all = fetch_all(&engine, reader.clone(), 0, 100);
+        assert_eq!(count_all(&engine, reader.clone()), 25);
+
+        // Walk pages of 10, recording where each one starts, like the viewer does
+        let page_size = 10;
+        let mut page_offsets = vec![0];
+        loop {
+            let page = fetch_all(
+                &engine,
+                reader.clone(),
+                *page_offsets.last().unwrap(),
+                page_size,
+            );
+            if page.len() < page_size {
+                break;
+            }
+            page_offsets.push(page.last().unwrap().byte_offset + 1);
+        }
+        assert_eq!(page_offsets.len(), 3);
+
+        // The final page, (25 - 1) / 10 = 2, fetched directly from its recorded offset
+        let last_page = fetch_all(&engine, reader, page_offsets[2], page_size);
+        let offsets: Vec<_> = last_page.iter().map(|r| r.byte_offset).collect();
+        let expected: Vec<_> = all[20..].iter().map(|r| r.byte_offset).collect();
+        assert_eq!(offsets, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fetch_matches_before() -> anyhow::Result<()> {
+        // Spans several segments, with matches straddling their boundaries
+        const MB: usize = 1024 * 1024;
+        let mut data = vec![b'.'; 25 * MB];
+        let mut positions = vec![0, 17, 5000];
+        for boundary in (1..=6).map(|k| k * Segments::SIZE) {
+            positions.extend([boundary - 503, boundary - 3, boundary + 3]);
+        }
+        positions.push(data.len() - 6);
+        for &pos in &positions {
+            data[pos..pos + 6].copy_from_slice(b"needle");
+        }
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("needle".to_string(), false, true, false);
+        let all = fetch_all(&engine, reader.clone(), 0, 100);
+        assert_eq!(all.len(), positions.len());
+
+        let offsets = |results: &[SearchResult]| -> Vec<usize> {
+            results.iter().map(|r| r.byte_offset).collect()
+        };
+        let boundary = 2 * Segments::SIZE;
+        for end_offset in [0, 6, 5005, 5006, boundary + 2, boundary + 9, data.len()] {
+            let expected: Vec<_> = all
+                .iter()
+                .filter(|r| r.byte_offset + r.match_len <= end_offset)
+                .collect();
+            for max_results in [1, 3, 100] {
+                let before = fetch_before(&engine, reader.clone(), end_offset, max_results);
+                let skip = expected.len().saturating_sub(max_results);
+                let want: Vec<_> = expected[skip..].iter().map(|r| r.byte_offset).collect();
+                assert_eq!(
+                    offsets(&before),
+                    want,
+                    "end {} max {}",
+                    end_offset,
+                    max_results
+                );
+                assert!(before.iter().all(|r| r.match_len == 6));
+            }
+        }
+        Ok(())
+    }
+
+    fn encoded_spans(
+        encoding: &'static Encoding,
+        bytes: &[u8],
+        query: &str,
+    ) -> Vec<(usize, usize)> {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(bytes).unwrap();
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), encoding).unwrap());
+        let mut engine = SearchEngine::new();
+        engine.set_query(query.to_string(), false, false, false);
+        assert_eq!(
+            count_all(&engine, reader.clone()),
+            fetch_all(&engine, reader.clone(), 0, 100).len()
+        );
+        fetch_all(&engine, reader, 0, 100)
+            .iter()
+            .map(|r| (r.byte_offset, r.match_len))
+            .collect()
+    }
+
+    fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+        text.encode_utf16()
+            .flat_map(|unit| {
+                if big_endian {
+                    unit.to_be_bytes()
+                } else {
+                    unit.to_le_bytes()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fetch_matches_non_utf8_offsets() {
+        // "é" is one byte in Windows-1252 but two once decoded
+        let bytes = encoding_rs::WINDOWS_1252
+            .encode("café error\nnaïve error")
+            .0;
+        assert_eq!(
+            encoded_spans(encoding_rs::WINDOWS_1252, &bytes, "error"),
+            vec![(5, 5), (17, 5)]
+        );
+        assert_eq!(
+            encoded_spans(encoding_rs::WINDOWS_1252, &bytes, "naïve"),
+            vec![(11, 5)]
+        );
+
+        // Two bytes per code unit, four for the astral "𝄞"; the BOM keeps its bytes
+        let text = "\u{feff}𝄞 ab\nab";
+        for big_endian in [false, true] {
+            let encoding = if big_endian { UTF_16BE } else { UTF_16LE };
+            assert_eq!(
+                encoded_spans(encoding, &utf16(text, big_endian), "ab"),
+                vec![(8, 4), (14, 4)]
+            );
+        }
+
+        // Variable-width multibyte encoding
+        let bytes = encoding_rs::SHIFT_JIS.encode("日本 error 語error").0;
+        assert_eq!(
+            encoded_spans(encoding_rs::SHIFT_JIS, &bytes, "error"),
+            vec![(5, 5), (13, 5)]
+        );
+    }
+
+    #[test]
+    fn test_fetch_matches_utf16_odd_start() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&utf16("ab ab ab", false)).unwrap();
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_16LE).unwrap());
+        let mut engine = SearchEngine::new();
+        engine.set_query("ab".to_string(), false, false, false);
+
+        // "Next match after offset 0" starts mid code unit
+        let offsets: Vec<_> = fetch_all(&engine, reader, 1, 100)
+            .iter()
+            .map(|r| r.byte_offset)
+            .collect();
+        assert_eq!(offsets, vec![6, 12]);
+    }
+
+    #[test]
+    fn test_context_snippet() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(
+            file,
+            "first\r\n{}héllo match wörld{}\nlast",
+            "x".repeat(1000),
+            "y".repeat(1000)
+        )?;
+        let reader = FileReader::new(file.path().to_path_buf(), UTF_8)?;
+        let offset = 7 + 1000 + "héllo ".len();
+
+        // A window into the long line, never cutting "é" or "ö" in half
+        let (text, range) = SearchEngine::context_snippet(&reader, offset, 5, 6);
+        assert_eq!(text, "éllo match wörl");
+        assert_eq!(&text[range], "match");
+        let (text, _) = SearchEngine::context_snippet(&reader, offset, 5, 3);
+        assert_eq!(text, "lo match w");
+
+        // Short lines come whole, without their terminator
+        let (text, range) = SearchEngine::context_snippet(&reader, 1, 3, 100);
+        assert_eq!((text.as_str(), range), ("first", 1..4));
+        let (text, range) = SearchEngine::context_snippet(&reader, reader.len() - 4, 4, 100);
+        assert_eq!((text.as_str(), range), ("last", 0..4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_line_searched_in_pieces() -> anyhow::Result<()> {
+        // One line of three segments, "needle" straddling the first boundary
+        let size = Segments::SIZE;
+        let mut line = vec![b'a'; 3 * size];
+        line[size - 3..size + 3].copy_from_slice(b"needle");
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&line)?;
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_8)?);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("needle".to_string(), false, true, false);
+        engine.set_invert(true);
+        let segments = Segments::new(&reader, &engine, None);
+        let matcher = engine.matcher.clone().unwrap();
+        let running = AtomicBool::new(false);
+        assert_eq!(
+            segments.line_has_match(&matcher, 0, reader.len(), &running),
+            Some(true)
+        );
+        assert_eq!(
+            segments.line_has_match(&matcher, size + 3, reader.len(), &running),
+            Some(false)
+        );
+
+        // Cancelling takes effect before the next piece, not after the whole line
+        let cancelled = AtomicBool::new(true);
+        assert_eq!(
+            segments.line_has_match(&matcher, 0, reader.len(), &cancelled),
+            None
+        );
+
+        // The line holds the needle, so an inverted search doesn't list it
+        assert_eq!(count_all(&engine, reader), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pattern_error() {
+        let error = pattern_error("(", false, false, RegexFlags::default()).unwrap();
+        assert!(error.contains("unclosed group"), "{}", error);
+        assert_eq!(
+            pattern_error(r"\d+(a|b)", true, true, RegexFlags::default()),
+            None
+        );
+
+        // The engine refuses the same patterns
+        let mut engine = SearchEngine::new();
+        engine.set_query("[a-".to_string(), true, true, false);
+        assert!(pattern_error("[a-", true, false, RegexFlags::default()).is_some());
+        assert!(engine.find_in_text("[a-").is_empty());
+    }
+
+    #[test]
+    fn test_regex_flags() -> anyhow::Result<()> {
+        let find = |query: &str, use_regex: bool, flags: RegexFlags, text: &str| {
+            let mut engine = SearchEngine::new();
+            engine.set_query(query.to_string(), use_regex, true, false);
+            engine.set_regex_flags(flags);
+            engine.find_in_text(text)
+        };
+        let multiline = RegexFlags {
+            multiline: true,
+            ..RegexFlags::default()
+        };
+        let dot_all = RegexFlags {
+            dot_matches_newline: true,
+            ..RegexFlags::default()
+        };
+        let extended = RegexFlags {
+            ignore_whitespace: true,
+            ..RegexFlags::default()
+        };
+        assert!(find("^b", true, RegexFlags::default(), "a\nb").is_empty());
+        assert_eq!(find("^b", true, multiline, "a\nb"), vec![(2, 3)]);
+        assert!(find("a.b", true, RegexFlags::default(), "a\nb").is_empty());
+        assert_eq!(find("a.b", true, dot_all, "a\nb"), vec![(0, 3)]);
+        assert_eq!(find("a b  # comment", true, extended, "ab"), vec![(0, 2)]);
+        // Literal queries take the text as is
+        assert_eq!(find("a b", false, extended, "a b"), vec![(0, 3)]);
+        assert_eq!(RegexFlags::default().apply("x"), "x".to_string());
+        assert_eq!(
+            RegexFlags {
+                multiline: true,
+                dot_matches_newline: true,
+                ignore_whitespace: true
+            }
+            .apply("x"),
+            "(?msx)x"
+        );
+
+        // A multi-line match much longer than the default overlap, crossing a
+        // segment boundary
+        let mut data = vec![b'.'; Segments::SIZE + 10_000];
+        for line_end in (100..data.len()).step_by(100) {
+            data[line_end] = b'\n';
+        }
+        let begin = Segments::SIZE - 5000;
+        data[begin..begin + 5].copy_from_slice(b"BEGIN");
+        data[Segments::SIZE + 3000..Segments::SIZE + 3003].copy_from_slice(b"END");
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_8)?);
+        let mut engine = SearchEngine::new();
+        engine.set_query("BEGIN.*?END".to_string(), true, true, false);
+        engine.set_regex_flags(dot_all);
+        assert_eq!(count_all(&engine, reader.clone()), 1);
+        let fetched = fetch_all(&engine, reader, 0, usize::MAX);
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].byte_offset, begin);
+        assert_eq!(fetched[0].match_len, Segments::SIZE + 3003 - begin);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlap_follows_max_match_len() -> anyhow::Result<()> {
+        // Matches far longer than their pattern, starting before the first
+        // segment boundary and ending after it
+        let mut data = vec![b'.'; Segments::SIZE + 10_000];
+        let start = Segments::SIZE - 3000;
+        data[start] = b'a';
+        data[Segments::SIZE + 2000] = b'b';
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&data)?;
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_8)?);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("a.*b".to_string(), true, true, false);
+        assert!(engine.is_unbounded());
+        assert_eq!(count_all(&engine, reader.clone()), 0);
+
+        engine.set_max_match_len(Some(8000));
+        assert_eq!(count_all(&engine, reader.clone()), 1);
+        let fetched = fetch_all(&engine, reader.clone(), 0, usize::MAX);
+        assert_eq!(fetched.len(), 1);
+        assert_eq!(fetched[0].byte_offset, start);
+        assert_eq!(fetched[0].match_len, 5001);
+
+        // A bounded pattern sizes the overlap itself
+        let mut engine = SearchEngine::new();
+        engine.set_query("a.{5000}".to_string(), true, true, false);
+        assert!(!engine.is_unbounded());
+        assert_eq!(count_all(&engine, reader), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bom_is_not_searched() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        write!(file, "\u{feff}abc\nabc\n")?;
+        let reader = Arc::new(FileReader::new(file.path().to_path_buf(), UTF_8)?);
+
+        // Line 1 starts after the BOM, so whole-line and inverted matching see it
+        let mut engine = SearchEngine::new();
+        engine.set_query("abc".to_string(), false, true, true);
+        let offsets: Vec<_> = fetch_all(&engine, reader.clone(), 0, 100)
+            .iter()
+            .map(|r| (r.byte_offset, r.match_len))
+            .collect();
+        assert_eq!(offsets, vec![(3, 3), (7, 3)]);
+        assert_eq!(count_all(&engine, reader.clone()), 2);
+
+        engine.set_query("x".to_string(), false, true, false);
+        engine.set_invert(true);
+        let lines: Vec<_> = engine
+            .iter_matches(&reader)
+            .map(|r| r.byte_offset)
+            .collect();
+        assert_eq!(lines, vec![3, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_expand_replacement() {
+        let mut engine = SearchEngine::new();
+        engine.set_query(r"id=(?<n>\d+)".to_string(), true, false, false);
+        assert_eq!(engine.expand_replacement("#$1/${n}", "ID=42"), "#42/42");
+
+        // Literal queries, and text the regex doesn't match, take the template as is
+        engine.set_query("id=1".to_string(), false, true, false);
+        assert_eq!(engine.expand_replacement("$1", "id=1"), "$1");
+        engine.set_query(r"\d+".to_string(), true, true, false);
+        assert_eq!(engine.expand_replacement("<$0>", "abc"), "<$0>");
+    }
+
+    #[test]
+    fn test_set_patterns() -> anyhow::Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"E100 ok\nE2001 bad\nwarn E100\nE300\n")?;
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let codes = || vec!["E300".to_string(), "E100".to_string(), "E200".to_string()];
+        let found = |engine: &SearchEngine| -> Vec<(usize, usize)> {
+            fetch_all(engine, reader.clone(), 0, usize::MAX)
+                .iter()
+                .map(|r| (r.byte_offset, r.pattern))
+                .collect()
+        };
+
+        let mut engine = SearchEngine::new();
+        // Literal in either case mode, and as regexes
+        for (use_regex, case_sensitive) in [(false, true), (false, false), (true, true)] {
+            engine.set_patterns(codes(), use_regex, case_sensitive, false);
+            assert_eq!(found(&engine), vec![(0, 1), (8, 2), (23, 1), (28, 0)]);
+            assert_eq!(count_all(&engine, reader.clone()), 4);
+        }
+
+        // At the same offset the earlier pattern wins; overlaps are dropped
+        engine.set_patterns(vec![r"E\d+".to_string(), "E2".to_string()], true, true, false);
+        assert_eq!(engine.find_in_text("E2001 E2"), vec![(0, 5), (6, 8)]);
+        engine.set_patterns(vec!["E2".to_string(), r"E\d+".to_string()], true, true, false);
+        assert_eq!(engine.find_in_text("E2001 E2"), vec![(0, 2), (6, 8)]);
+
+        // Same matches as one alternation, even where a pattern's own match
+        // would overlap the one that follows
+        for (patterns, text) in [
+            (&["ab", "bcd|d"][..], "abcd"),
+            (&["a*", "b"], "aabab"),
+            (&["", "a"], "baab"),
+            (&["é?", "x"], "éxé"),
+            (&[r"\bb", "ab"], "ab b"),
+        ] {
+            let alternation = patterns
+                .iter()
+                .map(|p| format!("(?:{})", p))
+                .collect::<Vec<_>>()
+                .join("|");
+            engine.set_query(alternation, true, true, false);
+            let expected = engine.find_in_text(text);
+            engine.set_patterns(patterns.iter().map(|p| p.to_string()).collect(), true, true, false);
+            assert_eq!(engine.find_in_text(text), expected, "{:?} in {:?}", patterns, text);
+        }
+        engine.set_patterns(vec!["ab".to_string(), "bcd|d".to_string()], true, true, false);
+        assert_eq!(engine.find_patterns_in_text("abcd"), vec![(0, 2, 0), (3, 4, 1)]);
+
+        // Whole-line, and captures from whichever pattern matched
+        engine.set_patterns(vec!["E300".to_string(), "E100".to_string()], false, true, true);
+        assert_eq!(found(&engine), vec![(28, 0)]);
+        engine.set_patterns(vec![r"x(\d)".to_string(), r"E(\d+)".to_string()], true, true, false);
+        assert_eq!(engine.expand_replacement("<$1>", "E100"), "<100>");
+
+        // An invalid pattern invalidates the set; a single query is unaffected
+        engine.set_patterns(vec!["E1".to_string(), "(".to_string()], true, true, false);
+        assert!(engine.find_in_text("E1").is_empty());
+        engine.set_query("E100".to_string(), false, true, false);
+        assert_eq!(found(&engine), vec![(0, 0), (23, 0)]);
+        Ok(())
+    }
+
+    fn fetch_all(
+        engine: &SearchEngine,
+        reader: Arc<FileReader>,
+        start_offset: usize,
+        max_results: usize,
+    ) -> Vec<SearchResult> {
+        fetch_range(engine, reader, start_offset, max_results, None)
+    }
+
+    fn fetch_range(
+        engine: &SearchEngine,
+        reader: Arc<FileReader>,
+        start_offset: usize,
+        max_results: usize,
+        range: Option<(usize, usize)>,
+    ) -> Vec<SearchResult> {
+        let (tx, rx) = mpsc::sync_channel(10);
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        engine.fetch_matches(reader, tx, start_offset, max_results, range, cancel_token);
+        collect_fetched(rx)
+    }
+
+    fn fetch_before(
+        engine: &SearchEngine,
+        reader: Arc<FileReader>,
+        end_offset: usize,
+        max_results: usize,
+    ) -> Vec<SearchResult> {
+        let (tx, rx) = mpsc::sync_channel(10);
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        engine.fetch_matches_before(reader, tx, end_offset, max_results, cancel_token);
+        collect_fetched(rx)
+    }
+
+    fn collect_fetched(rx: mpsc::Receiver<SearchMessage>) -> Vec<SearchResult> {
+        let mut results = Vec::new();
+        loop {
+            match rx.recv() {
+                Ok(SearchMessage::ChunkResult(chunk)) => results.extend(chunk.matches),
+                Ok(SearchMessage::Done(SearchType::Fetch)) => break,
+                Ok(SearchMessage::Error(e)) => panic!("Error: {}", e),
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        results
+    }
+
+    fn count_all(engine: &SearchEngine, reader: Arc<FileReader>) -> usize {
+        count_range(engine, reader, None)
+    }
+
+    fn count_range(
+        engine: &SearchEngine,
+        reader: Arc<FileReader>,
+        range: Option<(usize, usize)>,
+    ) -> usize {
+        let (tx, rx) = mpsc::sync_channel(10);
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        engine.count_matches(reader, tx, range, cancel_token);
+
+        let mut count = 0;
+        loop {
+            match rx.recv() {
+                Ok(SearchMessage::CountResult(c, _)) => count += c,
+                Ok(SearchMessage::Done(SearchType::Count)) => break,
+                Ok(SearchMessage::Error(e)) => panic!("Error: {}", e),
+                Ok(_) => continue,
+                Err(_) => break,
+            }
+        }
+        count
+    }
+
+    // Counting a query that hits several times per line against fetching every
+    // match, which has to build and send a result for each one.
+    // Run with: cargo test --release -p large-text-core -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn bench_count_dense_matches() -> anyhow::Result<()> {
+        const TARGET_SIZE: usize = 256 * 1024 * 1024; // 256 MB
+        let mut file = NamedTempFile::new()?;
+        let line = b"2024-01-01T00:00:00Z INFO  worker-7 processed request id=123456 in 42ms\n";
+        let block = line.repeat(16 * 1024);
+        let mut written = 0;
+        while written < TARGET_SIZE {
+            file.write_all(&block)?;
+            written += block.len();
+        }
+        file.flush()?;
+
+        let reader = Arc::new(FileReader::new(
+            file.path().to_path_buf(),
+            detect_encoding(b"").encoding,
+        )?);
+        let mb = reader.len() as f64 / (1024.0 * 1024.0);
+
+        let mut engine = SearchEngine::new();
+        engine.set_query("e".to_string(), false, true, false);
+        let start = std::time::Instant::now();
+        let count = count_all(&engine, reader.clone());
+        let count_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let fetched = fetch_all(&engine, reader.clone(), 0, usize::MAX).len();
+        let fetch_time = start.elapsed();
+
+        assert_eq!(count, fetched);
+        println!(
+            "{} matches: count {:.0} MB/s, fetch all {:.0} MB/s",
+            count,
+            mb / count_time.as_secs_f64(),
+            mb / fetch_time.as_secs_f64()
+        );
+        Ok(())
+    }
 }
 
-//4662219
\ No newline at end of file
+//4662219
@@ -1,51 +1,94 @@
 use eframe::egui;
 use encoding_rs::Encoding;
 use notify::{RecursiveMode, Result as NotifyResult, Watcher};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver};
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Weak,
 };
 
-use large_text_core::file_reader::{available_encodings, detect_encoding, FileReader};
+use crate::command_palette::{ActionRegistry, CommandPalette};
+use crate::settings::{
+    Settings, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, MAX_SEARCH_HISTORY, MAX_WRAP_COLUMN,
+    MAX_WRAP_INDENT, MIN_PAGE_SIZE, MIN_WRAP_COLUMN,
+};
+use crate::syntax::{highlight_line, SyntaxFormat};
+use large_text_core::file_reader::{
+    available_encodings, detect_encoding, is_gzip, is_probably_binary, open_content,
+    DetectedEncoding, FileReader, MAX_MAPPED_LEN,
+};
 use large_text_core::line_indexer::LineIndexer;
-use large_text_core::replacer::{ReplaceMessage, Replacer};
-use large_text_core::search_engine::{SearchEngine, SearchMessage, SearchResult, SearchType};
+use large_text_core::multi_file_search::{FileMatch, MultiFileMessage, MultiFileSearch};
+use large_text_core::replacer::{
+    check_replacement_template, is_same_file, match_case, write_atomically, ReplaceMessage,
+    ReplacePreview, Replacer,
+};
+use large_text_core::search_engine::{
+    pattern_error, RegexFlags, SearchEngine, SearchMessage, SearchResult, SearchType,
+};
 
 //这里可以用更复杂一点的结构来增强可读性，或者抽象几个对象来继承
 pub struct TextViewerApp {
-    file_reader: Option<Arc<FileReader>>,
-    line_indexer: LineIndexer,
-    search_engine: SearchEngine,
+    // The active tab's file and everything tied to it
+    doc: Document,
 
     // UI State
-    scroll_line: usize,
     visible_lines: usize,
     font_size: f32,
     wrap_mode: bool,
+    // Wrap at `wrap_column` characters instead of the panel width
+    wrap_at_column: bool,
+    wrap_column: usize,
+    // Characters the continuation rows of a wrapped line are indented by
+    wrap_indent: usize,
+    show_ruler: bool,
     dark_mode: bool,
     show_line_numbers: bool,
+    // Color JSON, log and CSV lines by file extension
+    syntax_highlighting: bool,
+    // Mark line endings and trailing whitespace
+    show_whitespace: bool,
+    // Pin the last line above the view matching `header_pattern` (a regex) to the top
+    sticky_header: bool,
+    header_pattern: String,
+    header_lookup: Option<HeaderLookup>,
+    // Replace the text view with a list of matching lines (like grep)
+    results_only_view: bool,
+    // Show the raw bytes as `offset | hex | ascii` rows instead of text
+    hex_view: bool,
 
     // Search UI
     search_query: String,
+    // Queries searched for, oldest first, recalled with Up/Down in the search box
+    search_history: Vec<String>,
+    // The entry shown while stepping through the history, and what was typed
+    // before the first step
+    history_index: Option<usize>,
+    history_draft: String,
+    // Compile error of search_query as a regex, for the options in query_error_key
+    query_error: Option<String>,
+    query_error_key: (String, bool, bool, bool, RegexFlags),
     replace_query: String,
+    // Give each replacement the casing of the text it replaces
+    preserve_case: bool,
     show_search_bar: bool,
     show_replace: bool,
     use_regex: bool,
+    // Inline (?m), (?s) and (?x) for regex queries
+    regex_flags: RegexFlags,
+    // Longest match searches allow for across segment boundaries, in bytes;
+    // 0 leaves it to the pattern
+    longest_match: usize,
     case_sensitive: bool,
-    search_results: Vec<SearchResult>,
-    current_result_index: usize, // Global index (0 to total_results - 1)
-    total_search_results: usize,
-    search_page_start_index: usize, // Global index of the first result in search_results
-    page_offsets: Vec<usize>,       // Map of page_index -> start_byte_offset
-    search_error: Option<String>,
-    search_in_progress: bool,
-    search_find_all: bool,
-    search_message_rx: Option<Receiver<SearchMessage>>,
-    search_cancellation_token: Option<Arc<AtomicBool>>,
-    search_count_done: bool,
-    search_fetch_done: bool,
+    whole_line: bool,
+    invert_match: bool,
+    search_in_view: bool,
+    // Results loaded at once; later ones are fetched page by page
+    max_loaded_results: usize,
 
     // Replace UI
     replace_in_progress: bool,
@@ -53,44 +96,223 @@ pub struct TextViewerApp {
     replace_cancellation_token: Option<Arc<AtomicBool>>,
     replace_progress: Option<f32>,
     replace_status_message: Option<String>,
+    replace_strips_ansi: bool, // Current replace task is an ANSI strip export
+    // Replace All writes over the open file instead of asking for a new one
+    replace_in_place: bool,
+    // The file released while it's replaced in place, reopened when that ends
+    replace_reopen: Option<PathBuf>,
+    // All search results being written to a file
+    export_task: Option<ExportTask>,
+    // Replace All dry run, confirmed before anything is written
+    replace_preview_rx: Option<Receiver<anyhow::Result<ReplacePreview>>>,
+    replace_preview: Option<ReplacePreview>,
 
     // Go to line
     goto_line_input: String,
+    // Go to a byte offset, or a percentage of the file with a trailing '%'
+    goto_offset_input: String,
+    // Go to match (1-based result number)
+    goto_result_input: String,
 
     // File info
     show_file_info: bool,
 
-    // Tail mode
-    tail_mode: bool,
-    watcher: Option<Box<dyn Watcher>>,
-    file_change_rx: Option<Receiver<()>>, // 这是什么？
+    // Highlight terms, earlier ones winning where they overlap
+    highlight_terms: Vec<HighlightTerm>,
+    highlight_input: String,
+    show_highlights: bool,
 
     // Status messages
     status_message: String,
 
     // Encoding
-    selected_encoding: &'static Encoding,
     show_encoding_selector: bool,
 
-    // Programmatic scroll control
-    scroll_to_row: Option<usize>,
-    // Correction for f32 scroll precision issues in large files ？
-    scroll_correction: i64,
-    pending_scroll_target: Option<usize>,
-    last_scroll_offset: f32,
+    // Command palette (Ctrl+Shift+P)
+    command_palette: CommandPalette,
+    actions: std::rc::Rc<ActionRegistry<TextViewerApp>>,
+
+    // Windowed open dialog
+    show_open_range: bool,
+    open_range_start_input: String,
+    open_range_end_input: String,
+
+    // Wheel movement not yet worth a whole line (a whole row, when wrapping)
+    wheel_lines: f32,
+    // Screen rows per line when wrapping, for the reader (by address), width and
+    // font size in wrap_rows_key. Paging and the scroll limit need lines that
+    // aren't drawn, so their rows are measured by laying them out.
+    wrap_rows: HashMap<usize, usize>,
+    wrap_rows_key: (usize, u32, u32),
+    // Width wrapped lines were last drawn at
+    wrap_width: f32,
+    // Reader (by address), offset and 1-based column of the match last shown in
+    // the status bar, so its line isn't decoded again every frame
+    status_match_column: Option<(usize, usize, Option<usize>)>,
 
     // Focus control
     focus_search_input: bool,
 
-    // Unsaved changes
-    unsaved_changes: bool,
-    pending_replacements: Vec<PendingReplacement>,
+    // Auto-save
+    auto_save_enabled: bool,
+    auto_save_delay_secs: u64,
+
+    // Line index memory budget
+    index_budget_enabled: bool,
+    index_budget_mb: usize,
+
+    // Open tabs; the entry at `active_tab` is a placeholder for `doc`
+    tabs: Vec<Document>,
+    active_tab: usize,
+
+    // Most recently opened file, remembered in the settings
+    last_file: Option<PathBuf>,
+    // Last session's file, offered for reopening at startup
+    reopen_offer: Option<PathBuf>,
+    // A file that looked binary, opened only if the user confirms
+    binary_prompt: Option<PathBuf>,
+    // Line of the file just opened to jump to once it's indexed, given as `+N`
+    // on the command line or picked in Find in Files
+    pending_line: Option<usize>,
+    // Stdin being read before any of it arrived; it gets a tab with its first bytes
+    pending_stdin: Option<(Receiver<Vec<u8>>, Option<&'static Encoding>)>,
+
+    // Find in Files
+    show_find_in_files: bool,
+    find_in_files_dir: String,
+    find_in_files_glob: String,
+    folder_search: Option<FolderSearch>,
+    // The folder the results below are from, and the results by file
+    folder_root: PathBuf,
+    folder_results: BTreeMap<PathBuf, Vec<FileMatch>>,
+    // Files searched, of how many
+    folder_progress: (usize, usize),
+    // Files and folders that couldn't be read, with why
+    folder_errors: Vec<(PathBuf, String)>,
+}
 
-    // Performance measurement
-    open_start_time: Option<std::time::Instant>,
-    search_count_start_time: Option<std::time::Instant>,
+// A line index built on another thread. Dropping it (another file opened, the
+// tab closed) cancels the work.
+struct IndexTask {
+    rx: Receiver<anyhow::Result<LineIndexer>>,
+    progress: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+    len: usize,
+}
+
+impl Drop for IndexTask {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+// Search results written to a file on another thread, as `line: content` or,
+// for a `.csv` path, CSV rows. Dropping it cancels the export.
+struct ExportTask {
+    // Results written, once done
+    rx: Receiver<anyhow::Result<usize>>,
+    // Offset of the last result written
+    progress: Arc<AtomicUsize>,
+    cancel: Arc<AtomicBool>,
+    len: usize,
+    path: PathBuf,
+}
+
+impl Drop for ExportTask {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+// A Find in Files run over a folder. Dropping it cancels the rest of the batch.
+struct FolderSearch {
+    rx: Receiver<MultiFileMessage>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl Drop for FolderSearch {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+// The sticky header for one first line in view, searched for backward from it
+// on another thread. Kept until the view, pattern or file changes, so it isn't
+// looked up every frame. Dropping it cancels the search.
+struct HeaderLookup {
+    reader: Weak<FileReader>,
+    first_line: usize,
+    pattern: String,
+    rx: Option<Receiver<SearchMessage>>,
+    cancel: Arc<AtomicBool>,
+    // Line number and (start of the) text, once found
+    header: Option<(usize, String)>,
+}
+
+impl Drop for HeaderLookup {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+// A term highlighted in its own color wherever it appears, independent of the search
+struct HighlightTerm {
+    query: String,
+    use_regex: bool,
+    case_sensitive: bool,
+    color: egui::Color32,
+    engine: SearchEngine,
 }
 
+// Lines longer than this are only laid out around the visible columns, with
+// this many columns to spare on either side
+const LONG_LINE_BYTES: usize = 16 * 1024;
+const LONG_LINE_MARGIN: usize = 16;
+
+// The match minimap beside the scrollbar: how finely it divides the file, and
+// its width in points
+const MINIMAP_BUCKETS: usize = 512;
+const MINIMAP_WIDTH: f32 = 6.0;
+
+// Bytes of context shown either side of a match in result lists, and in the
+// status bar
+const RESULT_CONTEXT_BYTES: usize = 500;
+const STATUS_CONTEXT_BYTES: usize = 40;
+
+// Context exported with each result, on either side of it (cut at line ends)
+const EXPORT_CONTEXT_BYTES: usize = 1000;
+
+// Longest sticky header shown; a huge line is cut off
+const HEADER_PREVIEW_BYTES: usize = 1000;
+
+// Bytes per row of the hex view
+const HEX_ROW_BYTES: usize = 16;
+
+// Upper bound of the Longest Match setting; each search segment is read with
+// that much extra
+const MAX_LONGEST_MATCH: usize = 64 * 1024 * 1024;
+
+// Find All stops counting here, so a pattern matching nearly every byte
+// doesn't keep the count going for minutes
+const MAX_COUNTED_RESULTS: usize = 10_000_000;
+
+// Files larger than this are indexed in the background, showing the lines in
+// the first INDEX_HEAD_BYTES until it's done
+const INDEX_HEAD_BYTES: usize = 4 * 1024 * 1024;
+
+// How often tail mode checks the file watcher while the window is idle
+const TAIL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+// Colors handed to new highlight terms in turn
+const HIGHLIGHT_PALETTE: [egui::Color32; 6] = [
+    egui::Color32::from_rgb(255, 110, 110),
+    egui::Color32::from_rgb(255, 220, 90),
+    egui::Color32::from_rgb(120, 220, 120),
+    egui::Color32::from_rgb(110, 180, 255),
+    egui::Color32::from_rgb(230, 140, 255),
+    egui::Color32::from_rgb(255, 170, 90),
+];
+
 #[derive(Clone)] //？
 struct PendingReplacement {
     offset: usize,
@@ -98,29 +320,106 @@ struct PendingReplacement {
     new_text: String,
 }
 
-//默认初始化结构体
-impl Default for TextViewerApp {
+// Everything that belongs to one open tab: the active one is the app's `doc`,
+// the others are parked in `tabs` until they're switched to.
+struct Document {
+    file_reader: Option<Arc<FileReader>>,
+    line_indexer: Arc<LineIndexer>,
+    search_engine: SearchEngine,
+    // Windowed open: byte range of the file to map, None maps the whole file
+    open_window: Option<(usize, usize)>,
+    selected_encoding: &'static Encoding,
+    // Result of the last auto-detection; cleared when the user picks an encoding
+    encoding_detection: Option<DetectedEncoding>,
+    // Programmatic scroll control. `scroll_line` is the first line shown, and
+    // jumps set it directly rather than through a pixel offset, which an f32
+    // can't hold exactly for files with many millions of lines.
+    scroll_line: usize,
+    scroll_to_row: Option<usize>,
+    // Horizontal offset from the last frame, used to clamp long lines to the view
+    last_scroll_offset_x: f32,
+    // First row of the hex view, and the byte clicked in it
+    hex_row: usize,
+    hex_selected: Option<usize>,
+    // Bookmarked line numbers (0-based)
+    bookmarks: BTreeSet<usize>,
+    // Lines picked in the gutter: the first clicked, then the shift-clicked end
+    line_selection: Option<(usize, usize)>,
+    // Byte bounds the current search is limited to
+    search_range: Option<(usize, usize)>,
+    search_results: Vec<SearchResult>,
+    current_result_index: usize, // Global index (0 to total_results - 1)
+    total_search_results: usize,
+    // Distinct lines the counted matches are on; 0 until counted
+    total_match_lines: usize,
+    // The count stopped at MAX_COUNTED_RESULTS, so there are more than it says
+    search_truncated: bool,
+    // Matches per MINIMAP_BUCKETS-th of the file, from the last count
+    match_density: Vec<usize>,
+    search_page_start_index: usize, // Global index of the first result in search_results
+    search_page_size: usize, // max_loaded_results when the current search started
+    // Global result to select once the page holding it has been loaded
+    pending_result_jump: Option<usize>,
+    page_offsets: Vec<usize>,       // Map of page_index -> start_byte_offset
+    search_error: Option<String>,
+    search_in_progress: bool,
+    search_find_all: bool,
+    search_message_rx: Option<Receiver<SearchMessage>>,
+    search_cancellation_token: Option<Arc<AtomicBool>>,
+    search_count_done: bool,
+    search_count_progress: Option<f32>, // Fraction of the file counted so far
+    search_fetch_done: bool,
+    // Tail mode
+    tail_mode: bool,
+    watcher: Option<Box<dyn Watcher>>,
+    file_change_rx: Option<Receiver<()>>, // 这是什么？
+    // Stdin still being read into this document, see open_stdin
+    stdin_rx: Option<Receiver<Vec<u8>>>,
+    unsaved_changes: bool,
+    // Kept sorted by descending offset, so applying them in order never shifts
+    // the offsets of the ones still to come
+    pending_replacements: Vec<PendingReplacement>,
+    // Offsets of pending replacements in the order they were made, for undo,
+    // and the undone ones that redo can bring back
+    undo_stack: Vec<usize>,
+    redo_stack: Vec<PendingReplacement>,
+    last_edit_time: Option<std::time::Instant>,
+    auto_save_backup_done: bool,
+    // Performance measurement
+    open_start_time: Option<std::time::Instant>,
+    search_count_start_time: Option<std::time::Instant>,
+    // Full index being built while line_indexer only covers the top of the file
+    index_task: Option<IndexTask>,
+    // Highlight term colors by pattern index, when the search is for the terms
+    search_pattern_colors: Vec<egui::Color32>,
+}
+
+impl Default for Document {
     fn default() -> Self {
         Self {
             file_reader: None,
-            line_indexer: LineIndexer::new(),
+            line_indexer: Arc::new(LineIndexer::new()),
             search_engine: SearchEngine::new(),
+            open_window: None,
+            selected_encoding: encoding_rs::UTF_8,
+            encoding_detection: None,
             scroll_line: 0,
-            visible_lines: 50,
-            font_size: 14.0,
-            wrap_mode: false,
-            dark_mode: true,
-            show_line_numbers: true,
-            search_query: String::new(),
-            replace_query: String::new(),
-            show_search_bar: false,
-            show_replace: false,
-            use_regex: false,
-            case_sensitive: false,
+            scroll_to_row: None,
+            last_scroll_offset_x: 0.0,
+            hex_row: 0,
+            hex_selected: None,
+            bookmarks: BTreeSet::new(),
+            line_selection: None,
+            search_range: None,
             search_results: Vec::new(),
             current_result_index: 0,
             total_search_results: 0,
+            total_match_lines: 0,
+            search_truncated: false,
+            match_density: Vec::new(),
             search_page_start_index: 0,
+            search_page_size: DEFAULT_PAGE_SIZE,
+            pending_result_jump: None,
             page_offsets: Vec::new(),
             search_error: None,
             search_in_progress: false,
@@ -128,256 +427,1049 @@ impl Default for TextViewerApp {
             search_message_rx: None,
             search_cancellation_token: None,
             search_count_done: false,
+            search_count_progress: None,
             search_fetch_done: false,
+            search_count_start_time: None,
+            tail_mode: false,
+            watcher: None,
+            file_change_rx: None,
+            stdin_rx: None,
+            unsaved_changes: false,
+            pending_replacements: Vec::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            last_edit_time: None,
+            auto_save_backup_done: false,
+            open_start_time: None,
+            index_task: None,
+            search_pattern_colors: Vec::new(),
+        }
+    }
+}
+
+//默认初始化结构体
+impl Default for TextViewerApp {
+    fn default() -> Self {
+        Self {
+            doc: Document::default(),
+            visible_lines: 50,
+            font_size: 14.0,
+            wrap_mode: false,
+            wrap_at_column: false,
+            wrap_column: 80,
+            wrap_indent: 4,
+            show_ruler: false,
+            dark_mode: true,
+            show_line_numbers: true,
+            syntax_highlighting: false,
+            show_whitespace: false,
+            sticky_header: false,
+            header_pattern: String::new(),
+            header_lookup: None,
+            results_only_view: false,
+            hex_view: false,
+            search_query: String::new(),
+            search_history: Vec::new(),
+            history_index: None,
+            history_draft: String::new(),
+            query_error: None,
+            query_error_key: Default::default(),
+            replace_query: String::new(),
+            preserve_case: false,
+            show_search_bar: false,
+            show_replace: false,
+            use_regex: false,
+            regex_flags: RegexFlags::default(),
+            longest_match: 0,
+            case_sensitive: false,
+            whole_line: false,
+            invert_match: false,
+            search_in_view: false,
+            max_loaded_results: DEFAULT_PAGE_SIZE,
             replace_in_progress: false,
             replace_message_rx: None,
             replace_cancellation_token: None,
             replace_progress: None,
             replace_status_message: None,
+            replace_strips_ansi: false,
+            replace_in_place: false,
+            replace_reopen: None,
+            export_task: None,
+            replace_preview_rx: None,
+            replace_preview: None,
             goto_line_input: String::new(),
+            goto_offset_input: String::new(),
+            goto_result_input: String::new(),
             show_file_info: false,
-            tail_mode: false,
-            watcher: None,
-            file_change_rx: None,
+            highlight_terms: Vec::new(),
+            highlight_input: String::new(),
+            show_highlights: false,
             status_message: String::new(),
-            selected_encoding: encoding_rs::UTF_8,
             show_encoding_selector: false,
+            command_palette: CommandPalette::default(),
+            actions: std::rc::Rc::new(Self::register_actions()),
+            show_open_range: false,
+            open_range_start_input: String::new(),
+            open_range_end_input: String::new(),
             focus_search_input: false,
-            scroll_to_row: None,
-            scroll_correction: 0,
-            pending_scroll_target: None,
-            last_scroll_offset: 0.0,
-            unsaved_changes: false,
-            pending_replacements: Vec::new(),
-            open_start_time: None,
-            search_count_start_time: None,
+            wheel_lines: 0.0,
+            wrap_rows: HashMap::new(),
+            wrap_rows_key: (0, 0, 0),
+            wrap_width: 0.0,
+            status_match_column: None,
+            auto_save_enabled: false,
+            auto_save_delay_secs: 30,
+            index_budget_enabled: false,
+            index_budget_mb: 256,
+            tabs: vec![Document::default()],
+            active_tab: 0,
+            last_file: None,
+            reopen_offer: None,
+            binary_prompt: None,
+            pending_line: None,
+            pending_stdin: None,
+            show_find_in_files: false,
+            find_in_files_dir: String::new(),
+            find_in_files_glob: "*.log".to_string(),
+            folder_search: None,
+            folder_root: PathBuf::new(),
+            folder_results: BTreeMap::new(),
+            folder_progress: (0, 0),
+            folder_errors: Vec::new(),
         }
     }
 }
 
 //主体
 impl TextViewerApp {
-    fn open_file(&mut self, path: PathBuf) {
-        self.open_start_time = Some(std::time::Instant::now());
-        match FileReader::new(path.clone(), self.selected_encoding) {
-            Ok(reader) => {
-                //初始化文件读取器
-                self.file_reader = Some(Arc::new(reader)); //将文件读取器包装在 Arc（原子引用计数）中，以便在多线程环境中安全共享。
-                self.line_indexer
-                    .index_file(self.file_reader.as_ref().unwrap()); //索引文件行
-                self.scroll_line = 0;
-                self.scroll_to_row = Some(0); // Reset scroll to top for new file
-                self.status_message = format!("Opened: {}", path.display());
-                self.search_engine.clear();
-                self.search_results.clear();
-                self.total_search_results = 0;
-                self.search_page_start_index = 0;
-                self.page_offsets.clear();
-                self.current_result_index = 0;
-
-                // Setup file watcher if tail mode is enabled
-                if self.tail_mode {
-                    self.setup_file_watcher(); //有啥用
-                }
-            }
-            Err(e) => {
-                self.status_message = format!("Error opening file: {}", e);
-            }
-        }
+    /// Restores the preferences saved by the last session.
+    pub fn apply_settings(&mut self, settings: Settings) {
+        self.font_size = settings.font_size;
+        self.dark_mode = settings.dark_mode;
+        self.wrap_mode = settings.wrap_mode;
+        self.wrap_at_column = settings.wrap_at_column;
+        self.wrap_column = settings.wrap_column;
+        self.wrap_indent = settings.wrap_indent;
+        self.show_ruler = settings.show_ruler;
+        self.show_line_numbers = settings.show_line_numbers;
+        self.syntax_highlighting = settings.syntax_highlighting;
+        self.show_whitespace = settings.show_whitespace;
+        self.sticky_header = settings.sticky_header;
+        self.header_pattern = settings.header_pattern;
+        self.case_sensitive = settings.case_sensitive;
+        self.use_regex = settings.use_regex;
+        self.doc.tail_mode = settings.tail_mode;
+        self.max_loaded_results = settings.results_per_page;
+        self.reopen_offer = settings.last_file.clone().filter(|path| path.is_file());
+        self.last_file = settings.last_file;
+        self.search_history = settings.search_history;
     }
 
-    fn setup_file_watcher(&mut self) {
-        if let Some(ref reader) = self.file_reader {
-            let (tx, rx) = channel(); // 创建一个 MPSC（多生产者单消费者）通道，用于文件变化事件的通信。
-            let path = reader.path().clone();
-            // 创建文件监视器
-            if let Ok(mut watcher) =
-                notify::recommended_watcher(move |res: NotifyResult<notify::Event>| {
-                    if let Ok(_event) = res {
-                        let _ = tx.send(());
-                    }
-                })
-            {
-                if watcher.watch(&path, RecursiveMode::NonRecursive).is_ok() {
-                    self.watcher = Some(Box::new(watcher));
-                    self.file_change_rx = Some(rx);
-                }
-            }
+    fn settings(&self) -> Settings {
+        Settings {
+            font_size: self.font_size,
+            dark_mode: self.dark_mode,
+            wrap_mode: self.wrap_mode,
+            wrap_at_column: self.wrap_at_column,
+            wrap_column: self.wrap_column,
+            wrap_indent: self.wrap_indent,
+            show_ruler: self.show_ruler,
+            show_line_numbers: self.show_line_numbers,
+            syntax_highlighting: self.syntax_highlighting,
+            show_whitespace: self.show_whitespace,
+            sticky_header: self.sticky_header,
+            header_pattern: self.header_pattern.clone(),
+            case_sensitive: self.case_sensitive,
+            use_regex: self.use_regex,
+            tail_mode: self.doc.tail_mode,
+            results_per_page: self.max_loaded_results,
+            last_file: self.last_file.clone(),
+            search_history: self.search_history.clone(),
         }
     }
 
-    fn check_file_changes(&mut self) {
-        if let Some(ref rx) = self.file_change_rx {
-            if rx.try_recv().is_ok() {
-                // File changed, reload
-                if let Some(ref reader) = self.file_reader {
-                    let path = reader.path().clone();
-                    let encoding = reader.encoding();
-                    self.selected_encoding = encoding;
-                    self.open_file(path);
+    // Respects the byte window chosen via "Open Byte Range..." so reloads keep it
+    fn create_reader(
+        &self,
+        path: PathBuf,
+        encoding: &'static Encoding,
+    ) -> anyhow::Result<FileReader> {
+        match self.doc.open_window {
+            Some((start, end)) => FileReader::new_windowed(path, encoding, start, end),
+            None => FileReader::new(path, encoding),
+        }
+    }
 
-                    // Scroll to bottom in tail mode
-                    if self.tail_mode {
-                        let total_lines = self.line_indexer.total_lines();
-                        let target_line = total_lines.saturating_sub(self.visible_lines);
-                        self.scroll_line = target_line;
-                        self.scroll_to_row = Some(target_line);
-                    }
-                }
-            }
+    fn detect_file_encoding(&mut self, path: &std::path::Path) {
+        if let Ok(buffer) = read_prefix(path, self.doc.open_window.map(|(start, _)| start)) {
+            let detected = detect_encoding(&buffer);
+            self.doc.selected_encoding = detected.encoding;
+            self.doc.encoding_detection = Some(detected);
         }
     }
 
-    fn perform_search(&mut self, find_all: bool) {
-        self.search_error = None;
-        self.search_results.clear();
-        self.current_result_index = 0;
-        self.total_search_results = 0;
-        self.search_page_start_index = 0;
-        self.page_offsets.clear();
-        self.search_engine.clear();
-
-        if self.search_in_progress {
-            self.status_message = "Search already running...".to_string();
-            return;
+    fn open_file_dialog(&mut self) {
+        if let Some(path) = rfd::FileDialog::new().pick_file() {
+            self.open_path(path);
         }
+    }
 
-        let Some(ref reader) = self.file_reader else {
-            self.status_message = "Open a file before searching".to_string();
+    // A file being opened gets its own tab, unless the current one is empty
+    fn prepare_tab(&mut self) {
+        if self.doc.file_reader.is_none() {
             return;
-        };
+        }
+        self.tabs[self.active_tab] = std::mem::take(&mut self.doc);
+        self.tabs.push(Document::default());
+        self.active_tab = self.tabs.len() - 1;
+    }
 
-        if self.search_query.is_empty() {
-            self.status_message = "Enter a search query first".to_string();
+    fn switch_tab(&mut self, index: usize) {
+        if index == self.active_tab || index >= self.tabs.len() {
             return;
         }
+        let doc = std::mem::take(&mut self.tabs[index]);
+        self.tabs[self.active_tab] = std::mem::replace(&mut self.doc, doc);
+        self.active_tab = index;
 
-        self.search_engine.set_query(
-            self.search_query.clone(),
-            self.use_regex,
-            self.case_sensitive,
-        );
-
-        let reader = reader.clone();
-        // Use a bounded channel to provide backpressure to search threads
-        // This prevents memory explosion if the UI thread can't keep up with results
-        let (tx, rx) = std::sync::mpsc::sync_channel(10_000);
+        // The scroll area is shared, so put it back where this tab left it
+        self.doc.scroll_to_row = Some(self.doc.scroll_line);
+        self.status_message = format!("Switched to {}", self.tab_title(index));
+    }
 
-        self.search_message_rx = Some(rx);
-        self.search_in_progress = true;
-        self.search_find_all = find_all;
-        self.search_count_done = false;
-        self.search_fetch_done = false;
+    fn close_active_tab(&mut self) {
+        if self.doc.unsaved_changes {
+            let discard = rfd::MessageDialog::new()
+                .set_title("Unsaved changes")
+                .set_description(format!(
+                    "Close {} and discard its pending replacements?",
+                    self.tab_title(self.active_tab)
+                ))
+                .set_buttons(rfd::MessageButtons::YesNo)
+                .show();
+            if discard != rfd::MessageDialogResult::Yes {
+                return;
+            }
+        }
+        if let Some(token) = &self.doc.search_cancellation_token {
+            token.store(true, Ordering::Relaxed);
+        }
 
-        let cancel_token = Arc::new(AtomicBool::new(false));
-        self.search_cancellation_token = Some(cancel_token.clone());
+        // Dropping the replaced document closes the file
+        self.doc = Document::default();
+        if self.tabs.len() > 1 {
+            self.tabs.remove(self.active_tab);
+            let index = self.active_tab.min(self.tabs.len() - 1);
+            self.doc = std::mem::take(&mut self.tabs[index]);
+            self.active_tab = index;
+            self.doc.scroll_to_row = Some(self.doc.scroll_line);
+        }
+        self.status_message = "Closed tab".to_string();
+    }
 
-        self.status_message = if find_all {
-            "Searching all matches...".to_string()
+    fn tab_title(&self, index: usize) -> String {
+        let (reader, unsaved) = if index == self.active_tab {
+            (self.doc.file_reader.as_ref(), self.doc.unsaved_changes)
         } else {
-            "Searching first match...".to_string()
+            (
+                self.tabs[index].file_reader.as_ref(),
+                self.tabs[index].unsaved_changes,
+            )
         };
+        let name = match reader {
+            Some(reader) if reader.has_file() => reader.path().file_name().map_or_else(
+                || reader.path().display().to_string(),
+                |name| name.to_string_lossy().into_owned(),
+            ),
+            Some(_) => "stdin".to_string(),
+            None => "Untitled".to_string(),
+        };
+        match unsaved {
+            true => format!("{} *", name),
+            false => name,
+        }
+    }
 
-        if find_all {
-            self.search_count_start_time = Some(std::time::Instant::now());
-            // Start two tasks:
-            // 1. Count all matches (parallel)
-            // 2. Fetch first page of matches (sequential/chunked)
-
-            let tx_count = tx.clone();
-            let reader_count = reader.clone();
-            let query = self.search_query.clone();
-            let use_regex = self.use_regex;
-            let case_sensitive = self.case_sensitive;
-            let cancel_token_count = cancel_token.clone();
-
-            std::thread::spawn(move || {
-                // Task 1: Count
-                let mut engine = SearchEngine::new();
-                engine.set_query(query, use_regex, case_sensitive);
-                engine.count_matches(reader_count, tx_count, cancel_token_count);
-            });
-
-            let tx_fetch = tx.clone();
-            let reader_fetch = reader.clone();
-            let query_fetch = self.search_query.clone();
-            let cancel_token_fetch = cancel_token.clone();
-
-            std::thread::spawn(move || {
-                // Task 2: Fetch first page
-                let mut engine = SearchEngine::new();
-                engine.set_query(query_fetch, use_regex, case_sensitive);
-                engine.fetch_matches(reader_fetch, tx_fetch, 0, 1000, cancel_token_fetch);
-            });
-        } else {
-            // Find first match only
-            let tx_fetch = tx.clone();
-            let reader_fetch = reader.clone();
-            let query = self.search_query.clone();
-            let use_regex = self.use_regex;
-            let case_sensitive = self.case_sensitive;
-            let cancel_token_fetch = cancel_token.clone();
+    /// Opens the whole of `path`, detecting its encoding. A file that looks
+    /// binary is only opened once the user confirms.
+    pub fn open_path(&mut self, path: PathBuf) {
+        if read_prefix(&path, None).is_ok_and(|prefix| is_probably_binary(&prefix)) {
+            self.binary_prompt = Some(path);
+            return;
+        }
+        self.open_text_path(path);
+    }
 
-            std::thread::spawn(move || {
-                let mut engine = SearchEngine::new();
-                engine.set_query(query, use_regex, case_sensitive);
-                engine.fetch_matches(reader_fetch, tx_fetch, 0, 1, cancel_token_fetch);
-            });
+    fn open_text_path(&mut self, path: PathBuf) {
+        self.prepare_tab();
+        // Too big to map whole here (32-bit): start with the first window
+        let too_big = std::fs::metadata(&path).is_ok_and(|m| m.len() > MAX_MAPPED_LEN as u64);
+        let compressed = read_prefix(&path, Some(0)).is_ok_and(|prefix| is_gzip(&prefix));
+        self.doc.open_window = (too_big && !compressed).then_some((0, MAX_MAPPED_LEN));
+        // Auto-detect encoding
+        self.detect_file_encoding(&path);
+        self.open_file(path);
+        if self.doc.open_window.is_some() && self.doc.file_reader.is_some() {
+            self.status_message = format!(
+                "File too large to map at once; showing its first {} MB (File → Next Window for more)",
+                MAX_MAPPED_LEN / (1024 * 1024)
+            );
         }
     }
 
-    fn poll_search_results(&mut self) {
-        if !self.search_in_progress {
+    // Reopens a windowed file half a window further on (or back), keeping the
+    // tab and encoding
+    fn move_window(&mut self, forward: bool) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        if self.doc.unsaved_changes {
+            self.status_message = "Save or undo the pending replacements first".to_string();
+            return;
+        }
+        let Some((start, end)) = reader.adjacent_window(forward) else {
+            self.status_message = match reader.window() {
+                Some(_) => "No more of the file that way".to_string(),
+                None => "The whole file is open".to_string(),
+            };
             return;
+        };
+        self.doc.open_window = Some((start, end));
+        self.open_file(reader.path().clone());
+        if self.doc.file_reader.is_some() {
+            self.status_message = format!("Showing bytes {}..{} of the file", start, end);
         }
+    }
 
-        if let Some(ref rx) = self.search_message_rx {
-            let mut new_results_added = false;
-            // Process all available messages
-            while let Ok(msg) = rx.try_recv() {
-                match msg {
-                    SearchMessage::CountResult(count) => {
-                        self.total_search_results += count;
-                        if self.search_find_all {
-                            self.status_message =
-                                format!("Found {} matches...", self.total_search_results);
+    /// Opens `path` decoded as `encoding`, without detecting it or asking
+    /// about binary content.
+    pub fn open_path_as(&mut self, path: PathBuf, encoding: &'static Encoding) {
+        self.prepare_tab();
+        self.doc.open_window = None;
+        self.doc.selected_encoding = encoding;
+        self.doc.encoding_detection = None;
+        self.open_file(path);
+    }
+
+    /// Shows content that isn't a file on disk, such as a pipe into stdin.
+    /// The encoding is detected unless given.
+    pub fn open_bytes(&mut self, data: Vec<u8>, encoding: Option<&'static Encoding>) {
+        self.prepare_tab();
+        self.doc.open_window = None;
+        match encoding {
+            Some(encoding) => {
+                self.doc.selected_encoding = encoding;
+                self.doc.encoding_detection = None;
+            }
+            None => {
+                let detected = detect_encoding(&data[..data.len().min(4096)]);
+                self.doc.selected_encoding = detected.encoding;
+                self.doc.encoding_detection = Some(detected);
+            }
+        }
+        self.doc.open_start_time = Some(std::time::Instant::now());
+        self.show_reader(FileReader::from_bytes(data, self.doc.selected_encoding));
+    }
+
+    /// Reads stdin on a background thread, so the window is up before the pipe
+    /// closes (or even if it never does, as with `tail -f`). A tab shows it
+    /// once the first bytes arrive, and grows as more come in.
+    pub fn open_stdin(&mut self, encoding: Option<&'static Encoding>) {
+        let (tx, rx) = channel();
+        std::thread::spawn(move || {
+            let mut stdin = std::io::stdin().lock();
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                match stdin.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
                         }
                     }
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        eprintln!("Error reading stdin: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        self.pending_stdin = Some((rx, encoding));
+    }
+
+    fn poll_stdin(&mut self) {
+        if let Some((rx, _)) = &self.pending_stdin {
+            let (data, closed) = drain_chunks(rx);
+            if !data.is_empty() {
+                let (rx, encoding) = self.pending_stdin.take().unwrap();
+                self.open_bytes(data, encoding);
+                self.doc.stdin_rx = (!closed).then_some(rx);
+            } else if closed {
+                // Nothing was piped in after all
+                self.pending_stdin = None;
+            }
+        }
+
+        let Some(rx) = &self.doc.stdin_rx else {
+            return;
+        };
+        let (data, closed) = drain_chunks(rx);
+        if closed {
+            self.doc.stdin_rx = None;
+        }
+        if data.is_empty() {
+            return;
+        }
+        let Some(reader) = self.doc.file_reader.take() else {
+            return;
+        };
+        let old_len = reader.len();
+        // Grown in place unless a search or the indexer still holds the reader
+        let mut reader = Arc::try_unwrap(reader).unwrap_or_else(|shared| {
+            FileReader::from_bytes(shared.all_data().to_vec(), shared.encoding())
+        });
+        self.status_message = match reader.extend_bytes(&data) {
+            Ok(()) => format!("Read {} bytes from stdin", reader.len()),
+            Err(e) => format!("Error reading stdin: {}", e),
+        };
+        if self.doc.index_task.is_some() {
+            // Still being indexed: start over on the longer content
+            self.doc.file_reader = Some(Arc::new(reader));
+            self.start_indexing();
+        } else {
+            Arc::make_mut(&mut self.doc.line_indexer).append_index(&reader, old_len);
+            self.doc.file_reader = Some(Arc::new(reader));
+        }
+        if self.doc.tail_mode {
+            let total_lines = self.doc.line_indexer.total_lines();
+            let target_line = total_lines.saturating_sub(self.visible_lines);
+            self.doc.scroll_line = target_line;
+            self.doc.scroll_to_row = Some(target_line);
+        }
+    }
+
+    /// Scrolls to 1-based `line` of the file just opened, as soon as the
+    /// background index reaches it.
+    pub fn open_at_line(&mut self, line: usize) {
+        self.pending_line = Some(line);
+    }
+
+    fn apply_pending_line(&mut self) {
+        let Some(line) = self.pending_line else {
+            return;
+        };
+        if self.pending_stdin.is_some() {
+            // The content hasn't arrived yet
+        } else if self.doc.file_reader.is_none() {
+            self.pending_line = None;
+        } else if line <= self.doc.line_indexer.total_lines()
+            || (self.doc.index_task.is_none() && self.doc.stdin_rx.is_none())
+        {
+            self.pending_line = None;
+            // Past the end once fully indexed: the file is shorter, go to its last line
+            self.jump_to_line(line.min(self.doc.line_indexer.total_lines().max(1)));
+        }
+    }
+
+    // Reopens the file from disk, as it is now, keeping the encoding, byte
+    // range and place in it, and runs the search that was showing again
+    fn reload_file(&mut self) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        if !reader.has_file() {
+            self.status_message = "Nothing to reload: the content came from stdin".to_string();
+            return;
+        }
+        if self.doc.unsaved_changes {
+            let discard = rfd::MessageDialog::new()
+                .set_title("Unsaved changes")
+                .set_description(format!(
+                    "Reload {} from disk and discard its pending replacements?",
+                    self.tab_title(self.active_tab)
+                ))
+                .set_buttons(rfd::MessageButtons::YesNo)
+                .show();
+            if discard != rfd::MessageDialogResult::Yes {
+                return;
+            }
+            self.doc.pending_replacements.clear();
+            self.doc.undo_stack.clear();
+            self.doc.redo_stack.clear();
+            self.doc.unsaved_changes = false;
+        }
+        if let Some(token) = &self.doc.search_cancellation_token {
+            token.store(true, Ordering::Relaxed);
+        }
+        let searched = (!self.search_query.is_empty()
+            || !self.doc.search_pattern_colors.is_empty())
+            && (self.doc.search_in_progress || self.doc.total_search_results > 0);
+        let searched_terms = !self.doc.search_pattern_colors.is_empty();
+        self.doc.search_in_progress = false;
+        self.doc.search_message_rx = None;
+
+        let line = self.doc.scroll_line;
+        self.open_file(reader.path().clone());
+        if self
+            .doc
+            .file_reader
+            .as_ref()
+            .is_none_or(|new| Arc::ptr_eq(new, &reader))
+        {
+            // Couldn't be opened again; open_file said why
+            return;
+        }
+        self.status_message = format!("Reloaded: {}", reader.path().display());
+        self.open_at_line(line + 1);
+        if searched && searched_terms {
+            self.search_highlight_terms();
+        } else if searched {
+            self.perform_search(self.doc.search_find_all);
+        }
+    }
+
+    fn open_file(&mut self, path: PathBuf) {
+        self.doc.open_start_time = Some(std::time::Instant::now());
+        match self.create_reader(path, self.doc.selected_encoding) {
+            Ok(reader) => self.show_reader(reader),
+            Err(e) => {
+                self.status_message = format!("Error opening file: {}", e);
+            }
+        }
+    }
+
+    fn show_reader(&mut self, reader: FileReader) {
+        self.status_message = match reader.has_file() {
+            true => format!("Opened: {}", reader.path().display()),
+            false => format!("Opened {} bytes from stdin", reader.len()),
+        };
+        if reader.has_file() {
+            self.last_file = Some(reader.path().clone());
+        }
+        self.reopen_offer = None;
+        //初始化文件读取器
+        self.doc.file_reader = Some(Arc::new(reader)); //将文件读取器包装在 Arc（原子引用计数）中，以便在多线程环境中安全共享。
+        self.start_indexing(); //索引文件行
+        self.doc.scroll_line = 0;
+        self.doc.scroll_to_row = Some(0); // Reset scroll to top for new file
+        self.doc.line_selection = None;
+        self.doc.hex_row = 0;
+        self.doc.hex_selected = None;
+        self.doc.search_engine.clear();
+        self.doc.search_results.clear();
+        self.doc.total_search_results = 0;
+        self.doc.total_match_lines = 0;
+        self.doc.search_truncated = false;
+        self.doc.match_density.clear();
+        self.doc.search_page_start_index = 0;
+        self.doc.page_offsets.clear();
+        self.doc.current_result_index = 0;
+        self.doc.auto_save_backup_done = false;
+
+        // Setup file watcher if tail mode is enabled
+        if self.doc.tail_mode {
+            self.setup_file_watcher(); //有啥用
+        }
+    }
+
+    fn index_budget(&self) -> Option<usize> {
+        self.index_budget_enabled
+            .then_some(self.index_budget_mb * 1024 * 1024)
+    }
+
+    // Re-index the open file after the memory budget changed
+    fn apply_index_budget(&mut self) {
+        if self.doc.file_reader.is_some() {
+            self.start_indexing();
+            self.doc.scroll_to_row = Some(self.doc.scroll_line);
+        }
+    }
+
+    // Indexes the open file. A big one gets its first lines indexed right away
+    // and the rest on a worker thread, picked up by poll_index_task.
+    fn start_indexing(&mut self) {
+        self.doc.index_task = None;
+        self.wrap_rows.clear();
+        let budget = self.index_budget();
+        Arc::make_mut(&mut self.doc.line_indexer).set_memory_budget(budget);
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        if reader.len() <= INDEX_HEAD_BYTES {
+            Arc::make_mut(&mut self.doc.line_indexer).index_file(&reader);
+            return;
+        }
+        Arc::make_mut(&mut self.doc.line_indexer).index_head(&reader, INDEX_HEAD_BYTES);
+
+        let mut indexer = LineIndexer::new();
+        indexer.set_memory_budget(self.index_budget());
+        let progress = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        self.doc.index_task = Some(IndexTask {
+            rx,
+            progress: progress.clone(),
+            cancel: cancel.clone(),
+            len: reader.len(),
+        });
+        std::thread::spawn(move || {
+            let result = indexer
+                .index_file_with_progress(&reader, &progress, &cancel)
+                .map(|()| indexer);
+            let _ = tx.send(result);
+        });
+    }
+
+    fn poll_index_task(&mut self) {
+        let Some(task) = &self.doc.index_task else {
+            return;
+        };
+        let indexer = match task.rx.try_recv() {
+            Ok(Ok(indexer)) => indexer,
+            Err(std::sync::mpsc::TryRecvError::Empty) => return,
+            Ok(Err(e)) => {
+                self.status_message = format!("Indexing failed: {}", e);
+                self.doc.index_task = None;
+                return;
+            }
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => {
+                self.doc.index_task = None;
+                return;
+            }
+        };
+        self.doc.index_task = None;
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        // Keep the same text in view; a sparse index numbers lines differently
+        let anchor = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(self.doc.scroll_line, &reader)
+            .map(|(start, _)| start);
+        self.doc.line_indexer = Arc::new(indexer);
+        self.wrap_rows.clear();
+        if let Some(offset) = anchor {
+            self.doc.scroll_line = self
+                .doc
+                .line_indexer
+                .find_line_at_offset_with_reader(offset, &reader);
+            self.doc.scroll_to_row = Some(self.doc.scroll_line);
+        }
+    }
+
+    // Fraction of the file the background index has got through
+    fn index_progress(&self) -> Option<f32> {
+        self.doc
+            .index_task
+            .as_ref()
+            .map(|task| task.progress.load(Ordering::Relaxed) as f32 / task.len.max(1) as f32)
+    }
+
+    // "N matches on M lines", leaving out the lines when they aren't known or
+    // say nothing new (inverted results are lines)
+    fn matches_label(&self) -> String {
+        let matches = self.doc.total_search_results;
+        let lines = self.doc.total_match_lines;
+        if self.doc.search_truncated {
+            format!("first {} matches (more exist)", matches)
+        } else if lines == 0 || self.doc.search_engine.is_inverted() {
+            format!("{} matches", matches)
+        } else {
+            format!(
+                "{} matches on {} line{}",
+                matches,
+                lines,
+                if lines == 1 { "" } else { "s" }
+            )
+        }
+    }
+
+    // "Found N matches", or "Showing first N matches (more exist)" when the
+    // count was cut short
+    fn found_label(&self) -> String {
+        let verb = if self.doc.search_truncated {
+            "Showing"
+        } else {
+            "Found"
+        };
+        format!("{} {}", verb, self.matches_label())
+    }
+
+    // What the current results are of, for the results panel
+    fn searched_for(&self) -> String {
+        if self.doc.search_pattern_colors.is_empty() {
+            format!("'{}'", self.search_query)
+        } else {
+            "the highlight terms".to_string()
+        }
+    }
+
+    fn searching_label(&self) -> String {
+        match self.doc.search_count_progress {
+            Some(progress) => format!("Searching... {:.0}%", progress * 100.0),
+            None => "Searching...".to_string(),
+        }
+    }
+
+    fn line_count_label(&self) -> String {
+        let total = self.doc.line_indexer.total_lines();
+        if self.doc.index_task.is_some() {
+            return format!("{}+", total);
+        }
+        match self.doc.line_indexer.is_estimated() {
+            true => format!("~{}", total),
+            false => total.to_string(),
+        }
+    }
+
+    // Exact even when the index only keeps every Nth line
+    fn line_at_offset(&self, offset: usize) -> usize {
+        match self.doc.file_reader {
+            Some(ref reader) => self
+                .doc
+                .line_indexer
+                .find_line_at_offset_with_reader(offset, reader),
+            None => self.doc.line_indexer.find_line_at_offset(offset),
+        }
+    }
+
+    fn apply_tail_mode(&mut self) {
+        if self.doc.tail_mode {
+            self.setup_file_watcher();
+        } else {
+            self.doc.watcher = None;
+            self.doc.file_change_rx = None;
+        }
+    }
+
+    fn setup_file_watcher(&mut self) {
+        if let Some(ref reader) = self.doc.file_reader {
+            let (tx, rx) = channel(); // 创建一个 MPSC（多生产者单消费者）通道，用于文件变化事件的通信。
+            let path = reader.path().clone();
+            // 创建文件监视器
+            if let Ok(mut watcher) =
+                notify::recommended_watcher(move |res: NotifyResult<notify::Event>| {
+                    if let Ok(_event) = res {
+                        let _ = tx.send(());
+                    }
+                })
+            {
+                if watcher.watch(&path, RecursiveMode::NonRecursive).is_ok() {
+                    self.doc.watcher = Some(Box::new(watcher));
+                    self.doc.file_change_rx = Some(rx);
+                }
+            }
+        }
+    }
+
+    fn check_file_changes(&mut self) {
+        if let Some(ref rx) = self.doc.file_change_rx {
+            if rx.try_recv().is_ok() {
+                // File changed, reload
+                if let Some(reader) = self.doc.file_reader.clone() {
+                    let path = reader.path().clone();
+                    let encoding = reader.encoding();
+                    self.doc.selected_encoding = encoding;
+
+                    // A file that only grew keeps its index and search results
+                    let old_len = reader.len();
+                    let new_len = std::fs::metadata(&path).map_or(0, |m| m.len() as usize);
+                    // (a growing gzip file has to be decompressed again)
+                    let grown = if self.doc.open_window.is_none()
+                        && !reader.is_compressed()
+                        && new_len > old_len
+                    {
+                        FileReader::new(path.clone(), encoding).ok()
+                    } else {
+                        None
+                    };
+                    match grown {
+                        // Still being indexed: start over on the longer file
+                        Some(new_reader) if self.doc.index_task.is_some() => {
+                            self.doc.file_reader = Some(Arc::new(new_reader));
+                            self.start_indexing();
+                        }
+                        Some(new_reader) => {
+                            Arc::make_mut(&mut self.doc.line_indexer)
+                                .append_index(&new_reader, old_len);
+                            self.status_message =
+                                format!("Appended {} bytes", new_reader.len() - old_len);
+                            self.doc.file_reader = Some(Arc::new(new_reader));
+                        }
+                        None => self.open_file(path),
+                    }
+
+                    // Scroll to bottom in tail mode
+                    if self.doc.tail_mode {
+                        let total_lines = self.doc.line_indexer.total_lines();
+                        let target_line = total_lines.saturating_sub(self.visible_lines);
+                        self.doc.scroll_line = target_line;
+                        self.doc.scroll_to_row = Some(target_line);
+                    }
+                }
+            }
+        }
+    }
+
+    fn perform_search(&mut self, find_all: bool) {
+        if !self.reset_search() {
+            return;
+        }
+
+        self.refresh_query_error();
+        if let Some(ref error) = self.query_error {
+            self.status_message = format!("Invalid regex: {}", error_summary(error));
+            return;
+        }
+
+        if self.doc.file_reader.is_none() {
+            self.status_message = "Open a file before searching".to_string();
+            return;
+        }
+
+        if self.search_query.is_empty() {
+            self.status_message = "Enter a search query first".to_string();
+            return;
+        }
+
+        self.doc.search_engine.set_query(
+            self.search_query.clone(),
+            self.use_regex,
+            self.case_sensitive,
+            self.whole_line,
+        );
+        self.doc.search_engine.set_regex_flags(self.regex_flags);
+        self.doc
+            .search_engine
+            .set_max_match_len(self.max_match_len());
+        self.doc.search_engine.set_invert(self.invert_match);
+        self.record_search();
+        self.start_search(find_all);
+    }
+
+    // Find All for every highlight term at once, each match drawn in the color
+    // of the term it matched. The terms are searched as one pattern set, so
+    // they have to share their Regex and Match Case settings.
+    fn search_highlight_terms(&mut self) {
+        if !self.reset_search() {
+            return;
+        }
+        let Some(first) = self.highlight_terms.first() else {
+            self.status_message = "Add highlight terms first".to_string();
+            return;
+        };
+        let (use_regex, case_sensitive) = (first.use_regex, first.case_sensitive);
+        if self
+            .highlight_terms
+            .iter()
+            .any(|term| term.use_regex != use_regex || term.case_sensitive != case_sensitive)
+        {
+            self.status_message =
+                "Terms searched together need the same Regex and Match Case settings".to_string();
+            return;
+        }
+        if self.doc.file_reader.is_none() {
+            self.status_message = "Open a file before searching".to_string();
+            return;
+        }
+
+        let patterns = self
+            .highlight_terms
+            .iter()
+            .map(|t| t.query.clone())
+            .collect();
+        self.doc
+            .search_engine
+            .set_patterns(patterns, use_regex, case_sensitive, false);
+        self.doc
+            .search_engine
+            .set_max_match_len(self.max_match_len());
+        self.doc.search_pattern_colors = self.highlight_terms.iter().map(|t| t.color).collect();
+        self.start_search(true);
+    }
+
+    // Clears the last search's results; false while one is still running
+    fn reset_search(&mut self) -> bool {
+        self.doc.search_error = None;
+        self.doc.search_results.clear();
+        self.doc.current_result_index = 0;
+        self.doc.total_search_results = 0;
+        self.doc.total_match_lines = 0;
+        self.doc.search_truncated = false;
+        self.doc.match_density.clear();
+        self.doc.search_page_start_index = 0;
+        self.doc.search_page_size = self.max_loaded_results.max(MIN_PAGE_SIZE);
+        self.doc.pending_result_jump = None;
+        self.doc.page_offsets.clear();
+        self.doc.search_pattern_colors.clear();
+        self.doc.search_engine.clear();
+
+        if self.doc.search_in_progress {
+            self.status_message = "Search already running...".to_string();
+            return false;
+        }
+        true
+    }
+
+    // Runs the search `search_engine` is set up for: with `find_all`, counts
+    // every match while fetching the first page, otherwise just the first match
+    fn start_search(&mut self, find_all: bool) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        self.doc.search_range = if self.search_in_view {
+            Some(self.visible_byte_range(&reader))
+        } else {
+            None
+        };
+        let range = self.doc.search_range;
+        // Use a bounded channel to provide backpressure to search threads
+        // This prevents memory explosion if the UI thread can't keep up with results
+        let (tx, rx) = std::sync::mpsc::sync_channel(10_000);
+
+        self.doc.search_message_rx = Some(rx);
+        self.doc.search_in_progress = true;
+        self.doc.search_find_all = find_all;
+        self.doc.search_count_done = false;
+        self.doc.search_count_progress = None;
+        self.doc.search_fetch_done = false;
+
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        self.doc.search_cancellation_token = Some(cancel_token.clone());
+
+        self.status_message = if find_all {
+            "Searching all matches...".to_string()
+        } else {
+            "Searching first match...".to_string()
+        };
+
+        if find_all {
+            self.doc.search_count_start_time = Some(std::time::Instant::now());
+            // Start two tasks:
+            // 1. Count all matches (parallel)
+            // 2. Fetch first page of matches (sequential/chunked)
+
+            let tx_count = tx.clone();
+            let reader_count = reader.clone();
+            let mut engine = self.doc.search_engine.clone();
+            let cancel_token_count = cancel_token.clone();
+
+            std::thread::spawn(move || {
+                // Task 1: Count
+                engine.set_density_buckets(MINIMAP_BUCKETS);
+                engine.set_max_total(Some(MAX_COUNTED_RESULTS));
+                engine.count_matches(reader_count, tx_count, range, cancel_token_count);
+            });
+
+            let engine = self.doc.search_engine.clone();
+            let page_size = self.doc.search_page_size;
+
+            std::thread::spawn(move || {
+                // Task 2: Fetch first page
+                engine.fetch_matches(reader, tx, 0, page_size, range, cancel_token);
+            });
+        } else {
+            // Find first match only
+            let engine = self.doc.search_engine.clone();
+            std::thread::spawn(move || {
+                engine.fetch_matches(reader, tx, 0, 1, range, cancel_token);
+            });
+        }
+    }
+
+    fn record_search(&mut self) {
+        self.history_index = None;
+        if self.search_history.last() == Some(&self.search_query) {
+            return;
+        }
+        self.search_history.push(self.search_query.clone());
+        let excess = self.search_history.len().saturating_sub(MAX_SEARCH_HISTORY);
+        self.search_history.drain(..excess);
+    }
+
+    // Puts the previous (or next) history entry in the search box. Stepping
+    // past the newest brings back what was typed before. False when there's
+    // nowhere to go.
+    fn recall_search(&mut self, older: bool) -> bool {
+        let len = self.search_history.len();
+        let index = match (self.history_index, older) {
+            (None, true) if len > 0 => {
+                self.history_draft = self.search_query.clone();
+                Some(len - 1)
+            }
+            (Some(0), true) | (None, _) => return false,
+            (Some(index), true) => Some(index - 1),
+            (Some(index), false) => Some(index + 1).filter(|&next| next < len),
+        };
+        self.history_index = index;
+        self.search_query = match index {
+            Some(index) => self.search_history[index].clone(),
+            None => std::mem::take(&mut self.history_draft),
+        };
+        true
+    }
+
+    fn poll_search_results(&mut self) {
+        if !self.doc.search_in_progress {
+            return;
+        }
+
+        if let Some(ref rx) = self.doc.search_message_rx {
+            let mut new_results_added = false;
+            // Process all available messages
+            while let Ok(msg) = rx.try_recv() {
+                match msg {
+                    SearchMessage::CountResult(count, lines) => {
+                        self.doc.total_search_results += count;
+                        self.doc.total_match_lines += lines;
+                        if self.doc.search_find_all {
+                            self.status_message =
+                                format!("Found {} matches...", self.doc.total_search_results);
+                        }
+                    }
+                    SearchMessage::CountProgress(done, total) => {
+                        self.doc.search_count_progress = Some(done as f32 / total.max(1) as f32);
+                    }
+                    SearchMessage::CountDensity(density) => self.doc.match_density = density,
+                    SearchMessage::CountTruncated => self.doc.search_truncated = true,
                     SearchMessage::ChunkResult(chunk_result) => {
                         // Add results
-                        self.search_results.extend(chunk_result.matches);
+                        self.doc.search_results.extend(chunk_result.matches);
                         new_results_added = true;
                     }
                     SearchMessage::Done(search_type) => {
                         match search_type {
                             SearchType::Count => {
-                                self.search_count_done = true;
-                                if let Some(start_time) = self.search_count_start_time {
+                                self.doc.search_count_done = true;
+                                self.doc.search_count_progress = None;
+                                if let Some(start_time) = self.doc.search_count_start_time {
                                     let elapsed = start_time.elapsed();
                                     println!("Search count completed in: {:.2?}", elapsed);
                                     self.status_message = format!(
                                         "{} (Counted in {:.2?})",
                                         self.status_message, elapsed
                                     );
-                                    self.search_count_start_time = None;
+                                    self.doc.search_count_start_time = None;
                                 }
                             }
-                            SearchType::Fetch => self.search_fetch_done = true,
+                            SearchType::Fetch => self.doc.search_fetch_done = true,
                         }
 
-                        if self.search_find_all
-                            && self.search_count_done
-                            && self.search_results.len() == self.total_search_results
+                        if self.doc.search_find_all
+                            && self.doc.search_count_done
+                            && self.doc.search_results.len() == self.doc.total_search_results
                         {
-                            if let Some(token) = &self.search_cancellation_token {
+                            if let Some(token) = &self.doc.search_cancellation_token {
                                 token.store(true, Ordering::Relaxed);
                             }
                         }
                     }
                     SearchMessage::Error(e) => {
-                        self.search_in_progress = false;
-                        self.search_message_rx = None;
-                        self.search_error = Some(e.clone());
+                        self.doc.search_in_progress = false;
+                        self.doc.search_message_rx = None;
+                        self.doc.search_error = Some(e.clone());
                         self.status_message = format!("Search failed: {}", e);
                         return; // Stop processing messages
                     }
@@ -386,11 +1478,12 @@ impl TextViewerApp {
 
             // Check if channel is disconnected
             if let Err(std::sync::mpsc::TryRecvError::Disconnected) = rx.try_recv() {
-                self.search_in_progress = false;
-                self.search_message_rx = None;
+                self.doc.search_in_progress = false;
+                self.doc.search_message_rx = None;
 
                 // Final sort to ensure everything is in order
-                self.search_results.sort_by_key(|r| r.byte_offset);
+                self.doc.search_results.sort_by_key(|r| r.byte_offset);
+                self.record_next_page_offset();
 
                 // If we are in "Find All" mode, total_results should be at least search_results.len()
                 // But count task might be slower or faster.
@@ -398,51 +1491,54 @@ impl TextViewerApp {
                 // If fetch task finished, search_results is populated.
 
                 // If we are not finding all, total_results might be 0 (since we didn't run count task).
-                if !self.search_find_all {
-                    self.total_search_results = self.search_results.len();
+                if !self.doc.search_find_all {
+                    self.doc.total_search_results = self.doc.search_results.len();
                 } else {
                     // Ensure total is at least what we have
-                    self.total_search_results =
-                        self.total_search_results.max(self.search_results.len());
+                    self.doc.total_search_results = self
+                        .doc
+                        .total_search_results
+                        .max(self.doc.search_results.len());
                 }
 
-                let total = self.total_search_results;
+                let total = self.doc.total_search_results;
                 if total > 0 {
-                    if self.search_find_all {
-                        self.status_message = format!("Found {} matches", total);
+                    if self.doc.search_find_all {
+                        self.status_message = self.found_label();
                     } else {
                         self.status_message =
                             "Showing first match. Run Find All to see every result.".to_string();
                     }
 
                     // Ensure we scroll to the first result if we haven't yet
-                    if self.scroll_to_row.is_none() && !self.search_results.is_empty() {
-                        let target_line = self
-                            .line_indexer
-                            .find_line_at_offset(self.search_results[0].byte_offset);
-                        self.scroll_line = target_line;
-                        self.scroll_to_row = Some(target_line);
+                    if self.doc.scroll_to_row.is_none() && !self.doc.search_results.is_empty() {
+                        let target_line =
+                            self.line_at_offset(self.doc.search_results[0].byte_offset);
+                        self.doc.scroll_line = target_line;
+                        self.doc.scroll_to_row = Some(target_line);
                     }
                 } else {
                     self.status_message = "No matches found".to_string();
                 }
+
+                self.continue_result_jump();
             }
 
             if new_results_added {
                 // Sort results by byte offset to keep them in order
                 // Only sort once per frame after processing all available chunks
-                self.search_results.sort_by_key(|r| r.byte_offset);
+                self.doc.search_results.sort_by_key(|r| r.byte_offset);
+                // Never hold more than one page; the rest is fetched on demand
+                self.doc.search_results.truncate(self.doc.search_page_size);
 
                 // Check for scroll update after sort
-                if self.scroll_to_row.is_none()
-                    && !self.search_results.is_empty()
-                    && self.current_result_index == 0
+                if self.doc.scroll_to_row.is_none()
+                    && !self.doc.search_results.is_empty()
+                    && self.doc.current_result_index == 0
                 {
-                    let target_line = self
-                        .line_indexer
-                        .find_line_at_offset(self.search_results[0].byte_offset);
-                    self.scroll_line = target_line;
-                    self.scroll_to_row = Some(target_line);
+                    let target_line = self.line_at_offset(self.doc.search_results[0].byte_offset);
+                    self.doc.scroll_line = target_line;
+                    self.doc.scroll_to_row = Some(target_line);
                 }
             }
         }
@@ -463,9 +1559,14 @@ impl TextViewerApp {
                         self.replace_status_message =
                             Some(format!("Replacing... {:.1}%", progress * 100.0));
                     }
-                    ReplaceMessage::Done => {
-                        self.replace_status_message = Some("Replacement complete.".to_string());
-                        self.status_message = "Replacement complete.".to_string();
+                    ReplaceMessage::Done(count) => {
+                        let message = if self.replace_strips_ansi {
+                            format!("ANSI strip complete: {} escape sequences removed.", count)
+                        } else {
+                            format!("Replacement complete: {} occurrences replaced.", count)
+                        };
+                        self.replace_status_message = Some(message.clone());
+                        self.status_message = message;
                         done = true;
                     }
                     ReplaceMessage::Error(e) => {
@@ -482,112 +1583,445 @@ impl TextViewerApp {
             self.replace_message_rx = None;
             self.replace_cancellation_token = None;
             self.replace_progress = None;
+            if let Some(path) = self.replace_reopen.take() {
+                self.reopen_replaced(path);
+            }
         }
     }
 
-    fn perform_single_replace(&mut self) {
-        if self.search_results.is_empty() {
-            return;
+    // Opens the file replaced in place again, where it was left. The tab was
+    // emptied for it unless the user went to another one meanwhile.
+    fn reopen_replaced(&mut self, path: PathBuf) {
+        let message = std::mem::take(&mut self.status_message);
+        if self.doc.file_reader.is_none() {
+            let line = self.doc.scroll_line;
+            self.open_file(path);
+            self.doc.scroll_line = line;
+            self.doc.scroll_to_row = Some(line);
+        } else {
+            self.open_path(path);
+        }
+        self.status_message = message;
+    }
+
+    // Where Replace All writes: over the open file when replacing in place, else
+    // a file picked in a dialog. The flag says whether that's the open file
+    // (whatever the path), which then has to be released while it's rewritten.
+    fn replace_output(
+        &mut self,
+        input_path: &std::path::Path,
+        compressed: bool,
+    ) -> Option<(PathBuf, bool)> {
+        let output_path = if self.replace_in_place {
+            input_path.to_path_buf()
+        } else {
+            rfd::FileDialog::new()
+                .set_file_name(format!(
+                    "{}.modified",
+                    input_path.file_name().unwrap().to_string_lossy()
+                ))
+                .save_file()?
+        };
+        if !is_same_file(input_path, &output_path) {
+            return Some((output_path, false));
+        }
+        // The output is decompressed text, which mustn't replace the archive
+        if compressed {
+            self.status_message =
+                "Can't replace inside a gzip-compressed file; pick a new file".to_string();
+            return None;
+        }
+        if self.doc.unsaved_changes {
+            self.status_message =
+                "Save or undo the pending edits before replacing in place".to_string();
+            return None;
+        }
+        // Rename over the file itself rather than a symlink to it
+        let output_path = std::fs::canonicalize(&output_path).unwrap_or(output_path);
+        Some((output_path, true))
+    }
+
+    // Lets go of the open file before it's rewritten in place, as save_to does;
+    // the rewritten file is renamed over it when the replace finishes
+    fn release_for_replace(&mut self, path: PathBuf) {
+        if let Some(token) = &self.doc.search_cancellation_token {
+            token.store(true, Ordering::Relaxed);
+        }
+        self.doc.index_task = None;
+        self.doc.file_reader = None;
+        self.replace_reopen = Some(path);
+        self.status_message = "Replacing in place; the file reopens when done".to_string();
+    }
+
+    fn max_match_len(&self) -> Option<usize> {
+        (self.longest_match > 0).then_some(self.longest_match)
+    }
+
+    // The query as the replacer takes it, which has no separate regex flags
+    fn replace_pattern(&self) -> String {
+        if self.use_regex {
+            self.regex_flags.apply(&self.search_query)
+        } else {
+            self.search_query.clone()
+        }
+    }
+
+    // Recompiles the query only when it or the options changed since last time
+    fn refresh_query_error(&mut self) {
+        let (query, use_regex, case_sensitive, whole_line, flags) = &self.query_error_key;
+        if *query == self.search_query
+            && *use_regex == self.use_regex
+            && *case_sensitive == self.case_sensitive
+            && *whole_line == self.whole_line
+            && *flags == self.regex_flags
+        {
+            return;
+        }
+        self.query_error_key = (
+            self.search_query.clone(),
+            self.use_regex,
+            self.case_sensitive,
+            self.whole_line,
+            self.regex_flags,
+        );
+        self.query_error = if self.use_regex && !self.search_query.is_empty() {
+            pattern_error(
+                &self.search_query,
+                self.case_sensitive,
+                self.whole_line,
+                self.regex_flags,
+            )
+        } else {
+            None
+        };
+    }
+
+    fn perform_single_replace(&mut self) {
+        if self.doc.search_results.is_empty() {
+            return;
+        }
+        if self.invert_match {
+            self.status_message = "Replace isn't available with Invert Match".to_string();
+            return;
         }
 
-        let local_index = if self.current_result_index >= self.search_page_start_index {
-            self.current_result_index - self.search_page_start_index
+        let local_index = if self.doc.current_result_index >= self.doc.search_page_start_index {
+            self.doc.current_result_index - self.doc.search_page_start_index
         } else {
             return;
         };
 
-        if local_index >= self.search_results.len() {
+        if local_index >= self.doc.search_results.len() {
             return;
         }
 
-        let match_info = self.search_results[local_index].clone();
+        let match_info = self.doc.search_results[local_index].clone();
 
         // Queue the replacement
-        self.pending_replacements.push(PendingReplacement {
-            offset: match_info.byte_offset,
-            old_len: match_info.match_len,
-            new_text: self.replace_query.clone(),
-        });
-        self.unsaved_changes = true;
-        self.status_message = "Replacement pending. Save to apply changes.".to_string();
+        let insert_at = self
+            .doc
+            .pending_replacements
+            .partition_point(|r| r.offset > match_info.byte_offset);
+        let already_pending = self
+            .doc
+            .pending_replacements
+            .get(insert_at)
+            .is_some_and(|r| r.offset == match_info.byte_offset);
+        // The neighbours before and after it in the file, as the list runs backward
+        let match_end = match_info.byte_offset + match_info.match_len;
+        let overlaps = self
+            .doc
+            .pending_replacements
+            .get(insert_at)
+            .is_some_and(|before| before.offset + before.old_len > match_info.byte_offset)
+            || insert_at
+                .checked_sub(1)
+                .is_some_and(|after| self.doc.pending_replacements[after].offset < match_end);
+        if already_pending {
+            self.status_message = "This match is already replaced".to_string();
+        } else if overlaps {
+            self.status_message = "This match overlaps a pending replacement".to_string();
+        } else {
+            // Expand `$1` and friends now so the preview shows what Replace All would
+            // write, using the engine that found the match rather than the current options
+            let matched = self.doc.file_reader.as_ref().map(|reader| {
+                reader.get_chunk(
+                    match_info.byte_offset,
+                    match_info.byte_offset + match_info.match_len,
+                )
+            });
+            let new_text = match &matched {
+                Some(matched) => self
+                    .doc
+                    .search_engine
+                    .expand_replacement(&self.replace_query, matched),
+                None => self.replace_query.clone(),
+            };
+            let new_text = match matched {
+                Some(matched) if self.preserve_case => match_case(&matched, &new_text),
+                _ => new_text,
+            };
+            self.doc.pending_replacements.insert(
+                insert_at,
+                PendingReplacement {
+                    offset: match_info.byte_offset,
+                    old_len: match_info.match_len,
+                    new_text,
+                },
+            );
+            self.doc.undo_stack.push(match_info.byte_offset);
+            self.doc.redo_stack.clear();
+            self.doc.unsaved_changes = true;
+            self.doc.last_edit_time = Some(std::time::Instant::now());
+            self.status_message = "Replacement pending. Save to apply changes.".to_string();
+        }
+
+        // Move on to the next match, like Replace in most editors
+        self.go_to_next_result();
+    }
+
+    // Takes back the most recent pending replacement. Saved ones are on disk
+    // and out of reach, since saving empties both stacks.
+    fn undo_replacement(&mut self) {
+        let Some(offset) = self.doc.undo_stack.pop() else {
+            self.status_message = "Nothing to undo".to_string();
+            return;
+        };
+        if let Some(index) = self
+            .doc
+            .pending_replacements
+            .iter()
+            .position(|r| r.offset == offset)
+        {
+            self.doc
+                .redo_stack
+                .push(self.doc.pending_replacements.remove(index));
+        }
+        self.doc.unsaved_changes = !self.doc.pending_replacements.is_empty();
+        self.doc.last_edit_time = Some(std::time::Instant::now());
+        self.status_message = format!(
+            "Undid replacement ({} pending)",
+            self.doc.pending_replacements.len()
+        );
+    }
+
+    fn redo_replacement(&mut self) {
+        let Some(replacement) = self.doc.redo_stack.pop() else {
+            self.status_message = "Nothing to redo".to_string();
+            return;
+        };
+        let insert_at = self
+            .doc
+            .pending_replacements
+            .partition_point(|r| r.offset > replacement.offset);
+        self.doc.undo_stack.push(replacement.offset);
+        self.doc.pending_replacements.insert(insert_at, replacement);
+        self.doc.unsaved_changes = true;
+        self.doc.last_edit_time = Some(std::time::Instant::now());
+        self.status_message = format!(
+            "Redid replacement ({} pending)",
+            self.doc.pending_replacements.len()
+        );
     }
 
+    // Ctrl+S: write the edits back to the open file, asking for a path only
+    // when there's no plain file on disk to write to
     fn save_file(&mut self) {
-        let Some(ref reader) = self.file_reader else {
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        if reader.has_file() && !reader.is_compressed() {
+            let path = reader.path().clone();
+            self.save_to(path);
+        } else {
+            self.save_as();
+        }
+    }
+
+    fn save_as(&mut self) {
+        let Some(ref reader) = self.doc.file_reader else {
             return;
         };
+        // Saving opens the new file in its place, which would cut the pipe off
+        if self.doc.stdin_rx.is_some() {
+            self.status_message = "Wait for stdin to close before saving".to_string();
+            return;
+        }
         let input_path = reader.path().clone();
-        let encoding = reader.encoding();
 
         if let Some(output_path) = rfd::FileDialog::new()
             .set_file_name(input_path.file_name().unwrap().to_string_lossy())
             .save_file()
         {
-            // If saving to the same file
-            if output_path == input_path {
-                // Apply pending replacements in-place if possible
-                // We need to close the reader first to release the lock
-                self.file_reader = None;
-
-                let mut success = true;
-                for replacement in &self.pending_replacements {
-                    if let Err(e) = Replacer::replace_single(
-                        &input_path,
-                        replacement.offset,
-                        replacement.old_len,
-                        &replacement.new_text,
-                    ) {
-                        self.status_message = format!("Error saving: {}", e);
-                        success = false;
-                        break;
-                    }
-                }
+            self.save_to(output_path);
+        }
+    }
 
-                if success {
-                    self.pending_replacements.clear();
-                    self.unsaved_changes = false;
-                    self.status_message = "File saved successfully".to_string();
-                }
+    fn save_to(&mut self, output_path: PathBuf) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        let input_path = reader.path().clone();
+        let encoding = reader.encoding();
+        // Pending offsets are relative to the opened window, Replacer works on the whole file
+        let base_offset = reader.to_file_offset(0);
+        // Also through another path to it, such as a symlink
+        let same_file = is_same_file(&input_path, &output_path);
+
+        // Edits apply to the decompressed text, so it can only be saved as a new file
+        if reader.is_compressed() && same_file {
+            self.status_message =
+                "Can't save edits into a gzip-compressed file; use Save As".to_string();
+            return;
+        }
 
-                // Re-open file
-                match FileReader::new(input_path.clone(), encoding) {
-                    Ok(reader) => {
-                        self.file_reader = Some(Arc::new(reader));
-                        self.line_indexer
-                            .index_file(self.file_reader.as_ref().unwrap());
-                        self.perform_search(self.search_find_all);
-                    }
-                    Err(e) => {
-                        self.status_message = format!("Error re-opening file: {}", e);
-                    }
+        // Pending replacements are kept last-offset-first
+        let edits = self
+            .doc
+            .pending_replacements
+            .iter()
+            .rev()
+            .map(|r| (base_offset + r.offset, r.old_len, r.new_text.as_str()));
+
+        // Always a full rewrite renamed into place, even for same-length edits, so a
+        // failed save leaves the original untouched rather than half-patched
+        let saved = if reader.is_compressed() || !reader.has_file() {
+            Replacer::write_with_edits(reader.all_data(), &output_path, edits)
+        } else {
+            // Stream from a plain handle, so the mapping can be released before the
+            // rewritten file is renamed over it
+            if same_file {
+                drop(reader);
+                self.doc.file_reader = None;
+            }
+            std::fs::File::open(&input_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    Replacer::write_with_edits(std::io::BufReader::new(file), &output_path, edits)
+                })
+        };
+
+        let saved_ok = saved.is_ok();
+        match saved {
+            Ok(()) => {
+                self.doc.pending_replacements.clear();
+                self.doc.undo_stack.clear();
+                self.doc.redo_stack.clear();
+                self.doc.unsaved_changes = false;
+                self.status_message = "File saved successfully".to_string();
+            }
+            Err(e) => self.status_message = format!("Error saving: {}", e),
+        }
+
+        if same_file {
+            // Re-open file
+            match self.create_reader(input_path, encoding) {
+                Ok(reader) => {
+                    self.doc.file_reader = Some(Arc::new(reader));
+                    self.start_indexing();
+                    self.perform_search(self.doc.search_find_all);
                 }
-            } else {
-                // Saving to a different file
-                // Fallback: Copy file to output, then apply replacements in-place on the output file.
-                if std::fs::copy(&input_path, &output_path).is_ok() {
-                    let mut success = true;
-                    for replacement in &self.pending_replacements {
-                        if let Err(e) = Replacer::replace_single(
-                            &output_path,
-                            replacement.offset,
-                            replacement.old_len,
-                            &replacement.new_text,
-                        ) {
-                            self.status_message = format!("Error saving: {}", e);
-                            success = false;
-                            break;
-                        }
-                    }
-                    if success {
-                        self.pending_replacements.clear();
-                        self.unsaved_changes = false;
-                        self.status_message = "File saved successfully".to_string();
-                        self.open_file(output_path);
-                    }
-                } else {
-                    self.status_message = "Error copying file for save".to_string();
+                Err(e) => {
+                    self.status_message = format!("Error re-opening file: {}", e);
                 }
             }
+        } else if saved_ok {
+            self.open_file(output_path);
+        }
+    }
+
+    // Idle auto-save: flush pending replacements in place once edits have settled
+    fn check_auto_save(&mut self, ctx: &egui::Context) {
+        if !self.auto_save_enabled || !self.doc.unsaved_changes || self.replace_in_progress {
+            return;
+        }
+        let Some(last_edit) = self.doc.last_edit_time else {
+            return;
+        };
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        // Nothing on disk holds this content, so edits can only be saved as a new file
+        if !reader.has_file() || reader.is_compressed() {
+            return;
+        }
+
+        let delay = std::time::Duration::from_secs(self.auto_save_delay_secs);
+        let elapsed = last_edit.elapsed();
+        if elapsed < delay {
+            // egui only repaints on input, so wake up when the timer is due
+            ctx.request_repaint_after(delay - elapsed);
+            return;
+        }
+
+        let path = reader.path().clone();
+
+        // Keep a copy of the original content before the first in-place auto-save
+        if !self.doc.auto_save_backup_done {
+            let backup_path = PathBuf::from(format!("{}.bak", path.display()));
+            if let Err(e) = std::fs::copy(&path, &backup_path) {
+                self.status_message = format!("Auto-save skipped, backup failed: {}", e);
+                self.doc.last_edit_time = None;
+                return;
+            }
+            self.doc.auto_save_backup_done = true;
+        }
+
+        self.doc.last_edit_time = None;
+        self.save_to(path);
+        if !self.doc.unsaved_changes {
+            self.status_message = "Auto-saved pending replacements".to_string();
+        }
+    }
+
+    // Count what Replace All would do, then ask before writing anything
+    fn start_replace_preview(&mut self) {
+        if self.replace_in_progress || self.replace_preview_rx.is_some() {
+            return;
+        }
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        if !reader.has_file() {
+            self.status_message =
+                "Replace All needs a file on disk; save the piped-in text first".to_string();
+            return;
+        }
+
+        let input_path = reader.path().clone();
+        let query = self.replace_pattern();
+        let replace_with = self.replace_query.clone();
+        let use_regex = self.use_regex;
+        let case_sensitive = self.case_sensitive;
+        let preserve_case = self.preserve_case;
+
+        let (tx, rx) = channel();
+        self.replace_preview_rx = Some(rx);
+        self.replace_status_message = Some("Counting replacements...".to_string());
+        std::thread::spawn(move || {
+            let _ = tx.send(Replacer::preview(
+                &input_path,
+                &query,
+                &replace_with,
+                use_regex,
+                case_sensitive,
+                preserve_case,
+                5,
+            ));
+        });
+    }
+
+    fn poll_replace_preview(&mut self) {
+        let Some(ref rx) = self.replace_preview_rx else {
+            return;
+        };
+        if let Ok(result) = rx.try_recv() {
+            self.replace_preview_rx = None;
+            self.replace_status_message = None;
+            match result {
+                Ok(preview) => self.replace_preview = Some(preview),
+                Err(e) => self.replace_status_message = Some(format!("Replace failed: {}", e)),
+            }
         }
     }
 
@@ -596,28 +2030,34 @@ impl TextViewerApp {
             return;
         }
 
-        let Some(ref reader) = self.file_reader else {
+        let Some(reader) = self.doc.file_reader.clone() else {
             return;
         };
         let input_path = reader.path().clone();
+        if !reader.has_file() {
+            self.status_message =
+                "Replace All needs a file on disk; save the piped-in text first".to_string();
+            return;
+        }
+        let compressed = reader.is_compressed();
+        drop(reader);
 
-        // Ask for output file
-        if let Some(output_path) = rfd::FileDialog::new()
-            .set_file_name(format!(
-                "{}.modified",
-                input_path.file_name().unwrap().to_string_lossy()
-            ))
-            .save_file()
-        {
-            let query = self.search_query.clone();
+        if let Some((output_path, in_place)) = self.replace_output(&input_path, compressed) {
+            if in_place {
+                self.release_for_replace(input_path.clone());
+            }
+            let query = self.replace_pattern();
             let replace_with = self.replace_query.clone();
             let use_regex = self.use_regex;
+            let case_sensitive = self.case_sensitive;
+            let preserve_case = self.preserve_case;
 
             let (tx, rx) = std::sync::mpsc::channel();
             self.replace_message_rx = Some(rx);
             self.replace_in_progress = true;
             self.replace_progress = Some(0.0);
             self.replace_status_message = None;
+            self.replace_strips_ansi = false;
 
             let cancel_token = Arc::new(AtomicBool::new(false));
             self.replace_cancellation_token = Some(cancel_token.clone());
@@ -629,6 +2069,8 @@ impl TextViewerApp {
                     &query,
                     &replace_with,
                     use_regex,
+                    case_sensitive,
+                    preserve_case,
                     tx,
                     cancel_token,
                 );
@@ -636,25 +2078,280 @@ impl TextViewerApp {
         }
     }
 
+    // Replace exactly the matches the last Find All found, at their offsets,
+    // rather than searching the file again with the current query
+    fn replace_found_matches(&mut self) {
+        if self.replace_in_progress
+            || !self.doc.search_find_all
+            || self.doc.total_search_results == 0
+            || self.doc.search_engine.is_inverted()
+        {
+            return;
+        }
+
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        let input_path = reader.path().clone();
+        if !reader.has_file() {
+            self.status_message =
+                "Replace All needs a file on disk; save the piped-in text first".to_string();
+            return;
+        }
+
+        if let Some((output_path, in_place)) =
+            self.replace_output(&input_path, reader.is_compressed())
+        {
+            if in_place {
+                self.release_for_replace(input_path.clone());
+            }
+            // The engine still holds the query and options the results came from
+            let engine = self.doc.search_engine.clone();
+            let range = self.doc.search_range;
+            let total = self.doc.total_search_results;
+            let replace_with = self.replace_query.clone();
+            let preserve_case = self.preserve_case;
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            self.replace_message_rx = Some(rx);
+            self.replace_in_progress = true;
+            self.replace_progress = Some(0.0);
+            self.replace_status_message = None;
+            self.replace_strips_ansi = false;
+
+            let cancel_token = Arc::new(AtomicBool::new(false));
+            self.replace_cancellation_token = Some(cancel_token.clone());
+
+            std::thread::spawn(move || {
+                let (start, end) = range.unwrap_or((0, usize::MAX));
+                let mut edits = Vec::with_capacity(total);
+                let len = reader.len().max(1);
+                for result in engine.iter_matches(&reader) {
+                    if cancel_token.load(Ordering::Relaxed) {
+                        let _ = tx.send(ReplaceMessage::Error(format!(
+                            "Cancelled; {} was not written",
+                            output_path.display()
+                        )));
+                        return;
+                    }
+                    let match_end = result.byte_offset + result.match_len;
+                    if result.byte_offset < start || match_end > end {
+                        continue;
+                    }
+                    let matched = reader.get_chunk_str(result.byte_offset, match_end);
+                    let mut new_text = engine.expand_replacement(&replace_with, &matched);
+                    if preserve_case {
+                        new_text = match_case(&matched, &new_text);
+                    }
+                    edits.push((
+                        reader.to_file_offset(result.byte_offset),
+                        result.match_len,
+                        new_text,
+                    ));
+                    if edits.len() % 1000 == 0 {
+                        let _ = tx.send(ReplaceMessage::Progress(result.byte_offset, len));
+                    }
+                }
+                // The mapping must be gone before the output is renamed over it
+                drop(reader);
+                let _ = match Replacer::replace_at_offsets(&input_path, &output_path, edits) {
+                    Ok(count) => tx.send(ReplaceMessage::Done(count)),
+                    Err(e) => tx.send(ReplaceMessage::Error(e.to_string())),
+                };
+            });
+        }
+    }
+
+    // Export a copy of the file with ANSI color/control escapes removed
+    fn strip_ansi_export(&mut self) {
+        if self.replace_in_progress {
+            return;
+        }
+
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        let input_path = reader.path().clone();
+        if !reader.has_file() {
+            self.status_message =
+                "Stripping ANSI codes needs a file on disk; save the piped-in text first"
+                    .to_string();
+            return;
+        }
+
+        if let Some(output_path) = rfd::FileDialog::new()
+            .set_file_name(format!(
+                "{}.clean",
+                input_path.file_name().unwrap().to_string_lossy()
+            ))
+            .save_file()
+        {
+            let (tx, rx) = std::sync::mpsc::channel();
+            self.replace_message_rx = Some(rx);
+            self.replace_in_progress = true;
+            self.replace_progress = Some(0.0);
+            self.replace_status_message = None;
+            self.replace_strips_ansi = true;
+            self.status_message = "Stripping ANSI escape sequences...".to_string();
+
+            let cancel_token = Arc::new(AtomicBool::new(false));
+            self.replace_cancellation_token = Some(cancel_token.clone());
+
+            std::thread::spawn(move || {
+                Replacer::strip_ansi(&input_path, &output_path, tx, cancel_token);
+            });
+        }
+    }
+
+    // Writes every result of the current search, not just the loaded page, to
+    // a file picked in a dialog. Results stream from the search straight to
+    // disk, so any number of them fits.
+    fn export_results(&mut self) {
+        if self.export_task.is_some() || self.doc.total_search_results == 0 {
+            return;
+        }
+        // Line numbers come from the index, which only covers the top until it's done
+        if self.doc.index_task.is_some() {
+            self.status_message = "Wait for indexing to finish before exporting".to_string();
+            return;
+        }
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        let name = reader
+            .path()
+            .file_name()
+            .map_or("results".into(), |name| name.to_string_lossy());
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Text", &["txt"])
+            .add_filter("CSV", &["csv"])
+            .set_file_name(format!("{}.results.txt", name))
+            .save_file()
+        else {
+            return;
+        };
+        let csv = path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+
+        // The engine still holds the query and options the results came from
+        let engine = self.doc.search_engine.clone();
+        let range = self.doc.search_range;
+        // Shared rather than copied; an index change meanwhile makes its own copy
+        let indexer = self.doc.line_indexer.clone();
+        let progress = Arc::new(AtomicUsize::new(0));
+        let cancel = Arc::new(AtomicBool::new(false));
+        let (tx, rx) = channel();
+        self.export_task = Some(ExportTask {
+            rx,
+            progress: progress.clone(),
+            cancel: cancel.clone(),
+            len: reader.len(),
+            path: path.clone(),
+        });
+        self.status_message = format!("Exporting results to {}...", path.display());
+
+        std::thread::spawn(move || {
+            let (fetch_tx, fetch_rx) = std::sync::mpsc::sync_channel(16);
+            engine.fetch_matches(
+                reader.clone(),
+                fetch_tx,
+                0,
+                usize::MAX,
+                range,
+                cancel.clone(),
+            );
+            let result = write_atomically(&path, |temp_path| {
+                let mut out = std::io::BufWriter::new(std::fs::File::create(temp_path)?);
+                if csv {
+                    writeln!(out, "line_number,byte_offset,content")?;
+                }
+                let mut written = 0;
+                loop {
+                    let chunk = match fetch_rx.recv() {
+                        Ok(SearchMessage::ChunkResult(chunk)) => chunk,
+                        Ok(SearchMessage::Done(_)) => break,
+                        Ok(SearchMessage::Error(e)) => anyhow::bail!(e),
+                        Ok(_) => continue,
+                        // The search stops without a word when cancelled
+                        Err(_) => anyhow::bail!("Cancelled"),
+                    };
+                    for result in chunk.matches {
+                        let offset = result.byte_offset;
+                        // Numbered like the gutter, so an exported line can be found again
+                        let line = indexer.find_line_at_offset_with_reader(offset, &reader);
+                        let (text, _) = SearchEngine::context_snippet(
+                            &reader,
+                            offset,
+                            result.match_len,
+                            EXPORT_CONTEXT_BYTES,
+                        );
+                        if csv {
+                            writeln!(
+                                out,
+                                "{},{},{}",
+                                line + 1,
+                                reader.to_file_offset(offset),
+                                csv_field(&text)
+                            )?;
+                        } else {
+                            writeln!(out, "{}: {}", line + 1, text)?;
+                        }
+                        written += 1;
+                        progress.store(offset, Ordering::Relaxed);
+                    }
+                    if cancel.load(Ordering::Relaxed) {
+                        anyhow::bail!("Cancelled");
+                    }
+                }
+                out.flush()?;
+                Ok(written)
+            });
+            let _ = tx.send(result);
+        });
+    }
+
+    fn poll_export_task(&mut self) {
+        let Some(task) = &self.export_task else {
+            return;
+        };
+        let Ok(result) = task.rx.try_recv() else {
+            return;
+        };
+        self.status_message = match result {
+            Ok(count) => format!("Exported {} results to {}", count, task.path.display()),
+            Err(_) if task.cancel.load(Ordering::Relaxed) => {
+                format!("Export cancelled; {} was not written", task.path.display())
+            }
+            Err(e) => format!("Export failed: {}", e),
+        };
+        self.export_task = None;
+    }
+
+    fn export_progress(&self) -> Option<f32> {
+        self.export_task
+            .as_ref()
+            .map(|task| task.progress.load(Ordering::Relaxed) as f32 / task.len.max(1) as f32)
+    }
+
     fn go_to_next_result(&mut self) {
-        if self.total_search_results == 0 {
+        if self.doc.total_search_results == 0 {
             return;
         }
 
-        let next_index = (self.current_result_index + 1) % self.total_search_results;
+        let next_index = (self.doc.current_result_index + 1) % self.doc.total_search_results;
 
         // Check if next_index is within current page
-        let page_end_index = self.search_page_start_index + self.search_results.len();
+        let page_end_index = self.doc.search_page_start_index + self.doc.search_results.len();
 
-        if next_index >= self.search_page_start_index && next_index < page_end_index {
+        if next_index >= self.doc.search_page_start_index && next_index < page_end_index {
             // In current page
-            self.current_result_index = next_index;
-            let local_index = next_index - self.search_page_start_index; //这里最好加一个聚焦偏移
-            let result = &self.search_results[local_index];
-            let target_line = self.line_indexer.find_line_at_offset(result.byte_offset);
-            self.scroll_line = target_line;
-            self.scroll_to_row = Some(target_line);
-            self.pending_scroll_target = Some(target_line);
+            self.doc.current_result_index = next_index;
+            let local_index = next_index - self.doc.search_page_start_index; //这里最好加一个聚焦偏移
+            let result = &self.doc.search_results[local_index];
+            let target_line = self.line_at_offset(result.byte_offset);
+            self.doc.scroll_line = target_line;
+            self.doc.scroll_to_row = Some(target_line);
         } else {
             // Need to fetch next page
             // If we are wrapping around to 0
@@ -664,11 +2361,12 @@ impl TextViewerApp {
                 // Fetch next page starting from the end of current page
                 // We need the byte offset to start searching from.
                 // If we are just moving to the next page sequentially, we can use the last result's offset.
-                if let Some(last_result) = self.search_results.last() {
+                if let Some(last_result) = self.doc.search_results.last() {
                     // We should record the current page start offset before moving
-                    if self.page_offsets.len() <= next_index / 1000 && self.page_offsets.is_empty()
+                    if self.doc.page_offsets.len() <= next_index / self.doc.search_page_size
+                        && self.doc.page_offsets.is_empty()
                     {
-                        self.page_offsets.push(0);
+                        self.doc.page_offsets.push(0);
                     }
 
                     let start_offset = last_result.byte_offset + 1;
@@ -678,133 +2376,473 @@ impl TextViewerApp {
                     self.fetch_page(0, 0);
                 }
             }
-            self.current_result_index = next_index;
+            self.doc.current_result_index = next_index;
         }
     }
 
     fn go_to_previous_result(&mut self) {
-        if self.total_search_results == 0 {
+        if self.doc.total_search_results == 0 {
             return;
         }
 
-        let prev_index = if self.current_result_index == 0 {
-            self.total_search_results - 1
+        let prev_index = if self.doc.current_result_index == 0 {
+            self.doc.total_search_results - 1
         } else {
-            self.current_result_index - 1
+            self.doc.current_result_index - 1
         };
 
         // Check if prev_index is within current page
-        let page_end_index = self.search_page_start_index + self.search_results.len();
+        let page_end_index = self.doc.search_page_start_index + self.doc.search_results.len();
 
-        if prev_index >= self.search_page_start_index && prev_index < page_end_index {
+        if prev_index >= self.doc.search_page_start_index && prev_index < page_end_index {
             // In current page
-            self.current_result_index = prev_index;
-            let local_index = prev_index - self.search_page_start_index;
-            let result = &self.search_results[local_index];
-            let target_line = self.line_indexer.find_line_at_offset(result.byte_offset);
-            self.scroll_line = target_line;
-            self.scroll_to_row = Some(target_line);
-            self.pending_scroll_target = Some(target_line);
+            self.doc.current_result_index = prev_index;
+            let local_index = prev_index - self.doc.search_page_start_index;
+            let result = &self.doc.search_results[local_index];
+            let target_line = self.line_at_offset(result.byte_offset);
+            self.doc.scroll_line = target_line;
+            self.doc.scroll_to_row = Some(target_line);
         } else {
             // Need to fetch previous page (or last page if wrapping)
-            if prev_index == self.total_search_results - 1 {
-                self.status_message = "Cannot wrap to end in paginated mode yet.".to_string();
+            if prev_index == self.doc.total_search_results - 1 {
+                self.fetch_last_page();
             } else {
                 // Fetch previous page
                 // We need the start offset of the page containing `prev_index`.
-                // We assume pages are 1000 items.
-                let target_page_idx = prev_index / 1000;
-                let target_page_start_index = target_page_idx * 1000;
+                // Every page holds search_page_size items.
+                let target_page_idx = prev_index / self.doc.search_page_size;
+                let target_page_start_index = target_page_idx * self.doc.search_page_size;
 
-                if let Some(&offset) = self.page_offsets.get(target_page_idx) {
+                if let Some(&offset) = self.doc.page_offsets.get(target_page_idx) {
                     self.fetch_page(target_page_start_index, offset);
-                    self.current_result_index = prev_index;
+                    self.doc.current_result_index = prev_index;
+                } else if let Some(first) = self.doc.search_results.first() {
+                    // Page never visited going forward: the results before
+                    // the current page's first one make up that page
+                    let end_offset = first.byte_offset;
+                    let count = self.doc.search_page_start_index - target_page_start_index;
+                    self.fetch_page_before(target_page_start_index, end_offset, count, prev_index);
                 } else {
-                    // Fallback: Search from 0
-                    self.fetch_page(0, 0);
-                    self.current_result_index = 0; // Reset to 0 if lost
+                    self.jump_to_result(prev_index);
                 }
             }
         }
     }
 
     fn fetch_page(&mut self, start_index: usize, start_offset: usize) {
-        if self.search_in_progress {
+        if self.doc.search_in_progress {
             return;
         }
 
-        let Some(ref reader) = self.file_reader else {
+        let Some(ref reader) = self.doc.file_reader else {
             return;
         };
 
-        self.search_results.clear();
-        self.search_page_start_index = start_index;
+        self.doc.search_results.clear();
+        self.doc.search_page_start_index = start_index;
 
         // Update page_offsets
-        let page_idx = start_index / 1000;
-        if page_idx >= self.page_offsets.len() {
-            if page_idx == self.page_offsets.len() {
-                self.page_offsets.push(start_offset);
+        let page_idx = start_index / self.doc.search_page_size;
+        if page_idx >= self.doc.page_offsets.len() {
+            if page_idx == self.doc.page_offsets.len() {
+                self.doc.page_offsets.push(start_offset);
             }
         } else {
             // Update existing?
-            self.page_offsets[page_idx] = start_offset;
+            self.doc.page_offsets[page_idx] = start_offset;
         }
 
         let reader = reader.clone();
-        let query = self.search_query.clone();
-        let use_regex = self.use_regex;
-        let case_sensitive = self.case_sensitive;
+        // The engine the search was started with, whatever the options are now
+        let engine = self.doc.search_engine.clone();
         let (tx, rx) = std::sync::mpsc::sync_channel(10_000);
-        self.search_message_rx = Some(rx);
-        self.search_in_progress = true;
+        self.doc.search_message_rx = Some(rx);
+        self.doc.search_in_progress = true;
 
         let cancel_token = Arc::new(AtomicBool::new(false));
-        self.search_cancellation_token = Some(cancel_token.clone());
+        self.doc.search_cancellation_token = Some(cancel_token.clone());
 
         self.status_message = format!(
             "Loading results {}...{}",
             start_index + 1,
-            start_index + 1000
+            start_index + self.doc.search_page_size
         );
 
+        let page_size = self.doc.search_page_size;
+        let range = self.doc.search_range;
         std::thread::spawn(move || {
-            let mut engine = SearchEngine::new();
-            engine.set_query(query, use_regex, case_sensitive);
-            engine.fetch_matches(reader, tx, start_offset, 1000, cancel_token);
+            engine.fetch_matches(reader, tx, start_offset, page_size, range, cancel_token);
         });
     }
 
-    fn go_to_line(&mut self) {
-        if let Ok(line_num) = self.goto_line_input.parse::<usize>() {
-            if line_num > 0 && line_num <= self.line_indexer.total_lines() {
-                let target_line = line_num - 5; // 0-indexed
-                // Show a few lines of context above the target line for better orientation
-                self.scroll_line = target_line.saturating_sub(3);
-                self.scroll_to_row = Some(target_line);
-                self.pending_scroll_target = Some(target_line);
-                self.status_message = format!("Jumped to line {}", line_num);
-            } else {
-                self.status_message = "Line number out of range".to_string();
-            }
-        } else {
-            self.status_message = "Invalid line number".to_string();
+    // Load the `count` results ending at `end_offset` as the page starting at
+    // global result `start_index`, then select `select_index`
+    fn fetch_page_before(
+        &mut self,
+        start_index: usize,
+        end_offset: usize,
+        count: usize,
+        select_index: usize,
+    ) {
+        if self.doc.search_in_progress {
+            return;
         }
-    }
 
-    // Performance tracking
-    fn handle_first_frame_timing(&mut self) {
-        if let Some(start_time) = self.open_start_time {
-            let elapsed = start_time.elapsed();
-            println!("File opened and first frame rendered in: {:.2?}", elapsed);
-            self.status_message = format!("{} (Rendered in {:.2?})", self.status_message, elapsed);
-            self.open_start_time = None;
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+
+        self.doc.search_results.clear();
+        self.doc.search_page_start_index = start_index;
+        self.doc.pending_result_jump = Some(select_index);
+
+        let reader = reader.clone();
+        let engine = self.doc.search_engine.clone();
+        let (tx, rx) = std::sync::mpsc::sync_channel(10_000);
+        self.doc.search_message_rx = Some(rx);
+        self.doc.search_in_progress = true;
+
+        let cancel_token = Arc::new(AtomicBool::new(false));
+        self.doc.search_cancellation_token = Some(cancel_token.clone());
+
+        self.status_message = format!(
+            "Loading results {}...{}",
+            start_index + 1,
+            start_index + count
+        );
+
+        std::thread::spawn(move || {
+            engine.fetch_matches_before(reader, tx, end_offset, count, cancel_token);
+        });
+    }
+
+    // Wrap to the last result by searching backwards from the end of the
+    // searched range. A truncated count doesn't know where the last page
+    // starts, so that case still walks forward.
+    fn fetch_last_page(&mut self) {
+        let total = self.doc.total_search_results;
+        if self.doc.search_truncated {
+            self.jump_to_result(total - 1);
+            return;
+        }
+        let Some(file_len) = self.doc.file_reader.as_ref().map(|reader| reader.len()) else {
+            return;
+        };
+        let end_offset = self.doc.search_range.map_or(file_len, |(_, end)| end);
+        let count = (total - 1) % self.doc.search_page_size + 1;
+        self.fetch_page_before(total - count, end_offset, count, total - 1);
+    }
+
+    // Select global result `index`, loading its page first if needed
+    fn jump_to_result(&mut self, index: usize) {
+        let local_index = index.checked_sub(self.doc.search_page_start_index);
+        if let Some(result) = local_index.and_then(|idx| self.doc.search_results.get(idx)) {
+            let target_line = self.line_at_offset(result.byte_offset);
+            self.doc.current_result_index = index;
+            self.doc.pending_result_jump = None;
+            self.doc.scroll_line = target_line;
+            self.doc.scroll_to_row = Some(target_line);
+            return;
+        }
+
+        self.doc.pending_result_jump = Some(index);
+        self.request_result_page(index);
+        if !self.doc.search_in_progress {
+            // Nothing left to load
+            self.doc.pending_result_jump = None;
+            self.status_message = format!("Could not reach result {}", index + 1);
+        }
+    }
+
+    // Called when a page finished loading; keeps walking until the jump target is loaded
+    fn continue_result_jump(&mut self) {
+        if let Some(index) = self.doc.pending_result_jump {
+            self.jump_to_result(index);
+        }
+    }
+
+    // A full page tells us where the following page starts, which is how
+    // pages beyond those already visited become reachable.
+    fn record_next_page_offset(&mut self) {
+        if !self.doc.search_find_all || self.doc.search_results.len() < self.doc.search_page_size {
+            return;
+        }
+        if self.doc.page_offsets.is_empty() && self.doc.search_page_start_index == 0 {
+            self.doc.page_offsets.push(0);
+        }
+        let next_page = self.doc.search_page_start_index / self.doc.search_page_size + 1;
+        if self.doc.page_offsets.len() == next_page {
+            if let Some(last) = self.doc.search_results.last() {
+                self.doc.page_offsets.push(last.byte_offset + 1);
+            }
+        }
+    }
+
+    // Load the page holding global result `index`. Unvisited pages are reached
+    // by walking forward from the furthest known page, one page per call.
+    fn request_result_page(&mut self, index: usize) {
+        if self.doc.search_in_progress {
+            return;
+        }
+        let page_idx =
+            (index / self.doc.search_page_size).min(self.doc.page_offsets.len().saturating_sub(1));
+        let page_start_index = page_idx * self.doc.search_page_size;
+        if page_start_index == self.doc.search_page_start_index
+            && !self.doc.search_results.is_empty()
+        {
+            // Already loaded and it didn't lead any further
+            return;
+        }
+        let offset = self.doc.page_offsets.get(page_idx).copied().unwrap_or(0);
+        self.fetch_page(page_start_index, offset);
+    }
+
+    fn go_to_line(&mut self) {
+        if let Ok(line_num) = self.goto_line_input.parse::<usize>() {
+            self.jump_to_line(line_num);
+        } else {
+            self.status_message = "Invalid line number".to_string();
+        }
+    }
+
+    fn jump_to_line(&mut self, line_num: usize) {
+        if line_num > 0 && line_num <= self.doc.line_indexer.total_lines() {
+            let target_line = line_num - 1; // 0-indexed
+                                            // Show a few lines of context above the target line for better orientation
+            self.doc.scroll_line = target_line.saturating_sub(3);
+            self.doc.scroll_to_row = Some(target_line);
+            self.status_message = format!("Jumped to line {}", line_num);
+        } else {
+            self.status_message = "Line number out of range".to_string();
+        }
+    }
+
+    fn toggle_bookmark(&mut self) {
+        if self.doc.file_reader.is_none() {
+            return;
+        }
+        let line = self.doc.scroll_line;
+        self.status_message = if self.doc.bookmarks.remove(&line) {
+            format!("Removed bookmark on line {}", line + 1)
+        } else {
+            self.doc.bookmarks.insert(line);
+            format!("Bookmarked line {}", line + 1)
+        };
+    }
+
+    // Next (or previous) bookmark from the current line, wrapping around
+    fn go_to_bookmark(&mut self, forward: bool) {
+        let line = self.doc.scroll_line;
+        let target = if forward {
+            self.doc
+                .bookmarks
+                .range(line + 1..)
+                .next()
+                .or_else(|| self.doc.bookmarks.first())
+        } else {
+            self.doc
+                .bookmarks
+                .range(..line)
+                .next_back()
+                .or_else(|| self.doc.bookmarks.last())
+        };
+        let Some(&target) = target else {
+            self.status_message = "No bookmarks".to_string();
+            return;
+        };
+        self.doc.scroll_line = target;
+        self.doc.scroll_to_row = Some(target);
+        self.status_message = format!("Bookmark at line {}", target + 1);
+    }
+
+    fn go_to_offset(&mut self) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            self.status_message = "Open a file first".to_string();
+            return;
+        };
+        let input = self.goto_offset_input.trim();
+        // Offsets are in terms of the whole file, even when only a byte range is open
+        let base = reader.to_file_offset(0);
+        let offset = match input.strip_suffix('%') {
+            Some(percent) => match percent.trim().parse::<f64>() {
+                Ok(percent) if (0.0..=100.0).contains(&percent) => {
+                    Ok(((reader.len() as f64 * percent / 100.0) as usize)
+                        .min(reader.len().saturating_sub(1)))
+                }
+                Ok(_) => Err("Percentage must be between 0 and 100".to_string()),
+                Err(_) => Err("Invalid percentage".to_string()),
+            },
+            None => match input.parse::<usize>() {
+                Ok(offset) if offset >= base && offset - base < reader.len() => Ok(offset - base),
+                Ok(_) => Err(format!(
+                    "Offset out of range ({}..{})",
+                    base,
+                    base + reader.len()
+                )),
+                Err(_) => Err("Invalid byte offset".to_string()),
+            },
+        };
+
+        match offset {
+            Ok(offset) => {
+                let target_line = self.line_at_offset(offset);
+                self.doc.scroll_line = target_line;
+                self.doc.scroll_to_row = Some(target_line);
+                self.status_message = format!(
+                    "Jumped to offset {} (line {})",
+                    base + offset,
+                    target_line + 1
+                );
+            }
+            Err(message) => self.status_message = message,
+        }
+    }
+
+    fn go_to_result(&mut self) {
+        match self.goto_result_input.trim().parse::<usize>() {
+            Ok(number) if number > 0 && number <= self.doc.total_search_results => {
+                self.jump_to_result(number - 1);
+                if self.doc.pending_result_jump.is_some() {
+                    self.status_message = format!("Loading match {}...", number);
+                }
+            }
+            Ok(_) => self.status_message = "Match number out of range".to_string(),
+            Err(_) => self.status_message = "Invalid match number".to_string(),
+        }
+    }
+
+    // Byte bounds of the lines currently on screen
+    fn visible_byte_range(&self, reader: &FileReader) -> (usize, usize) {
+        let last_line = self.doc.scroll_line + self.visible_lines.saturating_sub(1);
+        let start = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(self.doc.scroll_line, reader)
+            .map_or(reader.len(), |(start, _)| start);
+        let end = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(last_line, reader)
+            .map_or(reader.len(), |(_, end)| end);
+        (start, end.max(start))
+    }
+
+    // Raw text of a line (without its terminator), read through the line index
+    fn read_line_text(&self, line_num: usize) -> Option<String> {
+        let reader = self.doc.file_reader.as_ref()?;
+        let (start, end) = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(line_num, reader)?;
+        let text = reader.get_chunk_str(start, end);
+        Some(
+            text.trim_end_matches('\n')
+                .trim_end_matches('\r')
+                .to_string(),
+        )
+    }
+
+    // Copy the top visible line as `path:line: content` (or `line: content`)
+    fn copy_line_reference(&mut self, ctx: &egui::Context, include_path: bool) {
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        let path = include_path.then(|| reader.path().clone());
+        let line_num = self.doc.scroll_line;
+        if let Some(text) = self.read_line_text(line_num) {
+            ctx.copy_text(format_line_reference(path.as_deref(), line_num, &text));
+            self.status_message = format!("Copied line {}", line_num + 1);
+        }
+    }
+
+    // A gutter click selects its line; with Shift it extends the selection
+    fn select_gutter_line(&mut self, line_num: usize, extend: bool) {
+        self.doc.line_selection = match self.doc.line_selection {
+            Some((first, _)) if extend => Some((first, line_num)),
+            Some((first, last)) if first == line_num && last == line_num => None,
+            _ => Some((line_num, line_num)),
+        };
+        if let Some((first, last)) = self.doc.line_selection {
+            let (first, last) = (first.min(last), first.max(last));
+            self.status_message = if first == last {
+                format!("Selected line {}; Ctrl+C copies it", first + 1)
+            } else {
+                format!(
+                    "Selected lines {}-{}; Ctrl+C copies them",
+                    first + 1,
+                    last + 1
+                )
+            };
+        }
+    }
+
+    // Copies the gutter-selected lines whole, however long, as one block
+    fn copy_line_selection(&mut self, ctx: &egui::Context) {
+        let Some((first, last)) = self.doc.line_selection else {
+            self.status_message = "No lines selected".to_string();
+            return;
+        };
+        let Some(ref reader) = self.doc.file_reader else {
+            return;
+        };
+        let (first, last) = (first.min(last), first.max(last));
+        let range = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(first, reader)
+            .zip(self.doc.line_indexer.get_line_with_reader(last, reader));
+        let Some(((start, _), (_, end))) = range else {
+            self.status_message = format!("Could not read lines {}-{}", first + 1, last + 1);
+            return;
+        };
+        let text = reader.get_chunk_str(start, end);
+        let text = text.trim_end_matches('\n').trim_end_matches('\r');
+        ctx.copy_text(text.to_string());
+        self.status_message = match last - first + 1 {
+            1 => format!("Copied line {}", first + 1),
+            count => format!("Copied {} lines ({}-{})", count, first + 1, last + 1),
+        };
+    }
+
+    // The selected match, when its page is loaded
+    fn current_result(&self) -> Option<&SearchResult> {
+        self.doc
+            .current_result_index
+            .checked_sub(self.doc.search_page_start_index)
+            .and_then(|idx| self.doc.search_results.get(idx))
+    }
+
+    // Copies the whole line holding the selected match
+    fn copy_current_match(&mut self, ctx: &egui::Context) {
+        let Some(result) = self.current_result() else {
+            self.status_message = "No match selected".to_string();
+            return;
+        };
+        let line_num = self.line_at_offset(result.byte_offset);
+        if let Some(text) = self.read_line_text(line_num) {
+            ctx.copy_text(text);
+            self.status_message = format!(
+                "Copied line {} (match {} of {})",
+                line_num + 1,
+                self.doc.current_result_index + 1,
+                self.doc.total_search_results
+            );
+        }
+    }
+
+    // Performance tracking
+    fn handle_first_frame_timing(&mut self) {
+        if let Some(start_time) = self.doc.open_start_time {
+            let elapsed = start_time.elapsed();
+            println!("File opened and first frame rendered in: {:.2?}", elapsed);
+            self.status_message = format!("{} (Rendered in {:.2?})", self.status_message, elapsed);
+            self.doc.open_start_time = None;
         }
     }
 
     // Window title management
     fn update_window_title(&self, ctx: &egui::Context) {
-        let title = if self.unsaved_changes {
+        let title = if self.doc.unsaved_changes {
             "Large Text Viewer *"
         } else {
             "Large Text Viewer"
@@ -814,6 +2852,17 @@ impl TextViewerApp {
 
     // Keyboard shortcuts handling
     fn handle_keyboard_shortcuts(&mut self, ctx: &egui::Context) {
+        // Ctrl+Shift+P / Cmd+Shift+P: Command palette
+        if ctx.input_mut(|i| {
+            i.consume_key(egui::Modifiers::CTRL | egui::Modifiers::SHIFT, egui::Key::P)
+                || i.consume_key(
+                    egui::Modifiers::MAC_CMD | egui::Modifiers::SHIFT,
+                    egui::Key::P,
+                )
+        }) {
+            self.command_palette.toggle();
+        }
+
         // Ctrl+S / Cmd+S: Save file
         if ctx.input_mut(|i| {
             i.consume_key(egui::Modifiers::CTRL, egui::Key::S)
@@ -822,6 +2871,55 @@ impl TextViewerApp {
             self.save_file();
         }
 
+        // Ctrl+Shift+Z / Ctrl+Z: Redo / undo a pending replacement, unless a text
+        // box has focus and wants them for itself (Shift first, as with F2)
+        if ctx.memory(|m| m.focused().is_none()) {
+            if ctx.input_mut(|i| {
+                i.consume_key(egui::Modifiers::CTRL | egui::Modifiers::SHIFT, egui::Key::Z)
+                    || i.consume_key(
+                        egui::Modifiers::MAC_CMD | egui::Modifiers::SHIFT,
+                        egui::Key::Z,
+                    )
+            }) {
+                self.redo_replacement();
+            }
+            if ctx.input_mut(|i| {
+                i.consume_key(egui::Modifiers::CTRL, egui::Key::Z)
+                    || i.consume_key(egui::Modifiers::MAC_CMD, egui::Key::Z)
+            }) {
+                self.undo_replacement();
+            }
+        }
+
+        // Ctrl+W / Cmd+W: Close the active tab
+        if ctx.input_mut(|i| {
+            i.consume_key(egui::Modifiers::CTRL, egui::Key::W)
+                || i.consume_key(egui::Modifiers::MAC_CMD, egui::Key::W)
+        }) {
+            self.close_active_tab();
+        }
+
+        // F5: Reload the file from disk
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::F5)) {
+            self.reload_file();
+        }
+
+        // Ctrl+B / Cmd+B: Toggle a bookmark on the current line
+        if ctx.input_mut(|i| {
+            i.consume_key(egui::Modifiers::CTRL, egui::Key::B)
+                || i.consume_key(egui::Modifiers::MAC_CMD, egui::Key::B)
+        }) {
+            self.toggle_bookmark();
+        }
+
+        // Shift+F2 / F2: Previous / next bookmark (Shift first, F2 alone would match it too)
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::SHIFT, egui::Key::F2)) {
+            self.go_to_bookmark(false);
+        }
+        if ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::F2)) {
+            self.go_to_bookmark(true);
+        }
+
         // Ctrl+R / Cmd+R: Toggle replace
         if ctx.input_mut(|i| {
             i.consume_key(egui::Modifiers::CTRL, egui::Key::R)
@@ -831,6 +2929,43 @@ impl TextViewerApp {
             self.show_replace = !self.show_replace;
         }
 
+        // Ctrl+Shift+C / Cmd+Shift+C: Copy line as path:line: content
+        if ctx.input_mut(|i| {
+            i.consume_key(egui::Modifiers::CTRL | egui::Modifiers::SHIFT, egui::Key::C)
+                || i.consume_key(
+                    egui::Modifiers::MAC_CMD | egui::Modifiers::SHIFT,
+                    egui::Key::C,
+                )
+        }) {
+            self.copy_line_reference(ctx, true);
+        }
+
+        // Ctrl+Alt+C / Cmd+Alt+C: Copy line as line: content
+        if ctx.input_mut(|i| {
+            i.consume_key(egui::Modifiers::CTRL | egui::Modifiers::ALT, egui::Key::C)
+                || i.consume_key(
+                    egui::Modifiers::MAC_CMD | egui::Modifiers::ALT,
+                    egui::Key::C,
+                )
+        }) {
+            self.copy_line_reference(ctx, false);
+        }
+
+        // Ctrl+C / Cmd+C: Copy the lines selected in the gutter, else the
+        // selected match's line, unless some text is selected or a text box has
+        // focus (those copy as usual)
+        let copy = ctx.input(|i| i.events.iter().any(|e| matches!(e, egui::Event::Copy)));
+        if copy
+            && ctx.memory(|m| m.focused().is_none())
+            && !egui::text_selection::LabelSelectionState::load(ctx).has_selection()
+        {
+            if self.doc.line_selection.is_some() {
+                self.copy_line_selection(ctx);
+            } else if self.doc.total_search_results > 0 {
+                self.copy_current_match(ctx);
+            }
+        }
+
         // Ctrl+F / Cmd+F: Toggle search
         if ctx.input_mut(|i| {
             i.consume_key(egui::Modifiers::CTRL, egui::Key::F)
@@ -855,17 +2990,38 @@ impl TextViewerApp {
     // Background tasks polling
     fn poll_background_tasks(&mut self, ctx: &egui::Context) {
         // Check for file changes in tail mode
-        if self.tail_mode {
+        if self.doc.tail_mode {
             self.check_file_changes();
-            ctx.request_repaint(); // Keep refreshing
+            // Wake up to poll the watcher without redrawing every frame
+            ctx.request_repaint_after(TAIL_POLL_INTERVAL);
+        }
+
+        // Keep reading whatever is piped in
+        if self.pending_stdin.is_some() || self.doc.stdin_rx.is_some() {
+            self.poll_stdin();
+            ctx.request_repaint_after(TAIL_POLL_INTERVAL);
         }
 
-        // Poll search and replace results
+        // Poll indexing, search and replace results
+        self.poll_index_task();
+        self.apply_pending_line();
         self.poll_search_results();
         self.poll_replace_results();
+        self.poll_replace_preview();
+        self.poll_export_task();
+        self.poll_folder_search();
+
+        self.check_auto_save(ctx);
 
         // Keep UI responsive during long operations
-        if self.search_in_progress || self.replace_in_progress {
+        if self.doc.search_in_progress
+            || self.replace_in_progress
+            || self.replace_preview_rx.is_some()
+            || self.doc.index_task.is_some()
+            || self.export_task.is_some()
+            || self.folder_search.is_some()
+            || self.header_lookup.as_ref().is_some_and(|l| l.rx.is_some())
+        {
             ctx.request_repaint(); // Keep spinner animated
         }
     }
@@ -874,6 +3030,7 @@ impl TextViewerApp {
     fn render_ui(&mut self, ctx: &egui::Context) {
         // Render panels first (order matters for layout)
         self.render_menu_bar(ctx);
+        self.render_tab_strip(ctx);
         self.render_status_bar(ctx);
         self.render_toolbar(ctx);
         self.render_search_results_bar(ctx);
@@ -884,9 +3041,733 @@ impl TextViewerApp {
         // Windows can be rendered anytime (they float above)
         self.render_encoding_selector(ctx);
         self.render_file_info(ctx);
+        self.render_open_range_dialog(ctx);
+        self.render_command_palette(ctx);
+        self.render_replace_preview(ctx);
+        self.render_reopen_offer(ctx);
+        self.render_binary_prompt(ctx);
+        self.render_highlight_terms(ctx);
+        self.render_find_in_files(ctx);
+    }
+
+    fn add_highlight_term(&mut self) {
+        let query = self.highlight_input.trim().to_string();
+        if query.is_empty() {
+            return;
+        }
+        // Uses the search bar's Regex and Match Case settings
+        let mut engine = SearchEngine::new();
+        engine.set_query(query.clone(), self.use_regex, self.case_sensitive, false);
+        let color = HIGHLIGHT_PALETTE[self.highlight_terms.len() % HIGHLIGHT_PALETTE.len()];
+        self.highlight_terms.push(HighlightTerm {
+            query,
+            use_regex: self.use_regex,
+            case_sensitive: self.case_sensitive,
+            color,
+            engine,
+        });
+        self.highlight_input.clear();
+    }
+
+    fn render_highlight_terms(&mut self, ctx: &egui::Context) {
+        if !self.show_highlights {
+            return;
+        }
+        let mut open = true;
+        egui::Window::new("Highlight Terms")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let mut remove = None;
+                for (index, term) in self.highlight_terms.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.color_edit_button_srgba(&mut term.color);
+                        let mut label = term.query.clone();
+                        if term.use_regex {
+                            label.push_str("  (regex)");
+                        }
+                        if term.case_sensitive {
+                            label.push_str("  (match case)");
+                        }
+                        ui.label(label);
+                        if ui.small_button("✖").clicked() {
+                            remove = Some(index);
+                        }
+                    });
+                }
+                if let Some(index) = remove {
+                    self.highlight_terms.remove(index);
+                }
+                if self.highlight_terms.is_empty() {
+                    ui.weak("No terms yet");
+                } else if ui
+                    .button("Find All Terms")
+                    .on_hover_text("Search for every term at once, each match in its term's color")
+                    .clicked()
+                {
+                    self.search_highlight_terms();
+                    self.show_search_bar = true;
+                }
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.highlight_input)
+                            .desired_width(180.0)
+                            .hint_text("e.g. ERROR"),
+                    );
+                    let submitted =
+                        response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    if ui.button("Add").clicked() || submitted {
+                        self.add_highlight_term();
+                    }
+                });
+                ui.weak("New terms use the current Regex and Match Case settings");
+            });
+        self.show_highlights = open;
+    }
+
+    // Runs the current query, with the search bar's options, over the files
+    // in the chosen folder
+    fn start_folder_search(&mut self) {
+        self.refresh_query_error();
+        if let Some(ref error) = self.query_error {
+            self.status_message = format!("Invalid regex: {}", error_summary(error));
+            return;
+        }
+        if self.search_query.is_empty() {
+            self.status_message = "Enter a search query first".to_string();
+            return;
+        }
+        let root = PathBuf::from(self.find_in_files_dir.trim());
+        if !root.is_dir() {
+            self.status_message = format!("Not a folder: {}", root.display());
+            return;
+        }
+        self.record_search();
+
+        let mut engine = SearchEngine::new();
+        engine.set_query(
+            self.search_query.clone(),
+            self.use_regex,
+            self.case_sensitive,
+            self.whole_line,
+        );
+        engine.set_regex_flags(self.regex_flags);
+        engine.set_max_match_len(self.max_match_len());
+        engine.set_invert(self.invert_match);
+
+        let (tx, rx) = channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        MultiFileSearch::new(root.clone(), &self.find_in_files_glob).search(
+            &engine,
+            tx,
+            cancel.clone(),
+        );
+        self.folder_search = Some(FolderSearch { rx, cancel });
+        self.folder_root = root;
+        self.folder_results.clear();
+        self.folder_progress = (0, 0);
+        self.folder_errors.clear();
+    }
+
+    fn poll_folder_search(&mut self) {
+        let Some(task) = &self.folder_search else {
+            return;
+        };
+        let mut finished = false;
+        while let Ok(message) = task.rx.try_recv() {
+            match message {
+                MultiFileMessage::Match(path, found) => {
+                    self.folder_results.entry(path).or_default().push(found);
+                }
+                MultiFileMessage::Progress(done, total) => self.folder_progress = (done, total),
+                MultiFileMessage::Error(path, e) => self.folder_errors.push((path, e)),
+                MultiFileMessage::Done => {
+                    finished = true;
+                    break;
+                }
+            }
+        }
+        if finished {
+            self.folder_search = None;
+            let matches: usize = self.folder_results.values().map(Vec::len).sum();
+            self.status_message = format!(
+                "Found {} matches in {} of {} files",
+                matches,
+                self.folder_results.len(),
+                self.folder_progress.1
+            );
+            if !self.folder_errors.is_empty() {
+                self.status_message += &format!("; {} couldn't be read", self.folder_errors.len());
+            }
+        }
+    }
+
+    fn render_find_in_files(&mut self, ctx: &egui::Context) {
+        if !self.show_find_in_files {
+            return;
+        }
+        let mut open = true;
+        let mut jump = None;
+        egui::Window::new("Find in Files")
+            .open(&mut open)
+            .default_width(520.0)
+            .show(ctx, |ui| {
+                egui::Grid::new("find_in_files_grid").show(ui, |ui| {
+                    ui.label("Folder:");
+                    ui.horizontal(|ui| {
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.find_in_files_dir)
+                                .desired_width(320.0),
+                        );
+                        if ui.button("Browse...").clicked() {
+                            if let Some(dir) = rfd::FileDialog::new().pick_folder() {
+                                self.find_in_files_dir = dir.display().to_string();
+                            }
+                        }
+                    });
+                    ui.end_row();
+                    ui.label("Files:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.find_in_files_glob)
+                            .desired_width(320.0)
+                            .hint_text("*.log *.txt"),
+                    )
+                    .on_hover_text("File names to search; * and ? are wildcards, empty for all");
+                    ui.end_row();
+                });
+                ui.horizontal(|ui| {
+                    if self.folder_search.is_some() {
+                        let (done, total) = self.folder_progress;
+                        ui.add(egui::Spinner::new());
+                        ui.label(format!("Searched {} of {} files", done, total));
+                        if ui.button("Cancel").clicked() {
+                            self.folder_search = None;
+                            self.status_message = "Find in Files cancelled".to_string();
+                        }
+                    } else {
+                        let search = ui.add_enabled(
+                            !self.search_query.is_empty(),
+                            egui::Button::new(format!("Search for '{}'", self.search_query)),
+                        );
+                        if search.clicked() {
+                            self.start_folder_search();
+                        }
+                    }
+                });
+                ui.weak("Uses the search bar's query and options");
+                ui.separator();
+
+                egui::ScrollArea::vertical()
+                    .max_height(400.0)
+                    .auto_shrink([false, true])
+                    .show(ui, |ui| {
+                        if !self.folder_errors.is_empty() {
+                            egui::CollapsingHeader::new(format!(
+                                "Couldn't be read ({})",
+                                self.folder_errors.len()
+                            ))
+                            .id_salt("folder_errors")
+                            .show(ui, |ui| {
+                                for (path, e) in &self.folder_errors {
+                                    // The folder itself keeps its full name
+                                    let name = path
+                                        .strip_prefix(&self.folder_root)
+                                        .ok()
+                                        .filter(|name| !name.as_os_str().is_empty())
+                                        .unwrap_or(path);
+                                    ui.colored_label(
+                                        ui.visuals().warn_fg_color,
+                                        format!("{}: {}", name.display(), e),
+                                    );
+                                }
+                            });
+                        }
+                        for (path, matches) in &self.folder_results {
+                            let name = path.strip_prefix(&self.folder_root).unwrap_or(path);
+                            egui::CollapsingHeader::new(format!(
+                                "{} ({})",
+                                name.display(),
+                                matches.len()
+                            ))
+                            .id_salt(path)
+                            .show(ui, |ui| {
+                                for found in matches {
+                                    let row = egui::RichText::new(format!(
+                                        "{:6}: {}",
+                                        found.line, found.text
+                                    ))
+                                    .monospace();
+                                    if ui.selectable_label(false, row).clicked() {
+                                        jump = Some((path.clone(), found.line));
+                                    }
+                                }
+                            });
+                        }
+                    });
+            });
+        self.show_find_in_files = open;
+        if let Some((path, line)) = jump {
+            // Another result in the file on screen doesn't open it again
+            if self
+                .doc
+                .file_reader
+                .as_ref()
+                .is_some_and(|r| *r.path() == path)
+            {
+                self.jump_to_line(line);
+            } else {
+                self.open_path(path);
+                self.open_at_line(line);
+            }
+        }
+    }
+
+    fn render_reopen_offer(&mut self, ctx: &egui::Context) {
+        let Some(path) = self.reopen_offer.clone() else {
+            return;
+        };
+        egui::Window::new("Reopen last file?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(path.display().to_string());
+                ui.horizontal(|ui| {
+                    if ui.button("Reopen").clicked() {
+                        self.reopen_offer = None;
+                        self.open_path(path.clone());
+                    }
+                    if ui.button("Not now").clicked() {
+                        self.reopen_offer = None;
+                    }
+                });
+            });
+    }
+
+    fn render_binary_prompt(&mut self, ctx: &egui::Context) {
+        let Some(path) = self.binary_prompt.clone() else {
+            return;
+        };
+        egui::Window::new("Binary file")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label(path.display().to_string());
+                ui.label("This looks like a binary file, open anyway?");
+                ui.horizontal(|ui| {
+                    if ui.button("Open Anyway").clicked() {
+                        self.binary_prompt = None;
+                        self.open_text_path(path.clone());
+                    }
+                    if ui.button("Open as Hex").clicked() {
+                        self.binary_prompt = None;
+                        self.open_text_path(path.clone());
+                        self.hex_view = true;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.binary_prompt = None;
+                    }
+                });
+            });
+    }
+
+    fn render_tab_strip(&mut self, ctx: &egui::Context) {
+        if self.tabs.len() < 2 && self.doc.file_reader.is_none() {
+            return;
+        }
+        let mut switch_to = None;
+        let mut close = None;
+        egui::TopBottomPanel::top("tab_strip").show(ctx, |ui| {
+            egui::ScrollArea::horizontal().show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    for index in 0..self.tabs.len() {
+                        let title = self.tab_title(index);
+                        if ui
+                            .selectable_label(index == self.active_tab, title)
+                            .clicked()
+                        {
+                            switch_to = Some(index);
+                        }
+                        if ui
+                            .small_button("×")
+                            .on_hover_text("Close (Ctrl+W)")
+                            .clicked()
+                        {
+                            close = Some(index);
+                        }
+                        ui.separator();
+                    }
+                });
+            });
+        });
+        if let Some(index) = close {
+            self.switch_tab(index);
+            self.close_active_tab();
+        } else if let Some(index) = switch_to {
+            self.switch_tab(index);
+        }
+    }
+
+    fn render_replace_preview(&mut self, ctx: &egui::Context) {
+        let Some(ref preview) = self.replace_preview else {
+            return;
+        };
+        let mut confirmed = false;
+        let mut closed = false;
+        egui::Window::new("Replace All")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                if preview.total == 0 {
+                    ui.label("Nothing to replace.");
+                } else {
+                    ui.label(format!(
+                        "{} occurrences will be replaced, written to a new file.",
+                        preview.total
+                    ));
+                    ui.separator();
+                    for (offset, old, new) in &preview.samples {
+                        ui.monospace(format!(
+                            "@{}: {} → {}",
+                            offset,
+                            shorten(old, 60),
+                            shorten(new, 60)
+                        ));
+                    }
+                    if preview.total > preview.samples.len() {
+                        ui.weak(format!(
+                            "...and {} more",
+                            preview.total - preview.samples.len()
+                        ));
+                    }
+                }
+                ui.separator();
+                ui.horizontal(|ui| {
+                    if preview.total > 0 && ui.button("Replace All...").clicked() {
+                        confirmed = true;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        closed = true;
+                    }
+                });
+            });
+
+        if confirmed || closed {
+            self.replace_preview = None;
+        }
+        if confirmed {
+            self.perform_replace();
+        }
+    }
+
+    fn render_command_palette(&mut self, ctx: &egui::Context) {
+        let actions = self.actions.clone();
+        if let Some(action) = self
+            .command_palette
+            .show(ctx, &actions)
+            .and_then(|idx| actions.get(idx))
+        {
+            action.run(self, ctx);
+        }
+    }
+
+    // Everything the command palette can run. New features add an entry here.
+    fn register_actions() -> ActionRegistry<Self> {
+        let mut actions = ActionRegistry::default();
+
+        // File
+        actions.register("Open File...", None, |app: &mut Self, _| {
+            app.open_file_dialog()
+        });
+        actions.register("Open Byte Range...", None, |app: &mut Self, _| {
+            app.show_open_range = true
+        });
+        actions.register("Next Window", None, |app: &mut Self, _| {
+            app.move_window(true)
+        });
+        actions.register("Previous Window", None, |app: &mut Self, _| {
+            app.move_window(false)
+        });
+        actions.register("Save", Some("Ctrl+S"), |app: &mut Self, _| app.save_file());
+        actions.register("Save As...", None, |app: &mut Self, _| app.save_as());
+        actions.register("Undo Replacement", Some("Ctrl+Z"), |app: &mut Self, _| {
+            app.undo_replacement()
+        });
+        actions.register(
+            "Redo Replacement",
+            Some("Ctrl+Shift+Z"),
+            |app: &mut Self, _| app.redo_replacement(),
+        );
+        actions.register("Close Tab", Some("Ctrl+W"), |app: &mut Self, _| {
+            app.close_active_tab()
+        });
+        actions.register("Reload from Disk", Some("F5"), |app: &mut Self, _| {
+            app.reload_file()
+        });
+        actions.register("Toggle Auto-save", None, |app: &mut Self, _| {
+            app.auto_save_enabled = !app.auto_save_enabled
+        });
+        actions.register("File Info", None, |app: &mut Self, _| {
+            app.show_file_info = !app.show_file_info
+        });
+        actions.register("Exit", None, |_, ctx| {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close)
+        });
+
+        // View
+        actions.register("Toggle Word Wrap", None, |app: &mut Self, _| {
+            app.wrap_mode = !app.wrap_mode
+        });
+        actions.register("Toggle Wrap at Column", None, |app: &mut Self, _| {
+            app.wrap_at_column = !app.wrap_at_column
+        });
+        actions.register("Toggle Column Ruler", None, |app: &mut Self, _| {
+            app.show_ruler = !app.show_ruler
+        });
+        actions.register("Toggle Line Numbers", None, |app: &mut Self, _| {
+            app.show_line_numbers = !app.show_line_numbers
+        });
+        actions.register("Toggle Syntax Highlighting", None, |app: &mut Self, _| {
+            app.syntax_highlighting = !app.syntax_highlighting
+        });
+        actions.register("Toggle Whitespace Markers", None, |app: &mut Self, _| {
+            app.show_whitespace = !app.show_whitespace
+        });
+        actions.register("Toggle Sticky Header", None, |app: &mut Self, _| {
+            app.sticky_header = !app.sticky_header
+        });
+        actions.register("Toggle Dark Mode", None, |app: &mut Self, _| {
+            app.dark_mode = !app.dark_mode
+        });
+        actions.register("Toggle Matching Lines Only", None, |app: &mut Self, _| {
+            app.results_only_view = !app.results_only_view
+        });
+        actions.register("Toggle Hex View", None, |app: &mut Self, _| {
+            app.hex_view = !app.hex_view;
+            app.sync_hex_view();
+        });
+        actions.register("Select Encoding...", None, |app: &mut Self, _| {
+            app.show_encoding_selector = true
+        });
+        for (name, encoding) in available_encodings() {
+            actions.register(
+                format!("Encoding: {}", name),
+                None,
+                move |app: &mut Self, _| app.set_encoding(encoding),
+            );
+        }
+
+        // Search and navigation
+        actions.register("Find", Some("Ctrl+F"), |app: &mut Self, _| {
+            app.show_search_bar = true;
+            app.focus_search_input = true;
+        });
+        actions.register("Replace", Some("Ctrl+R"), |app: &mut Self, _| {
+            app.show_search_bar = true;
+            app.show_replace = true;
+        });
+        actions.register("Find All", None, |app: &mut Self, _| {
+            app.perform_search(true)
+        });
+        actions.register("Next Result", None, |app: &mut Self, _| {
+            app.go_to_next_result()
+        });
+        actions.register("Previous Result", None, |app: &mut Self, _| {
+            app.go_to_previous_result()
+        });
+        actions.register("Copy Match Line", Some("Ctrl+C"), |app: &mut Self, ctx| {
+            app.copy_current_match(ctx)
+        });
+        actions.register("Toggle Bookmark", Some("Ctrl+B"), |app: &mut Self, _| {
+            app.toggle_bookmark()
+        });
+        actions.register("Next Bookmark", Some("F2"), |app: &mut Self, _| {
+            app.go_to_bookmark(true)
+        });
+        actions.register(
+            "Previous Bookmark",
+            Some("Shift+F2"),
+            |app: &mut Self, _| app.go_to_bookmark(false),
+        );
+        actions.register("Clear Bookmarks", None, |app: &mut Self, _| {
+            app.doc.bookmarks.clear()
+        });
+        actions.register("Copy Selected Lines", None, |app: &mut Self, ctx| {
+            app.copy_line_selection(ctx)
+        });
+        actions.register("Highlight Terms...", None, |app: &mut Self, _| {
+            app.show_highlights = true
+        });
+        actions.register("Go to Line...", None, |app: &mut Self, _| {
+            app.show_search_bar = true
+        });
+        actions.register("Toggle Regex", None, |app: &mut Self, _| {
+            app.use_regex = !app.use_regex
+        });
+        actions.register("Toggle Match Case", None, |app: &mut Self, _| {
+            app.case_sensitive = !app.case_sensitive
+        });
+        actions.register("Toggle Match Whole Line", None, |app: &mut Self, _| {
+            app.whole_line = !app.whole_line
+        });
+        actions.register("Toggle Invert Match", None, |app: &mut Self, _| {
+            app.invert_match = !app.invert_match
+        });
+        actions.register(
+            "Toggle Search Visible Lines Only",
+            None,
+            |app: &mut Self, _| app.search_in_view = !app.search_in_view,
+        );
+
+        // Tools and exports
+        actions.register("Toggle Tail Mode", None, |app: &mut Self, _| {
+            app.doc.tail_mode = !app.doc.tail_mode;
+            app.apply_tail_mode();
+        });
+        actions.register("Replace All Found...", None, |app: &mut Self, _| {
+            app.replace_found_matches()
+        });
+        actions.register("Export Results...", None, |app: &mut Self, _| {
+            app.export_results()
+        });
+        actions.register("Find in Files...", None, |app: &mut Self, _| {
+            app.show_find_in_files = true
+        });
+        actions.register("Strip ANSI Codes...", None, |app: &mut Self, _| {
+            if app.doc.file_reader.is_some() && !app.replace_in_progress {
+                app.strip_ansi_export();
+            }
+        });
+        actions.register(
+            "Copy Line Reference",
+            Some("Ctrl+Shift+C"),
+            |app: &mut Self, ctx| app.copy_line_reference(ctx, true),
+        );
+        actions.register(
+            "Copy Line with Number",
+            Some("Ctrl+Alt+C"),
+            |app: &mut Self, ctx| app.copy_line_reference(ctx, false),
+        );
+
+        actions
     }
 
     //ui
+    // One row of a result list: the line number, then the matching line with the match highlighted
+    fn search_result_job(
+        &self,
+        reader: &FileReader,
+        result: &SearchResult,
+        is_current: bool,
+    ) -> egui::text::LayoutJob {
+        // 只在需要时读取行内容（懒加载）
+        let line_num = self.line_at_offset(result.byte_offset);
+
+        // 优化：只读取匹配周围的上下文，而不是整行
+        // 这样即使行很长也不会卡顿
+        let (line_text, match_range) = SearchEngine::context_snippet(
+            reader,
+            result.byte_offset,
+            result.match_len,
+            RESULT_CONTEXT_BYTES,
+        );
+        let line_text = line_text.as_str();
+        let (match_start_in_line, match_end_in_line) = (match_range.start, match_range.end);
+
+        // 构建带高亮的文本
+        let mut job = egui::text::LayoutJob::default();
+        job.wrap.max_width = f32::INFINITY; // 禁止换行
+
+        // 行内容颜色
+        let text_color = if self.dark_mode {
+            egui::Color32::LIGHT_GRAY
+        } else {
+            egui::Color32::BLACK
+        };
+
+        let highlight_bg = if is_current {
+            if self.dark_mode {
+                egui::Color32::from_rgb(80, 80, 120)
+            } else {
+                egui::Color32::from_rgb(200, 200, 255)
+            }
+        } else {
+            if self.dark_mode {
+                egui::Color32::from_rgb(60, 60, 40)
+            } else {
+                egui::Color32::from_rgb(255, 255, 200)
+            }
+        };
+
+        // Line number - 参考格式："Line 25906976"
+        job.append(
+            &format!("Line {:8}    ", line_num + 1),
+            0.0,
+            egui::TextFormat {
+                font_id: egui::FontId::monospace(self.font_size),
+                color: if self.dark_mode {
+                    egui::Color32::GRAY
+                } else {
+                    egui::Color32::DARK_GRAY
+                },
+                ..Default::default()
+            },
+        );
+
+        // 安全地分割文本
+        let safe_start = match_start_in_line.min(line_text.len());
+        let safe_end = match_end_in_line.min(line_text.len());
+
+        // 匹配前的文本
+        if safe_start > 0 {
+            job.append(
+                &line_text[..safe_start],
+                0.0,
+                egui::TextFormat {
+                    font_id: egui::FontId::monospace(self.font_size),
+                    color: text_color,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // 匹配的文本（高亮）
+        if safe_start < safe_end && safe_end <= line_text.len() {
+            job.append(
+                &line_text[safe_start..safe_end],
+                0.0,
+                egui::TextFormat {
+                    font_id: egui::FontId::monospace(self.font_size),
+                    color: if self.dark_mode {
+                        egui::Color32::WHITE
+                    } else {
+                        egui::Color32::BLACK
+                    },
+                    background: highlight_bg,
+                    ..Default::default()
+                },
+            );
+        }
+
+        // 匹配后的文本
+        if safe_end < line_text.len() {
+            job.append(
+                &line_text[safe_end..],
+                0.0,
+                egui::TextFormat {
+                    font_id: egui::FontId::monospace(self.font_size),
+                    color: text_color,
+                    ..Default::default()
+                },
+            );
+        }
+
+        job
+    }
+
     fn render_search_results_bar(&mut self, ctx: &egui::Context) {
         if !self.show_search_bar {
             return;
@@ -907,34 +3788,76 @@ impl TextViewerApp {
                 ui.separator();
 
                 // 显示搜索统计信息 - 参考格式："Found 20320 occurrences of '0xf00f9344'"
-                if self.search_in_progress {
+                if self.doc.search_in_progress {
                     ui.horizontal(|ui| {
                         ui.spinner();
-                        if self.total_search_results > 0 {
+                        if self.doc.total_search_results > 0 {
                             ui.label(format!(
-                                "Found {} occurrences of '{}'...",
-                                self.total_search_results, self.search_query
+                                "Found {} occurrences of {}...",
+                                self.doc.total_search_results,
+                                self.searched_for()
                             ));
                         } else {
-                            ui.label("Searching...");
+                            ui.label(self.searching_label());
+                        }
+                    });
+                } else if self.doc.total_search_results > 0 {
+                    ui.label(
+                        egui::RichText::new(format!(
+                            "{} for {}.",
+                            self.found_label(),
+                            self.searched_for()
+                        ))
+                            .strong(),
+                    );
+                } else if !self.search_query.is_empty() {
+                    ui.label("No matches found");
+                }
+
+                if self.doc.total_search_results > 0
+                    && ui
+                        .button("Copy match")
+                        .on_hover_text("Copy the selected match's line (Ctrl+C)")
+                        .clicked()
+                {
+                    self.copy_current_match(ctx);
+                }
+
+                // Results beyond the loaded page are fetched a page at a time
+                let loaded = self.doc.search_results.len();
+                let page_start = self.doc.search_page_start_index;
+                if self.doc.search_find_all && loaded > 0 && loaded < self.doc.total_search_results
+                {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Showing {}-{} of {}",
+                            page_start + 1,
+                            page_start + loaded,
+                            self.doc.total_search_results
+                        ));
+                        let idle = !self.doc.search_in_progress;
+                        if ui
+                            .add_enabled(idle && page_start > 0, egui::Button::new("◀ Previous"))
+                            .clicked()
+                        {
+                            self.jump_to_result(
+                                page_start.saturating_sub(self.doc.search_page_size),
+                            );
+                        }
+                        let has_more = page_start + loaded < self.doc.total_search_results;
+                        if ui
+                            .add_enabled(idle && has_more, egui::Button::new("Load more ▶"))
+                            .clicked()
+                        {
+                            self.jump_to_result(page_start + loaded);
                         }
                     });
-                } else if self.total_search_results > 0 {
-                    ui.label(
-                        egui::RichText::new(format!(
-                            "Found {} occurrences of '{}'.",
-                            self.total_search_results, self.search_query
-                        ))
-                            .strong(),
-                    );
-                } else if !self.search_query.is_empty() {
-                    ui.label("No matches found");
                 }
 
                 ui.separator();
 
                 // 使用虚拟滚动显示搜索结果列表
-                if let Some(ref reader) = self.file_reader {
+                if let Some(reader) = self.doc.file_reader.clone() {
                     let text_height = ui.text_style_height(&egui::TextStyle::Monospace);
 
                     egui::ScrollArea::both()
@@ -942,145 +3865,24 @@ impl TextViewerApp {
                         .show_rows(
                             ui,
                             text_height,
-                            self.search_results.len(),
+                            self.doc.search_results.len(),
                             |ui, row_range| {
                                 for idx in row_range {
-                                    if idx >= self.search_results.len() {
+                                    if idx >= self.doc.search_results.len() {
                                         break;
                                     }
 
-                                    let result = &self.search_results[idx];
-                                    let global_idx = self.search_page_start_index + idx;
-                                    let is_current = global_idx == self.current_result_index;
-
-                                    // 只在需要时读取行内容（懒加载）
-                                    let line_num = self.line_indexer.find_line_at_offset(result.byte_offset);
-
-                                    // 优化：只读取匹配周围的上下文，而不是整行
-                                    // 这样即使行很长也不会卡顿
-                                    let context_size = 500; // 匹配前后各读取 500 字节
-                                    let read_start = result.byte_offset.saturating_sub(context_size);
-                                    let read_end = (result.byte_offset + result.match_len + context_size).min(reader.len());
-
-                                    let chunk = reader.get_chunk(read_start, read_end);
-
-                                    // 找到匹配在 chunk 中的位置
-                                    let match_offset_in_chunk = result.byte_offset - read_start;
-
-                                    // 提取一行文本（从换行符到换行符）
-                                    let line_start_in_chunk = chunk[..match_offset_in_chunk]
-                                        .rfind('\n')
-                                        .map(|pos| pos + 1)
-                                        .unwrap_or(0);
-
-                                    let line_end_in_chunk = chunk[match_offset_in_chunk..]
-                                        .find('\n')
-                                        .map(|pos| match_offset_in_chunk + pos)
-                                        .unwrap_or(chunk.len());
-
-                                    let line_text = &chunk[line_start_in_chunk..line_end_in_chunk];
-
-                                    // 匹配在 line_text 中的位置
-                                    let match_start_in_line = match_offset_in_chunk - line_start_in_chunk;
-                                    let match_end_in_line = match_start_in_line + result.match_len;
-
-                                    // 构建带高亮的文本
-                                    let mut job = egui::text::LayoutJob::default();
-                                    job.wrap.max_width = f32::INFINITY; // 禁止换行
-
-                                    // 行内容颜色
-                                    let text_color = if self.dark_mode {
-                                        egui::Color32::LIGHT_GRAY
-                                    } else {
-                                        egui::Color32::BLACK
-                                    };
-
-                                    let highlight_bg = if is_current {
-                                        if self.dark_mode {
-                                            egui::Color32::from_rgb(80, 80, 120)
-                                        } else {
-                                            egui::Color32::from_rgb(200, 200, 255)
-                                        }
-                                    } else {
-                                        if self.dark_mode {
-                                            egui::Color32::from_rgb(60, 60, 40)
-                                        } else {
-                                            egui::Color32::from_rgb(255, 255, 200)
-                                        }
-                                    };
-
-                                    // Line number - 参考格式："Line 25906976"
-                                    job.append(
-                                        &format!("Line {:8}    ", line_num + 1),
-                                        0.0,
-                                        egui::TextFormat {
-                                            font_id: egui::FontId::monospace(self.font_size),
-                                            color: if self.dark_mode {
-                                                egui::Color32::GRAY
-                                            } else {
-                                                egui::Color32::DARK_GRAY
-                                            },
-                                            ..Default::default()
-                                        },
-                                    );
-
-                                    // 安全地分割文本
-                                    let safe_start = match_start_in_line.min(line_text.len());
-                                    let safe_end = match_end_in_line.min(line_text.len());
-
-                                    // 匹配前的文本
-                                    if safe_start > 0 {
-                                        job.append(
-                                            &line_text[..safe_start],
-                                            0.0,
-                                            egui::TextFormat {
-                                                font_id: egui::FontId::monospace(self.font_size),
-                                                color: text_color,
-                                                ..Default::default()
-                                            },
-                                        );
-                                    }
-
-                                    // 匹配的文本（高亮）
-                                    if safe_start < safe_end && safe_end <= line_text.len() {
-                                        job.append(
-                                            &line_text[safe_start..safe_end],
-                                            0.0,
-                                            egui::TextFormat {
-                                                font_id: egui::FontId::monospace(self.font_size),
-                                                color: if self.dark_mode {
-                                                    egui::Color32::WHITE
-                                                } else {
-                                                    egui::Color32::BLACK
-                                                },
-                                                background: highlight_bg,
-                                                ..Default::default()
-                                            },
-                                        );
-                                    }
+                                    let result = &self.doc.search_results[idx];
+                                    let global_idx = self.doc.search_page_start_index + idx;
+                                    let is_current = global_idx == self.doc.current_result_index;
 
-                                    // 匹配后的文本
-                                    if safe_end < line_text.len() {
-                                        job.append(
-                                            &line_text[safe_end..],
-                                            0.0,
-                                            egui::TextFormat {
-                                                font_id: egui::FontId::monospace(self.font_size),
-                                                color: text_color,
-                                                ..Default::default()
-                                            },
-                                        );
-                                    }
+                                let job = self.search_result_job(&reader, result, is_current);
 
                                     let response = ui.selectable_label(is_current, job);
 
                                     // 点击跳转到该结果
                                     if response.clicked() {
-                                        self.current_result_index = global_idx;
-                                        let target_line = self.line_indexer.find_line_at_offset(result.byte_offset);
-                                        self.scroll_line = target_line;
-                                        self.scroll_to_row = Some(target_line);
-                                        self.pending_scroll_target = Some(target_line);
+                                        self.jump_to_result(global_idx);
                                     }
                                 }
                             },
@@ -1094,26 +3896,100 @@ impl TextViewerApp {
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
                     if ui.button("Open...").clicked() {
-                        if let Some(path) = rfd::FileDialog::new().pick_file() {
-                            // Auto-detect encoding
-                            if let Ok(mut file) = std::fs::File::open(&path) {
-                                let mut buffer = [0; 4096];
-                                if let Ok(n) = std::io::Read::read(&mut file, &mut buffer) {
-                                    self.selected_encoding = detect_encoding(&buffer[..n]);
-                                }
+                        self.open_file_dialog();
+                        ui.close_menu();
+                    }
+
+                    if ui.button("Open Byte Range...").clicked() {
+                        self.show_open_range = true;
+                        ui.close_menu();
+                    }
+                    let windowed = self
+                        .doc
+                        .file_reader
+                        .as_ref()
+                        .is_some_and(|reader| reader.window().is_some());
+                    ui.add_enabled_ui(windowed, |ui| {
+                        ui.horizontal(|ui| {
+                            if ui.button("◀ Previous Window").clicked() {
+                                self.move_window(false);
                             }
-                            self.open_file(path);
-                        }
+                            if ui.button("Next Window ▶").clicked() {
+                                self.move_window(true);
+                            }
+                        })
+                        .response
+                        .on_hover_text("Move the byte range by half its size");
+                    });
+
+                    if ui
+                        .add_enabled(
+                            self.doc.file_reader.is_some(),
+                            egui::Button::new("Close Tab (Ctrl+W)"),
+                        )
+                        .clicked()
+                    {
+                        self.close_active_tab();
                         ui.close_menu();
                     }
 
+                    let on_disk = self.doc.file_reader.as_ref().is_some_and(|r| r.has_file());
                     if ui
-                        .add_enabled(self.unsaved_changes, egui::Button::new("Save (Ctrl+S)"))
+                        .add_enabled(on_disk, egui::Button::new("Reload (F5)"))
+                        .on_hover_text("Read the file again as it is on disk now")
+                        .clicked()
+                    {
+                        self.reload_file();
+                        ui.close_menu();
+                    }
+
+                    if ui
+                        .add_enabled(self.doc.unsaved_changes, egui::Button::new("Save (Ctrl+S)"))
                         .clicked()
                     {
                         self.save_file();
                         ui.close_menu();
                     }
+                    if ui
+                        .add_enabled(
+                            self.doc.file_reader.is_some(),
+                            egui::Button::new("Save As..."),
+                        )
+                        .clicked()
+                    {
+                        self.save_as();
+                        ui.close_menu();
+                    }
+
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut self.auto_save_enabled, "Auto-save after");
+                        ui.add(
+                            egui::DragValue::new(&mut self.auto_save_delay_secs)
+                                .range(5..=3600)
+                                .suffix(" s idle"),
+                        );
+                    })
+                    .response
+                    .on_hover_text("Writes pending replacements in place (a .bak copy is kept)");
+
+                    let budget_changed = ui
+                        .horizontal(|ui| {
+                            let toggled = ui
+                                .checkbox(&mut self.index_budget_enabled, "Limit line index to")
+                                .changed();
+                            let resized = ui
+                                .add(
+                                    egui::DragValue::new(&mut self.index_budget_mb)
+                                        .range(1..=65536)
+                                        .suffix(" MB"),
+                                )
+                                .changed();
+                            toggled || (resized && self.index_budget_enabled)
+                        })
+                        .inner;
+                    if budget_changed {
+                        self.apply_index_budget();
+                    }
 
                     if ui.button("File Info").clicked() {
                         self.show_file_info = !self.show_file_info;
@@ -1127,8 +4003,59 @@ impl TextViewerApp {
 
                 ui.menu_button("View", |ui| {
                     ui.checkbox(&mut self.wrap_mode, "Word Wrap");
+                    ui.add_enabled(
+                        self.wrap_mode,
+                        egui::Checkbox::new(&mut self.wrap_at_column, "Wrap at Column"),
+                    );
+                    ui.checkbox(&mut self.show_ruler, "Column Ruler");
+                    ui.horizontal(|ui| {
+                        ui.label("Column:");
+                        ui.add(
+                            egui::DragValue::new(&mut self.wrap_column)
+                                .range(MIN_WRAP_COLUMN..=MAX_WRAP_COLUMN),
+                        );
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Wrap Indent:");
+                        ui.add_enabled(
+                            self.wrap_mode,
+                            egui::DragValue::new(&mut self.wrap_indent).range(0..=MAX_WRAP_INDENT),
+                        )
+                        .on_hover_text("Indent the continuation rows of a wrapped line");
+                    });
                     ui.checkbox(&mut self.show_line_numbers, "Line Numbers");
+                    ui.checkbox(&mut self.syntax_highlighting, "Syntax Highlighting")
+                        .on_hover_text("Color .json, .log and .csv files by their format");
+                    ui.checkbox(&mut self.show_whitespace, "Whitespace Markers")
+                        .on_hover_text("Show ¶ for LF, ␍␊ for CRLF and · for trailing whitespace");
+                    ui.checkbox(&mut self.sticky_header, "Sticky Header")
+                        .on_hover_text(
+                        "Keep the last line above the view that matches the header regex in sight",
+                    );
+                    ui.horizontal(|ui| {
+                        ui.label("Header:");
+                        ui.add_enabled(
+                            self.sticky_header,
+                            egui::TextEdit::singleline(&mut self.header_pattern)
+                                .desired_width(160.0)
+                                .hint_text(r"^\d{4}-\d{2}-\d{2}"),
+                        );
+                    });
+                    if let Some(error) = header_pattern_error(&self.header_pattern) {
+                        ui.colored_label(ui.visuals().error_fg_color, error_summary(&error));
+                    }
                     ui.checkbox(&mut self.dark_mode, "Dark Mode");
+                    ui.checkbox(&mut self.results_only_view, "Matching Lines Only")
+                        .on_hover_text(
+                            "After Find All, list only the matching lines; click one to jump back",
+                        );
+                    if ui.checkbox(&mut self.hex_view, "Hex View").changed() {
+                        self.sync_hex_view();
+                    }
+                    if ui.button("Highlight Terms...").clicked() {
+                        self.show_highlights = true;
+                        ui.close_menu();
+                    }
 
                     ui.separator();
 
@@ -1141,6 +4068,14 @@ impl TextViewerApp {
                         self.show_encoding_selector = true;
                         ui.close_menu();
                     }
+
+                    if ui
+                        .add(egui::Button::new("Command Palette").shortcut_text("Ctrl+Shift+P"))
+                        .clicked()
+                    {
+                        self.command_palette.toggle();
+                        ui.close_menu();
+                    }
                 });
 
                 ui.menu_button("Search", |ui| {
@@ -1162,20 +4097,113 @@ impl TextViewerApp {
                     }
                     ui.separator();
                     ui.checkbox(&mut self.use_regex, "Use Regex");
+                    ui.add_enabled_ui(self.use_regex, |ui| {
+                        ui.checkbox(&mut self.regex_flags.multiline, "Regex: Multiline (?m)");
+                        ui.checkbox(
+                            &mut self.regex_flags.dot_matches_newline,
+                            "Regex: Dot Matches Newline (?s)",
+                        );
+                        ui.checkbox(
+                            &mut self.regex_flags.ignore_whitespace,
+                            "Regex: Ignore Whitespace (?x)",
+                        );
+                    });
                     ui.checkbox(&mut self.case_sensitive, "Match Case");
+                    ui.checkbox(&mut self.whole_line, "Match Whole Line");
+                    ui.checkbox(&mut self.invert_match, "Invert Match");
+                    ui.checkbox(&mut self.search_in_view, "Search Visible Lines Only");
+                    ui.separator();
+                    if ui
+                        .add_enabled(
+                            self.doc.total_search_results > 0 && self.export_task.is_none(),
+                            egui::Button::new("Export Results..."),
+                        )
+                        .on_hover_text(
+                            "Save every result with its line number, as text or (.csv) CSV",
+                        )
+                        .clicked()
+                    {
+                        self.export_results();
+                        ui.close_menu();
+                    }
+                    if ui
+                        .button("Find in Files...")
+                        .on_hover_text("Search every matching file in a folder")
+                        .clicked()
+                    {
+                        self.show_find_in_files = true;
+                        ui.close_menu();
+                    }
+                    ui.separator();
+                    ui.horizontal(|ui| {
+                        ui.label("Load at most");
+                        ui.add(
+                            egui::DragValue::new(&mut self.max_loaded_results)
+                                .range(MIN_PAGE_SIZE..=MAX_PAGE_SIZE)
+                                .suffix(" results"),
+                        );
+                    })
+                    .response
+                    .on_hover_text("Per page; applies to the next search");
+                    ui.horizontal(|ui| {
+                        ui.label("Longest match");
+                        ui.add(
+                            egui::DragValue::new(&mut self.longest_match)
+                                .range(0..=MAX_LONGEST_MATCH)
+                                .suffix(" bytes")
+                                .custom_formatter(|n, _| {
+                                    if n == 0.0 {
+                                        "auto".to_string()
+                                    } else {
+                                        format!("{}", n)
+                                    }
+                                }),
+                        );
+                    })
+                    .response
+                    .on_hover_text(
+                        "Matches up to this long are found even where they cross one of the \
+                         4 MB pieces the file is searched in. Auto uses the pattern's own \
+                         limit, or about 1 KB (64 KB with the m or s flag) when it has none, \
+                         as with .* or +",
+                    );
                 });
 
                 ui.menu_button("Tools", |ui| {
                     if ui
-                        .checkbox(&mut self.tail_mode, "Tail Mode (Auto-refresh)")
+                        .checkbox(&mut self.doc.tail_mode, "Tail Mode (Auto-refresh)")
                         .changed()
                     {
-                        if self.tail_mode {
-                            self.setup_file_watcher();
-                        } else {
-                            self.watcher = None;
-                            self.file_change_rx = None;
-                        }
+                        self.apply_tail_mode();
+                    }
+
+                    if ui
+                        .add_enabled(
+                            self.doc.file_reader.is_some() && !self.replace_in_progress,
+                            egui::Button::new("Strip ANSI Codes..."),
+                        )
+                        .on_hover_text("Save a copy with ANSI color/escape sequences removed")
+                        .clicked()
+                    {
+                        self.strip_ansi_export();
+                        ui.close_menu();
+                    }
+
+                    ui.separator();
+
+                    if ui
+                        .add(egui::Button::new("Copy Line Reference").shortcut_text("Ctrl+Shift+C"))
+                        .clicked()
+                    {
+                        self.copy_line_reference(ctx, true);
+                        ui.close_menu();
+                    }
+                    if ui
+                        .add(egui::Button::new("Copy Line with Number").shortcut_text("Ctrl+Alt+C"))
+                        .clicked()
+                    {
+                        self.copy_line_reference(ctx, false);
+                        ui.close_menu();
                     }
                 });
             });
@@ -1190,8 +4218,40 @@ impl TextViewerApp {
         egui::TopBottomPanel::bottom("toolbar").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 ui.label("Search:");
-                let response =
-                    ui.add(egui::TextEdit::singleline(&mut self.search_query).desired_width(300.0));
+                // The box is a single line, so the caret is always on its
+                // first and last line and Up/Down are free for the history
+                let search_id = egui::Id::new("search_query");
+                let mut recalled = false;
+                if ui.memory(|m| m.has_focus(search_id)) {
+                    let (up, down) = ui.input_mut(|i| {
+                        (
+                            i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+                            i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+                        )
+                    });
+                    if up || down {
+                        recalled = self.recall_search(up);
+                    }
+                }
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.search_query)
+                        .id(search_id)
+                        .desired_width(300.0)
+                        .hint_text("Up/Down: search history"),
+                );
+                if response.changed() {
+                    self.history_index = None;
+                }
+                if recalled {
+                    // Caret to the end of the recalled query
+                    if let Some(mut state) = egui::TextEdit::load_state(ui.ctx(), search_id) {
+                        let end = egui::text::CCursor::new(self.search_query.chars().count());
+                        state
+                            .cursor
+                            .set_char_range(Some(egui::text::CCursorRange::one(end)));
+                        state.store(ui.ctx(), search_id);
+                    }
+                }
 
                 if self.focus_search_input {
                     response.request_focus();
@@ -1202,20 +4262,46 @@ impl TextViewerApp {
                     .on_hover_text("Match Case");
                 ui.checkbox(&mut self.use_regex, ".*")
                     .on_hover_text("Use Regex");
+                if self.use_regex {
+                    ui.checkbox(&mut self.regex_flags.multiline, "m")
+                        .on_hover_text("Multiline: ^ and $ match at every line");
+                    ui.checkbox(&mut self.regex_flags.dot_matches_newline, "s")
+                        .on_hover_text("Dot matches newline, so matches can span lines");
+                    ui.checkbox(&mut self.regex_flags.ignore_whitespace, "x")
+                        .on_hover_text("Ignore whitespace and # comments in the pattern");
+                }
+                ui.checkbox(&mut self.whole_line, "^$")
+                    .on_hover_text("Match Whole Line");
+                ui.checkbox(&mut self.invert_match, "!")
+                    .on_hover_text("Invert Match (show lines without the query)");
+                ui.checkbox(&mut self.search_in_view, "View")
+                    .on_hover_text("Search Visible Lines Only");
+
+                self.refresh_query_error();
+                if let Some(ref error) = self.query_error {
+                    ui.colored_label(egui::Color32::RED, error_summary(error))
+                        .on_hover_text(egui::RichText::new(error).monospace());
+                }
 
                 if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
                     self.perform_search(false);
                 }
 
                 if ui
-                    .add_enabled(!self.search_in_progress, egui::Button::new("🔍 Find"))
+                    .add_enabled(
+                        !self.doc.search_in_progress && self.query_error.is_none(),
+                        egui::Button::new("🔍 Find"),
+                    )
                     .clicked()
                 {
                     self.perform_search(false);
                 }
 
                 if ui
-                    .add_enabled(!self.search_in_progress, egui::Button::new("🔎 Find All"))
+                    .add_enabled(
+                        !self.doc.search_in_progress && self.query_error.is_none(),
+                        egui::Button::new("🔎 Find All"),
+                    )
                     .clicked()
                 {
                     self.perform_search(true);
@@ -1229,23 +4315,41 @@ impl TextViewerApp {
                     self.go_to_next_result();
                 }
 
-                if self.search_in_progress {
+                if self.doc.search_in_progress {
                     ui.add(egui::Spinner::new().size(18.0));
-                    ui.label("Searching...");
+                    ui.label(self.searching_label());
                     if ui.button("Stop").clicked() {
-                        if let Some(token) = &self.search_cancellation_token {
+                        if let Some(token) = &self.doc.search_cancellation_token {
                             token.store(true, Ordering::Relaxed);
                         }
-                        self.search_in_progress = false;
+                        self.doc.search_in_progress = false;
                         self.status_message = "Search stopped by user".to_string();
                     }
                 }
 
-                let total_results = self.total_search_results;
+                let total_results = self.doc.total_search_results;
                 if total_results > 0 {
                     // Show current position over total
-                    let current = (self.current_result_index + 1).min(total_results);
+                    let current = (self.doc.current_result_index + 1).min(total_results);
                     ui.label(format!("{}/{}", current, total_results));
+                    if self.doc.search_find_all && self.doc.search_results.len() < total_results {
+                        ui.weak(format!(
+                            "({} loaded / {} total)",
+                            self.doc.search_results.len(),
+                            total_results
+                        ))
+                        .on_hover_text("More results exist; they load as you navigate");
+                    }
+                }
+
+                if total_results > 0 {
+                    ui.label("Go to match:");
+                    let response = ui.add(
+                        egui::TextEdit::singleline(&mut self.goto_result_input).desired_width(60.0),
+                    );
+                    if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        self.go_to_result();
+                    }
                 }
 
                 ui.separator();
@@ -1261,6 +4365,16 @@ impl TextViewerApp {
                 if ui.button("Go").clicked() {
                     self.go_to_line();
                 }
+
+                ui.label("Go to offset:");
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.goto_offset_input)
+                        .desired_width(100.0)
+                        .hint_text("bytes or 90%"),
+                );
+                if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    self.go_to_offset();
+                }
             });
 
             if self.show_replace {
@@ -1272,6 +4386,15 @@ impl TextViewerApp {
                             .desired_width(200.0)
                             .hint_text("Replacement text..."),
                     );
+                    ui.checkbox(&mut self.preserve_case, "Preserve Case")
+                        .on_hover_text(
+                            "Match each replacement to the casing of the text it replaces",
+                        );
+                    ui.checkbox(&mut self.replace_in_place, "In Place")
+                        .on_hover_text(
+                            "Replace All overwrites the open file, through a temp file renamed \
+                         over it when done, instead of asking where to save",
+                    );
 
                     if self.replace_in_progress {
                         if ui.button("Stop Replace").clicked() {
@@ -1287,36 +4410,179 @@ impl TextViewerApp {
                         if ui.button("Replace").clicked() {
                             self.perform_single_replace();
                         }
-                        if ui.button("Replace All").clicked() {
-                            self.perform_replace();
+                        if self.replace_preview_rx.is_some() {
+                            ui.spinner();
+                        } else if ui.button("Replace All").clicked() {
+                            self.start_replace_preview();
+                        }
+                        if self.doc.search_find_all
+                            && self.doc.total_search_results > 0
+                            && !self.doc.search_engine.is_inverted()
+                            && ui
+                                .button(format!("Replace {} Found", self.doc.total_search_results))
+                                .on_hover_text(
+                                    "Replace exactly the matches found, at their offsets",
+                                )
+                                .clicked()
+                        {
+                            self.replace_found_matches();
                         }
                     }
                 });
 
+                if self.use_regex {
+                    if let Some(warning) =
+                        check_replacement_template(&self.replace_pattern(), &self.replace_query)
+                    {
+                        ui.colored_label(
+                            egui::Color32::from_rgb(230, 160, 0),
+                            format!("⚠ {}", warning),
+                        );
+                    }
+                }
+
                 if let Some(ref msg) = self.replace_status_message {
                     ui.label(msg);
                 }
             }
 
-            if let Some(ref error) = self.search_error {
+            if let Some(ref error) = self.doc.search_error {
                 ui.colored_label(egui::Color32::RED, format!("Search error: {}", error));
             }
         });
     }
 
+    // 1-based column of the match at `offset`, counted in characters from its
+    // line start; `None` when a sparse index can't place the line exactly
+    fn match_column(&mut self, offset: usize) -> Option<usize> {
+        let reader = self.doc.file_reader.clone()?;
+        let key = Arc::as_ptr(&reader) as usize;
+        if let Some((cached_key, cached_offset, column)) = self.status_match_column {
+            if cached_key == key && cached_offset == offset {
+                return column;
+            }
+        }
+        let column = self
+            .doc
+            .line_indexer
+            .get_line_with_reader(self.line_at_offset(offset), &reader)
+            .filter(|&(start, end)| start <= offset && offset < end)
+            .map(|(start, _)| reader.get_chunk_str(start, offset).chars().count() + 1);
+        self.status_match_column = Some((key, offset, column));
+        column
+    }
+
     fn render_status_bar(&mut self, ctx: &egui::Context) {
+        let match_column = self
+            .current_result()
+            .map(|result| result.byte_offset)
+            .and_then(|offset| self.match_column(offset));
         egui::TopBottomPanel::bottom("status_bar").show(ctx, |ui| {
             ui.horizontal(|ui| {
-                if let Some(ref reader) = self.file_reader {
+                if let Some(ref reader) = self.doc.file_reader {
                     ui.label(format!("File: {}", reader.path().display()));
                     ui.separator();
                     ui.label(format!("Size: {} bytes", reader.len()));
                     ui.separator();
-                    ui.label(format!("Lines: ~{}", self.line_indexer.total_lines()));
+                    if let Some((start, end)) = reader.window() {
+                        ui.label(format!("Window: {}..{}", start, end));
+                        ui.separator();
+                    }
+                    ui.label(format!("Lines: {}", self.line_count_label()));
+                    if let Some(progress) = self.index_progress() {
+                        ui.add(
+                            egui::ProgressBar::new(progress)
+                                .desired_width(120.0)
+                                .text(format!("Indexing {:.0}%", progress * 100.0)),
+                        );
+                    }
+                    if let Some(progress) = self.export_progress() {
+                        ui.add(
+                            egui::ProgressBar::new(progress)
+                                .desired_width(120.0)
+                                .text(format!("Exporting {:.0}%", progress * 100.0)),
+                        );
+                        if ui
+                            .small_button("✖")
+                            .on_hover_text("Cancel export")
+                            .clicked()
+                        {
+                            if let Some(task) = &self.export_task {
+                                task.cancel.store(true, Ordering::Relaxed);
+                            }
+                        }
+                    }
                     ui.separator();
                     ui.label(format!("Encoding: {}", reader.encoding().name()));
+                    if self.doc.encoding_detection.is_some_and(|detected| {
+                        detected.is_uncertain() && detected.encoding == reader.encoding()
+                    }) {
+                        ui.colored_label(egui::Color32::YELLOW, "⚠").on_hover_text(
+                            "Encoding was guessed with low confidence; \
+                             pick another if the text looks garbled",
+                        );
+                    }
                     ui.separator();
-                    ui.label(format!("Line: {}", self.scroll_line + 1));
+                    let top_offset = self
+                        .doc
+                        .line_indexer
+                        .get_line_with_reader(self.doc.scroll_line, reader)
+                        .map(|(start, _)| reader.to_file_offset(start));
+                    match top_offset {
+                        Some(offset) => ui.label(format!(
+                            "Line: {} (byte {})",
+                            self.doc.scroll_line + 1,
+                            offset
+                        )),
+                        None => ui.label(format!("Line: {}", self.doc.scroll_line + 1)),
+                    };
+                    if let Some(offset) = self.doc.hex_selected.filter(|_| self.hex_view) {
+                        if let Some(byte) = reader.get_bytes(offset, offset + 1).first() {
+                            let offset = reader.to_file_offset(offset);
+                            ui.separator();
+                            ui.label(format!(
+                                "Byte: {} (0x{:X}) = 0x{:02X}",
+                                offset, offset, byte
+                            ));
+                        }
+                    }
+                    if let Some(result) = self
+                        .current_result()
+                        .filter(|_| self.doc.total_search_results > 0)
+                    {
+                        let (text, range) = SearchEngine::context_snippet(
+                            reader,
+                            result.byte_offset,
+                            result.match_len,
+                            STATUS_CONTEXT_BYTES,
+                        );
+                        ui.separator();
+                        let column = match_column
+                            .map(|column| format!(", col {}", column))
+                            .unwrap_or_default();
+                        ui.label(format!(
+                            "Match {}/{} at byte {}{}:",
+                            self.doc.current_result_index + 1,
+                            self.doc.total_search_results,
+                            reader.to_file_offset(result.byte_offset),
+                            column
+                        ));
+                        let mut job = egui::text::LayoutJob::default();
+                        let plain = egui::TextFormat {
+                            font_id: egui::TextStyle::Monospace.resolve(ui.style()),
+                            color: ui.visuals().text_color(),
+                            ..Default::default()
+                        };
+                        let matched = egui::TextFormat {
+                            color: egui::Color32::BLACK,
+                            background: match_color(true),
+                            ..plain.clone()
+                        };
+                        job.append(&text[..range.start], 0.0, plain.clone());
+                        job.append(&text[range.clone()], 0.0, matched);
+                        job.append(&text[range.end..], 0.0, plain);
+                        ui.label(job);
+                    }
                 } else {
                     ui.label("No file opened - Click File → Open to start");
                 }
@@ -1329,303 +4595,870 @@ impl TextViewerApp {
         });
     }
 
-    fn render_text_area(&mut self, ctx: &egui::Context) {
+    fn render_results_only_view(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let Some(reader) = self.doc.file_reader.clone() else {
+                return;
+            };
+            let text_height = ui.text_style_height(&egui::TextStyle::Monospace);
+            let mut first_missing = None;
+            let mut clicked = None;
+
+            // Row i is global result i; rows outside the loaded page are fetched on demand
+            egui::ScrollArea::both()
+                .id_salt("results_only_view")
+                .auto_shrink([false; 2])
+                .show_rows(
+                    ui,
+                    text_height,
+                    self.doc.total_search_results,
+                    |ui, row_range| {
+                        for global_idx in row_range {
+                            let local_idx = global_idx
+                                .checked_sub(self.doc.search_page_start_index)
+                                .filter(|&idx| idx < self.doc.search_results.len());
+                            let Some(local_idx) = local_idx else {
+                                first_missing.get_or_insert(global_idx);
+                                ui.label(egui::RichText::new("Loading...").monospace().weak());
+                                continue;
+                            };
+
+                            let result = &self.doc.search_results[local_idx];
+                            let is_current = global_idx == self.doc.current_result_index;
+                            let job = self.search_result_job(&reader, result, is_current);
+                            if ui.selectable_label(is_current, job).clicked() {
+                                clicked = Some((global_idx, result.byte_offset));
+                            }
+                        }
+                    },
+                );
+
+            if let Some(index) = first_missing {
+                self.request_result_page(index);
+            }
+
+            // Jump to the clicked match in the full view
+            if let Some((index, byte_offset)) = clicked {
+                self.doc.current_result_index = index;
+                self.results_only_view = false;
+                let target_line = self.line_at_offset(byte_offset);
+                self.doc.scroll_line = target_line;
+                self.doc.scroll_to_row = Some(target_line);
+            }
+        });
+    }
+
+    // Keeps the same place in view when switching between text and hex
+    fn sync_hex_view(&mut self) {
+        let Some(reader) = self.doc.file_reader.clone() else {
+            return;
+        };
+        if self.hex_view {
+            let offset = self
+                .doc
+                .line_indexer
+                .get_line_with_reader(self.doc.scroll_line, &reader)
+                .map_or(0, |(start, _)| start);
+            self.doc.hex_row = offset / HEX_ROW_BYTES;
+        } else {
+            let offset = self
+                .doc
+                .hex_selected
+                .unwrap_or(self.doc.hex_row * HEX_ROW_BYTES);
+            self.doc.scroll_line = self.line_at_offset(offset);
+            self.doc.scroll_to_row = Some(self.doc.scroll_line);
+        }
+    }
+
+    // The raw bytes as `offset | hex | ascii` rows, pending edits not applied.
+    // Like the text view it keeps its first row rather than a pixel offset, so
+    // it reaches the end of any file. Bytes of loaded search results are
+    // highlighted, and clicking a byte selects it for the status bar.
+    fn render_hex_view(&mut self, ctx: &egui::Context) {
+        egui::CentralPanel::default().show(ctx, |ui| {
+            let Some(reader) = self.doc.file_reader.clone() else {
+                return;
+            };
+            let font_id = egui::FontId::monospace(self.font_size);
+            let line_height = ui.fonts(|f| f.row_height(&font_id));
+            let char_width = ui.fonts(|f| f.glyph_width(&font_id, '0'));
+            let total_rows = reader.len().div_ceil(HEX_ROW_BYTES);
+            let page_rows = ((ui.available_height() / line_height).floor() as usize).max(1);
+            let max_row = total_rows.saturating_sub(page_rows);
+
+            // Jumps name a line: land on the current result when it's in or just
+            // after that line, else on the line's first byte
+            if let Some(line) = self.doc.scroll_to_row.take() {
+                let line_start = self
+                    .doc
+                    .line_indexer
+                    .get_line_with_reader(line, &reader)
+                    .map_or(0, |(start, _)| start);
+                let offset = self
+                    .current_result()
+                    .map(|result| result.byte_offset)
+                    .filter(|&offset| {
+                        offset >= line_start && self.line_at_offset(offset) <= line + 3
+                    })
+                    .unwrap_or(line_start);
+                self.doc.hex_row = offset / HEX_ROW_BYTES;
+            }
+            let full_rect = ui.available_rect_before_wrap();
+            if ui.rect_contains_pointer(full_rect) {
+                let dy = ui.input_mut(|i| std::mem::take(&mut i.smooth_scroll_delta.y));
+                self.wheel_lines -= dy / line_height;
+                let whole = self.wheel_lines.trunc();
+                self.wheel_lines -= whole;
+                self.doc.hex_row = self.doc.hex_row.saturating_add_signed(whole as isize);
+            }
+            if ctx.memory(|m| m.focused().is_none()) {
+                let (page_up, page_down) = ctx.input_mut(|i| {
+                    (
+                        i.consume_key(egui::Modifiers::NONE, egui::Key::PageUp),
+                        i.consume_key(egui::Modifiers::NONE, egui::Key::PageDown),
+                    )
+                });
+                if page_up {
+                    self.doc.hex_row = self.doc.hex_row.saturating_sub(page_rows);
+                }
+                if page_down {
+                    self.doc.hex_row = self.doc.hex_row.saturating_add(page_rows);
+                }
+            }
+            self.doc.hex_row = self.doc.hex_row.min(max_row);
+
+            let bar_width = ui.spacing().scroll.bar_width;
+            let bar_rect = egui::Rect::from_min_max(
+                egui::pos2(full_rect.right() - bar_width, full_rect.top()),
+                full_rect.right_bottom(),
+            );
+            if let Some(row) = line_scrollbar(
+                ui,
+                bar_rect,
+                self.doc.hex_row,
+                max_row,
+                page_rows,
+                total_rows,
+            ) {
+                self.doc.hex_row = row;
+            }
+
+            // Loaded results overlapping the rows in view, as (start, end, color)
+            let first_byte = self.doc.hex_row * HEX_ROW_BYTES;
+            let end_byte = ((self.doc.hex_row + page_rows + 1) * HEX_ROW_BYTES).min(reader.len());
+            let start_idx = self
+                .doc
+                .search_results
+                .partition_point(|r| r.byte_offset + r.match_len <= first_byte);
+            let matches: Vec<(usize, usize, egui::Color32)> = self
+                .doc
+                .search_results
+                .iter()
+                .enumerate()
+                .skip(start_idx)
+                .take_while(|(_, r)| r.byte_offset < end_byte)
+                .map(|(idx, r)| {
+                    let is_current =
+                        self.doc.search_page_start_index + idx == self.doc.current_result_index;
+                    (
+                        r.byte_offset,
+                        r.byte_offset + r.match_len,
+                        match_color(is_current),
+                    )
+                })
+                .collect();
+            let selection = ui.visuals().selection.bg_fill;
+            let background = |offset: usize| {
+                if self.doc.hex_selected == Some(offset) {
+                    return selection;
+                }
+                matches
+                    .iter()
+                    .find(|&&(start, end, _)| start <= offset && offset < end)
+                    .map_or(egui::Color32::TRANSPARENT, |&(_, _, color)| color)
+            };
+            let text_color = if self.dark_mode {
+                egui::Color32::LIGHT_GRAY
+            } else {
+                egui::Color32::BLACK
+            };
+            let format = |background: egui::Color32| egui::TextFormat {
+                font_id: font_id.clone(),
+                color: if background == egui::Color32::TRANSPARENT {
+                    text_color
+                } else {
+                    egui::Color32::BLACK
+                },
+                background,
+                ..Default::default()
+            };
+            let digits = format!("{:X}", reader.to_file_offset(reader.len()))
+                .len()
+                .max(8);
+
+            let text_rect = full_rect.with_max_x(bar_rect.left() - ui.spacing().item_spacing.x);
+            let mut rows_ui = ui.new_child(egui::UiBuilder::new().max_rect(text_rect));
+            rows_ui.set_clip_rect(text_rect);
+            let mut clicked = None;
+            for row in self.doc.hex_row..(self.doc.hex_row + page_rows + 1).min(total_rows) {
+                let start = row * HEX_ROW_BYTES;
+                let bytes = reader.get_bytes(start, start + HEX_ROW_BYTES);
+                let mut hex = egui::text::LayoutJob::default();
+                let mut ascii = egui::text::LayoutJob::default();
+                for (i, &byte) in bytes.iter().enumerate() {
+                    let background = background(start + i);
+                    hex.append(&format!("{:02X}", byte), 0.0, format(background));
+                    hex.append(" ", 0.0, format(egui::Color32::TRANSPARENT));
+                    let shown = if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    };
+                    ascii.append(&shown.to_string(), 0.0, format(background));
+                }
+                // The last row is padded so its ascii column lines up
+                let padding = " ".repeat((HEX_ROW_BYTES - bytes.len()) * 3);
+                hex.append(&padding, 0.0, format(egui::Color32::TRANSPARENT));
+
+                rows_ui.horizontal(|ui| {
+                    ui.label(
+                        egui::RichText::new(format!(
+                            "{:0width$X}",
+                            reader.to_file_offset(start),
+                            width = digits
+                        ))
+                        .font(font_id.clone())
+                        .weak(),
+                    );
+                    ui.label(egui::RichText::new("│").font(font_id.clone()).weak());
+                    // Each byte is three columns wide in the hex part, one in the ascii part
+                    for (job, columns) in [(hex, 3), (ascii, 1)] {
+                        let response = ui.add(
+                            egui::Label::new(job)
+                                .selectable(false)
+                                .sense(egui::Sense::click()),
+                        );
+                        if let Some(pos) = response
+                            .interact_pointer_pos()
+                            .filter(|_| response.clicked())
+                        {
+                            let column = ((pos.x - response.rect.left()) / char_width) as usize;
+                            let index = column / columns;
+                            if index < bytes.len() {
+                                clicked = Some(start + index);
+                            }
+                        }
+                        if columns == 3 {
+                            ui.label(egui::RichText::new("│").font(font_id.clone()).weak());
+                        }
+                    }
+                });
+            }
+            if clicked.is_some() {
+                self.doc.hex_selected = clicked;
+            }
+        });
+    }
+
+    // Screen rows `line` takes up: one, unless wrapping at `width`
+    fn line_rows(
+        &mut self,
+        ui: &egui::Ui,
+        reader: &Arc<FileReader>,
+        line: usize,
+        width: f32,
+    ) -> usize {
+        if !self.wrap_mode {
+            return 1;
+        }
+        let font_id = egui::FontId::monospace(self.font_size);
+        let char_width = ui.fonts(|f| f.glyph_width(&font_id, '0'));
+        // Continuation rows are laid out narrower by their indent
+        let width = width - self.wrap_indent_width(char_width, width);
+        let key = (
+            Arc::as_ptr(reader) as usize,
+            width.to_bits(),
+            self.font_size.to_bits(),
+        );
+        if self.wrap_rows_key != key {
+            self.wrap_rows_key = key;
+            self.wrap_rows.clear();
+        }
+        if let Some(&rows) = self.wrap_rows.get(&line) {
+            return rows;
+        }
+        let rows = match self.doc.line_indexer.get_line_with_reader(line, reader) {
+            Some((start, end)) => {
+                let text = reader.get_chunk_str(start, end.min(reader.len()));
+                let text = text
+                    .trim_end_matches('\n')
+                    .trim_end_matches('\r')
+                    .to_owned();
+                let galley = ui.fonts(|f| f.layout(text, font_id, egui::Color32::WHITE, width));
+                galley.rows.len().max(1)
+            }
+            None => 1,
+        };
+        self.wrap_rows.insert(line, rows);
+        rows
+    }
+
+    // Points the continuation rows of a line wrapping at `width` are indented
+    // by; never more than half of it
+    fn wrap_indent_width(&self, char_width: f32, width: f32) -> f32 {
+        (self.wrap_indent as f32 * char_width).min(width / 2.0)
+    }
+
+    // How many whole lines from `first` fit in `rows` screen rows, at least one
+    fn lines_fitting(
+        &mut self,
+        ui: &egui::Ui,
+        reader: &Arc<FileReader>,
+        first: usize,
+        rows: usize,
+        width: f32,
+    ) -> usize {
+        let total_lines = self.doc.line_indexer.total_lines();
+        let (mut used, mut count) = (0, 0);
+        while first + count < total_lines {
+            used += self.line_rows(ui, reader, first + count, width);
+            if used > rows {
+                break;
+            }
+            count += 1;
+        }
+        count.max(1)
+    }
+
+    // First line of the page that ends just before `line`, at least one line back
+    fn page_start_before(
+        &mut self,
+        ui: &egui::Ui,
+        reader: &Arc<FileReader>,
+        line: usize,
+        rows: usize,
+        width: f32,
+    ) -> usize {
+        let (mut first, mut used) = (line, 0);
+        while first > 0 {
+            used += self.line_rows(ui, reader, first - 1, width);
+            if used > rows {
+                break;
+            }
+            first -= 1;
+        }
+        first.min(line.saturating_sub(1))
+    }
+
+    fn render_text_area(&mut self, ctx: &egui::Context) {
+        if self.results_only_view && self.doc.search_find_all && self.doc.total_search_results > 0 {
+            self.render_results_only_view(ctx);
+            return;
+        }
+        if self.hex_view {
+            self.render_hex_view(ctx);
+            return;
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            if let Some(ref reader) = self.file_reader {
+            if let Some(reader) = self.doc.file_reader.clone() {
+                let reader = &reader;
                 let available_height = ui.available_height();
                 let font_id = egui::FontId::monospace(self.font_size); //？
                 let line_height = ui.fonts(|f| f.row_height(&font_id));
                 self.visible_lines = ((available_height / line_height).ceil() as usize).saturating_add(2);
-
-                let mut scroll_area = if self.wrap_mode {
-                    egui::ScrollArea::vertical()
+                // Monospace, so any glyph gives the column width
+                let char_width = ui.fonts(|f| f.glyph_width(&font_id, '0'));
+                let column_width = self.wrap_column as f32 * char_width;
+                // Columns in view horizontally, going by last frame's scroll offset
+                let view_columns = (
+                    (self.doc.last_scroll_offset_x / char_width) as usize,
+                    (ui.available_width() / char_width).ceil() as usize,
+                );
+                let fixed_wrap = self.wrap_mode && self.wrap_at_column;
+                let syntax =
+                    SyntaxFormat::from_path(reader.path()).filter(|_| self.syntax_highlighting);
+
+                let total_lines = self.doc.line_indexer.total_lines();
+                // Whole rows that fit; the last line can scroll up to the bottom, no further.
+                // With wrapping a line can take several rows, so pages and the limit are
+                // counted in lines by measuring each one.
+                let page_rows = ((available_height / line_height).floor() as usize).max(1);
+                let full_rect = ui.available_rect_before_wrap();
+                let wrap_width = if fixed_wrap {
+                    column_width
+                } else if self.wrap_width > 0.0 {
+                    self.wrap_width
                 } else {
-                    egui::ScrollArea::both()
+                    full_rect.width()
+                };
+                let max_anchor = if total_lines == 0 {
+                    0
+                } else {
+                    self.page_start_before(ui, reader, total_lines, page_rows, wrap_width)
+                };
+
+                // Jumps set the first line directly, so they land exactly whatever the file size
+                if let Some(target_row) = self.doc.scroll_to_row.take() {
+                    self.doc.scroll_line = target_row;
                 }
-                    // Tie scroll memory to the current file path so new files start at the top
-                    .id_salt(
-                        self.file_reader
-                            .as_ref()
-                            .map(|r| r.path().display().to_string())
-                            .unwrap_or_else(|| "no_file".to_string()),
-                    )
+                if ui.rect_contains_pointer(full_rect) {
+                    // Taken here so the horizontal scroll area below can't turn it sideways
+                    let dy = ui.input_mut(|i| std::mem::take(&mut i.smooth_scroll_delta.y));
+                    self.wheel_lines -= dy / line_height;
+                    // The wheel moves by rows, so a wrapped line goes by once its rows
+                    // have been scrolled (a page's worth at most)
+                    while self.wheel_lines >= 1.0 && self.doc.scroll_line < max_anchor {
+                        let rows = self.line_rows(ui, reader, self.doc.scroll_line, wrap_width);
+                        let rows = rows.min(page_rows) as f32;
+                        if self.wheel_lines < rows {
+                            break;
+                        }
+                        self.wheel_lines -= rows;
+                        self.doc.scroll_line += 1;
+                    }
+                    while self.wheel_lines <= -1.0 && self.doc.scroll_line > 0 {
+                        let rows = self.line_rows(ui, reader, self.doc.scroll_line - 1, wrap_width);
+                        let rows = rows.min(page_rows) as f32;
+                        if -self.wheel_lines < rows {
+                            break;
+                        }
+                        self.wheel_lines += rows;
+                        self.doc.scroll_line -= 1;
+                    }
+                    let at_end = if self.wheel_lines > 0.0 {
+                        self.doc.scroll_line >= max_anchor
+                    } else {
+                        self.doc.scroll_line == 0
+                    };
+                    if at_end {
+                        self.wheel_lines = 0.0;
+                    }
+                }
+                if ctx.memory(|m| m.focused().is_none()) {
+                    let (page_up, page_down) = ctx.input_mut(|i| {
+                        (
+                            i.consume_key(egui::Modifiers::NONE, egui::Key::PageUp),
+                            i.consume_key(egui::Modifiers::NONE, egui::Key::PageDown),
+                        )
+                    });
+                    if page_up {
+                        self.doc.scroll_line = self.page_start_before(
+                            ui,
+                            reader,
+                            self.doc.scroll_line,
+                            page_rows,
+                            wrap_width,
+                        );
+                    }
+                    if page_down {
+                        let shown = self.lines_fitting(
+                            ui,
+                            reader,
+                            self.doc.scroll_line,
+                            page_rows,
+                            wrap_width,
+                        );
+                        self.doc.scroll_line = self.doc.scroll_line.saturating_add(shown);
+                    }
+                }
+                self.doc.scroll_line = self.doc.scroll_line.min(max_anchor);
+                // Whole lines on screen from the first one
+                let shown_lines =
+                    self.lines_fitting(ui, reader, self.doc.scroll_line, page_rows, wrap_width);
+                if self.wrap_mode {
+                    self.visible_lines = shown_lines + 1;
+                }
+
+                // The scrollbar is drawn from the first line, not the other way round
+                let bar_width = ui.spacing().scroll.bar_width;
+                let bar_rect = egui::Rect::from_min_max(
+                    egui::pos2(full_rect.right() - bar_width, full_rect.top()),
+                    full_rect.right_bottom(),
+                );
+                if let Some(line) = line_scrollbar(
+                    ui,
+                    bar_rect,
+                    self.doc.scroll_line,
+                    max_anchor,
+                    shown_lines,
+                    total_lines,
+                ) {
+                    self.doc.scroll_line = line;
+                }
+                // Where the matches are across the whole file, next to the scrollbar
+                let mut text_right = bar_rect.left();
+                if self.doc.match_density.iter().any(|&count| count > 0) {
+                    let map_rect = egui::Rect::from_min_max(
+                        egui::pos2(bar_rect.left() - MINIMAP_WIDTH, full_rect.top()),
+                        egui::pos2(bar_rect.left(), full_rect.bottom()),
+                    );
+                    if let Some(fraction) = match_minimap(ui, map_rect, &self.doc.match_density) {
+                        let offset = (fraction * reader.len() as f64) as usize;
+                        self.doc.scroll_line = self.line_at_offset(offset).min(max_anchor);
+                    }
+                    text_right = map_rect.left();
+                }
+                let text_rect = full_rect.with_max_x(text_right - ui.spacing().item_spacing.x);
+
+                let scroll_area = egui::ScrollArea::new([!self.wrap_mode || fixed_wrap, false])
+                    // Tie scroll memory to the current file path so new files start at the left
+                    .id_salt(reader.path().display().to_string())
                     .auto_shrink([false, false])
                     .scroll_bar_visibility(egui::scroll_area::ScrollBarVisibility::AlwaysVisible)
                     .drag_to_scroll(true);
 
-                // Apply programmatic scroll if requested
-                let mut programmatic_scroll = false;
-                if let Some(target_row) = self.scroll_to_row.take() {
-                    scroll_area =
-                        scroll_area.vertical_scroll_offset(target_row as f32 * line_height);
-                    programmatic_scroll = true;
-                }
-
-                let mut first_visible_row = None;
+                let mut ruler_x = None;
+                let mut gutter_click = None;
+                let mut drawn_width = wrap_width;
+                let mut drawn_rows = Vec::new();
+                let anchor = self.doc.scroll_line;
+
+                let mut text_ui = ui.new_child(egui::UiBuilder::new().max_rect(text_rect));
+                let output = scroll_area.show(&mut text_ui, |ui| {
+                    // For contiguous rendering, we find the start offset of the first line
+                    // and then read sequentially.
+                    let Some((current_offset, _)) =
+                        self.doc.line_indexer.get_line_with_reader(anchor, reader)
+                    else {
+                        return;
+                    };
+                    let mut lines = reader.lines_from(current_offset);
+                    // One more than fits, for the partly visible row at the bottom
+                    let render_range = anchor..(anchor + shown_lines + 1).min(total_lines);
+
+                    for line_num in render_range {
+                        let Some((start, line_bytes)) = lines.next() else {
+                            break;
+                        };
+                        let end = start + line_bytes.len(); // Includes newline
+
+                        // Borrowed from the file unless it needs decoding or an edit
+                        let mut full_text = reader.get_chunk_str(start, end);
+
+                        // Apply pending replacements to the view. One may span several
+                        // lines (joining them, say): each line loses its part of the
+                        // old text and the new text shows on the line it starts on.
+                        // Line numbers only change once the file is saved and
+                        // re-indexed.
+                        for replacement in &self.doc.pending_replacements {
+                            let rep_start = replacement.offset;
+                            let rep_end = rep_start + replacement.old_len;
+                            let on_line = if rep_start >= start {
+                                rep_start < end
+                            } else {
+                                rep_end > start
+                            };
+                            if !on_line {
+                                continue;
+                            }
 
-                let output = scroll_area.show_rows(
-                    ui,
-                    line_height,
-                    self.line_indexer.total_lines(),
-                    |ui, row_range| {
-                        // Calculate scroll correction if we just jumped
-                        if let Some(target) = self.pending_scroll_target.take() {
-                            self.scroll_correction = target as i64 - row_range.start as i64;
+                            let rel_start = reader.decoded_len(start, rep_start.max(start));
+                            let rel_end = reader.decoded_len(start, rep_end.min(end));
+                            let new_text = if rep_start >= start {
+                                replacement.new_text.as_str()
+                            } else {
+                                ""
+                            };
+                            if full_text.is_char_boundary(rel_start)
+                                && full_text.is_char_boundary(rel_end)
+                            {
+                                full_text
+                                    .to_mut()
+                                    .replace_range(rel_start..rel_end, new_text);
+                            }
                         }
 
-                        // Apply correction to find the actual start line we want to render
-                        let corrected_start_line =
-                            (row_range.start as i64 + self.scroll_correction).max(0) as usize;
+                        let line_text = full_text.trim_end_matches('\n').trim_end_matches('\r');
+                        let line_ending = &full_text[line_text.len()..];
 
-                        // Capture the first visible row (corrected)
-                        if first_visible_row.is_none() {
-                            first_visible_row = Some(corrected_start_line);
-                        }
+                        // Collect matches that fall within this line's byte span; this works even with sparse line indexing
+                        let mut line_matches: Vec<(usize, usize, egui::Color32)> = Vec::new();
 
-                        // For contiguous rendering, we find the start offset of the first line
-                        // and then read sequentially.
-                        let mut current_offset = if let Some((start, _)) = self
-                            .line_indexer
-                            .get_line_with_reader(corrected_start_line, reader)
+                        // Determine the byte offset of the currently selected result
+                        let selected_offset = if self.doc.total_search_results > 0
+                            && self.doc.current_result_index >= self.doc.search_page_start_index
                         {
-                            start
+                            let local_idx =
+                                self.doc.current_result_index - self.doc.search_page_start_index;
+                            self.doc
+                                .search_results
+                                .get(local_idx)
+                                .map(|r| r.byte_offset)
                         } else {
-                            return;
+                            None
                         };
 
-                        // We iterate over the count of rows requested, but starting from our corrected line
-                        let count = row_range.end - row_range.start;
-                        let render_range = corrected_start_line..(corrected_start_line + count);
-
-                        for line_num in render_range {
-                            // Read line starting at current_offset
-                            // We need to find the end of the line
-                            let chunk_size = 4096; // Read in chunks to find newline
-                            let mut line_end = current_offset;
-                            let mut found_newline = false;
-
-                            // Scan for newline
-                            while !found_newline {
-                                let chunk = reader.get_bytes(line_end, line_end + chunk_size);
-                                if chunk.is_empty() {
+                        // Inverted results are whole lines, not occurrences of the query.
+                        // Otherwise every occurrence in view is highlighted, also after a
+                        // single find that fetched only the first; only the current
+                        // result gets the selected color.
+                        if !self.invert_match {
+                            // Positions in line_text are decoded offsets, not file offsets
+                            let selected_in_line = selected_offset
+                                .filter(|&offset| offset >= start && offset < end)
+                                .map(|offset| reader.decoded_len(start, offset));
+
+                            // Use find_in_text to find matches in the current line (highlight all visible)
+                            for (m_start, m_end, pattern) in
+                                self.doc.search_engine.find_patterns_in_text(line_text)
+                            {
+                                let is_selected = Some(m_start) == selected_in_line;
+                                // A highlight terms search colors each match by its term
+                                let color = match self.doc.search_pattern_colors.get(pattern) {
+                                    Some(&color) if !is_selected => color,
+                                    _ => match_color(is_selected),
+                                };
+                                line_matches.push((m_start, m_end, color));
+                            }
+                        } else {
+                            // Only highlight results present in search_results
+                            // Use binary search to find the first potential match
+                            // This assumes search_results is sorted by byte_offset
+                            let start_idx = self
+                                .doc
+                                .search_results
+                                .partition_point(|r| r.byte_offset < start);
+
+                            for (idx, res) in
+                                self.doc.search_results.iter().enumerate().skip(start_idx)
+                            {
+                                if res.byte_offset >= end {
                                     break;
                                 }
 
-                                if let Some(pos) = chunk.iter().position(|&b| b == b'\n') {
-                                    line_end += pos + 1; // Include newline
-                                    found_newline = true;
-                                } else {
-                                    line_end += chunk.len();
-                                }
-
-                                if line_end >= reader.len() {
-                                    break;
+                                let rel_start = reader.decoded_len(start, res.byte_offset);
+                                if rel_start >= line_text.len() {
+                                    continue;
                                 }
-                            }
+                                let rel_end = reader
+                                    .decoded_len(start, res.byte_offset + res.match_len)
+                                    .min(line_text.len());
 
-                            let start = current_offset;
-                            let end = line_end;
-                            current_offset = end; // Next line starts here
+                                // Check if this is the currently selected result
+                                // We need to map local index to global index
+                                let global_idx = self.doc.search_page_start_index + idx;
+                                let is_selected = global_idx == self.doc.current_result_index;
 
-                            if start >= reader.len() {
-                                break;
+                                line_matches.push((rel_start, rel_end, match_color(is_selected)));
                             }
+                        }
 
-                            let mut line_text_owned = reader.get_chunk(start, end);
-
-                            // Apply pending replacements to the view
-                            for replacement in &self.pending_replacements {
-                                let rep_start = replacement.offset;
-                                let rep_end = rep_start + replacement.old_len;
+                        // Highlight terms fill in around the search matches, in list order
+                        for term in &self.highlight_terms {
+                            let spans = term.engine.find_in_text(line_text);
+                            add_uncovered_spans(&mut line_matches, spans, term.color);
+                        }
 
-                                if rep_start >= start && rep_end <= end {
-                                    let rel_start = rep_start - start;
-                                    let rel_end = rep_end - start;
+                        // Laying out a huge line stalls the UI, so without wrapping only the
+                        // columns in view are shown, with space standing in for the rest
+                        let full_text = line_text;
+                        let (line_text, hidden_before, hidden_after) =
+                            if !self.wrap_mode && line_text.len() > LONG_LINE_BYTES {
+                                let (first, count) = view_columns;
+                                let first = first.saturating_sub(LONG_LINE_MARGIN);
+                                let (range, before, after) =
+                                    visible_columns(line_text, first, count + 2 * LONG_LINE_MARGIN);
+                                clip_spans(&mut line_matches, &range);
+                                (&line_text[range], before, after)
+                            } else {
+                                (line_text, 0, 0)
+                            };
 
-                                    if line_text_owned.is_char_boundary(rel_start)
-                                        && line_text_owned.is_char_boundary(rel_end)
-                                    {
-                                        line_text_owned.replace_range(
-                                            rel_start..rel_end,
-                                            &replacement.new_text,
-                                        );
-                                    }
+                        ui.horizontal(|ui| {
+                            // Gutter: a marker column once anything is bookmarked, then the number
+                            let bookmarked = self.doc.bookmarks.contains(&line_num);
+                            if self.show_line_numbers || !self.doc.bookmarks.is_empty() {
+                                let mut gutter = String::new();
+                                if !self.doc.bookmarks.is_empty() {
+                                    gutter.push(if bookmarked { '●' } else { ' ' });
+                                }
+                                if self.show_line_numbers {
+                                    gutter.push_str(&format!("{:6} ", line_num + 1));
+                                }
+                                let color = if bookmarked {
+                                    egui::Color32::GOLD
+                                } else {
+                                    egui::Color32::DARK_GRAY
+                                };
+                                let mut ln_text =
+                                    egui::RichText::new(gutter).monospace().color(color);
+                                let selected = self.doc.line_selection.is_some_and(|(a, b)| {
+                                    (a.min(b)..=a.max(b)).contains(&line_num)
+                                });
+                                if selected {
+                                    ln_text = ln_text
+                                        .color(ui.visuals().selection.stroke.color)
+                                        .background_color(ui.visuals().selection.bg_fill);
+                                }
+                                // Make line numbers non-selectable so drag-select only captures
+                                // the content text; clicking one selects its whole line instead
+                                let number = ui
+                                    .add(
+                                        egui::Label::new(ln_text)
+                                            .selectable(false)
+                                            .sense(egui::Sense::click()),
+                                    )
+                                    .on_hover_cursor(egui::CursorIcon::PointingHand);
+                                if number.clicked() {
+                                    let shift = ui.input(|i| i.modifiers.shift);
+                                    gutter_click = Some((line_num, shift));
                                 }
                             }
 
-                            let line_text = line_text_owned
-                                .trim_end_matches('\n')
-                                .trim_end_matches('\r');
-
-                            // Collect matches that fall within this line's byte span; this works even with sparse line indexing
-                            let mut line_matches: Vec<(usize, usize, bool)> = Vec::new();
+                            if hidden_before > 0 {
+                                let spacing = ui.spacing().item_spacing.x;
+                                ui.add_space(
+                                    (hidden_before as f32 * char_width - spacing).max(0.0),
+                                );
+                            }
 
-                            // Determine the byte offset of the currently selected result
-                            let selected_offset = if self.total_search_results > 0
-                                && self.current_result_index >= self.search_page_start_index
-                            {
-                                let local_idx =
-                                    self.current_result_index - self.search_page_start_index;
-                                self.search_results.get(local_idx).map(|r| r.byte_offset)
+                            // Build label with highlighted search matches over the
+                            // syntax colors. Only the text in view is tokenized.
+                            let mut syntax_spans = syntax
+                                .map(|format| highlight_line(line_text, format, self.dark_mode))
+                                .unwrap_or_default();
+                            // The markers only go on once the end of the line is in view
+                            let marked;
+                            let line_text = if self.show_whitespace && hidden_after == 0 {
+                                marked = mark_whitespace(
+                                    line_text,
+                                    line_ending,
+                                    &mut line_matches,
+                                    &mut syntax_spans,
+                                    ui.visuals().weak_text_color(),
+                                );
+                                marked.as_str()
                             } else {
-                                None
+                                line_text
                             };
-
-                            if self.search_find_all {
-                                // Use find_in_text to find matches in the current line (highlight all visible)
-                                for (m_start, m_end) in self.search_engine.find_in_text(line_text) {
-                                    let abs_start = start + m_start;
-                                    let is_selected = Some(abs_start) == selected_offset;
-                                    line_matches.push((m_start, m_end, is_selected));
-                                }
+                            let text = if !line_matches.is_empty() || !syntax_spans.is_empty() {
+                                let default_color = if self.dark_mode {
+                                    egui::Color32::LIGHT_GRAY
+                                } else {
+                                    egui::Color32::BLACK
+                                };
+                                egui::WidgetText::from(styled_line_job(
+                                    line_text,
+                                    &line_matches,
+                                    &syntax_spans,
+                                    egui::FontId::monospace(self.font_size),
+                                    default_color,
+                                ))
                             } else {
-                                // Only highlight results present in search_results (e.g. single find)
-                                // Use binary search to find the first potential match
-                                // This assumes search_results is sorted by byte_offset
-                                let start_idx = self
-                                    .search_results
-                                    .partition_point(|r| r.byte_offset < start);
-
-                                for (idx, res) in
-                                    self.search_results.iter().enumerate().skip(start_idx)
-                                {
-                                    if res.byte_offset >= end {
-                                        break;
-                                    }
-
-                                    let rel_start = res.byte_offset.saturating_sub(start);
-                                    if rel_start >= line_text.len() {
-                                        continue;
-                                    }
-                                    let rel_end = (rel_start + res.match_len).min(line_text.len());
+                                egui::RichText::new(line_text)
+                                    .monospace()
+                                    .size(self.font_size)
+                                    .into()
+                            };
 
-                                    // Check if this is the currently selected result
-                                    // We need to map local index to global index
-                                    let global_idx = self.search_page_start_index + idx;
-                                    let is_selected = global_idx == self.current_result_index;
+                            // Apply wrap mode. A label wraps at the width it's given, so
+                            // fixed-column wrapping lays it out in a column-wide child ui.
+                            let label = if fixed_wrap {
+                                let indent = self.wrap_indent_width(char_width, column_width);
+                                ui.allocate_ui(egui::vec2(column_width, line_height), |ui| {
+                                    add_wrapped_label(ui, text, indent)
+                                })
+                                .inner
+                            } else if self.wrap_mode {
+                                drawn_width = ui.available_width();
+                                let indent = self.wrap_indent_width(char_width, drawn_width);
+                                add_wrapped_label(ui, text, indent)
+                            } else {
+                                ui.add(egui::Label::new(text).extend())
+                            };
+                            if self.wrap_mode {
+                                let rows = (label.rect.height() / line_height).round().max(1.0);
+                                drawn_rows.push((line_num, rows as usize));
+                            }
+                            if ruler_x.is_none() {
+                                let text_left =
+                                    label.rect.left() - hidden_before as f32 * char_width;
+                                ruler_x = Some(text_left + column_width);
+                            }
+                            if hidden_after > 0 {
+                                ui.add_space(hidden_after as f32 * char_width);
+                            }
 
-                                    line_matches.push((rel_start, rel_end, is_selected));
-                                }
+                            // Enable text selection for copy-paste
+                            if label.hovered() {
+                                ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::Text);
                             }
 
-                            ui.horizontal(|ui| {
-                                if self.show_line_numbers {
-                                    let ln_text =
-                                        egui::RichText::new(format!("{:6} ", line_num + 1))
-                                            .monospace()
-                                            .color(egui::Color32::DARK_GRAY);
-                                    // Make line numbers non-selectable so drag-select only captures the content text
-                                    ui.add(egui::Label::new(ln_text).selectable(false));
+                            // Right-click to copy a reference to this line
+                            label.context_menu(|ui| {
+                                if ui.button("Copy Line Reference").clicked() {
+                                    ui.ctx().copy_text(format_line_reference(
+                                        Some(reader.path()),
+                                        line_num,
+                                        full_text,
+                                    ));
+                                    ui.close_menu();
                                 }
+                                if ui.button("Copy Line with Number").clicked() {
+                                    ui.ctx().copy_text(format_line_reference(
+                                        None, line_num, full_text,
+                                    ));
+                                    ui.close_menu();
+                                }
+                            });
 
-                                // Build label with highlighted search matches
-                                let label = if !line_matches.is_empty() {
-                                    // Create a LayoutJob to highlight matches within the line using their byte offsets
-                                    let mut job = egui::text::LayoutJob::default();
-                                    let mut last_end = 0;
-
-                                    for (abs_start, abs_end, is_selected) in line_matches.iter() {
-                                        if *abs_start > last_end {
-                                            job.append(
-                                                &line_text[last_end..*abs_start],
-                                                0.0,
-                                                egui::TextFormat {
-                                                    font_id: egui::FontId::monospace(
-                                                        self.font_size,
-                                                    ),
-                                                    color: if self.dark_mode {
-                                                        egui::Color32::LIGHT_GRAY
-                                                    } else {
-                                                        egui::Color32::BLACK
-                                                    },
-                                                    ..Default::default()
-                                                },
-                                            );
-                                        }
-
-                                        let match_end = (*abs_end).min(line_text.len());
-                                        job.append(
-                                            &line_text[*abs_start..match_end],
-                                            0.0,
-                                            egui::TextFormat {
-                                                font_id: egui::FontId::monospace(self.font_size),
-                                                color: egui::Color32::BLACK,
-                                                background: if *is_selected {
-                                                    egui::Color32::from_rgb(255, 200, 0)
-                                                // orange-ish for current match
-                                                } else {
-                                                    egui::Color32::YELLOW
-                                                },
-                                                ..Default::default()
-                                            },
-                                        );
-
-                                        last_end = match_end;
-                                    }
-
-                                    // Add remaining text after last match
-                                    if last_end < line_text.len() {
-                                        job.append(
-                                            &line_text[last_end..],
-                                            0.0,
-                                            egui::TextFormat {
-                                                font_id: egui::FontId::monospace(self.font_size),
-                                                color: if self.dark_mode {
-                                                    egui::Color32::LIGHT_GRAY
-                                                } else {
-                                                    egui::Color32::BLACK
-                                                },
-                                                ..Default::default()
-                                            },
-                                        );
-                                    }
-
-                                    if self.wrap_mode {
-                                        job.wrap = egui::text::TextWrapping {
-                                            max_width: ui.available_width(),
-                                            ..Default::default()
-                                        };
-                                    }
-
-                                    ui.add(egui::Label::new(job).extend())
-                                } else {
-                                    let text = egui::RichText::new(line_text)
-                                        .monospace()
-                                        .size(self.font_size);
-
-                                    // Apply wrap mode
-                                    if self.wrap_mode {
-                                        ui.add(egui::Label::new(text).wrap())
-                                    } else {
-                                        ui.add(egui::Label::new(text).extend())
-                                    }
-                                };
+                            // Ensure labels don't consume scroll events
+                            label.surrender_focus();
+                        });
+                    }
+                });
 
-                                // Enable text selection for copy-paste
-                                if label.hovered() {
-                                    ui.output_mut(|o| o.cursor_icon = egui::CursorIcon::Text);
-                                }
+                if let Some((line_num, extend)) = gutter_click {
+                    self.select_gutter_line(line_num, extend);
+                }
 
-                                // Ensure labels don't consume scroll events
-                                label.surrender_focus();
-                            });
-                        }
-                    },
-                );
+                // The header row covers the first line; clicking it goes to the header
+                if let Some((header_line, header_text)) = self.sticky_header_line(reader) {
+                    let rect = output
+                        .inner_rect
+                        .with_max_y(output.inner_rect.top() + line_height);
+                    let painter = ui.painter_at(output.inner_rect);
+                    painter.rect_filled(rect, 0.0, ui.visuals().faint_bg_color);
+                    let text = if self.show_line_numbers {
+                        format!("{:6} {}", header_line + 1, header_text)
+                    } else {
+                        header_text
+                    };
+                    painter.text(
+                        rect.left_center() + egui::vec2(ui.spacing().item_spacing.x, 0.0),
+                        egui::Align2::LEFT_CENTER,
+                        text,
+                        font_id.clone(),
+                        ui.visuals().strong_text_color(),
+                    );
+                    let stroke = egui::Stroke::new(1.0, ui.visuals().weak_text_color());
+                    painter.hline(rect.x_range(), rect.bottom(), stroke);
+                    let header = ui
+                        .interact(rect, ui.id().with("sticky_header"), egui::Sense::click())
+                        .on_hover_cursor(egui::CursorIcon::PointingHand)
+                        .on_hover_text("Go to this line");
+                    if header.clicked() {
+                        self.doc.scroll_line = header_line;
+                    }
+                }
 
-                // Check for manual scroll
-                let current_offset = output.state.offset.y;
-                if !programmatic_scroll && (current_offset - self.last_scroll_offset).abs() > 1.0 {
-                    // Manual scroll detected (drag or wheel)
-                    // Reset correction as user is establishing new position
-                    self.scroll_correction = 0;
+                // Drawn lines are measured exactly, replacing their estimates. A new
+                // width takes effect next frame, when the cache starts over.
+                if drawn_width == wrap_width {
+                    self.wrap_rows.extend(drawn_rows);
+                } else if !fixed_wrap {
+                    self.wrap_width = drawn_width;
+                    ctx.request_repaint();
                 }
-                self.last_scroll_offset = current_offset;
 
-                // Update scroll_line to match what was actually displayed
-                if let Some(first_row) = first_visible_row {
-                    self.scroll_line = first_row;
+                if let Some(x) = ruler_x.filter(|_| self.show_ruler) {
+                    let rect = output.inner_rect;
+                    let stroke = egui::Stroke::new(1.0, ui.visuals().weak_text_color());
+                    ui.painter_at(rect).vline(x, rect.y_range(), stroke);
                 }
+
+                self.doc.last_scroll_offset_x = output.state.offset.x;
             } else {
                 ui.centered_and_justified(|ui| {
                     ui.heading("Large Text Viewer");
@@ -1635,6 +5468,93 @@ impl TextViewerApp {
         });
     }
 
+    // The header above the first line in view, starting a lookup whenever
+    // that line, the pattern or the file changed. None while it's being
+    // looked up, as well as when there's none.
+    fn sticky_header_line(&mut self, reader: &Arc<FileReader>) -> Option<(usize, String)> {
+        let pattern = self.header_pattern.trim();
+        if !self.sticky_header || pattern.is_empty() || self.doc.scroll_line == 0 {
+            self.header_lookup = None;
+            return None;
+        }
+        let current = self.header_lookup.as_ref().is_some_and(|lookup| {
+            lookup.first_line == self.doc.scroll_line
+                && lookup.pattern == pattern
+                && Weak::ptr_eq(&lookup.reader, &Arc::downgrade(reader))
+        });
+        if !current {
+            self.header_lookup = None;
+            let (first_start, _) = self
+                .doc
+                .line_indexer
+                .get_line_with_reader(self.doc.scroll_line, reader)?;
+            let mut engine = SearchEngine::new();
+            engine.set_query(pattern.to_string(), true, true, false);
+            // So `^` anchors at line starts
+            engine.set_regex_flags(RegexFlags {
+                multiline: true,
+                ..RegexFlags::default()
+            });
+            let (tx, rx) = std::sync::mpsc::sync_channel(2);
+            let cancel = Arc::new(AtomicBool::new(false));
+            engine.fetch_matches_before(reader.clone(), tx, first_start, 1, cancel.clone());
+            self.header_lookup = Some(HeaderLookup {
+                reader: Arc::downgrade(reader),
+                first_line: self.doc.scroll_line,
+                pattern: pattern.to_string(),
+                rx: Some(rx),
+                cancel,
+                header: None,
+            });
+        }
+
+        let lookup = self.header_lookup.as_mut()?;
+        if let Some(rx) = &lookup.rx {
+            let mut found = None;
+            let mut finished = false;
+            while let Ok(message) = rx.try_recv() {
+                match message {
+                    SearchMessage::ChunkResult(chunk) => found = chunk.matches.last().cloned(),
+                    SearchMessage::Done(_) | SearchMessage::Error(_) => finished = true,
+                    _ => {}
+                }
+            }
+            if finished {
+                lookup.rx = None;
+            }
+            if let Some(found) = found {
+                let line = self
+                    .doc
+                    .line_indexer
+                    .find_line_at_offset_with_reader(found.byte_offset, reader);
+                lookup.header = self
+                    .doc
+                    .line_indexer
+                    .get_line_with_reader(line, reader)
+                    .map(|(start, end)| {
+                        let end = end.min(start + HEADER_PREVIEW_BYTES);
+                        let text = reader.get_chunk_str(start, end);
+                        (line, text.trim_end_matches(['\r', '\n']).to_string())
+                    });
+            }
+        }
+        lookup.header.clone()
+    }
+
+    fn set_encoding(&mut self, encoding: &'static Encoding) {
+        self.doc.selected_encoding = encoding;
+        self.doc.encoding_detection = None;
+
+        // Reload file with new encoding
+        if let Some(reader) = self.doc.file_reader.clone() {
+            if reader.has_file() {
+                self.open_file(reader.path().clone());
+            } else {
+                self.show_reader(FileReader::from_bytes(reader.all_data().to_vec(), encoding));
+            }
+        }
+    }
+
     fn render_encoding_selector(&mut self, ctx: &egui::Context) {
         if self.show_encoding_selector {
             egui::Window::new("Select Encoding")
@@ -1643,17 +5563,13 @@ impl TextViewerApp {
                 .show(ctx, |ui| {
                     for (name, encoding) in available_encodings() {
                         if ui
-                            .selectable_label(std::ptr::eq(self.selected_encoding, encoding), name)
+                            .selectable_label(
+                                std::ptr::eq(self.doc.selected_encoding, encoding),
+                                name,
+                            )
                             .clicked()
                         {
-                            self.selected_encoding = encoding;
-
-                            // Reload file with new encoding
-                            if let Some(ref reader) = self.file_reader {
-                                let path = reader.path().clone();
-                                self.open_file(path);
-                            }
-
+                            self.set_encoding(encoding);
                             self.show_encoding_selector = false;
                         }
                     }
@@ -1665,9 +5581,73 @@ impl TextViewerApp {
         }
     }
 
+    fn render_open_range_dialog(&mut self, ctx: &egui::Context) {
+        if !self.show_open_range {
+            return;
+        }
+        egui::Window::new("Open Byte Range")
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label("Map only bytes [start, end) of a file:");
+                egui::Grid::new("open_range_grid").show(ui, |ui| {
+                    ui.label("Start offset:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.open_range_start_input)
+                            .desired_width(160.0)
+                            .hint_text("0"),
+                    );
+                    ui.end_row();
+                    ui.label("End offset:");
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.open_range_end_input)
+                            .desired_width(160.0)
+                            .hint_text("end of file"),
+                    );
+                    ui.end_row();
+                });
+
+                ui.horizontal(|ui| {
+                    if ui.button("Choose File...").clicked() {
+                        let start = self.open_range_start_input.trim();
+                        let end = self.open_range_end_input.trim();
+                        let start = if start.is_empty() {
+                            Ok(0)
+                        } else {
+                            start.parse::<usize>()
+                        };
+                        let end = if end.is_empty() {
+                            Ok(usize::MAX)
+                        } else {
+                            end.parse::<usize>()
+                        };
+
+                        match (start, end) {
+                            (Ok(start), Ok(end)) if start < end => {
+                                if let Some(path) = rfd::FileDialog::new().pick_file() {
+                                    self.prepare_tab();
+                                    self.doc.open_window = Some((start, end));
+                                    self.detect_file_encoding(&path);
+                                    self.open_file(path);
+                                    self.show_open_range = false;
+                                }
+                            }
+                            _ => {
+                                self.status_message = "Invalid byte range".to_string();
+                            }
+                        }
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.show_open_range = false;
+                    }
+                });
+            });
+    }
+
     fn render_file_info(&mut self, ctx: &egui::Context) {
         if self.show_file_info {
-            if let Some(ref reader) = self.file_reader {
+            let line_count = self.line_count_label();
+            if let Some(ref reader) = self.doc.file_reader {
                 egui::Window::new("File Information")
                     .collapsible(false)
                     .resizable(false)
@@ -1678,8 +5658,44 @@ impl TextViewerApp {
                             reader.len(),
                             reader.len() as f64 / 1_000_000.0
                         ));
-                        ui.label(format!("Lines: ~{}", self.line_indexer.total_lines()));
-                        ui.label(format!("Encoding: {}", reader.encoding().name()));
+                        if let Some((start, end)) = reader.window() {
+                            ui.label(format!(
+                                "Window: bytes {}..{} of the file (offsets shown relative to {})",
+                                start, end, start
+                            ));
+                        }
+                        if reader.is_compressed() {
+                            ui.label(
+                                "Compressed: gzip (size and offsets are of the decompressed text)",
+                            );
+                        }
+                        ui.label(format!("Lines: {}", line_count));
+                        ui.label(format!(
+                            "Line index: every {} line(s), {:.2} MB",
+                            self.doc.line_indexer.line_stride(),
+                            self.doc.line_indexer.index_size_bytes() as f64 / 1_000_000.0
+                        ));
+                        match self.doc.encoding_detection {
+                            Some(detected) if detected.encoding == reader.encoding() => {
+                                ui.label(format!(
+                                    "Encoding: {} ({})",
+                                    reader.encoding().name(),
+                                    detected.reason.description()
+                                ));
+                                if detected.confidence < 1.0 {
+                                    ui.label(format!(
+                                        "Detection confidence: {:.0}%",
+                                        detected.confidence * 100.0
+                                    ));
+                                }
+                            }
+                            _ => {
+                                ui.label(format!(
+                                    "Encoding: {} (selected manually)",
+                                    reader.encoding().name()
+                                ));
+                            }
+                        }
 
                         if ui.button("Close").clicked() {
                             self.show_file_info = false;
@@ -1690,6 +5706,334 @@ impl TextViewerApp {
     }
 }
 
+// Single-line excerpt of at most `max_chars` characters
+// The last line of a regex error, which names the problem; the lines before
+// it repeat the pattern and point at the offending spot
+fn error_summary(error: &str) -> &str {
+    error.lines().last().unwrap_or(error).trim()
+}
+
+fn match_color(is_selected: bool) -> egui::Color32 {
+    if is_selected {
+        egui::Color32::from_rgb(255, 200, 0) // orange-ish for current match
+    } else {
+        egui::Color32::YELLOW
+    }
+}
+
+// Adds the parts of `candidates` not yet covered by `spans`, keeping `spans`
+// sorted by start. Both are byte ranges on char boundaries of the same line.
+fn add_uncovered_spans(
+    spans: &mut Vec<(usize, usize, egui::Color32)>,
+    candidates: Vec<(usize, usize)>,
+    color: egui::Color32,
+) {
+    for (mut start, end) in candidates {
+        let mut pieces = Vec::new();
+        for &(taken_start, taken_end, _) in spans.iter() {
+            if taken_end <= start || taken_start >= end {
+                continue;
+            }
+            if taken_start > start {
+                pieces.push((start, taken_start, color));
+            }
+            start = start.max(taken_end);
+        }
+        if start < end {
+            pieces.push((start, end, color));
+        }
+        spans.extend(pieces);
+        spans.sort_by_key(|&(start, _, _)| start);
+    }
+}
+
+// A vertical scrollbar for `total` lines with `first` at the top. The thumb is
+// placed from the line number, so it never drifts from what's shown. Returns
+// the new first line while it's clicked or dragged.
+// Everything `rx` has ready, and whether its sender is gone
+fn drain_chunks(rx: &Receiver<Vec<u8>>) -> (Vec<u8>, bool) {
+    let mut data = Vec::new();
+    loop {
+        match rx.try_recv() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(std::sync::mpsc::TryRecvError::Empty) => return (data, false),
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => return (data, true),
+        }
+    }
+}
+
+fn line_scrollbar(
+    ui: &mut egui::Ui,
+    rect: egui::Rect,
+    first: usize,
+    max_first: usize,
+    page_rows: usize,
+    total: usize,
+) -> Option<usize> {
+    let response = ui.interact(
+        rect,
+        ui.id().with("line_scrollbar"),
+        egui::Sense::click_and_drag(),
+    );
+    let min_thumb = ui.spacing().scroll.bar_width * 2.0;
+    let thumb_height = thumb_height(rect.height(), page_rows, total, min_thumb);
+    let travel = rect.height() - thumb_height;
+    let thumb_top = rect.top() + thumb_offset(first, max_first, travel as f64) as f32;
+    let thumb = egui::Rect::from_min_size(
+        egui::pos2(rect.left(), thumb_top),
+        egui::vec2(rect.width(), thumb_height),
+    );
+
+    let visuals = ui.style().interact(&response);
+    let rounding = rect.width() / 2.0;
+    ui.painter()
+        .rect_filled(rect, rounding, ui.visuals().extreme_bg_color);
+    ui.painter().rect_filled(thumb, rounding, visuals.bg_fill);
+
+    let pointer = response.interact_pointer_pos()?;
+    if max_first == 0 || travel <= 0.0 {
+        return None;
+    }
+    let offset = (pointer.y - rect.top() - thumb_height / 2.0) as f64;
+    Some(line_at_thumb_offset(offset, max_first, travel as f64))
+}
+
+// Height of the scrollbar thumb showing `page_rows` of `total` rows on a track
+// `track` high; no shorter than `min_thumb` unless the track is
+fn thumb_height(track: f32, page_rows: usize, total: usize, min_thumb: f32) -> f32 {
+    (track * page_rows as f32 / total.max(1) as f32).clamp(min_thumb.min(track), track)
+}
+
+// How far down its `travel` the thumb sits with `first` the top line. f64: an
+// f32 can't tell neighbouring lines apart in a file this size.
+fn thumb_offset(first: usize, max_first: usize, travel: f64) -> f64 {
+    if max_first == 0 {
+        return 0.0;
+    }
+    first.min(max_first) as f64 / max_first as f64 * travel
+}
+
+// The top line for the thumb `offset` down its `travel`, the inverse of
+// `thumb_offset`; offsets past either end are clamped
+fn line_at_thumb_offset(offset: f64, max_first: usize, travel: f64) -> usize {
+    if max_first == 0 || travel <= 0.0 {
+        return 0;
+    }
+    let fraction = (offset / travel).clamp(0.0, 1.0);
+    (fraction * max_first as f64).round() as usize
+}
+
+// A strip plotting `density`, the matches in each equal slice of the file, top
+// to bottom. Returns where it was clicked as a fraction of the file.
+fn match_minimap(ui: &mut egui::Ui, rect: egui::Rect, density: &[usize]) -> Option<f64> {
+    let response = ui
+        .interact(rect, ui.id().with("match_minimap"), egui::Sense::click())
+        .on_hover_text("Where the matches are; click to jump there");
+    let max = density.iter().copied().max().unwrap_or(0).max(1);
+    let bucket_height = rect.height() / density.len().max(1) as f32;
+    for (bucket, &count) in density.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        // Log scale, so a lone match still shows next to a dense cluster
+        let strength = ((count as f32).ln_1p() / (max as f32).ln_1p()).max(0.3);
+        let slice = egui::Rect::from_min_size(
+            egui::pos2(rect.left(), rect.top() + bucket as f32 * bucket_height),
+            egui::vec2(rect.width(), bucket_height.max(1.0)),
+        );
+        ui.painter()
+            .rect_filled(slice, 0.0, match_color(false).gamma_multiply(strength));
+    }
+
+    if !response.clicked() {
+        return None;
+    }
+    let pointer = response.interact_pointer_pos()?;
+    Some(((pointer.y - rect.top()) / rect.height()).clamp(0.0, 1.0) as f64)
+}
+
+// The byte range of columns `first..first + count` of `text`, and how many
+// columns fall before and after it. Every char counts as one column.
+fn visible_columns(
+    text: &str,
+    first: usize,
+    count: usize,
+) -> (std::ops::Range<usize>, usize, usize) {
+    let mut chars = text.char_indices().map(|(i, _)| i);
+    let start = chars.nth(first).unwrap_or(text.len());
+    let end = chars.nth(count.saturating_sub(1)).unwrap_or(text.len());
+    let before = text[..start].chars().count();
+    let after = text[end..].chars().count();
+    (start..end, before, after)
+}
+
+// A wrapping label whose rows after the first are moved right by `indent`
+// (and wrapped that much narrower), so a wrapped line reads apart from the
+// line below it
+fn add_wrapped_label(ui: &mut egui::Ui, text: egui::WidgetText, indent: f32) -> egui::Response {
+    if indent <= 0.0 {
+        return ui.add(egui::Label::new(text).wrap());
+    }
+    let mut job = text.into_layout_job(ui.style(), egui::FontSelection::Default, ui.text_valign());
+    job.wrap.max_width = ui.available_width() - indent;
+    let mut galley = ui.fonts(|f| f.layout_job(job));
+    if galley.rows.len() > 1 {
+        let shift = egui::vec2(indent, 0.0);
+        let shifted = Arc::make_mut(&mut galley);
+        for row in shifted.rows.iter_mut().skip(1) {
+            row.rect = row.rect.translate(shift);
+            row.visuals.mesh.translate(shift);
+            row.visuals.mesh_bounds = row.visuals.mesh_bounds.translate(shift);
+            for glyph in &mut row.glyphs {
+                glyph.pos.x += indent;
+            }
+            shifted.rect = shifted.rect.union(row.rect);
+            shifted.mesh_bounds = shifted.mesh_bounds.union(row.visuals.mesh_bounds);
+        }
+    }
+    ui.add(egui::Label::new(galley))
+}
+
+// A layout job for `text` with `backgrounds` (search matches, drawn in black)
+// and `foregrounds` (syntax colors). Both are sorted, non-overlapping byte
+// spans; the text is split wherever either of them starts or ends.
+fn styled_line_job(
+    text: &str,
+    backgrounds: &[(usize, usize, egui::Color32)],
+    foregrounds: &[(usize, usize, egui::Color32)],
+    font_id: egui::FontId,
+    default_color: egui::Color32,
+) -> egui::text::LayoutJob {
+    let mut cuts: Vec<usize> = backgrounds
+        .iter()
+        .chain(foregrounds)
+        .flat_map(|&(start, end, _)| [start, end])
+        .chain([0, text.len()])
+        .filter(|&cut| cut <= text.len())
+        .collect();
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let covering = |spans: &[(usize, usize, egui::Color32)], at: usize| {
+        let idx = spans.partition_point(|&(_, end, _)| end <= at);
+        spans
+            .get(idx)
+            .filter(|&&(start, _, _)| start <= at)
+            .map(|&(_, _, color)| color)
+    };
+
+    let mut job = egui::text::LayoutJob::default();
+    for piece in cuts.windows(2) {
+        let (start, end) = (piece[0], piece[1]);
+        let format = match covering(backgrounds, start) {
+            Some(background) => egui::TextFormat {
+                font_id: font_id.clone(),
+                color: egui::Color32::BLACK,
+                background,
+                ..Default::default()
+            },
+            None => egui::TextFormat {
+                font_id: font_id.clone(),
+                color: covering(foregrounds, start).unwrap_or(default_color),
+                ..Default::default()
+            },
+        };
+        job.append(&text[start..end], 0.0, format);
+    }
+    job
+}
+
+// `text` with its trailing spaces and tabs shown as `·` and `ending` (the line
+// break cut off it) as `¶` or `␍␊`, both in `color`. The spans are moved to
+// match; foreground ones give way to the markers.
+fn mark_whitespace(
+    text: &str,
+    ending: &str,
+    backgrounds: &mut [(usize, usize, egui::Color32)],
+    foregrounds: &mut Vec<(usize, usize, egui::Color32)>,
+    color: egui::Color32,
+) -> String {
+    let content_end = text.trim_end_matches([' ', '\t']).len();
+    let mut marked = text[..content_end].to_string();
+    marked.extend(std::iter::repeat_n('·', text.len() - content_end));
+    if ending.ends_with("\r\n") {
+        marked.push_str("␍␊");
+    } else if ending.ends_with('\n') {
+        marked.push('¶');
+    }
+    // Each whitespace byte became a two-byte middot
+    let moved = |at: usize| at + at.saturating_sub(content_end);
+    for (start, end, _) in backgrounds.iter_mut() {
+        (*start, *end) = (moved(*start), moved(*end));
+    }
+    clip_spans(foregrounds, &(0..content_end));
+    if content_end < marked.len() {
+        foregrounds.push((content_end, marked.len(), color));
+    }
+    marked
+}
+
+// Why the sticky header pattern doesn't compile, compiled as it's searched
+fn header_pattern_error(pattern: &str) -> Option<String> {
+    let flags = RegexFlags {
+        multiline: true,
+        ..RegexFlags::default()
+    };
+    pattern_error(pattern.trim(), true, false, flags)
+}
+
+// Cuts `spans` down to `range` and makes them relative to its start
+fn clip_spans(spans: &mut Vec<(usize, usize, egui::Color32)>, range: &std::ops::Range<usize>) {
+    spans.retain_mut(|(start, end, _)| {
+        *start = (*start).clamp(range.start, range.end) - range.start;
+        *end = (*end).clamp(range.start, range.end) - range.start;
+        start < end
+    });
+}
+
+// The first 4 KB of `path`, read from `start` in the raw file if given, else
+// from the start of its content (decompressed, for gzip files)
+fn read_prefix(path: &std::path::Path, start: Option<usize>) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::with_capacity(4096);
+    match start {
+        Some(start) => {
+            let mut file = std::fs::File::open(path)?;
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.take(4096).read_to_end(&mut buffer)?;
+        }
+        None => {
+            let (content, _) = open_content(path).map_err(std::io::Error::other)?;
+            content.take(4096).read_to_end(&mut buffer)?;
+        }
+    }
+    Ok(buffer)
+}
+
+// One CSV field, quoted when it holds a delimiter, quote or line break
+fn csv_field(text: &str) -> Cow<'_, str> {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\"")).into()
+    } else {
+        text.into()
+    }
+}
+
+fn shorten(text: &str, max_chars: usize) -> String {
+    let text = text.replace('\n', "⏎");
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+// grep/compiler style reference, e.g. `/var/log/syslog:42: message`
+fn format_line_reference(path: Option<&std::path::Path>, line_num: usize, text: &str) -> String {
+    match path {
+        Some(path) => format!("{}:{}: {}", path.display(), line_num + 1, text),
+        None => format!("{}: {}", line_num + 1, text),
+    }
+}
+
 //绑定数据结构
 impl eframe::App for TextViewerApp {
     // 每帧更新
@@ -1712,4 +6056,55 @@ impl eframe::App for TextViewerApp {
         // Render all UI components
         self.render_ui(ctx);
     }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if let Err(e) = self.settings().save() {
+            eprintln!("Error saving settings: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thumb_height() {
+        // In proportion to the page
+        assert_eq!(thumb_height(400.0, 50, 100, 20.0), 200.0);
+        // Never smaller than the minimum, nor taller than the track
+        assert_eq!(thumb_height(400.0, 50, 100_000_000, 20.0), 20.0);
+        assert_eq!(thumb_height(400.0, 50, 10, 20.0), 400.0);
+        assert_eq!(thumb_height(400.0, 50, 0, 20.0), 400.0);
+        // A track shorter than the minimum is filled
+        assert_eq!(thumb_height(10.0, 1, 100_000_000, 20.0), 10.0);
+    }
+
+    #[test]
+    fn test_thumb_offset_clamps() {
+        assert_eq!(thumb_offset(0, 0, 300.0), 0.0);
+        assert_eq!(thumb_offset(5, 10, 300.0), 150.0);
+        assert_eq!(thumb_offset(20, 10, 300.0), 300.0);
+        assert_eq!(line_at_thumb_offset(-50.0, 1000, 300.0), 0);
+        assert_eq!(line_at_thumb_offset(1e9, 1000, 300.0), 1000);
+        assert_eq!(line_at_thumb_offset(150.0, 1000, 300.0), 500);
+        assert_eq!(line_at_thumb_offset(150.0, 0, 300.0), 0);
+        assert_eq!(line_at_thumb_offset(150.0, 1000, 0.0), 0);
+    }
+
+    #[test]
+    fn test_thumb_round_trip_100m_lines() {
+        let total = 100_000_000;
+        let page_rows = 60;
+        let max_first = total - page_rows;
+        let travel = (900.0 - thumb_height(900.0, page_rows, total, 16.0)) as f64;
+        let lines = [0, 1, 12_345_678, 89_999_999, 90_000_000, max_first];
+        for first in lines {
+            let offset = thumb_offset(first, max_first, travel);
+            assert_eq!(line_at_thumb_offset(offset, max_first, travel), first);
+        }
+        // Neighbouring lines get distinct thumb positions
+        let offset = |first| thumb_offset(first, max_first, travel);
+        assert!(offset(90_000_001) > offset(90_000_000));
+    }
 }
@@ -0,0 +1,194 @@
+use eframe::egui;
+
+type ActionFn<T> = Box<dyn Fn(&mut T, &egui::Context)>;
+
+/// A named command that can be run from the palette.
+pub struct Action<T> {
+    pub name: String,
+    pub shortcut: Option<&'static str>,
+    run: ActionFn<T>,
+}
+
+impl<T> Action<T> {
+    pub fn run(&self, target: &mut T, ctx: &egui::Context) {
+        (self.run)(target, ctx)
+    }
+}
+
+/// Every action the palette can offer. Features add their entries with `register`.
+pub struct ActionRegistry<T> {
+    actions: Vec<Action<T>>,
+}
+
+impl<T> Default for ActionRegistry<T> {
+    fn default() -> Self {
+        Self {
+            actions: Vec::new(),
+        }
+    }
+}
+
+impl<T> ActionRegistry<T> {
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        shortcut: Option<&'static str>,
+        run: impl Fn(&mut T, &egui::Context) + 'static,
+    ) {
+        self.actions.push(Action {
+            name: name.into(),
+            shortcut,
+            run: Box::new(run),
+        });
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Action<T>> {
+        self.actions.get(index)
+    }
+
+    // Indices of matching actions, best match first
+    fn filter(&self, query: &str) -> Vec<usize> {
+        let mut scored: Vec<(i32, usize)> = self
+            .actions
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, action)| fuzzy_score(query, &action.name).map(|score| (score, idx)))
+            .collect();
+        // Stable sort keeps registration order among equal scores
+        scored.sort_by_key(|&(score, _)| std::cmp::Reverse(score));
+        scored.into_iter().map(|(_, idx)| idx).collect()
+    }
+}
+
+// Subsequence match, ignoring case. Consecutive characters and matches at word
+// starts score higher; `None` means some query character wasn't found.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let candidate: Vec<char> = candidate.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut prev_match: Option<usize> = None;
+
+    for q in query.chars().flat_map(char::to_lowercase) {
+        if q.is_whitespace() {
+            continue;
+        }
+        let found = pos + candidate[pos..].iter().position(|&c| c == q)?;
+        score += 1;
+        if prev_match.is_some_and(|prev| prev + 1 == found) {
+            score += 5;
+        }
+        if found == 0 || !candidate[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+        prev_match = Some(found);
+        pos = found + 1;
+    }
+    // Prefer shorter names when everything else is equal
+    Some(score * 100 - candidate.len() as i32)
+}
+
+#[derive(Default)]
+pub struct CommandPalette {
+    pub open: bool,
+    query: String,
+    selected: usize,
+    just_opened: bool,
+}
+
+impl CommandPalette {
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        if self.open {
+            self.query.clear();
+            self.selected = 0;
+            self.just_opened = true;
+        }
+    }
+
+    /// Draws the palette and returns the index of the action to run, if one was picked.
+    pub fn show<T>(&mut self, ctx: &egui::Context, registry: &ActionRegistry<T>) -> Option<usize> {
+        if !self.open {
+            return None;
+        }
+
+        let matches = registry.filter(&self.query);
+        let mut chosen = None;
+
+        let (up, down, enter, escape) = ctx.input_mut(|i| {
+            (
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp),
+                i.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown),
+                i.key_pressed(egui::Key::Enter),
+                i.key_pressed(egui::Key::Escape),
+            )
+        });
+        if up {
+            self.selected = self.selected.saturating_sub(1);
+        }
+        if down && self.selected + 1 < matches.len() {
+            self.selected += 1;
+        }
+        self.selected = self.selected.min(matches.len().saturating_sub(1));
+
+        egui::Window::new("Command Palette")
+            .title_bar(false)
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, [0.0, 60.0])
+            .fixed_size([420.0, 0.0])
+            .show(ctx, |ui| {
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.query)
+                        .hint_text("Type a command...")
+                        .desired_width(f32::INFINITY),
+                );
+                if self.just_opened {
+                    response.request_focus();
+                    self.just_opened = false;
+                }
+                if response.changed() {
+                    self.selected = 0;
+                }
+
+                ui.separator();
+
+                egui::ScrollArea::vertical()
+                    .max_height(320.0)
+                    .show(ui, |ui| {
+                        if matches.is_empty() {
+                            ui.weak("No matching commands");
+                        }
+                        for (row, &idx) in matches.iter().enumerate() {
+                            let Some(action) = registry.get(idx) else {
+                                continue;
+                            };
+                            let is_selected = row == self.selected;
+                            let label = ui.horizontal(|ui| {
+                                let label = ui.selectable_label(is_selected, &action.name);
+                                if let Some(shortcut) = action.shortcut {
+                                    ui.with_layout(
+                                        egui::Layout::right_to_left(egui::Align::Center),
+                                        |ui| ui.weak(shortcut),
+                                    );
+                                }
+                                label
+                            });
+                            if is_selected && (up || down) {
+                                label.inner.scroll_to_me(None);
+                            }
+                            if label.inner.clicked() {
+                                chosen = Some(idx);
+                            }
+                        }
+                    });
+            });
+
+        if enter {
+            chosen = chosen.or_else(|| matches.get(self.selected).copied());
+        }
+        if chosen.is_some() || escape {
+            self.open = false;
+        }
+        chosen
+    }
+}
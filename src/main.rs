@@ -1,7 +1,25 @@
 mod app;
+mod command_palette;
+mod settings;
+mod syntax;
 
 use app::TextViewerApp;
 use eframe::egui;
+use encoding_rs::Encoding;
+use settings::Settings;
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: large-text-viewer [+LINE] [--encoding NAME] [FILE | -]";
+
+// What the command line asks to open
+#[derive(Default)]
+struct Args {
+    file: Option<OsString>,
+    // 1-based
+    line: Option<usize>,
+    encoding: Option<&'static Encoding>,
+}
 
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions {
@@ -11,9 +29,96 @@ fn main() -> eframe::Result<()> {
         ..Default::default()
     };
 
+    let args = match parse_args(std::env::args_os().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // `large-text-viewer FILE`, or `-` / a pipe to read stdin (`journalctl | large-text-viewer`)
+    let mut app = TextViewerApp::default();
+    app.apply_settings(Settings::load());
+    match args.file {
+        Some(arg) if arg == "-" => app.open_stdin(args.encoding),
+        Some(arg) => match args.encoding {
+            Some(encoding) => app.open_path_as(PathBuf::from(arg), encoding),
+            None => app.open_path(PathBuf::from(arg)),
+        },
+        None if stdin_is_piped() => app.open_stdin(args.encoding),
+        None => {}
+    }
+    if let Some(line) = args.line {
+        app.open_at_line(line);
+    }
+
     eframe::run_native(
         "Large Text Viewer",
         options,
-        Box::new(|_cc| Ok(Box::new(TextViewerApp::default()))),
+        Box::new(|_cc| Ok(Box::new(app))),
     )
 }
+
+// `+N` and `--encoding NAME` (or `--encoding=NAME`) may come before or after
+// the file; anything after `--` is taken as the file name
+fn parse_args(args: impl IntoIterator<Item = OsString>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    let mut options = true;
+    while let Some(arg) = args.next() {
+        let text = arg.to_str().filter(|_| options);
+        if text == Some("--") {
+            options = false;
+        } else if let Some(line) = text.and_then(|t| t.strip_prefix('+')) {
+            let line = line
+                .parse()
+                .ok()
+                .filter(|&line| line > 0)
+                .ok_or_else(|| format!("invalid line number: +{}", line))?;
+            parsed.line = Some(line);
+        } else if text.is_some_and(|t| t == "--encoding" || t.starts_with("--encoding=")) {
+            let name = match text.and_then(|t| t.strip_prefix("--encoding=")) {
+                Some(name) => name.to_string(),
+                None => args
+                    .next()
+                    .ok_or("--encoding needs a name")?
+                    .to_string_lossy()
+                    .into_owned(),
+            };
+            let encoding = Encoding::for_label(name.as_bytes())
+                .ok_or_else(|| format!("unknown encoding: {}", name))?;
+            parsed.encoding = Some(encoding);
+        } else if text.is_some_and(|t| t.starts_with("--")) {
+            return Err(format!("unknown option: {}", arg.to_string_lossy()));
+        } else if parsed.file.is_none() {
+            parsed.file = Some(arg);
+        } else {
+            return Err(format!("unexpected argument: {}", arg.to_string_lossy()));
+        }
+    }
+    Ok(parsed)
+}
+
+// Without `-`, stdin is only read when something is piped or redirected into
+// it: a FIFO, or a regular file that isn't empty. A terminal, /dev/null or a
+// socket left there by whatever launched the viewer doesn't count.
+#[cfg(unix)]
+fn stdin_is_piped() -> bool {
+    use std::os::fd::AsFd;
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(fd) = std::io::stdin().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    std::fs::File::from(fd)
+        .metadata()
+        .is_ok_and(|meta| meta.file_type().is_fifo() || (meta.is_file() && meta.len() > 0))
+}
+
+#[cfg(not(unix))]
+fn stdin_is_piped() -> bool {
+    use std::io::IsTerminal;
+
+    !std::io::stdin().is_terminal()
+}
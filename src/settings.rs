@@ -0,0 +1,328 @@
+use std::path::{Path, PathBuf};
+
+/// Bounds for the fixed wrap column, in characters.
+pub const MIN_WRAP_COLUMN: usize = 20;
+pub const MAX_WRAP_COLUMN: usize = 400;
+
+/// Upper bound for the hanging indent of wrapped rows, in characters.
+pub const MAX_WRAP_INDENT: usize = 16;
+
+/// Search results loaded per page, and its bounds.
+pub const DEFAULT_PAGE_SIZE: usize = 1000;
+pub const MIN_PAGE_SIZE: usize = 1;
+pub const MAX_PAGE_SIZE: usize = 1_000_000;
+
+/// Searches remembered for recall in the search box.
+pub const MAX_SEARCH_HISTORY: usize = 50;
+
+/// UI preferences kept between sessions, stored as `key = value` lines (a small
+/// subset of TOML) in the platform config directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub font_size: f32,
+    pub dark_mode: bool,
+    pub wrap_mode: bool,
+    pub wrap_at_column: bool,
+    pub wrap_column: usize,
+    // Characters the continuation rows of a wrapped line are indented by
+    pub wrap_indent: usize,
+    pub show_ruler: bool,
+    pub show_line_numbers: bool,
+    pub syntax_highlighting: bool,
+    pub show_whitespace: bool,
+    pub sticky_header: bool,
+    pub header_pattern: String,
+    pub case_sensitive: bool,
+    pub use_regex: bool,
+    pub tail_mode: bool,
+    pub results_per_page: usize,
+    pub last_file: Option<PathBuf>,
+    // Oldest first
+    pub search_history: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            font_size: 14.0,
+            dark_mode: true,
+            wrap_mode: false,
+            wrap_at_column: false,
+            wrap_column: 80,
+            wrap_indent: 4,
+            show_ruler: false,
+            show_line_numbers: true,
+            syntax_highlighting: false,
+            show_whitespace: false,
+            sticky_header: false,
+            header_pattern: String::new(),
+            case_sensitive: false,
+            use_regex: false,
+            tail_mode: false,
+            results_per_page: DEFAULT_PAGE_SIZE,
+            last_file: None,
+            search_history: Vec::new(),
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the saved settings. A missing or unreadable file gives the
+    /// defaults, and so does any line that doesn't parse.
+    pub fn load() -> Self {
+        config_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map_or_else(Self::default, |text| Self::parse(&text))
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(path) = config_path() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_text())
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut settings = Self::default();
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "font_size" => {
+                    if let Some(size) = value.parse().ok().filter(|s: &f32| s.is_finite()) {
+                        settings.font_size = size.clamp(8.0, 32.0);
+                    }
+                }
+                "dark_mode" => set_bool(&mut settings.dark_mode, value),
+                "wrap_mode" => set_bool(&mut settings.wrap_mode, value),
+                "wrap_at_column" => set_bool(&mut settings.wrap_at_column, value),
+                "wrap_column" => {
+                    if let Ok(column) = value.parse::<usize>() {
+                        settings.wrap_column = column.clamp(MIN_WRAP_COLUMN, MAX_WRAP_COLUMN);
+                    }
+                }
+                "wrap_indent" => {
+                    if let Ok(indent) = value.parse::<usize>() {
+                        settings.wrap_indent = indent.min(MAX_WRAP_INDENT);
+                    }
+                }
+                "show_ruler" => set_bool(&mut settings.show_ruler, value),
+                "show_line_numbers" => set_bool(&mut settings.show_line_numbers, value),
+                "syntax_highlighting" => set_bool(&mut settings.syntax_highlighting, value),
+                "show_whitespace" => set_bool(&mut settings.show_whitespace, value),
+                "sticky_header" => set_bool(&mut settings.sticky_header, value),
+                "header_pattern" => {
+                    if let Some(pattern) = parse_string(value) {
+                        settings.header_pattern = pattern;
+                    }
+                }
+                "case_sensitive" => set_bool(&mut settings.case_sensitive, value),
+                "use_regex" => set_bool(&mut settings.use_regex, value),
+                "tail_mode" => set_bool(&mut settings.tail_mode, value),
+                "results_per_page" => {
+                    if let Ok(size) = value.parse::<usize>() {
+                        settings.results_per_page = size.clamp(MIN_PAGE_SIZE, MAX_PAGE_SIZE);
+                    }
+                }
+                "last_file" => settings.last_file = parse_string(value).map(PathBuf::from),
+                "search_history" => settings.search_history.extend(parse_string(value)),
+                _ => {}
+            }
+        }
+        let excess = settings
+            .search_history
+            .len()
+            .saturating_sub(MAX_SEARCH_HISTORY);
+        settings.search_history.drain(..excess);
+        settings
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "font_size = {}\ndark_mode = {}\nwrap_mode = {}\nwrap_at_column = {}\n\
+             wrap_column = {}\nwrap_indent = {}\nshow_ruler = {}\nshow_line_numbers = {}\n\
+             syntax_highlighting = {}\nshow_whitespace = {}\nsticky_header = {}\n\
+             case_sensitive = {}\nuse_regex = {}\ntail_mode = {}\nresults_per_page = {}\n",
+            self.font_size,
+            self.dark_mode,
+            self.wrap_mode,
+            self.wrap_at_column,
+            self.wrap_column,
+            self.wrap_indent,
+            self.show_ruler,
+            self.show_line_numbers,
+            self.syntax_highlighting,
+            self.show_whitespace,
+            self.sticky_header,
+            self.case_sensitive,
+            self.use_regex,
+            self.tail_mode,
+            self.results_per_page,
+        );
+        if !self.header_pattern.contains('\n') {
+            text.push_str(&format!(
+                "header_pattern = {}\n",
+                quote(&self.header_pattern)
+            ));
+        }
+        if let Some(path) = self.last_file.as_deref().and_then(Path::to_str) {
+            text.push_str(&format!("last_file = {}\n", quote(path)));
+        }
+        // One line per entry, as the format has no lists (nor room for line breaks)
+        for query in self.search_history.iter().filter(|q| !q.contains('\n')) {
+            text.push_str(&format!("search_history = {}\n", quote(query)));
+        }
+        text
+    }
+}
+
+fn set_bool(field: &mut bool, value: &str) {
+    if let Ok(value) = value.parse() {
+        *field = value;
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// A double-quoted string with `\\` and `\"` escapes
+fn parse_string(value: &str) -> Option<String> {
+    let inner = value.strip_prefix('"')?.strip_suffix('"')?;
+    let mut text = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(chars.next()?),
+            c => text.push(c),
+        }
+    }
+    Some(text)
+}
+
+fn config_path() -> Option<PathBuf> {
+    let env_dir = |name: &str| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    let base = if cfg!(windows) {
+        env_dir("APPDATA")?
+    } else if cfg!(target_os = "macos") {
+        env_dir("HOME")?.join("Library/Application Support")
+    } else {
+        env_dir("XDG_CONFIG_HOME").or_else(|| env_dir("HOME").map(|home| home.join(".config")))?
+    };
+    Some(base.join("large-text-viewer").join("settings.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let settings = Settings {
+            font_size: 18.5,
+            dark_mode: false,
+            wrap_mode: true,
+            wrap_at_column: true,
+            wrap_column: 120,
+            wrap_indent: 8,
+            show_ruler: true,
+            show_line_numbers: false,
+            syntax_highlighting: true,
+            show_whitespace: true,
+            sticky_header: true,
+            header_pattern: r#"^"id",\d+ = \\"#.to_string(),
+            case_sensitive: true,
+            use_regex: true,
+            tail_mode: true,
+            results_per_page: 250,
+            last_file: Some(PathBuf::from(r#"/tmp/a "quoted" \ path.log"#)),
+            search_history: vec![
+                "plain".to_string(),
+                r#"say "hi""#.to_string(),
+                r"C:\logs\".to_string(),
+                "key = value".to_string(),
+                String::new(),
+            ],
+        };
+        assert_eq!(Settings::parse(&settings.to_text()), settings);
+        assert_eq!(
+            Settings::parse(&Settings::default().to_text()),
+            Settings::default()
+        );
+    }
+
+    #[test]
+    fn test_line_breaks_are_not_saved() {
+        let settings = Settings {
+            header_pattern: "a\nb".to_string(),
+            search_history: vec!["one".to_string(), "two\nlines".to_string()],
+            ..Settings::default()
+        };
+        let parsed = Settings::parse(&settings.to_text());
+        assert_eq!(parsed.header_pattern, "");
+        assert_eq!(parsed.search_history, vec!["one".to_string()]);
+    }
+
+    #[test]
+    fn test_garbage_lines_are_skipped() {
+        let text = "not a setting\n\n# comment = true\nunknown_key = 3\n=\n \
+                    dark_mode = false \n[section]\nwrap_mode=true\n";
+        let settings = Settings::parse(text);
+        assert_eq!(
+            settings,
+            Settings {
+                dark_mode: false,
+                wrap_mode: true,
+                ..Settings::default()
+            }
+        );
+        assert_eq!(Settings::parse(""), Settings::default());
+    }
+
+    #[test]
+    fn test_bad_values_keep_defaults() {
+        let text = "font_size = big\ndark_mode = yes\nwrap_column = -5\nwrap_indent = 1.5\n\
+                    results_per_page = \nheader_pattern = unquoted\nlast_file = \"open\n\
+                    search_history = \"dangling \\\"\nsearch_history = \"ends in \\\n";
+        assert_eq!(Settings::parse(text), Settings::default());
+
+        let text = "font_size = NaN\nfont_size = inf\n";
+        assert_eq!(
+            Settings::parse(text).font_size,
+            Settings::default().font_size
+        );
+    }
+
+    #[test]
+    fn test_values_are_clamped() {
+        let text = "font_size = 100\nwrap_column = 1\nwrap_indent = 99\nresults_per_page = 0\n";
+        let settings = Settings::parse(text);
+        assert_eq!(settings.font_size, 32.0);
+        assert_eq!(settings.wrap_column, MIN_WRAP_COLUMN);
+        assert_eq!(settings.wrap_indent, MAX_WRAP_INDENT);
+        assert_eq!(settings.results_per_page, MIN_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_search_history_keeps_newest() {
+        let text: String = (0..MAX_SEARCH_HISTORY + 5)
+            .map(|i| format!("search_history = \"q{}\"\n", i))
+            .collect();
+        let history = Settings::parse(&text).search_history;
+        assert_eq!(history.len(), MAX_SEARCH_HISTORY);
+        assert_eq!(history.first().map(String::as_str), Some("q5"));
+        assert_eq!(
+            history.last().cloned(),
+            Some(format!("q{}", MAX_SEARCH_HISTORY + 4))
+        );
+    }
+}
@@ -0,0 +1,375 @@
+use eframe::egui::Color32;
+use std::path::Path;
+
+/// Formats that get per-line highlighting, picked from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyntaxFormat {
+    Json,
+    Log,
+    Csv,
+    Tsv,
+}
+
+impl SyntaxFormat {
+    /// `None` for anything else. A trailing `.gz` is looked through, so rotated
+    /// logs like `app.log.gz` count as logs.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+        match name.rsplit_once('.')?.1 {
+            "json" | "jsonl" | "ndjson" => Some(Self::Json),
+            "log" => Some(Self::Log),
+            "csv" => Some(Self::Csv),
+            "tsv" => Some(Self::Tsv),
+            _ => None,
+        }
+    }
+}
+
+// Foreground colors for one theme
+struct Palette {
+    key: Color32,
+    string: Color32,
+    number: Color32,
+    keyword: Color32,
+    punctuation: Color32,
+    timestamp: Color32,
+    error: Color32,
+    warning: Color32,
+    info: Color32,
+    debug: Color32,
+    columns: [Color32; 6],
+}
+
+const DARK: Palette = Palette {
+    key: Color32::from_rgb(120, 190, 255),
+    string: Color32::from_rgb(200, 220, 140),
+    number: Color32::from_rgb(240, 170, 110),
+    keyword: Color32::from_rgb(210, 150, 240),
+    punctuation: Color32::GRAY,
+    timestamp: Color32::from_rgb(120, 200, 200),
+    error: Color32::from_rgb(255, 110, 110),
+    warning: Color32::from_rgb(255, 200, 90),
+    info: Color32::from_rgb(130, 210, 130),
+    debug: Color32::GRAY,
+    columns: [
+        Color32::from_rgb(120, 190, 255),
+        Color32::from_rgb(200, 220, 140),
+        Color32::from_rgb(240, 170, 110),
+        Color32::from_rgb(210, 150, 240),
+        Color32::from_rgb(120, 200, 200),
+        Color32::from_rgb(240, 140, 170),
+    ],
+};
+
+const LIGHT: Palette = Palette {
+    key: Color32::from_rgb(0, 80, 170),
+    string: Color32::from_rgb(40, 120, 20),
+    number: Color32::from_rgb(170, 80, 0),
+    keyword: Color32::from_rgb(130, 40, 160),
+    punctuation: Color32::DARK_GRAY,
+    timestamp: Color32::from_rgb(0, 110, 120),
+    error: Color32::from_rgb(190, 0, 0),
+    warning: Color32::from_rgb(160, 100, 0),
+    info: Color32::from_rgb(20, 120, 20),
+    debug: Color32::DARK_GRAY,
+    columns: [
+        Color32::from_rgb(0, 80, 170),
+        Color32::from_rgb(40, 120, 20),
+        Color32::from_rgb(170, 80, 0),
+        Color32::from_rgb(130, 40, 160),
+        Color32::from_rgb(0, 110, 120),
+        Color32::from_rgb(170, 30, 90),
+    ],
+};
+
+/// Foreground spans for one line, as (start, end, color) byte ranges into
+/// `text`, sorted and not overlapping. Text outside them keeps the default color.
+/// Only looks at `text` itself, so a line cut out of a longer one is fine too.
+pub fn highlight_line(
+    text: &str,
+    format: SyntaxFormat,
+    dark: bool,
+) -> Vec<(usize, usize, Color32)> {
+    let palette = if dark { &DARK } else { &LIGHT };
+    match format {
+        SyntaxFormat::Json => json_spans(text, palette),
+        SyntaxFormat::Log => log_spans(text, palette),
+        SyntaxFormat::Csv => column_spans(text, b',', palette),
+        SyntaxFormat::Tsv => column_spans(text, b'\t', palette),
+    }
+}
+
+fn json_spans(text: &str, palette: &Palette) -> Vec<(usize, usize, Color32)> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        match bytes[i] {
+            b'"' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != b'"' {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(bytes.len());
+                // A string followed by a colon is an object key
+                let rest = text[i..].trim_start();
+                let color = if rest.starts_with(':') {
+                    palette.key
+                } else {
+                    palette.string
+                };
+                spans.push((start, i, color));
+            }
+            b'-' | b'0'..=b'9' => {
+                i += 1;
+                while i < bytes.len()
+                    && matches!(bytes[i], b'0'..=b'9' | b'.' | b'e' | b'E' | b'+' | b'-')
+                {
+                    i += 1;
+                }
+                spans.push((start, i, palette.number));
+            }
+            b'{' | b'}' | b'[' | b']' | b',' | b':' => {
+                i += 1;
+                spans.push((start, i, palette.punctuation));
+            }
+            b if b.is_ascii_alphabetic() => {
+                while i < bytes.len() && bytes[i].is_ascii_alphabetic() {
+                    i += 1;
+                }
+                if matches!(&text[start..i], "true" | "false" | "null") {
+                    spans.push((start, i, palette.keyword));
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    spans
+}
+
+fn log_spans(text: &str, palette: &Palette) -> Vec<(usize, usize, Color32)> {
+    let mut spans = Vec::new();
+    let mut words_from = 0;
+    if let Some(end) = timestamp_len(text) {
+        spans.push((0, end, palette.timestamp));
+        words_from = end;
+    }
+    let mut word_start = None;
+    for (i, c) in text[words_from..]
+        .char_indices()
+        .map(|(i, c)| (i + words_from, c))
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if c.is_alphanumeric() {
+            word_start.get_or_insert(i);
+            continue;
+        }
+        let Some(start) = word_start.take() else {
+            continue;
+        };
+        let color = match &text[start..i] {
+            "ERROR" | "ERR" | "FATAL" | "CRITICAL" | "CRIT" | "PANIC" => palette.error,
+            "WARN" | "WARNING" => palette.warning,
+            "INFO" | "NOTICE" => palette.info,
+            "DEBUG" | "TRACE" => palette.debug,
+            _ => continue,
+        };
+        spans.push((start, i, color));
+    }
+    spans
+}
+
+// Length of a leading timestamp such as `2024-05-01 12:00:00.123`,
+// `[2024-05-01T12:00:00Z]` or `12:00:00`: a run of digits and date/time
+// separators that starts with a digit and has at least six digits. Single
+// spaces inside are kept when a digit follows.
+fn timestamp_len(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let bracketed = bytes.first() == Some(&b'[');
+    let mut i = usize::from(bracketed);
+    if !bytes.get(i).is_some_and(u8::is_ascii_digit) {
+        return None;
+    }
+    let mut digits = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'0'..=b'9' => digits += 1,
+            b'-' | b':' | b'/' | b'.' | b',' | b'+' | b'T' | b'Z' => {}
+            b' ' if bytes.get(i + 1).is_some_and(u8::is_ascii_digit) => {}
+            _ => break,
+        }
+        i += 1;
+    }
+    if digits < 6 {
+        return None;
+    }
+    if bracketed && bytes.get(i) == Some(&b']') {
+        i += 1;
+    }
+    Some(i)
+}
+
+// Each field gets the color of its column, cycling through the palette.
+// Delimiters inside double-quoted fields don't end the field.
+fn column_spans(text: &str, delimiter: u8, palette: &Palette) -> Vec<(usize, usize, Color32)> {
+    let bytes = text.as_bytes();
+    let mut spans = Vec::new();
+    let mut column = 0;
+    let mut field_start = 0;
+    let mut quoted = false;
+    for (i, &b) in bytes.iter().enumerate() {
+        if b == b'"' {
+            quoted = !quoted;
+        } else if b == delimiter && !quoted {
+            let color = palette.columns[column % palette.columns.len()];
+            spans.push((field_start, i, color));
+            spans.push((i, i + 1, palette.punctuation));
+            column += 1;
+            field_start = i + 1;
+        }
+    }
+    let color = palette.columns[column % palette.columns.len()];
+    spans.push((field_start, bytes.len(), color));
+    spans.retain(|&(start, end, _)| start < end);
+    spans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Spans of `text` with their colors, checking they are sorted, don't
+    // overlap and fall on char boundaries
+    fn checked_spans(text: &str, format: SyntaxFormat) -> Vec<(&str, Color32)> {
+        let spans = highlight_line(text, format, true);
+        let mut last_end = 0;
+        for &(start, end, _) in &spans {
+            assert!(
+                last_end <= start && start < end && end <= text.len(),
+                "{:?}",
+                spans
+            );
+            assert!(text.is_char_boundary(start) && text.is_char_boundary(end));
+            last_end = end;
+        }
+        spans
+            .into_iter()
+            .map(|(start, end, color)| (&text[start..end], color))
+            .collect()
+    }
+
+    fn colored<'t>(spans: &[(&'t str, Color32)], color: Color32) -> Vec<&'t str> {
+        spans
+            .iter()
+            .filter(|&&(_, c)| c == color)
+            .map(|&(s, _)| s)
+            .collect()
+    }
+
+    #[test]
+    fn test_from_path() {
+        let format = |name: &str| SyntaxFormat::from_path(Path::new(name));
+        assert_eq!(format("data.JSONL"), Some(SyntaxFormat::Json));
+        assert_eq!(format("/var/log/app.log.gz"), Some(SyntaxFormat::Log));
+        assert_eq!(format("table.tsv"), Some(SyntaxFormat::Tsv));
+        assert_eq!(format("notes.txt"), None);
+        assert_eq!(format("Makefile"), None);
+    }
+
+    #[test]
+    fn test_json_spans() {
+        let text = r#"{"na\"me": "x\"y", "n": -1.5e3, "ok": true, "v": null}"#;
+        let spans = checked_spans(text, SyntaxFormat::Json);
+        assert_eq!(
+            colored(&spans, DARK.key),
+            vec![r#""na\"me""#, r#""n""#, r#""ok""#, r#""v""#]
+        );
+        assert_eq!(colored(&spans, DARK.string), vec![r#""x\"y""#]);
+        assert_eq!(colored(&spans, DARK.number), vec!["-1.5e3"]);
+        assert_eq!(colored(&spans, DARK.keyword), vec!["true", "null"]);
+
+        // An escaped backslash closes the string; one at the very end doesn't
+        let spans = checked_spans(r#"["a\\", "b\"#, SyntaxFormat::Json);
+        assert_eq!(colored(&spans, DARK.string), vec![r#""a\\""#, r#""b\"#]);
+        let spans = checked_spans(r#""\"#, SyntaxFormat::Json);
+        assert_eq!(colored(&spans, DARK.string), vec![r#""\"#]);
+    }
+
+    #[test]
+    fn test_json_non_ascii() {
+        let text = "{\"clé\": \"naïve \\é\", \"日本\": 1, trüe: 2}";
+        let spans = checked_spans(text, SyntaxFormat::Json);
+        assert_eq!(colored(&spans, DARK.key), vec!["\"clé\"", "\"日本\""]);
+        assert_eq!(colored(&spans, DARK.string), vec!["\"naïve \\é\""]);
+        assert_eq!(colored(&spans, DARK.number), vec!["1", "2"]);
+        assert!(colored(&spans, DARK.keyword).is_empty());
+        checked_spans("\"ü\\", SyntaxFormat::Json);
+    }
+
+    #[test]
+    fn test_timestamp_len() {
+        assert_eq!(timestamp_len("2024-05-01 12:00:00.123 INFO x"), Some(23));
+        assert_eq!(timestamp_len("2024-05-01T12:00:00Z rest"), Some(20));
+        assert_eq!(timestamp_len("[2024-05-01T12:00:00Z] rest"), Some(22));
+        assert_eq!(timestamp_len("[12:00:00 rest"), Some(9));
+        assert_eq!(timestamp_len("12:00:00"), Some(8));
+        // Too few digits, or not starting with one
+        assert_eq!(timestamp_len("12:00 up"), None);
+        assert_eq!(timestamp_len("[ 2024-05-01]"), None);
+        assert_eq!(timestamp_len("T2024-05-01"), None);
+        assert_eq!(timestamp_len(""), None);
+        assert_eq!(timestamp_len("["), None);
+        assert_eq!(timestamp_len("2024-05-01 12:00:00 ünïcode"), Some(19));
+    }
+
+    #[test]
+    fn test_log_spans() {
+        let text = "[2024-05-01 12:00:00] ERROR über WARNING: INFOx, débug DEBUG";
+        let spans = checked_spans(text, SyntaxFormat::Log);
+        assert_eq!(
+            colored(&spans, DARK.timestamp),
+            vec!["[2024-05-01 12:00:00]"]
+        );
+        assert_eq!(colored(&spans, DARK.error), vec!["ERROR"]);
+        assert_eq!(colored(&spans, DARK.warning), vec!["WARNING"]);
+        assert_eq!(colored(&spans, DARK.info), Vec::<&str>::new());
+        assert_eq!(colored(&spans, DARK.debug), vec!["DEBUG"]);
+
+        // Unbracketed, and a level right at the end
+        let spans = checked_spans("2024-05-01T12:00:00Z 日本 INFO", SyntaxFormat::Log);
+        assert_eq!(
+            colored(&spans, DARK.timestamp),
+            vec!["2024-05-01T12:00:00Z"]
+        );
+        assert_eq!(colored(&spans, DARK.info), vec!["INFO"]);
+    }
+
+    #[test]
+    fn test_column_spans() {
+        let text = "a,\"b, still b\",ç,,日本";
+        let spans = checked_spans(text, SyntaxFormat::Csv);
+        let fields: Vec<&str> = spans
+            .iter()
+            .filter(|&&(_, c)| c != DARK.punctuation)
+            .map(|&(s, _)| s)
+            .collect();
+        assert_eq!(fields, vec!["a", "\"b, still b\"", "ç", "日本"]);
+        assert_eq!(colored(&spans, DARK.punctuation).len(), 4);
+        // The empty fourth field still takes its column's color
+        assert_eq!(spans.last().map(|&(_, c)| c), Some(DARK.columns[4]));
+
+        let spans = checked_spans("x\t\"y\tz\"\t", SyntaxFormat::Tsv);
+        assert_eq!(
+            spans,
+            vec![
+                ("x", DARK.columns[0]),
+                ("\t", DARK.punctuation),
+                ("\"y\tz\"", DARK.columns[1]),
+                ("\t", DARK.punctuation),
+            ]
+        );
+        assert!(checked_spans("", SyntaxFormat::Csv).is_empty());
+    }
+}